@@ -0,0 +1,365 @@
+//! Derive macros for rowan, re-exported by rowan's `macros` feature.
+//!
+//! Only the shapes of items that make sense for AST nodes are supported.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, punctuated::Punctuated, Data, DataEnum, DeriveInput, Fields, Ident, Member,
+    Meta, Path, Token, Type, Visibility,
+};
+
+/// Derives `rowan::ast::AstNode`.
+///
+/// For a struct, the node is stored in its only field, and the kinds it
+/// accepts are given as a pattern:
+///
+/// ```ignore
+/// #[derive(AstNode)]
+/// #[ast(language = Lang, kind = SyntaxKind::FN | SyntaxKind::CLOSURE)]
+/// struct Callable(SyntaxNode<Lang>);
+/// ```
+///
+/// For an enum, every variant wraps another AST node type, and casting tries
+/// the variants in order:
+///
+/// ```ignore
+/// #[derive(AstNode)]
+/// #[ast(language = Lang)]
+/// enum Item { Fn(Fn), Struct(Struct) }
+/// ```
+#[proc_macro_derive(AstNode, attributes(ast))]
+pub fn derive_ast_node(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_ast_node(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// Derives `rowan::Language` for a `#[repr(u16)]` kind enum.
+///
+/// The language type is declared by the macro, and every other argument
+/// declares a classification set, as an `is_` method on the kind:
+///
+/// ```ignore
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Language)]
+/// #[repr(u16)]
+/// #[language(name = Lang, trivia = WHITESPACE | COMMENT)]
+/// enum SyntaxKind { WHITESPACE, COMMENT, IDENT, ROOT }
+/// ```
+///
+/// The `trivia`, `keyword` and `punct` sets also implement the hooks of the
+/// same names on `Language`, and `error = ERROR` implements `error_kind`.
+///
+/// The kind also gets `ALL`, `from_raw` and a `name` table.
+#[proc_macro_derive(Language, attributes(language))]
+pub fn derive_language(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_language(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand_ast_node(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "generic types are not supported"));
+    }
+    let mut language: Option<Type> = None;
+    let mut kind: Option<Punctuated<Path, Token![|]>> = None;
+    for attr in input.attrs.iter().filter(|it| it.path().is_ident("ast")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("language") {
+                language = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("kind") {
+                kind = Some(Punctuated::parse_separated_nonempty(meta.value()?)?);
+            } else {
+                return Err(meta.error("unknown argument"));
+            }
+            Ok(())
+        })?;
+    }
+    let name = &input.ident;
+    let language = language
+        .ok_or_else(|| syn::Error::new_spanned(name, "missing `#[ast(language = ...)]`"))?;
+
+    match &input.data {
+        Data::Struct(data) => {
+            let kind =
+                kind.ok_or_else(|| syn::Error::new_spanned(name, "missing `#[ast(kind = ...)]`"))?;
+            let field = struct_field(&data.fields)?;
+            Ok(expand_struct(name, &language, &kind, &field))
+        }
+        Data::Enum(data) => {
+            if let Some(kind) = kind {
+                return Err(syn::Error::new_spanned(
+                    kind,
+                    "the kinds of an enum are those of its variants",
+                ));
+            }
+            if data.variants.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "an AST node enum must have at least one variant",
+                ));
+            }
+            let variants = enum_variants(data)?;
+            Ok(expand_enum(name, &language, &variants))
+        }
+        Data::Union(data) => {
+            Err(syn::Error::new_spanned(data.union_token, "expected a struct or an enum"))
+        }
+    }
+}
+
+fn expand_language(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let kind = &input.ident;
+    let data = match &input.data {
+        Data::Enum(it) => it,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                kind,
+                "`Language` can only be derived for kind enums",
+            ))
+        }
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "generic types are not supported"));
+    }
+    let mut is_repr_u16 = false;
+    for attr in input.attrs.iter().filter(|it| it.path().is_ident("repr")) {
+        let reprs = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+        is_repr_u16 |= reprs.iter().any(|it| it.path().is_ident("u16"));
+    }
+    if !is_repr_u16 {
+        return Err(syn::Error::new_spanned(kind, "a kind enum must be `#[repr(u16)]`"));
+    }
+
+    let mut language: Option<Ident> = None;
+    let mut error: Option<Ident> = None;
+    let mut sets: Vec<(Ident, Punctuated<Ident, Token![|]>)> = Vec::new();
+    for attr in input.attrs.iter().filter(|it| it.path().is_ident("language")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                language = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("error") {
+                error = Some(meta.value()?.parse()?);
+            } else {
+                let set = meta.path.require_ident()?.clone();
+                sets.push((set, Punctuated::parse_separated_nonempty(meta.value()?)?));
+            }
+            Ok(())
+        })?;
+    }
+    let language = language
+        .ok_or_else(|| syn::Error::new_spanned(kind, "missing `#[language(name = ...)]`"))?;
+
+    let variants = data
+        .variants
+        .iter()
+        .map(|variant| match variant.fields {
+            Fields::Unit => Ok(&variant.ident),
+            _ => Err(syn::Error::new_spanned(variant, "every kind must be a unit variant")),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    if variants.is_empty() {
+        return Err(syn::Error::new_spanned(kind, "a kind enum must have at least one variant"));
+    }
+    Ok(gen_language(&input.vis, kind, &language, &variants, &sets, error.as_ref()))
+}
+
+fn gen_language(
+    vis: &Visibility,
+    kind: &Ident,
+    language: &Ident,
+    variants: &[&Ident],
+    sets: &[(Ident, Punctuated<Ident, Token![|]>)],
+    error: Option<&Ident>,
+) -> TokenStream2 {
+    let raws = variants.iter().map(|it| format_ident!("__RAW_{}", it)).collect::<Vec<_>>();
+    let names = variants.iter().map(|it| it.to_string());
+    let hooks = sets
+        .iter()
+        .filter(|(set, _)| ["trivia", "keyword", "punct"].iter().any(|it| set == it))
+        .map(|(set, _)| {
+            let is_set = format_ident!("is_{}", set);
+            quote! {
+                fn #is_set(kind: #kind) -> bool {
+                    kind.#is_set()
+                }
+            }
+        });
+    let error_hook = error.map(|error| {
+        quote! {
+            fn error_kind() -> ::core::option::Option<#kind> {
+                ::core::option::Option::Some(#kind::#error)
+            }
+        }
+    });
+    let set_fns = sets.iter().map(|(set, kinds)| {
+        let is_set = format_ident!("is_{}", set);
+        let kinds = kinds.iter();
+        quote! {
+            pub fn #is_set(self) -> bool {
+                ::core::matches!(self, #(#kind::#kinds)|*)
+            }
+        }
+    });
+    let invalid = format!("invalid {}: {{}}", kind);
+
+    quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #vis enum #language {}
+
+        impl ::rowan::Language for #language {
+            type Kind = #kind;
+
+            fn kind_from_raw(raw: ::rowan::SyntaxKind) -> #kind {
+                match #kind::from_raw(raw.0) {
+                    ::core::option::Option::Some(kind) => kind,
+                    ::core::option::Option::None => ::core::panic!(#invalid, raw.0),
+                }
+            }
+
+            fn kind_to_raw(kind: #kind) -> ::rowan::SyntaxKind {
+                ::rowan::SyntaxKind(kind as u16)
+            }
+
+            #(#hooks)*
+            #error_hook
+        }
+
+        impl ::core::convert::From<#kind> for ::rowan::SyntaxKind {
+            fn from(kind: #kind) -> ::rowan::SyntaxKind {
+                ::rowan::SyntaxKind(kind as u16)
+            }
+        }
+
+        impl #kind {
+            #(
+                #[doc(hidden)]
+                #[allow(non_upper_case_globals)]
+                const #raws: u16 = #kind::#variants as u16;
+            )*
+
+            /// All kinds, in declaration order.
+            pub const ALL: &'static [#kind] = &[#(#kind::#variants),*];
+
+            /// Returns `None` if `raw` does not correspond to a kind.
+            pub fn from_raw(raw: u16) -> ::core::option::Option<#kind> {
+                match raw {
+                    #(Self::#raws => ::core::option::Option::Some(#kind::#variants),)*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    #(#kind::#variants => #names,)*
+                }
+            }
+
+            #(#set_fns)*
+        }
+    }
+}
+
+/// Returns the field holding the syntax node.
+fn struct_field(fields: &Fields) -> syn::Result<Member> {
+    let field = match fields.iter().collect::<Vec<_>>().as_slice() {
+        [field] => *field,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                fields,
+                "an AST node struct must have exactly one field",
+            ))
+        }
+    };
+    Ok(match &field.ident {
+        Some(ident) => Member::Named(ident.clone()),
+        None => Member::Unnamed(0.into()),
+    })
+}
+
+/// Returns the names and wrapped types of the variants.
+fn enum_variants(data: &DataEnum) -> syn::Result<Vec<(&Ident, &Type)>> {
+    data.variants
+        .iter()
+        .map(|variant| match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                Ok((&variant.ident, &fields.unnamed[0].ty))
+            }
+            _ => Err(syn::Error::new_spanned(variant, "every variant must wrap a single AST node")),
+        })
+        .collect()
+}
+
+fn expand_struct(
+    name: &Ident,
+    language: &Type,
+    kind: &Punctuated<Path, Token![|]>,
+    field: &Member,
+) -> TokenStream2 {
+    quote! {
+        impl ::rowan::ast::AstNode for #name {
+            type Language = #language;
+
+            fn can_cast(kind: <#language as ::rowan::Language>::Kind) -> bool {
+                ::core::matches!(kind, #kind)
+            }
+
+            fn cast(node: ::rowan::SyntaxNode<#language>) -> ::core::option::Option<Self> {
+                if <Self as ::rowan::ast::AstNode>::can_cast(node.kind()) {
+                    ::core::option::Option::Some(#name { #field: node })
+                } else {
+                    ::core::option::Option::None
+                }
+            }
+
+            fn syntax(&self) -> &::rowan::SyntaxNode<#language> {
+                &self.#field
+            }
+        }
+    }
+}
+
+fn expand_enum(name: &Ident, language: &Type, variants: &[(&Ident, &Type)]) -> TokenStream2 {
+    assert!(!variants.is_empty());
+    let names = variants.iter().map(|(variant, _)| variant).collect::<Vec<_>>();
+    let tys = variants.iter().map(|(_, ty)| ty).collect::<Vec<_>>();
+    quote! {
+        impl ::rowan::ast::AstNode for #name {
+            type Language = #language;
+
+            fn can_cast(kind: <#language as ::rowan::Language>::Kind) -> bool {
+                let raw = <#language as ::rowan::Language>::kind_to_raw(kind);
+                #(
+                    <#tys as ::rowan::ast::AstNode>::can_cast(
+                        <#language as ::rowan::Language>::kind_from_raw(raw),
+                    )
+                )||*
+            }
+
+            fn cast(node: ::rowan::SyntaxNode<#language>) -> ::core::option::Option<Self> {
+                let raw = <#language as ::rowan::Language>::kind_to_raw(node.kind());
+                #(
+                    if <#tys as ::rowan::ast::AstNode>::can_cast(
+                        <#language as ::rowan::Language>::kind_from_raw(raw),
+                    ) {
+                        return <#tys as ::rowan::ast::AstNode>::cast(node).map(#name::#names);
+                    }
+                )*
+                ::core::option::Option::None
+            }
+
+            fn syntax(&self) -> &::rowan::SyntaxNode<#language> {
+                match self {
+                    #(#name::#names(it) => <#tys as ::rowan::ast::AstNode>::syntax(it),)*
+                }
+            }
+        }
+
+        #(
+            impl ::core::convert::From<#tys> for #name {
+                fn from(node: #tys) -> #name {
+                    #name::#names(node)
+                }
+            }
+        )*
+    }
+}
@@ -1,8 +1,9 @@
 use std::{borrow::Cow, fmt, iter, marker::PhantomData, ops::Range};
 
 use crate::{
-    cursor, green::GreenTokenData, Direction, GreenNode, GreenNodeData, GreenToken, NodeOrToken,
-    SyntaxKind, SyntaxText, TextRange, TextSize, TokenAtOffset, WalkEvent,
+    cursor, green::GreenTokenData, syntax_text::FilteredText, token_text::TokenText, Bias,
+    Direction, GreenNode, GreenNodeData, GreenToken, NodeOrToken, RangeBase, SyntaxKind,
+    SyntaxText, TextRange, TextSize, TokenAtOffset, WalkEvent,
 };
 
 pub trait Language: Sized + Clone + Copy + fmt::Debug + Eq + Ord + std::hash::Hash {
@@ -10,6 +11,40 @@ pub trait Language: Sized + Clone + Copy + fmt::Debug + Eq + Ord + std::hash::Ha
 
     fn kind_from_raw(raw: SyntaxKind) -> Self::Kind;
     fn kind_to_raw(kind: Self::Kind) -> SyntaxKind;
+
+    /// Whether tokens of this kind, like whitespace and comments, carry no
+    /// meaning. Trivia is skipped by slot accessors, non-trivia navigation
+    /// and dumps, and is left outside of nodes by
+    /// [`GreenNodeBuilder::finish_node_before_trivia`].
+    ///
+    /// [`GreenNodeBuilder::finish_node_before_trivia`]: crate::GreenNodeBuilder::finish_node_before_trivia
+    fn is_trivia(kind: Self::Kind) -> bool {
+        let _ = kind;
+        false
+    }
+
+    fn is_keyword(kind: Self::Kind) -> bool {
+        let _ = kind;
+        false
+    }
+
+    fn is_punct(kind: Self::Kind) -> bool {
+        let _ = kind;
+        false
+    }
+
+    /// The kind the parser uses for erroneous nodes and tokens, if any.
+    fn error_kind() -> Option<Self::Kind> {
+        None
+    }
+
+    /// Whether the kind exists in the given version of the language, for
+    /// languages which keep a single set of kinds across editions. The
+    /// meaning of `version` is up to the language.
+    fn kind_available(kind: Self::Kind, version: u32) -> bool {
+        let _ = (kind, version);
+        true
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -29,24 +64,7 @@ pub type SyntaxElement<L> = NodeOrToken<SyntaxNode<L>, SyntaxToken<L>>;
 impl<L: Language> fmt::Debug for SyntaxNode<L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if f.alternate() {
-            let mut level = 0;
-            for event in self.preorder_with_tokens() {
-                match event {
-                    WalkEvent::Enter(element) => {
-                        for _ in 0..level {
-                            write!(f, "  ")?;
-                        }
-                        match element {
-                            NodeOrToken::Node(node) => writeln!(f, "{:?}", node)?,
-                            NodeOrToken::Token(token) => writeln!(f, "{:?}", token)?,
-                        }
-                        level += 1;
-                    }
-                    WalkEvent::Leave(_) => level -= 1,
-                }
-            }
-            assert_eq!(level, 0);
-            Ok(())
+            self.dump(f, false)
         } else {
             write!(f, "{:?}@{:?}", self.kind(), self.text_range())
         }
@@ -62,10 +80,10 @@ impl<L: Language> fmt::Display for SyntaxNode<L> {
 impl<L: Language> fmt::Debug for SyntaxToken<L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}@{:?}", self.kind(), self.text_range())?;
-        if self.text().len() < 25 {
-            return write!(f, " {:?}", self.text());
+        let text = self.text_lossy();
+        if text.len() < 25 {
+            return write!(f, " {:?}", text);
         }
-        let text = self.text();
         for idx in 21..25 {
             if text.is_char_boundary(idx) {
                 let text = format!("{} ...", &text[..idx]);
@@ -105,6 +123,18 @@ impl<L: Language> SyntaxNode<L> {
         self.raw.replace_with(replacement)
     }
 
+    /// Returns the green tree of this node with all the `replacements` of
+    /// its descendants applied in one pass, see
+    /// [`cursor::SyntaxNode::replace_descendants`].
+    pub fn replace_descendants<I>(&self, replacements: I) -> GreenNode
+    where
+        I: IntoIterator<Item = (SyntaxElement<L>, NodeOrToken<GreenNode, GreenToken>)>,
+    {
+        self.raw.replace_descendants(
+            replacements.into_iter().map(|(element, green)| (element.into(), green)),
+        )
+    }
+
     pub fn kind(&self) -> L::Kind {
         L::kind_from_raw(self.raw.kind())
     }
@@ -117,14 +147,103 @@ impl<L: Language> SyntaxNode<L> {
         self.raw.index()
     }
 
+    /// The coordinate system in which this node starts at zero, such as the
+    /// one of `self.clone_subtree()`.
+    pub fn range_base(&self) -> RangeBase {
+        self.raw.range_base()
+    }
+
+    /// Range of this node relative to the start of `ancestor`, or `None` if
+    /// `ancestor` is not an ancestor of this node (or the node itself).
+    pub fn text_range_relative_to(&self, ancestor: &SyntaxNode<L>) -> Option<TextRange> {
+        self.raw.text_range_relative_to(&ancestor.raw)
+    }
+
     pub fn text(&self) -> SyntaxText {
         self.raw.text()
     }
 
+    /// Text of this node without leading and trailing whitespace.
+    pub fn text_trimmed(&self) -> SyntaxText {
+        self.raw.text_trimmed()
+    }
+
+    /// Text of this node, skipping the tokens for which `is_trivia` returns
+    /// `true`. Compare the results to check that two nodes are equal modulo
+    /// trivia.
+    pub fn text_without<F: Fn(L::Kind) -> bool>(
+        &self,
+        is_trivia: F,
+    ) -> FilteredText<impl Fn(SyntaxKind) -> bool> {
+        self.raw.text_without(move |kind| is_trivia(L::kind_from_raw(kind)))
+    }
+
+    /// Text of this node, skipping trivia, see [`Language::is_trivia`].
+    pub fn text_without_trivia(&self) -> FilteredText<impl Fn(SyntaxKind) -> bool> {
+        self.text_without(L::is_trivia)
+    }
+
     pub fn green(&self) -> Cow<'_, GreenNodeData> {
         self.raw.green()
     }
 
+    /// Same as the `{:#?}` dump of this node, but without trivia tokens.
+    pub fn dump_without_trivia(&self) -> String {
+        struct Dump<'a, L: Language>(&'a SyntaxNode<L>);
+        impl<L: Language> fmt::Display for Dump<'_, L> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.dump(f, true)
+            }
+        }
+        Dump(self).to_string()
+    }
+
+    fn dump(&self, f: &mut fmt::Formatter<'_>, skip_trivia: bool) -> fmt::Result {
+        let mut level = 0;
+        for event in self.preorder_with_tokens() {
+            match event {
+                WalkEvent::Enter(element) => {
+                    if skip_trivia && element.as_token().is_some_and(|it| it.is_trivia()) {
+                        continue;
+                    }
+                    for _ in 0..level {
+                        write!(f, "  ")?;
+                    }
+                    // Unlike `Debug` of a token, the text is never abbreviated, so
+                    // that `parse_dump` can read the dump back.
+                    match &element {
+                        NodeOrToken::Node(node) => writeln!(f, "{:?}", node)?,
+                        NodeOrToken::Token(token) => writeln!(
+                            f,
+                            "{:?}@{:?} {:?}",
+                            token.kind(),
+                            token.text_range(),
+                            token.text_lossy()
+                        )?,
+                    }
+                    if element.as_node().is_some() {
+                        level += 1;
+                    }
+                }
+                WalkEvent::Leave(NodeOrToken::Node(_)) => level -= 1,
+                WalkEvent::Leave(NodeOrToken::Token(_)) => (),
+            }
+        }
+        assert_eq!(level, 0);
+        Ok(())
+    }
+
+    /// Nodes and tokens of [`Language::error_kind`] in the subtree of this
+    /// node, in preorder.
+    pub fn errors(&self) -> impl Iterator<Item = SyntaxElement<L>> {
+        let error_kind = L::error_kind().map(L::kind_to_raw);
+        let descendants = error_kind.map(|_| self.descendants_with_tokens());
+        descendants
+            .into_iter()
+            .flatten()
+            .filter(move |it| Some(L::kind_to_raw(it.kind())) == error_kind)
+    }
+
     pub fn parent(&self) -> Option<SyntaxNode<L>> {
         self.raw.parent().map(Self::from)
     }
@@ -197,6 +316,26 @@ impl<L: Language> SyntaxNode<L> {
         self.raw.descendants_with_tokens().map(NodeOrToken::from)
     }
 
+    /// Like [`SyntaxNode::descendants_with_tokens`], but only visits the
+    /// elements whose ranges intersect `range`, for example the visible part
+    /// of a file.
+    pub fn descendants_with_tokens_in_range(
+        &self,
+        range: TextRange,
+    ) -> impl Iterator<Item = SyntaxElement<L>> {
+        self.raw.descendants_with_tokens_in_range(range).map(NodeOrToken::from)
+    }
+
+    /// Like [`SyntaxNode::descendants`], but yields the children before
+    /// their parents, ending with this node.
+    pub fn descendants_postorder(&self) -> impl Iterator<Item = SyntaxNode<L>> {
+        self.raw.descendants_postorder().map(SyntaxNode::from)
+    }
+
+    pub fn descendants_with_tokens_postorder(&self) -> impl Iterator<Item = SyntaxElement<L>> {
+        self.raw.descendants_with_tokens_postorder().map(NodeOrToken::from)
+    }
+
     /// Traverse the subtree rooted at the current node (including the current
     /// node) in preorder, excluding tokens.
     pub fn preorder(&self) -> Preorder<L> {
@@ -205,16 +344,24 @@ impl<L: Language> SyntaxNode<L> {
 
     /// Traverse the subtree rooted at the current node (including the current
     /// node) in preorder, including tokens.
-    pub fn preorder_with_tokens(&self) -> impl Iterator<Item = WalkEvent<SyntaxElement<L>>> {
-        self.raw.preorder_with_tokens().map(|event| event.map(NodeOrToken::from))
+    pub fn preorder_with_tokens(&self) -> PreorderWithTokens<L> {
+        PreorderWithTokens { raw: self.raw.preorder_with_tokens(), _p: PhantomData }
     }
 
     /// Find a token in the subtree corresponding to this node, which covers the offset.
     /// Precondition: offset must be withing node's range.
     pub fn token_at_offset(&self, offset: TextSize) -> TokenAtOffset<SyntaxToken<L>> {
+        trace_span!("SyntaxNode::token_at_offset");
         self.raw.token_at_offset(offset).map(SyntaxToken::from)
     }
 
+    /// Like [`SyntaxNode::token_at_offset`], but picks one of two tokens
+    /// around the offset, preferring the one which is not trivia, see
+    /// [`TokenAtOffset::pick`].
+    pub fn token_at_offset_biased(&self, offset: TextSize, bias: Bias) -> Option<SyntaxToken<L>> {
+        self.token_at_offset(offset).pick(bias, SyntaxToken::is_trivia)
+    }
+
     /// Return the deepest node or token in the current subtree that fully
     /// contains the range. If the range is empty and is contained in two leaf
     /// nodes, either one can be returned. Precondition: range must be contained
@@ -223,6 +370,12 @@ impl<L: Language> SyntaxNode<L> {
         NodeOrToken::from(self.raw.covering_element(range))
     }
 
+    /// Like [`SyntaxNode::covering_element`], but returns the parent if the
+    /// covering element is a token.
+    pub fn covering_node(&self, range: TextRange) -> SyntaxNode<L> {
+        SyntaxNode::from(self.raw.covering_node(range))
+    }
+
     /// Finds a [`SyntaxElement`] which intersects with a given `range`. If
     /// there are several intersecting elements, any one can be returned.
     ///
@@ -232,6 +385,14 @@ impl<L: Language> SyntaxNode<L> {
         self.raw.child_or_token_at_range(range).map(SyntaxElement::from)
     }
 
+    /// Returns the `n`th child or token for which `is_trivia` returns `false`.
+    ///
+    /// Only the green children are scanned, so just the returned element is
+    /// materialized.
+    pub fn slot<F: Fn(L::Kind) -> bool>(&self, n: usize, is_trivia: F) -> Option<SyntaxElement<L>> {
+        self.raw.slot(n, move |kind| is_trivia(L::kind_from_raw(kind))).map(SyntaxElement::from)
+    }
+
     /// Returns an independent copy of the subtree rooted at this node.
     ///
     /// The parent of the returned node will be `None`, the start offset will be
@@ -248,6 +409,12 @@ impl<L: Language> SyntaxNode<L> {
         self.raw.detach()
     }
 
+    /// Checks that the red and green trees agree, see
+    /// [`cursor::SyntaxNode::assert_invariants`].
+    pub fn assert_invariants(&self) {
+        self.raw.assert_invariants()
+    }
+
     pub fn splice_children(&self, to_delete: Range<usize>, to_insert: Vec<SyntaxElement<L>>) {
         let to_insert = to_insert.into_iter().map(cursor::SyntaxElement::from).collect::<Vec<_>>();
         self.raw.splice_children(to_delete, to_insert)
@@ -262,6 +429,18 @@ impl<L: Language> SyntaxToken<L> {
         self.raw.replace_with(new_token)
     }
 
+    /// Creates a mutable token without a parent, see
+    /// [`SyntaxNode::splice_children`].
+    pub fn new_detached(green: GreenToken) -> SyntaxToken<L> {
+        SyntaxToken::from(cursor::SyntaxToken::new_detached(green))
+    }
+
+    /// Replaces this token of a mutable tree in place, see
+    /// [`SyntaxNode::clone_for_update`]. Returns the new token.
+    pub fn replace_in_place(&self, new_token: GreenToken) -> SyntaxToken<L> {
+        SyntaxToken::from(self.raw.replace_in_place(new_token))
+    }
+
     pub fn kind(&self) -> L::Kind {
         L::kind_from_raw(self.raw.kind())
     }
@@ -274,10 +453,40 @@ impl<L: Language> SyntaxToken<L> {
         self.raw.index()
     }
 
+    /// Range of this token relative to the start of `ancestor`, or `None` if
+    /// `ancestor` is not an ancestor of this token.
+    pub fn text_range_relative_to(&self, ancestor: &SyntaxNode<L>) -> Option<TextRange> {
+        self.raw.text_range_relative_to(&ancestor.raw)
+    }
+
+    /// Text of this token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token was created from bytes which are not valid UTF-8,
+    /// see [`GreenTokenData::text`].
     pub fn text(&self) -> &str {
         self.raw.text()
     }
 
+    /// Text of this token, which doesn't borrow from the token itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token was created from bytes which are not valid UTF-8,
+    /// see [`GreenTokenData::text`].
+    pub fn token_text(&self) -> TokenText<'static> {
+        self.raw.token_text()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.raw.bytes()
+    }
+
+    pub fn text_lossy(&self) -> Cow<'_, str> {
+        self.raw.text_lossy()
+    }
+
     pub fn green(&self) -> &GreenTokenData {
         self.raw.green()
     }
@@ -313,6 +522,26 @@ impl<L: Language> SyntaxToken<L> {
         self.raw.prev_token().map(SyntaxToken::from)
     }
 
+    /// Next token in the tree which is not trivia, see
+    /// [`Language::is_trivia`].
+    pub fn next_non_trivia_token(&self) -> Option<SyntaxToken<L>> {
+        iter::successors(self.next_token(), |it| it.next_token()).find(|it| !it.is_trivia())
+    }
+    /// Previous token in the tree which is not trivia.
+    pub fn prev_non_trivia_token(&self) -> Option<SyntaxToken<L>> {
+        iter::successors(self.prev_token(), |it| it.prev_token()).find(|it| !it.is_trivia())
+    }
+
+    pub fn is_trivia(&self) -> bool {
+        L::is_trivia(self.kind())
+    }
+    pub fn is_keyword(&self) -> bool {
+        L::is_keyword(self.kind())
+    }
+    pub fn is_punct(&self) -> bool {
+        L::is_punct(self.kind())
+    }
+
     pub fn detach(&self) {
         self.raw.detach()
     }
@@ -410,6 +639,42 @@ impl<L: Language> Preorder<L> {
     pub fn skip_subtree(&mut self) {
         self.raw.skip_subtree()
     }
+
+    /// See [`cursor::Preorder::max_depth`].
+    pub fn max_depth(self, max_depth: usize) -> Preorder<L> {
+        Preorder { raw: self.raw.max_depth(max_depth), _p: PhantomData }
+    }
+
+    pub fn depth_exceeded(&self) -> bool {
+        self.raw.depth_exceeded()
+    }
+}
+
+pub struct PreorderWithTokens<L: Language> {
+    raw: cursor::PreorderWithTokens,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> PreorderWithTokens<L> {
+    pub fn skip_subtree(&mut self) {
+        self.raw.skip_subtree()
+    }
+
+    /// See [`cursor::PreorderWithTokens::max_depth`].
+    pub fn max_depth(self, max_depth: usize) -> PreorderWithTokens<L> {
+        PreorderWithTokens { raw: self.raw.max_depth(max_depth), _p: PhantomData }
+    }
+
+    pub fn depth_exceeded(&self) -> bool {
+        self.raw.depth_exceeded()
+    }
+}
+
+impl<L: Language> Iterator for PreorderWithTokens<L> {
+    type Item = WalkEvent<SyntaxElement<L>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.raw.next().map(|it| it.map(NodeOrToken::from))
+    }
 }
 
 impl<L: Language> Iterator for Preorder<L> {
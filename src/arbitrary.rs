@@ -0,0 +1,223 @@
+//! Generation of random green trees, for fuzzing.
+//!
+//! The trees are derived from a byte string, which is how fuzzers supply
+//! their input, so a fuzz target is just a few lines:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| {
+//!     let tree = TreeGen::new(0..10, 10..20, "ab \n").generate(data);
+//!     check_invariants(&SyntaxNode::new_root(tree));
+//! });
+//! ```
+//!
+//! For property tests, the `proptest` feature adds strategies, which shrink
+//! a failing case to a small one:
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn reparse((tree, edits) in gen.tree_with_edits_strategy()) {
+//!         check_reparse(&tree, &edits)?;
+//!     }
+//! }
+//! ```
+pub mod reference;
+#[cfg(feature = "proptest")]
+mod strategy;
+
+use std::ops::Range;
+
+use crate::{GreenNode, GreenNodeBuilder, SyntaxKind, TextEdit, TextRange, TextSize};
+
+/// Configuration of the generated trees.
+#[derive(Debug, Clone)]
+pub struct TreeGen {
+    node_kinds: Range<u16>,
+    token_kinds: Range<u16>,
+    alphabet: Vec<char>,
+    max_depth: u32,
+    max_children: u32,
+    max_token_len: u32,
+    max_edits: u32,
+}
+
+impl TreeGen {
+    /// Nodes and tokens get kinds from the given ranges, and token texts are
+    /// made of the characters of `alphabet`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a range or the alphabet is empty.
+    pub fn new(node_kinds: Range<u16>, token_kinds: Range<u16>, alphabet: &str) -> TreeGen {
+        assert!(!node_kinds.is_empty() && !token_kinds.is_empty(), "empty kind range");
+        assert!(!alphabet.is_empty(), "empty alphabet");
+        TreeGen {
+            node_kinds,
+            token_kinds,
+            alphabet: alphabet.chars().collect(),
+            max_depth: 8,
+            max_children: 8,
+            max_token_len: 4,
+            max_edits: 4,
+        }
+    }
+
+    pub fn max_depth(mut self, max_depth: u32) -> TreeGen {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn max_children(mut self, max_children: u32) -> TreeGen {
+        self.max_children = max_children;
+        self
+    }
+
+    /// Tokens have between one and `max_token_len` characters.
+    pub fn max_token_len(mut self, max_token_len: u32) -> TreeGen {
+        self.max_token_len = max_token_len.max(1);
+        self
+    }
+
+    pub fn max_edits(mut self, max_edits: u32) -> TreeGen {
+        self.max_edits = max_edits;
+        self
+    }
+
+    /// Builds a tree from `data`. The same data always gives the same tree,
+    /// and running out of data just makes the tree smaller.
+    pub fn generate(&self, data: &[u8]) -> GreenNode {
+        self.tree(&mut Unstructured { data })
+    }
+
+    /// Builds a tree and a sequence of edits of its text, to be applied with
+    /// [`TextEdit::apply_all`]. The edits insert text from the alphabet.
+    pub fn generate_with_edits(&self, data: &[u8]) -> (GreenNode, Vec<TextEdit>) {
+        let mut data = Unstructured { data };
+        let tree = self.tree(&mut data);
+        let mut text = tree.to_string();
+        let mut edits = Vec::new();
+        for _ in 0..data.up_to(self.max_edits) {
+            let n_chars = text.chars().count() as u32;
+            let start = data.up_to(n_chars);
+            let end = start + data.up_to(n_chars - start);
+            let insert = self.text(&mut data, 0);
+            edits.push(apply_edit(&mut text, start as usize..end as usize, insert));
+        }
+        (tree, edits)
+    }
+
+    fn tree(&self, data: &mut Unstructured<'_>) -> GreenNode {
+        let mut builder = GreenNodeBuilder::new();
+        self.node(data, &mut builder, 0);
+        builder.finish()
+    }
+
+    fn text(&self, data: &mut Unstructured<'_>, min_len: u32) -> String {
+        let len = min_len + data.up_to(self.max_token_len - min_len);
+        let alphabet = &self.alphabet;
+        (0..len).map(|_| alphabet[data.up_to(alphabet.len() as u32 - 1) as usize]).collect()
+    }
+
+    fn node(&self, data: &mut Unstructured<'_>, builder: &mut GreenNodeBuilder<'_>, depth: u32) {
+        builder.start_node(SyntaxKind(data.in_range(&self.node_kinds)));
+        let n_children = data.up_to(self.max_children);
+        for _ in 0..n_children {
+            // An odd byte makes a nested node, an even one a token.
+            if depth < self.max_depth && data.up_to(1) == 1 {
+                self.node(data, builder, depth + 1);
+            } else {
+                let kind = SyntaxKind(data.in_range(&self.token_kinds));
+                let text = self.text(data, 1);
+                builder.token(kind, &text);
+            }
+        }
+        builder.finish_node();
+    }
+}
+
+/// Replaces the characters of `text` in the `chars` range with `insert`, and
+/// returns the edit doing so.
+fn apply_edit(text: &mut String, chars: Range<usize>, insert: String) -> TextEdit {
+    let mut offsets = text.char_indices().map(|(idx, _)| idx).chain(Some(text.len()));
+    let start = offsets.nth(chars.start).unwrap();
+    let end = if chars.is_empty() { start } else { offsets.nth(chars.len() - 1).unwrap() };
+    let delete = TextRange::new(TextSize::from(start as u32), TextSize::from(end as u32));
+    let edit = TextEdit::replace(delete, insert);
+    edit.apply(text);
+    edit
+}
+
+struct Unstructured<'a> {
+    data: &'a [u8],
+}
+
+impl Unstructured<'_> {
+    /// Returns a number in `0..=max`, or `0` if the data is exhausted.
+    fn up_to(&mut self, max: u32) -> u32 {
+        if max == 0 {
+            return 0;
+        }
+        let mut value: u32 = 0;
+        let mut bound = max;
+        while bound > 0 {
+            let (&byte, rest) = match self.data.split_first() {
+                Some(it) => it,
+                None => break,
+            };
+            self.data = rest;
+            value = (value << 8) | u32::from(byte);
+            bound >>= 8;
+        }
+        value % (max + 1)
+    }
+
+    fn in_range(&mut self, range: &Range<u16>) -> u16 {
+        let offset = self.up_to(u32::from(range.end - range.start - 1));
+        range.start + offset as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cursor::SyntaxNode, NodeOrToken};
+
+    #[test]
+    fn test_generate() {
+        let gen = TreeGen::new(0..4, 4..8, "ab ").max_depth(3);
+        let data: Vec<u8> = (0..512u32).map(|it| (it * 7 % 256) as u8).collect();
+        let tree = gen.generate(&data);
+        assert_eq!(tree, gen.generate(&data));
+        assert!(tree.validate_text_lens().is_ok());
+
+        let root = SyntaxNode::new_root(tree);
+        root.assert_invariants();
+        for element in root.descendants_with_tokens() {
+            match element {
+                NodeOrToken::Node(node) => {
+                    assert!(node.kind().0 < 4);
+                    assert!(node.ancestors().count() <= 4);
+                }
+                NodeOrToken::Token(token) => {
+                    assert!((4..8).contains(&token.kind().0));
+                    assert!(matches!(token.text().len(), 1..=4));
+                    assert!(token.text().chars().all(|c| "ab ".contains(c)));
+                }
+            }
+        }
+
+        let empty = gen.generate(&[]);
+        assert_eq!(empty.children().len(), 0);
+    }
+
+    #[test]
+    fn test_reference() {
+        let gen = TreeGen::new(0..4, 4..8, "ab").max_depth(3).max_children(4).max_edits(16);
+        for seed in 0..if cfg!(miri) { 2 } else { 64u32 } {
+            let data: Vec<u8> = (0..256u32).map(|it| (it * (2 * seed + 1) % 251) as u8).collect();
+            if let Err(err) = reference::check(&gen, &data) {
+                panic!("seed {}: {}", seed, err)
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! A deliberately naive implementation of the red tree, to check the cursors
+//! against.
+//!
+//! Elements own their children and know nothing about their position: the
+//! offsets and parents are recomputed from the root for every query, which
+//! is slow but hard to get wrong.
+use crate::{
+    cursor::{SyntaxElement, SyntaxNode},
+    green::GreenElementRef,
+    GreenNodeData, NodeOrToken, SyntaxKind, TextRange, TextSize, TokenAtOffset, WalkEvent,
+};
+
+use super::{TreeGen, Unstructured};
+
+/// Elements are identified by their path, the indices of the children on the
+/// way from the root.
+pub type Path = Vec<usize>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefElement {
+    pub kind: SyntaxKind,
+    /// The text of a token, `None` for nodes.
+    pub text: Option<String>,
+    pub children: Vec<RefElement>,
+}
+
+impl RefElement {
+    pub fn from_green(green: &GreenNodeData) -> RefElement {
+        RefElement::from_green_element(NodeOrToken::Node(green))
+    }
+
+    fn from_green_element(green: GreenElementRef<'_>) -> RefElement {
+        match green {
+            NodeOrToken::Node(node) => RefElement {
+                kind: node.kind(),
+                text: None,
+                children: node.children().map(RefElement::from_green_element).collect(),
+            },
+            NodeOrToken::Token(token) => RefElement {
+                kind: token.kind(),
+                text: Some(token.text_lossy().into_owned()),
+                children: Vec::new(),
+            },
+        }
+    }
+
+    pub fn text_len(&self) -> TextSize {
+        match &self.text {
+            Some(text) => TextSize::of(text.as_str()),
+            None => self.children.iter().map(RefElement::text_len).sum(),
+        }
+    }
+
+    pub fn get(&self, path: &[usize]) -> Option<&RefElement> {
+        match path.split_first() {
+            None => Some(self),
+            Some((&idx, rest)) => self.children.get(idx)?.get(rest),
+        }
+    }
+
+    pub fn text_range(&self, path: &[usize]) -> TextRange {
+        let mut offset = TextSize::from(0);
+        let mut element = self;
+        for &idx in path {
+            offset += element.children[..idx].iter().map(RefElement::text_len).sum::<TextSize>();
+            element = &element.children[idx];
+        }
+        TextRange::at(offset, element.text_len())
+    }
+
+    /// The paths of all the elements, in preorder.
+    pub fn paths(&self) -> Vec<Path> {
+        let mut res = vec![Vec::new()];
+        for (idx, child) in self.children.iter().enumerate() {
+            res.extend(child.paths().into_iter().map(|mut path| {
+                path.insert(0, idx);
+                path
+            }));
+        }
+        res
+    }
+
+    /// The non-empty tokens whose range contains `offset`.
+    pub fn token_at_offset(&self, offset: TextSize) -> Vec<Path> {
+        self.paths()
+            .into_iter()
+            .filter(|path| self.get(path).unwrap().text.is_some())
+            .filter(|path| {
+                let range = self.text_range(path);
+                !range.is_empty() && range.start() <= offset && offset <= range.end()
+            })
+            .collect()
+    }
+
+    /// The deepest element containing the non-empty `range`.
+    pub fn covering_element(&self, range: TextRange) -> Path {
+        let paths = self.paths();
+        let covering = paths.into_iter().filter(|path| self.text_range(path).contains_range(range));
+        covering.max_by_key(|path| path.len()).unwrap()
+    }
+
+    pub fn remove(&mut self, path: &[usize]) -> RefElement {
+        let (&last, parent) = path.split_last().unwrap();
+        self.get_mut(parent).children.remove(last)
+    }
+
+    pub fn insert(&mut self, path: &[usize], element: RefElement) {
+        let (&last, parent) = path.split_last().unwrap();
+        self.get_mut(parent).children.insert(last, element)
+    }
+
+    fn get_mut(&mut self, path: &[usize]) -> &mut RefElement {
+        path.iter().fold(self, |it, &idx| &mut it.children[idx])
+    }
+}
+
+/// Generates a tree and a sequence of edits and queries from `data`, runs
+/// them against both the cursors and [`RefElement`], and describes the first
+/// difference.
+pub fn check(gen: &TreeGen, data: &[u8]) -> Result<(), String> {
+    let mut data = Unstructured { data };
+    let green = gen.tree(&mut data);
+    let mut reference = RefElement::from_green(&green);
+    compare(&SyntaxNode::new_root(green.clone()), &reference)?;
+
+    let root = SyntaxNode::new_root_mut(green);
+    for op in 0..data.up_to(gen.max_edits) {
+        let paths = reference.paths();
+        let path = paths[data.up_to(paths.len() as u32 - 1) as usize].clone();
+        let element = find(&root, &path);
+        let len = usize::from(root.text_range().len()) as u32;
+        let what = match data.up_to(3) {
+            0 => {
+                let offset = TextSize::from(data.up_to(len));
+                let expected = reference.token_at_offset(offset);
+                let actual: Vec<Path> = match root.token_at_offset(offset) {
+                    TokenAtOffset::None => Vec::new(),
+                    TokenAtOffset::Single(it) => vec![path_of(&it.into())],
+                    TokenAtOffset::Between(l, r) => vec![path_of(&l.into()), path_of(&r.into())],
+                };
+                check_eq(&format!("token_at_offset({:?})", offset), expected, actual)?;
+                continue;
+            }
+            1 if len > 0 => {
+                let start = data.up_to(len - 1);
+                let range =
+                    TextRange::new(start.into(), (start + 1 + data.up_to(len - start - 1)).into());
+                let expected = reference.covering_element(range);
+                let actual = path_of(&root.covering_element(range));
+                check_eq(&format!("covering_element({:?})", range), expected, actual)?;
+                continue;
+            }
+            2 if !path.is_empty() => {
+                element.detach();
+                reference.remove(&path);
+                format!("detach {:?}", path)
+            }
+            _ => {
+                let parent = match element {
+                    NodeOrToken::Node(it) => it,
+                    NodeOrToken::Token(_) => continue,
+                };
+                let idx = data.up_to(reference.get(&path).unwrap().children.len() as u32) as usize;
+                let new = gen.tree(&mut data);
+                let mut new_path = path.clone();
+                new_path.push(idx);
+                reference.insert(&new_path, RefElement::from_green(&new));
+                parent.splice_children(idx..idx, vec![SyntaxNode::new_root_mut(new).into()]);
+                format!("insert at {:?}", new_path)
+            }
+        };
+        compare(&root, &reference).map_err(|it| format!("after #{} ({}): {}", op, what, it))?;
+    }
+    Ok(())
+}
+
+/// Compares every element of the tree with the reference.
+fn compare(root: &SyntaxNode, reference: &RefElement) -> Result<(), String> {
+    root.assert_invariants();
+    let actual: Vec<SyntaxElement> = root
+        .preorder_with_tokens()
+        .filter_map(|event| match event {
+            WalkEvent::Enter(it) => Some(it),
+            WalkEvent::Leave(_) => None,
+        })
+        .collect();
+    let paths = reference.paths();
+    check_eq("element count", paths.len(), actual.len())?;
+    for (path, element) in paths.iter().zip(actual) {
+        let expected = reference.get(path).unwrap();
+        let what = |property: &str| format!("{} of {:?}", property, path);
+        check_eq(&what("path"), path.clone(), path_of(&element))?;
+        check_eq(&what("kind"), expected.kind, element.kind())?;
+        check_eq(&what("text_range"), reference.text_range(path), element.text_range())?;
+        let parent = element.parent().map(|it| path_of(&it.into()));
+        check_eq(&what("parent"), path.split_last().map(|it| it.1.to_vec()), parent)?;
+
+        let sibling = |idx: Option<usize>| {
+            let (_, parent) = path.split_last()?;
+            let mut path = parent.to_vec();
+            path.push(idx?);
+            reference.get(&path).map(|_| path)
+        };
+        let idx = path.last().copied();
+        let next = element.next_sibling_or_token().map(|it| path_of(&it));
+        check_eq(&what("next_sibling_or_token"), sibling(idx.map(|it| it + 1)), next)?;
+        let prev = element.prev_sibling_or_token().map(|it| path_of(&it));
+        check_eq(
+            &what("prev_sibling_or_token"),
+            sibling(idx.and_then(|it| it.checked_sub(1))),
+            prev,
+        )?;
+
+        match element {
+            NodeOrToken::Node(node) => {
+                let child = |idx: Option<usize>| {
+                    let mut path = path.clone();
+                    path.push(idx?);
+                    Some(path)
+                };
+                let n_children = expected.children.len();
+                let first = node.first_child_or_token().map(|it| path_of(&it));
+                check_eq(
+                    &what("first_child_or_token"),
+                    child((n_children > 0).then_some(0)),
+                    first,
+                )?;
+                let last = node.last_child_or_token().map(|it| path_of(&it));
+                check_eq(&what("last_child_or_token"), child(n_children.checked_sub(1)), last)?;
+            }
+            NodeOrToken::Token(token) => {
+                let text = token.text_lossy();
+                check_eq(&what("text"), expected.text.as_deref(), Some(&*text))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_eq<T: PartialEq + std::fmt::Debug>(
+    what: &str,
+    expected: T,
+    actual: T,
+) -> Result<(), String> {
+    if expected == actual {
+        return Ok(());
+    }
+    Err(format!("{}: expected {:?}, got {:?}", what, expected, actual))
+}
+
+fn path_of(element: &SyntaxElement) -> Path {
+    let mut res = Vec::new();
+    let mut element = element.clone();
+    while let Some(parent) = element.parent() {
+        res.push(element.index());
+        element = parent.into();
+    }
+    res.reverse();
+    res
+}
+
+fn find(root: &SyntaxNode, path: &[usize]) -> SyntaxElement {
+    let mut res: SyntaxElement = root.clone().into();
+    for &idx in path {
+        res = match res {
+            NodeOrToken::Node(it) => it.children_with_tokens().nth(idx).unwrap(),
+            NodeOrToken::Token(_) => unreachable!(),
+        };
+    }
+    res
+}
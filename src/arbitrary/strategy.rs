@@ -0,0 +1,131 @@
+//! [`proptest`] strategies for the trees and edits of a [`TreeGen`].
+//!
+//! The strategies are built from proptest combinators, so failing cases
+//! shrink the usual way: children and edits are removed, nodes collapse into
+//! tokens, texts get shorter and deleted ranges move to the start.
+use proptest::{
+    collection::vec,
+    prelude::*,
+    sample::{select, Index},
+};
+
+use crate::{green::GreenElement, GreenNode, GreenToken, SyntaxKind, TextEdit};
+
+use super::{apply_edit, TreeGen};
+
+impl TreeGen {
+    /// A strategy for the trees of this configuration, the counterpart of
+    /// [`TreeGen::generate`].
+    pub fn tree_strategy(&self) -> BoxedStrategy<GreenNode> {
+        let node_kinds = self.node_kinds.clone();
+        let max_children = self.max_children as usize;
+        let token = (self.token_kinds.clone(), self.text_strategy(1)).prop_map(
+            |(kind, text)| -> GreenElement { GreenToken::new(SyntaxKind(kind), &text).into() },
+        );
+        let element = token.prop_recursive(self.max_depth, 256, self.max_children, {
+            let node_kinds = node_kinds.clone();
+            move |element| {
+                (node_kinds.clone(), vec(element, 0..=max_children)).prop_map(
+                    |(kind, children)| -> GreenElement {
+                        GreenNode::new(SyntaxKind(kind), children).into()
+                    },
+                )
+            }
+        });
+        (node_kinds, vec(element, 0..=max_children))
+            .prop_map(|(kind, children)| GreenNode::new(SyntaxKind(kind), children))
+            .boxed()
+    }
+
+    /// A strategy for a tree and a sequence of edits of its text, to be
+    /// applied with [`TextEdit::apply_all`], the counterpart of
+    /// [`TreeGen::generate_with_edits`].
+    pub fn tree_with_edits_strategy(&self) -> BoxedStrategy<(GreenNode, Vec<TextEdit>)> {
+        // The positions are picked relative to the text at the time of the
+        // edit, so the edits fit the text however the earlier ones shrink.
+        let edit = (any::<Index>(), any::<Index>(), self.text_strategy(0));
+        let edits = vec(edit, 0..=self.max_edits as usize);
+        (self.tree_strategy(), edits)
+            .prop_map(|(tree, edits)| {
+                let mut text = tree.to_string();
+                let edits = edits
+                    .into_iter()
+                    .map(|(start, len, insert)| {
+                        let n_chars = text.chars().count();
+                        let start = start.index(n_chars + 1);
+                        let end = start + len.index(n_chars - start + 1);
+                        apply_edit(&mut text, start..end, insert)
+                    })
+                    .collect();
+                (tree, edits)
+            })
+            .boxed()
+    }
+
+    fn text_strategy(&self, min_len: u32) -> impl Strategy<Value = String> {
+        let len = min_len as usize..=self.max_token_len as usize;
+        vec(select(self.alphabet.clone()), len).prop_map(|chars| chars.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::test_runner::{TestError, TestRunner};
+
+    use super::*;
+    use crate::{cursor::SyntaxNode, NodeOrToken, TextSize};
+
+    fn gen() -> TreeGen {
+        TreeGen::new(0..4, 4..8, "ab ").max_depth(3)
+    }
+
+    proptest! {
+        #[test]
+        fn test_tree_strategy((tree, edits) in gen().tree_with_edits_strategy()) {
+            let mut text = tree.to_string();
+            let root = SyntaxNode::new_root(tree);
+            root.assert_invariants();
+            for element in root.descendants_with_tokens() {
+                match element {
+                    NodeOrToken::Node(node) => prop_assert!(node.ancestors().count() <= 4),
+                    NodeOrToken::Token(token) => prop_assert!(matches!(token.text().len(), 1..=4)),
+                }
+            }
+            TextEdit::apply_all(&edits, &mut text);
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let gen = gen();
+
+        let mut runner = TestRunner::deterministic();
+        let result = runner.run(&gen.tree_strategy(), |tree| {
+            let root = SyntaxNode::new_root(tree);
+            prop_assert!(root.descendants_with_tokens().all(|it| it.kind() != SyntaxKind(4)));
+            Ok(())
+        });
+        let tree = match result {
+            Err(TestError::Fail(_, tree)) => tree,
+            _ => panic!("no tree with a token of kind 4: {:?}", result),
+        };
+        let root = SyntaxNode::new_root(tree);
+        let tokens: Vec<_> =
+            root.descendants_with_tokens().filter_map(|it| it.into_token()).collect();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].text().len(), 1);
+
+        let mut runner = TestRunner::deterministic();
+        let result = runner.run(&gen.tree_with_edits_strategy(), |(_, edits)| {
+            prop_assert!(edits.is_empty());
+            Ok(())
+        });
+        let edits = match result {
+            Err(TestError::Fail(_, (_, edits))) => edits,
+            _ => panic!("no edits: {:?}", result),
+        };
+        assert_eq!(edits.len(), 1);
+        assert!(edits[0].delete.is_empty() && edits[0].insert.is_empty());
+        assert_eq!(edits[0].delete.start(), TextSize::from(0));
+    }
+}
@@ -7,14 +7,28 @@ use std::{
     mem::{self, ManuallyDrop},
     ops::Deref,
     ptr,
-    sync::atomic::{
-        self,
-        Ordering::{Acquire, Relaxed, Release},
-    },
 };
 
 use memoffset::offset_of;
 
+use self::sync::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+/// The atomics of the reference counts, in one place so that the orderings
+/// can be model checked with loom:
+///
+/// ```text
+/// RUSTFLAGS="--cfg loom" cargo test --release --lib arc
+/// ```
+mod sync {
+    #[cfg(loom)]
+    pub(crate) use loom::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(not(loom))]
+    pub(crate) use std::sync::atomic::{AtomicUsize, Ordering};
+}
+
 /// A soft limit on the amount of references that may be made to an `Arc`.
 ///
 /// Going above this limit will abort your program (although not
@@ -24,7 +38,7 @@ const MAX_REFCOUNT: usize = (isize::MAX) as usize;
 /// The object allocated by an Arc<T>
 #[repr(C)]
 pub(crate) struct ArcInner<T: ?Sized> {
-    pub(crate) count: atomic::AtomicUsize,
+    pub(crate) count: AtomicUsize,
     pub(crate) data: T,
 }
 
@@ -83,7 +97,7 @@ impl<T: ?Sized> Arc<T> {
     /// allocation
     #[inline]
     pub(crate) fn ptr_eq(this: &Self, other: &Self) -> bool {
-        this.ptr() == other.ptr()
+        ptr::eq(this.ptr() as *const (), other.ptr() as *const ())
     }
 
     pub(crate) fn ptr(&self) -> *mut ArcInner<T> {
@@ -193,14 +207,29 @@ impl<T: ?Sized> Drop for Arc<T> {
     }
 }
 
+impl<T: ?Sized> Arc<T> {
+    /// Drops `this`, but if it is the last reference, passes the contents to
+    /// `f` before freeing them, for the resources which `T` does not release
+    /// on its own.
+    #[inline]
+    pub(crate) fn drop_with(this: Self, f: impl FnOnce(&mut T)) {
+        let mut this = ManuallyDrop::new(this);
+        // The same protocol as in `drop`.
+        if this.inner().count.fetch_sub(1, Release) != 1 {
+            return;
+        }
+        this.inner().count.load(Acquire);
+        unsafe {
+            f(&mut (*this.ptr()).data);
+            this.drop_slow();
+        }
+    }
+}
+
 impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
     fn eq(&self, other: &Arc<T>) -> bool {
         Self::ptr_eq(self, other) || *(*self) == *(*other)
     }
-
-    fn ne(&self, other: &Arc<T>) -> bool {
-        !Self::ptr_eq(self, other) && *(*self) != *(*other)
-    }
 }
 
 impl<T: ?Sized + PartialOrd> PartialOrd for Arc<T> {
@@ -251,6 +280,10 @@ impl<H, T> HeaderSlice<H, [T]> {
     pub(crate) fn slice(&self) -> &[T] {
         &self.slice
     }
+
+    pub(crate) fn slice_mut(&mut self) -> &mut [T] {
+        &mut self.slice
+    }
 }
 
 impl<H, T> Deref for HeaderSlice<H, [T; 0]> {
@@ -323,6 +356,28 @@ impl<H, T> ThinArc<H, T> {
         result
     }
 
+    /// Like [`ThinArc::with_arc`], but allows to mutate the `Arc`, for
+    /// example with [`Arc::get_mut`].
+    #[inline]
+    pub(crate) fn with_arc_mut<F, U>(&mut self, f: F) -> U
+    where
+        F: FnOnce(&mut Arc<HeaderSlice<H, [T]>>) -> U,
+    {
+        let mut transient = unsafe {
+            ManuallyDrop::new(Arc {
+                p: ptr::NonNull::new_unchecked(thin_to_thick(self.ptr.as_ptr())),
+                phantom: PhantomData,
+            })
+        };
+        f(&mut transient)
+    }
+
+    /// See [`Arc::drop_with`].
+    #[inline]
+    pub(crate) fn drop_with(this: Self, f: impl FnOnce(&mut HeaderSlice<H, [T]>)) {
+        Arc::drop_with(Arc::from_thin(this), f)
+    }
+
     /// Creates a `ThinArc` for a HeaderSlice using the given header struct and
     /// iterator to generate the slice.
     pub(crate) fn from_header_and_iter<I>(header: H, mut items: I) -> Self
@@ -332,21 +387,8 @@ impl<H, T> ThinArc<H, T> {
         assert_ne!(mem::size_of::<T>(), 0, "Need to think about ZST");
 
         let num_items = items.len();
-
-        // Offset of the start of the slice in the allocation.
-        let inner_to_data_offset = offset_of!(ArcInner<HeaderSlice<H, [T; 0]>>, data);
-        let data_to_slice_offset = offset_of!(HeaderSlice<H, [T; 0]>, slice);
-        let slice_offset = inner_to_data_offset + data_to_slice_offset;
-
-        // Compute the size of the real payload.
-        let slice_size = mem::size_of::<T>().checked_mul(num_items).expect("size overflows");
-        let usable_size = slice_offset.checked_add(slice_size).expect("size overflows");
-
-        // Round up size to alignment.
-        let align = mem::align_of::<ArcInner<HeaderSlice<H, [T; 0]>>>();
-        let size = usable_size.wrapping_add(align - 1) & !(align - 1);
-        assert!(size >= usable_size, "size overflows");
-        let layout = Layout::from_size_align(size, align).expect("invalid layout");
+        let (layout, slice_offset) = thin_layout::<H, T>(num_items);
+        let usable_size = slice_offset + mem::size_of::<T>() * num_items;
 
         let ptr: *mut ArcInner<HeaderSlice<H, [T; 0]>>;
         unsafe {
@@ -366,7 +408,7 @@ impl<H, T> ThinArc<H, T> {
             // ptr = fake_slice as *mut [T] as *mut ArcInner<HeaderSlice<H, [T]>>;
             ptr = buffer as *mut _;
 
-            let count = atomic::AtomicUsize::new(1);
+            let count = AtomicUsize::new(1);
 
             // Write the data.
             //
@@ -397,6 +439,32 @@ impl<H, T> ThinArc<H, T> {
     }
 }
 
+/// The layout of the allocation of a `ThinArc` with `num_items` items, and
+/// the offset of the first item in it.
+fn thin_layout<H, T>(num_items: usize) -> (Layout, usize) {
+    // Offset of the start of the slice in the allocation.
+    let inner_to_data_offset = offset_of!(ArcInner<HeaderSlice<H, [T; 0]>>, data);
+    let data_to_slice_offset = offset_of!(HeaderSlice<H, [T; 0]>, slice);
+    let slice_offset = inner_to_data_offset + data_to_slice_offset;
+
+    // Compute the size of the real payload.
+    let slice_size = mem::size_of::<T>().checked_mul(num_items).expect("size overflows");
+    let usable_size = slice_offset.checked_add(slice_size).expect("size overflows");
+
+    // Round up size to alignment.
+    let align = mem::align_of::<ArcInner<HeaderSlice<H, [T; 0]>>>();
+    let size = usable_size.wrapping_add(align - 1) & !(align - 1);
+    assert!(size >= usable_size, "size overflows");
+    (Layout::from_size_align(size, align).expect("invalid layout"), slice_offset)
+}
+
+impl<H, T> HeaderSlice<H, [T; 0]> {
+    /// The size of the `ThinArc` allocation holding this.
+    pub(crate) fn allocation_size(&self) -> usize {
+        thin_layout::<H, T>(self.length).0.size()
+    }
+}
+
 impl<H, T> Deref for ThinArc<H, T> {
     type Target = HeaderSlice<H, [T]>;
 
@@ -461,3 +529,111 @@ impl<H: Hash, T: Hash> Hash for ThinArc<H, T> {
         (**self).hash(state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(loom)]
+    use loom::{model, thread};
+    #[cfg(not(loom))]
+    use std::thread;
+
+    #[cfg(not(loom))]
+    fn model(f: impl Fn()) {
+        // Repeat to make the interleavings which matter more likely, but
+        // keep the tests fast under miri.
+        for _ in 0..if cfg!(miri) { 4 } else { 256 } {
+            f()
+        }
+    }
+
+    /// Counts the drops, to check that the header is dropped exactly once.
+    struct DropCount(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Drop for DropCount {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_drop() {
+        model(|| {
+            let drops = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let arc =
+                ThinArc::from_header_and_iter(DropCount(drops.clone()), vec![1u8, 2].into_iter());
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let arc = arc.clone();
+                    thread::spawn(move || assert_eq!(arc.slice(), [1, 2]))
+                })
+                .collect();
+            drop(arc);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(drops.load(std::sync::atomic::Ordering::Relaxed), 1);
+        })
+    }
+
+    #[test]
+    fn test_clone_drop_race() {
+        model(|| {
+            let drops = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let thin =
+                ThinArc::from_header_and_iter(DropCount(drops.clone()), vec![1u8].into_iter());
+            let arc = Arc::from_thin(thin);
+            let shared = arc.clone();
+            // One thread clones while the other drops, so the count goes both
+            // up and down concurrently.
+            let thread = thread::spawn(move || {
+                let clone = shared.clone();
+                drop(shared);
+                assert_eq!(clone.slice(), [1]);
+            });
+            drop(arc);
+            thread.join().unwrap();
+            assert_eq!(drops.load(std::sync::atomic::Ordering::Relaxed), 1);
+        })
+    }
+
+    #[test]
+    fn test_concurrent_drop_with() {
+        fn release(arc: ThinArc<(), u8>, calls: &std::sync::atomic::AtomicUsize) {
+            ThinArc::drop_with(arc, |it| {
+                assert_eq!(it.slice(), [1]);
+                calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            })
+        }
+
+        model(|| {
+            let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let arc = ThinArc::from_header_and_iter((), vec![1u8].into_iter());
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let (arc, calls) = (arc.clone(), calls.clone());
+                    thread::spawn(move || release(arc, &calls))
+                })
+                .collect();
+            release(arc, &calls);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            // Only the last reference runs the callback.
+            assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+        })
+    }
+
+    #[test]
+    fn test_unique() {
+        model(|| {
+            let mut arc = ThinArc::from_header_and_iter((), vec![1u8].into_iter());
+            let clone = arc.clone();
+            let thread = thread::spawn(move || drop(clone));
+            thread.join().unwrap();
+            // The join makes the drop of the clone visible.
+            assert!(arc.with_arc_mut(|it| Arc::get_mut(it).is_some()));
+        })
+    }
+}
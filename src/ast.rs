@@ -0,0 +1,789 @@
+//! Building blocks for a typed AST on top of the untyped syntax tree.
+//!
+//! A typed AST node is a thin wrapper around a [`SyntaxNode`] of a specific
+//! kind. Language crates implement [`AstNode`] and [`AstToken`] for their
+//! wrappers, and get interoperable helpers from this module for free.
+mod factory;
+use std::{
+    cell::RefCell,
+    fmt,
+    hash::{Hash, Hasher},
+    iter,
+    marker::PhantomData,
+};
+
+use crate::{
+    Bias, Language, SyntaxKind, SyntaxNode, SyntaxNodeChildren, SyntaxToken, TextEdit, TextRange,
+};
+
+pub use self::factory::SyntaxFactory;
+#[cfg(feature = "macros")]
+pub use rowan_macros::AstNode;
+
+/// The main trait to go from untyped [`SyntaxNode`] to a typed AST.
+pub trait AstNode {
+    type Language: Language;
+
+    fn can_cast(kind: <Self::Language as Language>::Kind) -> bool
+    where
+        Self: Sized;
+
+    fn cast(node: SyntaxNode<Self::Language>) -> Option<Self>
+    where
+        Self: Sized;
+
+    fn syntax(&self) -> &SyntaxNode<Self::Language>;
+
+    fn clone_for_update(&self) -> Self
+    where
+        Self: Sized,
+    {
+        Self::cast(self.syntax().clone_for_update()).unwrap()
+    }
+
+    fn clone_subtree(&self) -> Self
+    where
+        Self: Sized,
+    {
+        Self::cast(self.syntax().clone_subtree()).unwrap()
+    }
+
+    /// See [`semantically_eq`].
+    fn semantically_eq(&self, other: &Self) -> bool
+    where
+        Self: Sized,
+    {
+        semantically_eq(self.syntax(), other.syntax())
+    }
+}
+
+/// Checks if two trees are equal, ignoring trivia and text positions.
+///
+/// Kinds of nodes and kinds and texts of non-trivia tokens are compared, see
+/// [`Language::is_trivia`]. Useful to check if an edit changed anything
+/// meaningful.
+pub fn semantically_eq<L: Language>(lhs: &SyntaxNode<L>, rhs: &SyntaxNode<L>) -> bool {
+    let is_trivia = |kind| L::is_trivia(L::kind_from_raw(kind));
+    lhs.green().structurally_eq_ignoring(&rhs.green(), is_trivia)
+}
+
+/// Like [`AstNode`], but wraps tokens rather than interior nodes.
+pub trait AstToken {
+    type Language: Language;
+
+    fn can_cast(kind: <Self::Language as Language>::Kind) -> bool
+    where
+        Self: Sized;
+
+    fn cast(token: SyntaxToken<Self::Language>) -> Option<Self>
+    where
+        Self: Sized;
+
+    fn syntax(&self) -> &SyntaxToken<Self::Language>;
+
+    /// Text of the token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token is not valid UTF-8, see [`SyntaxToken::text`].
+    fn text(&self) -> &str {
+        self.syntax().text()
+    }
+}
+
+/// An iterator over the children of a node which can be cast to `N`.
+#[derive(Debug, Clone)]
+pub struct AstChildren<N: AstNode> {
+    inner: SyntaxNodeChildren<N::Language>,
+    ph: PhantomData<N>,
+}
+
+impl<N: AstNode> AstChildren<N> {
+    pub fn new(parent: &SyntaxNode<N::Language>) -> Self {
+        AstChildren { inner: parent.children(), ph: PhantomData }
+    }
+}
+
+impl<N: AstNode> Iterator for AstChildren<N> {
+    type Item = N;
+    fn next(&mut self) -> Option<N> {
+        self.inner.find_map(N::cast)
+    }
+}
+
+/// A pointer to a syntax node, which does not keep the tree alive.
+///
+/// The node is identified by its kind and range, so the pointer can be
+/// resolved in any tree with the same text, and is invalidated by edits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SyntaxNodePtr<L: Language> {
+    kind: SyntaxKind,
+    range: TextRange,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> SyntaxNodePtr<L> {
+    pub fn new(node: &SyntaxNode<L>) -> Self {
+        SyntaxNodePtr {
+            kind: L::kind_to_raw(node.kind()),
+            range: node.text_range(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Finds the node in the tree rooted at `root`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `root` is not a root, or if there is no such node in it.
+    pub fn to_node(&self, root: &SyntaxNode<L>) -> SyntaxNode<L> {
+        self.resolve(root).unwrap_or_else(|| panic!("can't resolve {:?} with {:?}", self, root))
+    }
+
+    /// Like [`SyntaxNodePtr::to_node`], but returns `None` if there is no
+    /// such node, for example because the tree was reparsed after an edit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `root` is not a root.
+    pub fn resolve(&self, root: &SyntaxNode<L>) -> Option<SyntaxNode<L>> {
+        assert!(root.parent().is_none());
+        iter::successors(Some(root.clone()), |node| {
+            node.child_or_token_at_range(self.range)?.into_node()
+        })
+        .find(|it| it.text_range() == self.range && L::kind_to_raw(it.kind()) == self.kind)
+    }
+
+    pub fn cast<N: AstNode<Language = L>>(self) -> Option<AstPtr<N>> {
+        if !N::can_cast(L::kind_from_raw(self.kind)) {
+            return None;
+        }
+        Some(AstPtr { raw: self })
+    }
+
+    pub fn kind(&self) -> L::Kind {
+        L::kind_from_raw(self.kind)
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// Like [`SyntaxNodePtr`], but remembers the type of the AST node.
+pub struct AstPtr<N: AstNode> {
+    raw: SyntaxNodePtr<N::Language>,
+}
+
+impl<N: AstNode> AstPtr<N> {
+    pub fn new(node: &N) -> Self {
+        AstPtr { raw: SyntaxNodePtr::new(node.syntax()) }
+    }
+
+    /// Finds the node in the tree rooted at `root`, see
+    /// [`SyntaxNodePtr::to_node`].
+    pub fn to_node(&self, root: &SyntaxNode<N::Language>) -> N {
+        N::cast(self.raw.to_node(root)).unwrap()
+    }
+
+    /// Like [`AstPtr::to_node`], but returns `None` if there is no such node.
+    pub fn resolve(&self, root: &SyntaxNode<N::Language>) -> Option<N> {
+        N::cast(self.raw.resolve(root)?)
+    }
+
+    pub fn syntax_node_ptr(&self) -> SyntaxNodePtr<N::Language> {
+        self.raw.clone()
+    }
+
+    /// Casts to another AST type, if the kind of the node allows it.
+    pub fn cast<U: AstNode<Language = N::Language>>(self) -> Option<AstPtr<U>> {
+        self.raw.cast()
+    }
+}
+
+impl<N: AstNode> fmt::Debug for AstPtr<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AstPtr").field(&self.raw).finish()
+    }
+}
+
+impl<N: AstNode> Clone for AstPtr<N> {
+    fn clone(&self) -> Self {
+        AstPtr { raw: self.raw.clone() }
+    }
+}
+
+impl<N: AstNode> PartialEq for AstPtr<N> {
+    fn eq(&self, other: &AstPtr<N>) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<N: AstNode> Eq for AstPtr<N> {}
+
+impl<N: AstNode> Hash for AstPtr<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw.hash(state)
+    }
+}
+
+impl<N: AstNode> From<AstPtr<N>> for SyntaxNodePtr<N::Language> {
+    fn from(ptr: AstPtr<N>) -> SyntaxNodePtr<N::Language> {
+        ptr.raw
+    }
+}
+
+/// Like [`SyntaxNodePtr`], but survives edits: [`Anchor::rebase`] moves it
+/// through the edits applied to the text since it was created, so that it
+/// resolves in the reparsed tree if the node is still there.
+///
+/// By default the anchor doesn't grow when text is inserted right before or
+/// after the node, see [`Anchor::with_bias`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Anchor<L: Language> {
+    ptr: SyntaxNodePtr<L>,
+    start_bias: Bias,
+    end_bias: Bias,
+}
+
+impl<L: Language> Anchor<L> {
+    pub fn new(node: &SyntaxNode<L>) -> Self {
+        Anchor { ptr: SyntaxNodePtr::new(node), start_bias: Bias::Right, end_bias: Bias::Left }
+    }
+
+    /// Sets the sides of the edits touching the range which its start and end
+    /// stick to, see [`TextEdit::rebase_offset`].
+    pub fn with_bias(self, start: Bias, end: Bias) -> Self {
+        Anchor { start_bias: start, end_bias: end, ..self }
+    }
+
+    /// Moves the anchor through `edits`, applied one after another like with
+    /// [`TextEdit::apply_all`]. Returns `None` if the node was deleted.
+    pub fn rebase<'a>(&self, edits: impl IntoIterator<Item = &'a TextEdit>) -> Option<Self> {
+        let mut range = self.ptr.range;
+        for edit in edits {
+            let start = edit.rebase_offset(range.start(), self.start_bias);
+            let end = edit.rebase_offset(range.end(), self.end_bias);
+            if start > end || (start == end && !range.is_empty()) {
+                return None;
+            }
+            range = TextRange::new(start, end);
+        }
+        let ptr = SyntaxNodePtr { range, ..self.ptr.clone() };
+        Some(Anchor { ptr, ..self.clone() })
+    }
+
+    /// Finds a node of the same kind at the rebased range, see
+    /// [`SyntaxNodePtr::resolve`].
+    pub fn resolve(&self, root: &SyntaxNode<L>) -> Option<SyntaxNode<L>> {
+        self.ptr.resolve(root)
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        self.ptr.range
+    }
+}
+
+/// A dense map from raw kinds to small ids, usually one per AST node type.
+///
+/// [`AstNode::cast`] for an enum over many node types can look up the id of
+/// the kind in a `static` table, instead of checking every variant in turn.
+///
+/// ```ignore
+/// static KIND_IDS: KindTable<{ KIND_COUNT }> = KindTable::new(&[(FN, 0), (STRUCT, 1)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KindTable<const N: usize> {
+    ids: [u16; N],
+}
+
+impl<const N: usize> KindTable<N> {
+    /// Creates a table for the raw kinds below `N`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a kind is not below `N`, or if an id is `u16::MAX`.
+    pub const fn new(entries: &[(SyntaxKind, u16)]) -> KindTable<N> {
+        let mut ids = [u16::MAX; N];
+        let mut i = 0;
+        while i < entries.len() {
+            let (kind, id) = entries[i];
+            assert!((kind.0 as usize) < N, "kind is out of the table range");
+            assert!(id != u16::MAX, "id is reserved");
+            ids[kind.0 as usize] = id;
+            i += 1;
+        }
+        KindTable { ids }
+    }
+
+    #[inline]
+    pub fn get(&self, kind: SyntaxKind) -> Option<u16> {
+        match self.ids.get(usize::from(kind.0)) {
+            Some(&id) if id != u16::MAX => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Matches a [`SyntaxNode`] against several [`AstNode`] types.
+///
+/// ```ignore
+/// match_ast! {
+///     match node {
+///         ast::Fn(it) => ...,
+///         ast::Struct(it) => ...,
+///         _ => None,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! match_ast {
+    (match $node:ident { $($tt:tt)* }) => { $crate::match_ast!(match ($node) { $($tt)* }) };
+
+    (match ($node:expr) {
+        $( $( $path:ident )::+ ($it:pat) => $res:expr, )*
+        _ => $catch_all:expr $(,)?
+    }) => {{
+        $( if let Some($it) = <$($path)::* as $crate::ast::AstNode>::cast($node.clone()) { $res } else )*
+        { $catch_all }
+    }};
+}
+
+/// Declares a visitor trait with a method per [`AstNode`] type.
+///
+/// Every `visit_*` method walks the children of its node by default, so
+/// implementations only override the methods for the nodes they care about.
+/// `visit` dispatches an untyped node to the first matching method, using a
+/// lazily filled per-kind table.
+///
+/// ```ignore
+/// ast_visitor! {
+///     pub trait Visitor for Lang {
+///         fn visit_fn(ast::Fn);
+///         fn visit_struct(ast::Struct);
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! ast_visitor {
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $name:ident for $lang:ty {
+            $( fn $visit:ident($ty:ty); )+
+        }
+    ) => {
+        $(#[$attr])*
+        $vis trait $name {
+            $(
+                fn $visit(&mut self, node: $ty) {
+                    self.walk($crate::ast::AstNode::syntax(&node))
+                }
+            )+
+
+            fn visit(&mut self, node: $crate::SyntaxNode<$lang>) {
+                ::std::thread_local! {
+                    static TABLE: ::std::cell::RefCell<::std::vec::Vec<u16>> =
+                        ::std::cell::RefCell::new(::std::vec::Vec::new());
+                }
+                let raw = <$lang as $crate::Language>::kind_to_raw(node.kind());
+                let can_cast = [$(
+                    (|raw| <$ty as $crate::ast::AstNode>::can_cast(
+                        <$lang as $crate::Language>::kind_from_raw(raw),
+                    )) as fn($crate::SyntaxKind) -> bool
+                ),+];
+                let idx = TABLE.with(|table| $crate::ast::__dispatch(table, raw, &can_cast));
+                let visit = [$(
+                    (|this: &mut Self, node| this.$visit(
+                        <$ty as $crate::ast::AstNode>::cast(node).unwrap(),
+                    )) as fn(&mut Self, $crate::SyntaxNode<$lang>)
+                ),+];
+                match idx {
+                    Some(idx) => visit[idx](self, node),
+                    None => self.walk(&node),
+                }
+            }
+
+            fn walk(&mut self, node: &$crate::SyntaxNode<$lang>) {
+                for child in node.children() {
+                    self.visit(child)
+                }
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn __dispatch(
+    table: &RefCell<Vec<u16>>,
+    raw: SyntaxKind,
+    can_cast: &[fn(SyntaxKind) -> bool],
+) -> Option<usize> {
+    // 0 is an empty slot, 1 is no match, and `idx + 2` is a match.
+    let mut table = table.borrow_mut();
+    let slot = usize::from(raw.0);
+    if table.len() <= slot {
+        table.resize(slot + 1, 0);
+    }
+    if table[slot] == 0 {
+        let idx = can_cast.iter().position(|can_cast| can_cast(raw));
+        table[slot] = idx.map_or(1, |idx| idx as u16 + 2);
+    }
+    table[slot].checked_sub(2).map(usize::from)
+}
+
+/// Helpers for implementing typed accessors, usually from generated code.
+pub mod support {
+    use super::{AstChildren, AstNode};
+    use crate::{Language, SyntaxNode, SyntaxToken};
+
+    /// Returns the first child of `parent` which can be cast to `N`.
+    pub fn child<N: AstNode>(parent: &SyntaxNode<N::Language>) -> Option<N> {
+        parent.children().find_map(N::cast)
+    }
+
+    /// Returns the children of `parent` which can be cast to `N`.
+    pub fn children<N: AstNode>(parent: &SyntaxNode<N::Language>) -> AstChildren<N> {
+        AstChildren::new(parent)
+    }
+
+    /// Returns the first child token of `parent` of the given kind.
+    pub fn token<L: Language>(parent: &SyntaxNode<L>, kind: L::Kind) -> Option<SyntaxToken<L>> {
+        nth_token(parent, kind, 0)
+    }
+
+    /// Returns the `n`-th child token of `parent` of the given kind.
+    pub fn nth_token<L: Language>(
+        parent: &SyntaxNode<L>,
+        kind: L::Kind,
+        n: usize,
+    ) -> Option<SyntaxToken<L>> {
+        let kind = L::kind_to_raw(kind);
+        parent
+            .children_with_tokens()
+            .filter_map(|it| it.into_token())
+            .filter(|it| L::kind_to_raw(it.kind()) == kind)
+            .nth(n)
+    }
+
+    /// Returns the `n`-th non-trivia child of `parent`, if it can be cast to
+    /// `N`. See [`Language::is_trivia`].
+    pub fn slot_node<N: AstNode>(parent: &SyntaxNode<N::Language>, n: usize) -> Option<N> {
+        parent.slot(n, N::Language::is_trivia)?.into_node().and_then(N::cast)
+    }
+
+    /// Returns the `n`-th non-trivia child of `parent`, if it is a token.
+    pub fn slot_token<L: Language>(parent: &SyntaxNode<L>, n: usize) -> Option<SyntaxToken<L>> {
+        parent.slot(n, L::is_trivia)?.into_token()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+    use crate::{green::GreenElement, GreenNodeBuilder, NodeCache};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    enum Lang {}
+    impl Language for Lang {
+        type Kind = SyntaxKind;
+        fn kind_from_raw(raw: SyntaxKind) -> SyntaxKind {
+            raw
+        }
+        fn kind_to_raw(kind: SyntaxKind) -> SyntaxKind {
+            kind
+        }
+        fn is_trivia(kind: SyntaxKind) -> bool {
+            kind == WHITESPACE
+        }
+    }
+
+    const ROOT: SyntaxKind = SyntaxKind(0);
+    const ATOM: SyntaxKind = SyntaxKind(1);
+    const LIST: SyntaxKind = SyntaxKind(2);
+    const WORD: SyntaxKind = SyntaxKind(3);
+    const WHITESPACE: SyntaxKind = SyntaxKind(4);
+
+    macro_rules! ast_node {
+        ($ast:ident, $kind:ident) => {
+            #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+            struct $ast(SyntaxNode<Lang>);
+            impl AstNode for $ast {
+                type Language = Lang;
+                fn can_cast(kind: SyntaxKind) -> bool {
+                    kind == $kind
+                }
+                fn cast(node: SyntaxNode<Lang>) -> Option<Self> {
+                    if Self::can_cast(node.kind()) {
+                        Some(Self(node))
+                    } else {
+                        None
+                    }
+                }
+                fn syntax(&self) -> &SyntaxNode<Lang> {
+                    &self.0
+                }
+            }
+        };
+    }
+    ast_node!(Root, ROOT);
+    ast_node!(Atom, ATOM);
+    ast_node!(List, LIST);
+
+    fn parse() -> SyntaxNode<Lang> {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.start_node(ATOM);
+        builder.token(WORD, "a");
+        builder.finish_node();
+        builder.start_node(LIST);
+        builder.token(WORD, "b");
+        builder.token(WORD, "c");
+        builder.finish_node();
+        builder.start_node(ATOM);
+        builder.token(WORD, "d");
+        builder.finish_node();
+        builder.finish_node();
+        SyntaxNode::new_root(builder.finish())
+    }
+
+    #[test]
+    fn test_support() {
+        let root = Root::cast(parse()).unwrap();
+        let atoms: Vec<Atom> = support::children(root.syntax()).collect();
+        assert_eq!(atoms.len(), 2);
+        let list: List = support::child(root.syntax()).unwrap();
+        assert_eq!(support::token(list.syntax(), WORD).unwrap().text(), "b");
+        assert_eq!(support::nth_token(list.syntax(), WORD, 1).unwrap().text(), "c");
+        assert!(support::nth_token(list.syntax(), WORD, 2).is_none());
+    }
+
+    #[test]
+    fn test_ast_ptr() {
+        let root = parse();
+        let atom: Atom = support::children(&root).nth(1).unwrap();
+        let ptr = AstPtr::new(&atom);
+        let other_root = SyntaxNode::new_root(root.green().into_owned());
+        assert_eq!(ptr.to_node(&other_root).syntax().text(), "d");
+        assert!(ptr.clone().cast::<List>().is_none());
+        let raw: SyntaxNodePtr<Lang> = ptr.clone().into();
+        assert_eq!(raw.cast::<Atom>(), Some(ptr.clone()));
+
+        // Moves the atom `d` by a character.
+        let first = other_root.first_child().unwrap();
+        let token = crate::GreenToken::new(WORD, "xy");
+        let edited = first.replace_with(crate::GreenNode::new(first.kind(), vec![token.into()]));
+        assert!(ptr.resolve(&SyntaxNode::new_root(edited)).is_none());
+        assert_eq!(ptr.resolve(&root).unwrap().syntax().text(), "d");
+    }
+
+    #[test]
+    fn test_token_at_offset_biased() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.token(WORD, "a");
+        builder.token(WHITESPACE, " ");
+        builder.token(WORD, "b");
+        builder.token(WORD, "c");
+        builder.finish_node();
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+        let text = |offset: u32, bias| {
+            root.token_at_offset_biased(offset.into(), bias).unwrap().text().to_string()
+        };
+        assert_eq!(text(0, Bias::Right), "a");
+        assert_eq!(text(1, Bias::Right), "a");
+        assert_eq!(text(2, Bias::Left), "b");
+        assert_eq!(text(3, Bias::Left), "b");
+        assert_eq!(text(3, Bias::Right), "c");
+    }
+
+    #[test]
+    fn test_anchor() {
+        let root = parse();
+        let list = root.children().nth(1).unwrap();
+        assert_eq!(list.text(), "bc");
+        let anchor = Anchor::new(&list);
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+        let rebased = |edits: &[TextEdit]| anchor.rebase(edits).map(|it| it.text_range());
+
+        // Text around the node, touching it, and in it.
+        assert_eq!(rebased(&[TextEdit::insert(0.into(), "xy")]), Some(range(3, 5)));
+        assert_eq!(rebased(&[TextEdit::insert(1.into(), "x")]), Some(range(2, 4)));
+        assert_eq!(rebased(&[TextEdit::insert(3.into(), "x")]), Some(range(1, 3)));
+        assert_eq!(rebased(&[TextEdit::insert(2.into(), "x")]), Some(range(1, 4)));
+        assert_eq!(rebased(&[TextEdit::delete(range(1, 3))]), None);
+        assert_eq!(rebased(&[TextEdit::replace(range(1, 3), "xy")]), None);
+        let grown = anchor.clone().with_bias(Bias::Left, Bias::Right);
+        let edits = [TextEdit::insert(1.into(), "x"), TextEdit::insert(4.into(), "y")];
+        assert_eq!(grown.rebase(&edits).unwrap().text_range(), range(1, 5));
+
+        // `a` becomes `xa`, and the list moves by one.
+        let anchor = anchor.rebase(&[TextEdit::insert(0.into(), "x")]).unwrap();
+        let first = root.first_child().unwrap();
+        let token = crate::GreenToken::new(WORD, "xa");
+        let edited = first.replace_with(crate::GreenNode::new(first.kind(), vec![token.into()]));
+        let new_list = anchor.resolve(&SyntaxNode::new_root(edited)).unwrap();
+        assert_eq!(new_list.text(), "bc");
+        assert_eq!(new_list.text_range(), range(2, 4));
+    }
+
+    #[test]
+    fn test_syntax_factory() {
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(ATOM);
+        builder.token(WORD, "a");
+        builder.finish_node();
+        let parsed = builder.finish();
+
+        let mut factory = SyntaxFactory::<Lang>::with_cache(&mut cache);
+        let word = factory.token(WORD, "a");
+        let atom: Atom = factory.ast(ATOM, vec![word.into()]);
+        assert!(ptr::eq(&*atom.syntax().green(), &*parsed));
+
+        let mut factory = SyntaxFactory::<Lang>::new().with_formatter(|cache, kind, children| {
+            if kind == LIST {
+                let space = cache.make_token(SyntaxKind(4), " ");
+                for idx in (1..children.len()).rev() {
+                    children.insert(idx, space.clone().into());
+                }
+            }
+        });
+        let atoms: Vec<GreenElement> = ["a", "b", "c"]
+            .iter()
+            .map(|&text| {
+                let word = factory.token(WORD, text);
+                let atom: Atom = factory.ast(ATOM, vec![word.into()]);
+                factory.element(&atom)
+            })
+            .collect();
+        let list: List = factory.ast(LIST, atoms);
+        assert_eq!(list.syntax().text(), "a b c");
+    }
+
+    #[test]
+    fn test_match_ast() {
+        let describe = |node: SyntaxNode<Lang>| {
+            match_ast! {
+                match node {
+                    Atom(it) => format!("atom {}", it.syntax().text()),
+                    List(it) => format!("list of {}", it.syntax().children_with_tokens().count()),
+                    _ => "other".to_string(),
+                }
+            }
+        };
+        let root = parse();
+        let actual: Vec<String> = root.descendants().map(describe).collect();
+        assert_eq!(actual, ["other", "atom a", "list of 2", "atom d"]);
+    }
+
+    #[test]
+    fn test_ast_visitor() {
+        ast_visitor! {
+            trait Visitor for Lang {
+                fn visit_atom(Atom);
+                fn visit_list(List);
+            }
+        }
+        struct Atoms {
+            atoms: Vec<String>,
+            lists: usize,
+        }
+        impl Visitor for Atoms {
+            fn visit_atom(&mut self, node: Atom) {
+                self.atoms.push(node.syntax().text().to_string())
+            }
+            fn visit_list(&mut self, node: List) {
+                self.lists += 1;
+                self.walk(node.syntax())
+            }
+        }
+
+        let mut visitor = Atoms { atoms: Vec::new(), lists: 0 };
+        visitor.visit(parse());
+        assert_eq!(visitor.atoms, ["a", "d"]);
+        assert_eq!(visitor.lists, 1);
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WHITESPACE, " ");
+        builder.token(WORD, "a");
+        builder.token(WHITESPACE, " ");
+        builder.start_node(ATOM);
+        builder.token(WORD, "b");
+        builder.finish_node();
+        builder.finish_node();
+        let list = SyntaxNode::<Lang>::new_root(builder.finish());
+
+        let slots: Vec<usize> =
+            list.green().slots(Lang::is_trivia).map(|(index, _)| index).collect();
+        assert_eq!(slots, [1, 3]);
+        assert_eq!(support::slot_token(&list, 0).unwrap().text(), "a");
+        let atom: Atom = support::slot_node(&list, 1).unwrap();
+        assert_eq!(atom.syntax().text_range(), TextRange::new(3.into(), 4.into()));
+        assert!(support::slot_node::<Atom>(&list, 0).is_none());
+        assert!(list.slot(2, Lang::is_trivia).is_none());
+    }
+
+    #[test]
+    fn test_semantically_eq() {
+        let build = |tokens: &[(SyntaxKind, &str)]| {
+            let mut builder = GreenNodeBuilder::new();
+            builder.start_node(LIST);
+            for &(kind, text) in tokens {
+                builder.token(kind, text);
+            }
+            builder.finish_node();
+            List(SyntaxNode::new_root(builder.finish()))
+        };
+        let list = build(&[(WORD, "a"), (WHITESPACE, " "), (WORD, "b")]);
+        let reformatted =
+            build(&[(WHITESPACE, "  "), (WORD, "a"), (WHITESPACE, "\n"), (WORD, "b")]);
+        let renamed = build(&[(WORD, "a"), (WHITESPACE, " "), (WORD, "c")]);
+        let extended = build(&[(WORD, "a"), (WORD, "b"), (WORD, "c")]);
+
+        assert!(list.semantically_eq(&reformatted));
+        assert!(!list.semantically_eq(&renamed));
+        assert!(!list.semantically_eq(&extended));
+        assert!(semantically_eq(list.syntax(), reformatted.syntax()));
+    }
+
+    #[test]
+    fn test_kind_table() {
+        static KIND_IDS: KindTable<4> = KindTable::new(&[(ATOM, 0), (LIST, 1)]);
+
+        enum Item {
+            Atom(Atom),
+            List(List),
+        }
+        impl AstNode for Item {
+            type Language = Lang;
+            fn can_cast(kind: SyntaxKind) -> bool {
+                KIND_IDS.get(kind).is_some()
+            }
+            fn cast(node: SyntaxNode<Lang>) -> Option<Self> {
+                let res = match KIND_IDS.get(node.kind())? {
+                    0 => Item::Atom(Atom(node)),
+                    1 => Item::List(List(node)),
+                    _ => return None,
+                };
+                Some(res)
+            }
+            fn syntax(&self) -> &SyntaxNode<Lang> {
+                match self {
+                    Item::Atom(it) => it.syntax(),
+                    Item::List(it) => it.syntax(),
+                }
+            }
+        }
+
+        assert_eq!(KIND_IDS.get(ROOT), None);
+        assert_eq!(KIND_IDS.get(SyntaxKind(92)), None);
+        let items: Vec<Item> = parse().children().filter_map(Item::cast).collect();
+        assert!(matches!(items.as_slice(), [Item::Atom(_), Item::List(_), Item::Atom(_)]));
+    }
+}
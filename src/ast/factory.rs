@@ -0,0 +1,99 @@
+use std::{fmt, marker::PhantomData};
+
+use crate::{
+    ast::AstNode, cow_mut::CowMut, green::GreenElement, GreenNode, GreenToken, Language, NodeCache,
+    SyntaxNode,
+};
+
+type Formatter<'c, L> =
+    dyn FnMut(&mut NodeCache, <L as Language>::Kind, &mut Vec<GreenElement>) + 'c;
+
+/// Constructs new subtrees, for example for refactorings.
+///
+/// Elements are created through a [`NodeCache`], so the new subtrees share
+/// memory with each other and with the trees parsed using the same cache.
+/// A formatter can be set to adjust the children of every new node, for
+/// example to insert whitespace between them.
+pub struct SyntaxFactory<'c, L: Language> {
+    cache: CowMut<'c, NodeCache>,
+    formatter: Option<Box<Formatter<'c, L>>>,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> SyntaxFactory<'_, L> {
+    pub fn new() -> SyntaxFactory<'static, L> {
+        SyntaxFactory {
+            cache: CowMut::Owned(NodeCache::default()),
+            formatter: None,
+            _p: PhantomData,
+        }
+    }
+
+    pub fn with_cache(cache: &mut NodeCache) -> SyntaxFactory<'_, L> {
+        SyntaxFactory { cache: CowMut::Borrowed(cache), formatter: None, _p: PhantomData }
+    }
+}
+
+impl<'c, L: Language> SyntaxFactory<'c, L> {
+    /// Sets a hook which is called with the kind and the children of every
+    /// node before it is created.
+    pub fn with_formatter<F>(mut self, formatter: F) -> SyntaxFactory<'c, L>
+    where
+        F: FnMut(&mut NodeCache, L::Kind, &mut Vec<GreenElement>) + 'c,
+    {
+        self.formatter = Some(Box::new(formatter));
+        self
+    }
+
+    pub fn token(&mut self, kind: L::Kind, text: &str) -> GreenToken {
+        self.cache.make_token(L::kind_to_raw(kind), text)
+    }
+
+    pub fn node<I>(&mut self, kind: L::Kind, children: I) -> GreenNode
+    where
+        I: IntoIterator<Item = GreenElement>,
+    {
+        let mut children: Vec<GreenElement> = children.into_iter().collect();
+        let raw = L::kind_to_raw(kind);
+        if let Some(formatter) = &mut self.formatter {
+            formatter(&mut self.cache, L::kind_from_raw(raw), &mut children);
+        }
+        self.cache.make_node(raw, children)
+    }
+
+    /// Creates a node and wraps it into an AST type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` can't be cast from a node of this kind.
+    pub fn ast<N, I>(&mut self, kind: L::Kind, children: I) -> N
+    where
+        N: AstNode<Language = L>,
+        I: IntoIterator<Item = GreenElement>,
+    {
+        let node = SyntaxNode::new_root(self.node(kind, children));
+        let kind = node.kind();
+        N::cast(node).unwrap_or_else(|| panic!("can't cast a {:?} node", kind))
+    }
+
+    /// Returns the green tree of an existing AST node, to be used as a child
+    /// of a new node.
+    pub fn element<N: AstNode<Language = L>>(&self, node: &N) -> GreenElement {
+        node.syntax().green().into_owned().into()
+    }
+}
+
+impl<L: Language> Default for SyntaxFactory<'static, L> {
+    fn default() -> Self {
+        SyntaxFactory::new()
+    }
+}
+
+impl<L: Language> fmt::Debug for SyntaxFactory<'_, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyntaxFactory")
+            .field("cache", &*self.cache)
+            .field("formatter", &self.formatter.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,819 @@
+//! Generation of the `SyntaxKind` enum and a typed AST from an ungrammar.
+//!
+//! A definition whose rule is an alternative of other nodes, like
+//! `Expr = Literal | BinExpr`, becomes an enum; any other definition becomes
+//! a node struct with an accessor per token and child node. Accessors for
+//! repeated children, like `lhs:Expr '+' rhs:Expr`, are positional.
+//!
+//! The generated code refers to `SyntaxKind` and to the language type by
+//! name, so both have to be in scope where it is included.
+mod grammar;
+
+use std::{collections::HashMap, fmt::Write};
+
+pub use self::grammar::{Grammar, GrammarError, NodeData, Rule};
+
+/// Generates code for a single grammar.
+#[derive(Debug, Clone)]
+pub struct Codegen<'a> {
+    grammar: &'a Grammar,
+    language: String,
+    token_names: HashMap<String, String>,
+    extra_kinds: Vec<String>,
+}
+
+impl<'a> Codegen<'a> {
+    pub fn new(grammar: &'a Grammar, language: &str) -> Codegen<'a> {
+        Codegen {
+            grammar,
+            language: language.to_string(),
+            token_names: HashMap::new(),
+            extra_kinds: Vec::new(),
+        }
+    }
+
+    /// Overrides the kind name of the token with the given text.
+    ///
+    /// By default, keywords are named `FN_KW` and punctuation is named after
+    /// its characters, like `L_PAREN` or `COLON_COLON`.
+    pub fn token_name(mut self, text: &str, name: &str) -> Codegen<'a> {
+        self.token_names.insert(text.to_string(), name.to_string());
+        self
+    }
+
+    /// Adds a kind which does not appear in the grammar, such as `WHITESPACE`
+    /// or `ERROR`.
+    pub fn extra_kind(mut self, name: &str) -> Codegen<'a> {
+        self.extra_kinds.push(name.to_string());
+        self
+    }
+
+    /// Generates the `SyntaxKind` enum, with a table of the names the kinds
+    /// have in the grammar.
+    pub fn kinds(&self) -> String {
+        let kinds = self.kind_list();
+        let mut buf = String::new();
+        buf.push_str(HEADER);
+        buf.push_str(
+            "#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]\n\
+             #[allow(non_camel_case_types, clippy::upper_case_acronyms)]\n\
+             #[repr(u16)]\n\
+             pub enum SyntaxKind {\n",
+        );
+        for (kind, _) in kinds.iter() {
+            writeln!(buf, "    {},", kind).unwrap();
+        }
+        buf.push_str("}\n\nimpl SyntaxKind {\n");
+        buf.push_str("    /// Name of the kind in the grammar.\n");
+        buf.push_str("    pub fn name(self) -> &'static str {\n        match self {\n");
+        for (kind, name) in kinds.iter() {
+            writeln!(buf, "            SyntaxKind::{} => {:?},", kind, name).unwrap();
+        }
+        buf.push_str("        }\n    }\n}\n");
+        buf
+    }
+
+    /// Generates the AST node structs and enums.
+    ///
+    /// Enums cast through a table indexed by the discriminants of the
+    /// generated `SyntaxKind`.
+    pub fn nodes(&self) -> String {
+        let mut buf = String::new();
+        buf.push_str(HEADER);
+        buf.push_str("use rowan::ast::{support, AstChildren, AstNode};\n");
+        let uses_kind_ids =
+            self.grammar.nodes().iter().any(|it| {
+                is_enum(&it.rule) && !self.enum_arms(&enum_variants(&it.rule)).is_empty()
+            });
+        if uses_kind_ids {
+            let n_kinds = self.kind_list().len();
+            writeln!(buf, "\nstatic KIND_IDS: rowan::ast::KindTable<{}> =", n_kinds).unwrap();
+            writeln!(buf, "    rowan::ast::KindTable::new(&[").unwrap();
+            for (id, name) in self.struct_names().iter().enumerate() {
+                let kind = to_upper_snake_case(name);
+                writeln!(buf, "        (rowan::SyntaxKind(SyntaxKind::{} as u16), {}),", kind, id)
+                    .unwrap();
+            }
+            writeln!(buf, "    ]);").unwrap();
+        }
+        for node in self.grammar.nodes() {
+            buf.push('\n');
+            match &node.rule {
+                rule if is_enum(rule) => self.gen_enum(&mut buf, &node.name, &enum_variants(rule)),
+                rule => {
+                    let mut fields = Vec::new();
+                    lower_rule(rule, None, false, &mut fields);
+                    self.gen_struct(&mut buf, &node.name, &fields)
+                }
+            }
+        }
+        buf
+    }
+
+    /// Generates a constructor function for every node struct with a simple
+    /// enough rule, to be used with a [`SyntaxFactory`].
+    ///
+    /// Tokens with fixed text are inserted automatically and optional ones
+    /// are omitted; tokens named with [`Codegen::token_name`] are passed as
+    /// text. The node types have to be in scope.
+    ///
+    /// [`SyntaxFactory`]: crate::ast::SyntaxFactory
+    pub fn factory(&self) -> String {
+        let mut buf = String::new();
+        buf.push_str(HEADER);
+        buf.push_str("use rowan::ast::SyntaxFactory;\n");
+        for node in self.grammar.nodes() {
+            if is_enum(&node.rule) {
+                continue;
+            }
+            let mut params = Vec::new();
+            if lower_ctor(&node.rule, None, false, &mut params).is_none() {
+                continue;
+            }
+            buf.push('\n');
+            self.gen_ctor(&mut buf, &node.name, &params);
+        }
+        buf
+    }
+
+    /// Generates a `Visitor` trait with a method for every node struct, see
+    /// [`ast_visitor`]. The node types have to be in scope.
+    ///
+    /// [`ast_visitor`]: crate::ast_visitor
+    pub fn visitor(&self) -> String {
+        let mut buf = String::new();
+        buf.push_str(HEADER);
+        writeln!(buf, "rowan::ast_visitor! {{").unwrap();
+        writeln!(buf, "    pub trait Visitor for {} {{", self.language).unwrap();
+        for node in self.grammar.nodes() {
+            if is_enum(&node.rule) {
+                continue;
+            }
+            let method = format!("visit_{}", to_lower_snake_case(&node.name));
+            writeln!(buf, "        fn {}({});", method, node.name).unwrap();
+        }
+        buf.push_str("    }\n}\n");
+        buf
+    }
+
+    fn gen_ctor(&self, buf: &mut String, name: &str, params: &[Param]) {
+        let mut names: Vec<String> = Vec::new();
+        let mut args = String::new();
+        // The children pushed before the first conditional one initialize the
+        // vector.
+        let mut elements: Vec<String> = Vec::new();
+        let mut body = String::new();
+        for param in params {
+            let (base, ty) = match param {
+                Param::Token { text, opt: true, .. } if !self.token_names.contains_key(text) => {
+                    continue
+                }
+                Param::Token { text, .. } if !self.token_names.contains_key(text) => {
+                    let kind = self.token_kind(text);
+                    let child = format!("f.token(SyntaxKind::{}, {:?}).into()", kind, text);
+                    push_child(&mut elements, &mut body, child);
+                    continue;
+                }
+                Param::Token { label, text, opt } => {
+                    let base =
+                        label.clone().unwrap_or_else(|| self.token_kind(text).to_lowercase());
+                    (base, if *opt { "Option<&str>" } else { "&str" }.to_string())
+                }
+                Param::TokenKind { label, .. } => (label.clone(), "SyntaxKind".to_string()),
+                Param::Node { label, ty, opt, many } => {
+                    let base = label.clone().unwrap_or_else(|| {
+                        let name = to_lower_snake_case(ty);
+                        if *many {
+                            format!("{}s", name)
+                        } else {
+                            name
+                        }
+                    });
+                    let param_ty = match (opt, many) {
+                        (_, true) => format!("impl IntoIterator<Item = {}>", ty),
+                        (true, false) => format!("Option<{}>", ty),
+                        (false, false) => ty.clone(),
+                    };
+                    (base, param_ty)
+                }
+            };
+            let mut param_name = base.clone();
+            let mut idx = 1;
+            while names.contains(&param_name) {
+                idx += 1;
+                param_name = format!("{}_{}", base, idx);
+            }
+            names.push(param_name.clone());
+            let param_name = escape_ident(&param_name);
+            write!(args, ", {}: {}", param_name, ty).unwrap();
+
+            match param {
+                Param::Token { text, opt, .. } => {
+                    let kind = self.token_kind(text);
+                    if *opt {
+                        writeln!(body, "    if let Some(it) = {} {{", param_name).unwrap();
+                        writeln!(
+                            body,
+                            "        children.push(f.token(SyntaxKind::{}, it).into());",
+                            kind
+                        )
+                        .unwrap();
+                        writeln!(body, "    }}").unwrap();
+                    } else {
+                        let child = format!("f.token(SyntaxKind::{}, {}).into()", kind, param_name);
+                        push_child(&mut elements, &mut body, child);
+                    }
+                }
+                Param::TokenKind { .. } => {
+                    let child = format!("f.token({}, {}.name()).into()", param_name, param_name);
+                    push_child(&mut elements, &mut body, child);
+                }
+                Param::Node { many: true, .. } => {
+                    writeln!(body, "    for it in {} {{", param_name).unwrap();
+                    writeln!(body, "        children.push(f.element(&it));").unwrap();
+                    writeln!(body, "    }}").unwrap();
+                }
+                Param::Node { opt: true, .. } => {
+                    writeln!(body, "    if let Some(it) = &{} {{", param_name).unwrap();
+                    writeln!(body, "        children.push(f.element(it));").unwrap();
+                    writeln!(body, "    }}").unwrap();
+                }
+                Param::Node { .. } => {
+                    let child = format!("f.element(&{})", param_name);
+                    push_child(&mut elements, &mut body, child);
+                }
+            }
+        }
+
+        writeln!(
+            buf,
+            "pub fn {}(f: &mut SyntaxFactory<'_, {}>{}) -> {} {{",
+            escape_ident(&to_lower_snake_case(name)),
+            self.language,
+            args,
+            name
+        )
+        .unwrap();
+        let binding = if body.is_empty() { "children" } else { "mut children" };
+        let line = format!("    let {} = vec![{}];", binding, elements.join(", "));
+        if elements.is_empty() {
+            writeln!(buf, "    let {} = Vec::new();", binding).unwrap();
+        } else if line.len() <= 100 {
+            writeln!(buf, "{}", line).unwrap();
+        } else {
+            writeln!(buf, "    let {} = vec![", binding).unwrap();
+            for element in &elements {
+                writeln!(buf, "        {},", element).unwrap();
+            }
+            writeln!(buf, "    ];").unwrap();
+        }
+        buf.push_str(&body);
+        writeln!(buf, "    f.ast(SyntaxKind::{}, children)", to_upper_snake_case(name)).unwrap();
+        writeln!(buf, "}}").unwrap();
+    }
+
+    fn gen_struct(&self, buf: &mut String, name: &str, fields: &[Field]) {
+        let lang = &self.language;
+        writeln!(buf, "#[derive(Debug, Clone, PartialEq, Eq, Hash)]").unwrap();
+        writeln!(buf, "pub struct {} {{", name).unwrap();
+        writeln!(buf, "    pub(crate) syntax: rowan::SyntaxNode<{}>,", lang).unwrap();
+        writeln!(buf, "}}\n").unwrap();
+
+        if !fields.is_empty() {
+            writeln!(buf, "impl {} {{", name).unwrap();
+            // `Param (',' Param)*` is accessed as a whole.
+            let many: Vec<&str> = fields
+                .iter()
+                .filter_map(|it| match it {
+                    Field::Node { ty, many: true, .. } => Some(ty.as_str()),
+                    _ => None,
+                })
+                .collect();
+            let mut seen: Vec<String> = Vec::new();
+            let mut occurrences: HashMap<String, usize> = HashMap::new();
+            for field in fields {
+                let (method, key) = match field {
+                    Field::Token { label, texts } => {
+                        let kinds: Vec<String> =
+                            texts.iter().map(|it| self.token_kind(it)).collect();
+                        let method = label
+                            .clone()
+                            .unwrap_or_else(|| format!("{}_token", kinds.join("_").to_lowercase()));
+                        (method, kinds.join(" | "))
+                    }
+                    Field::Node { label, ty, many } => {
+                        let method = label.clone().unwrap_or_else(|| {
+                            let name = to_lower_snake_case(ty);
+                            if *many {
+                                format!("{}s", name)
+                            } else {
+                                name
+                            }
+                        });
+                        (method, ty.clone())
+                    }
+                };
+                let nth = *occurrences.entry(key).and_modify(|it| *it += 1).or_insert(0);
+                if let Field::Node { ty, many: false, label: None } = field {
+                    if many.contains(&ty.as_str()) {
+                        continue;
+                    }
+                }
+                if seen.contains(&method) {
+                    continue;
+                }
+                seen.push(method.clone());
+                let method = escape_ident(&method);
+
+                match field {
+                    Field::Token { texts, .. } => {
+                        let kinds: Vec<String> = texts
+                            .iter()
+                            .map(|it| format!("SyntaxKind::{}", self.token_kind(it)))
+                            .collect();
+                        writeln!(
+                            buf,
+                            "    pub fn {}(&self) -> Option<rowan::SyntaxToken<{}>> {{",
+                            method, lang
+                        )
+                        .unwrap();
+                        if kinds.len() > 1 {
+                            writeln!(buf, "        self.syntax").unwrap();
+                            writeln!(buf, "            .children_with_tokens()").unwrap();
+                            writeln!(buf, "            .filter_map(|it| it.into_token())").unwrap();
+                            let kinds = kinds.join(" | ");
+                            if nth == 0 {
+                                writeln!(
+                                    buf,
+                                    "            .find(|it| matches!(it.kind(), {}))",
+                                    kinds
+                                )
+                                .unwrap();
+                            } else {
+                                writeln!(
+                                    buf,
+                                    "            .filter(|it| matches!(it.kind(), {}))",
+                                    kinds
+                                )
+                                .unwrap();
+                                writeln!(buf, "            .nth({})", nth).unwrap();
+                            }
+                        } else if nth == 0 {
+                            writeln!(buf, "        support::token(&self.syntax, {})", kinds[0])
+                                .unwrap();
+                        } else {
+                            writeln!(
+                                buf,
+                                "        support::nth_token(&self.syntax, {}, {})",
+                                kinds[0], nth
+                            )
+                            .unwrap();
+                        }
+                    }
+                    Field::Node { ty, many: true, .. } => {
+                        writeln!(buf, "    pub fn {}(&self) -> AstChildren<{}> {{", method, ty)
+                            .unwrap();
+                        writeln!(buf, "        support::children(&self.syntax)").unwrap();
+                    }
+                    Field::Node { ty, many: false, .. } => {
+                        writeln!(buf, "    pub fn {}(&self) -> Option<{}> {{", method, ty).unwrap();
+                        if nth == 0 {
+                            writeln!(buf, "        support::child(&self.syntax)").unwrap();
+                        } else {
+                            writeln!(buf, "        support::children(&self.syntax).nth({})", nth)
+                                .unwrap();
+                        }
+                    }
+                }
+                writeln!(buf, "    }}").unwrap();
+            }
+            writeln!(buf, "}}\n").unwrap();
+        }
+
+        writeln!(buf, "impl AstNode for {} {{", name).unwrap();
+        writeln!(buf, "    type Language = {};", lang).unwrap();
+        writeln!(buf, "    fn can_cast(kind: SyntaxKind) -> bool {{").unwrap();
+        writeln!(buf, "        kind == SyntaxKind::{}", to_upper_snake_case(name)).unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "    fn cast(syntax: rowan::SyntaxNode<{}>) -> Option<Self> {{", lang)
+            .unwrap();
+        writeln!(buf, "        if Self::can_cast(syntax.kind()) {{").unwrap();
+        writeln!(buf, "            Some({} {{ syntax }})", name).unwrap();
+        writeln!(buf, "        }} else {{").unwrap();
+        writeln!(buf, "            None").unwrap();
+        writeln!(buf, "        }}").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "    fn syntax(&self) -> &rowan::SyntaxNode<{}> {{", lang).unwrap();
+        writeln!(buf, "        &self.syntax").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "}}").unwrap();
+    }
+
+    fn gen_enum(&self, buf: &mut String, name: &str, variants: &[&str]) {
+        let lang = &self.language;
+        let arms = self.enum_arms(variants);
+        writeln!(buf, "#[derive(Debug, Clone, PartialEq, Eq, Hash)]").unwrap();
+        writeln!(buf, "pub enum {} {{", name).unwrap();
+        for variant in variants {
+            writeln!(buf, "    {}({}),", variant, variant).unwrap();
+        }
+        writeln!(buf, "}}\n").unwrap();
+
+        writeln!(buf, "impl AstNode for {} {{", name).unwrap();
+        writeln!(buf, "    type Language = {};", lang).unwrap();
+        writeln!(buf, "    fn can_cast(kind: SyntaxKind) -> bool {{").unwrap();
+        // No node can be cast to an enum whose variants hold no structs, like
+        // enums which only refer to each other.
+        if arms.is_empty() {
+            writeln!(buf, "        let _ = kind;").unwrap();
+            writeln!(buf, "        false").unwrap();
+        } else {
+            let ids: Vec<usize> = arms.iter().flat_map(|(ids, _)| ids.iter().copied()).collect();
+            writeln!(
+                buf,
+                "        matches!(KIND_IDS.get(rowan::SyntaxKind(kind as u16)), Some({}))",
+                ids_pattern(&ids)
+            )
+            .unwrap();
+        }
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "    fn cast(syntax: rowan::SyntaxNode<{}>) -> Option<Self> {{", lang)
+            .unwrap();
+        if arms.is_empty() {
+            writeln!(buf, "        let _ = syntax;").unwrap();
+            writeln!(buf, "        None").unwrap();
+        } else {
+            self.gen_enum_cast(buf, name, &arms);
+        }
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "    fn syntax(&self) -> &rowan::SyntaxNode<{}> {{", lang).unwrap();
+        writeln!(buf, "        match self {{").unwrap();
+        for variant in variants {
+            writeln!(buf, "            {}::{}(it) => it.syntax(),", name, variant).unwrap();
+        }
+        writeln!(buf, "        }}").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "}}").unwrap();
+
+        for variant in variants {
+            writeln!(buf).unwrap();
+            writeln!(buf, "impl From<{}> for {} {{", variant, name).unwrap();
+            writeln!(buf, "    fn from(node: {}) -> {} {{", variant, name).unwrap();
+            writeln!(buf, "        {}::{}(node)", name, variant).unwrap();
+            writeln!(buf, "    }}").unwrap();
+            writeln!(buf, "}}").unwrap();
+        }
+    }
+
+    /// The ids of the structs each variant of an enum casts from, skipping
+    /// the variants which hold none.
+    fn enum_arms<'v>(&self, variants: &[&'v str]) -> Vec<(Vec<usize>, &'v str)> {
+        let struct_names = self.struct_names();
+        // As with trying the variants in order, a kind goes to the first
+        // variant which can hold it.
+        let mut seen = Vec::new();
+        variants
+            .iter()
+            .filter_map(|&variant| {
+                let mut structs = Vec::new();
+                self.collect_structs(variant, &mut structs);
+                let ids: Vec<usize> = structs
+                    .iter()
+                    .filter_map(|&it| struct_names.iter().position(|&name| name == it))
+                    .filter(|&it| {
+                        let new = !seen.contains(&it);
+                        seen.push(it);
+                        new
+                    })
+                    .collect();
+                if ids.is_empty() {
+                    return None;
+                }
+                Some((ids, variant))
+            })
+            .collect()
+    }
+
+    fn gen_enum_cast(&self, buf: &mut String, name: &str, arms: &[(Vec<usize>, &str)]) {
+        writeln!(
+            buf,
+            "        let res = match KIND_IDS.get(rowan::SyntaxKind(syntax.kind() as u16))? {{"
+        )
+        .unwrap();
+        for (ids, variant) in arms.iter() {
+            let ids = ids_pattern(ids);
+            if self.grammar.node(variant).is_some_and(|it| is_enum(&it.rule)) {
+                writeln!(
+                    buf,
+                    "            {} => {}::{}({}::cast(syntax)?),",
+                    ids, name, variant, variant
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    buf,
+                    "            {} => {}::{}({} {{ syntax }}),",
+                    ids, name, variant, variant
+                )
+                .unwrap();
+            }
+        }
+        writeln!(buf, "            _ => return None,").unwrap();
+        writeln!(buf, "        }};").unwrap();
+        writeln!(buf, "        Some(res)").unwrap();
+    }
+
+    /// Kinds of the generated `SyntaxKind`, with their names in the grammar.
+    fn kind_list(&self) -> Vec<(String, String)> {
+        let mut kinds: Vec<(String, String)> = Vec::new();
+        kinds.extend(self.extra_kinds.iter().map(|it| (it.clone(), it.clone())));
+        kinds.extend(self.grammar.tokens().iter().map(|it| (self.token_kind(it), it.clone())));
+        kinds.extend(
+            self.struct_names().iter().map(|&it| (to_upper_snake_case(it), it.to_string())),
+        );
+        kinds
+    }
+
+    /// Names of the nodes which become structs, in the order of their ids.
+    fn struct_names(&self) -> Vec<&str> {
+        let nodes = self.grammar.nodes().iter();
+        nodes.filter(|it| !is_enum(&it.rule)).map(|it| it.name.as_str()).collect()
+    }
+
+    /// Collects the names of the nodes an enum can hold, through nested
+    /// enums.
+    fn collect_structs<'g>(&'g self, name: &'g str, acc: &mut Vec<&'g str>) {
+        if acc.contains(&name) {
+            return;
+        }
+        acc.push(name);
+        let rule = self.grammar.node(name).map(|it| &it.rule).filter(|&it| is_enum(it));
+        if let Some(Rule::Alt(alts)) = rule {
+            for alt in alts {
+                if let Rule::Node(name) = alt {
+                    self.collect_structs(name, acc)
+                }
+            }
+        }
+    }
+
+    fn token_kind(&self, text: &str) -> String {
+        if let Some(name) = self.token_names.get(text) {
+            return name.clone();
+        }
+        if text.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return format!("{}_KW", text.to_uppercase());
+        }
+        let names: Vec<&str> = text.chars().map(punct_name).collect();
+        names.join("_")
+    }
+}
+
+/// Adds a `children.push` of `child` to `body`, or to the initial `elements`
+/// if nothing has been pushed conditionally yet.
+fn push_child(elements: &mut Vec<String>, body: &mut String, child: String) {
+    if body.is_empty() {
+        elements.push(child);
+    } else {
+        writeln!(body, "    children.push({});", child).unwrap();
+    }
+}
+
+/// Formats a pattern matching the `ids`, with runs of consecutive ids as
+/// ranges.
+fn ids_pattern(ids: &[usize]) -> String {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    let mut parts = Vec::new();
+    let mut rest = ids.as_slice();
+    while let Some(&start) = rest.first() {
+        let len = rest.iter().enumerate().take_while(|&(i, &it)| it == start + i).count();
+        match len {
+            1 | 2 => parts.extend(rest[..len].iter().map(|it| it.to_string())),
+            _ => parts.push(format!("{}..={}", start, start + len - 1)),
+        }
+        rest = &rest[len..];
+    }
+    parts.join(" | ")
+}
+
+const HEADER: &str = "// Generated by `rowan::codegen`, do not edit by hand.\n\n";
+
+fn is_enum(rule: &Rule) -> bool {
+    match rule {
+        Rule::Alt(alts) => alts.iter().all(|it| matches!(it, Rule::Node(_))),
+        _ => false,
+    }
+}
+
+/// The names of the alternatives of an enum rule.
+fn enum_variants(rule: &Rule) -> Vec<&str> {
+    match rule {
+        Rule::Alt(alts) => alts
+            .iter()
+            .map(|it| match it {
+                Rule::Node(name) => name.as_str(),
+                _ => unreachable!(),
+            })
+            .collect(),
+        _ => unreachable!(),
+    }
+}
+
+enum Field {
+    Token { label: Option<String>, texts: Vec<String> },
+    Node { label: Option<String>, ty: String, many: bool },
+}
+
+fn lower_rule(rule: &Rule, label: Option<&str>, many: bool, acc: &mut Vec<Field>) {
+    let label = label.map(|it| it.to_string());
+    match rule {
+        // `op:('+' | '-')` is a single accessor.
+        Rule::Labeled { label, rule } => match &**rule {
+            Rule::Alt(alts) if alts.iter().all(|it| matches!(it, Rule::Token(_))) => {
+                let texts = alts
+                    .iter()
+                    .map(|it| match it {
+                        Rule::Token(text) => text.clone(),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                acc.push(Field::Token { label: Some(label.clone()), texts })
+            }
+            rule => lower_rule(rule, Some(label), many, acc),
+        },
+        Rule::Node(ty) => acc.push(Field::Node { label, ty: ty.clone(), many }),
+        Rule::Token(text) => acc.push(Field::Token { label, texts: vec![text.clone()] }),
+        Rule::Opt(rule) => lower_rule(rule, label.as_deref(), many, acc),
+        Rule::Rep(rule) => lower_rule(rule, label.as_deref(), true, acc),
+        Rule::Seq(rules) | Rule::Alt(rules) => {
+            rules.iter().for_each(|rule| lower_rule(rule, None, many, acc))
+        }
+    }
+}
+
+enum Param {
+    Token { label: Option<String>, text: String, opt: bool },
+    TokenKind { label: String },
+    Node { label: Option<String>, ty: String, opt: bool, many: bool },
+}
+
+/// Lowers a rule to constructor parameters, or returns `None` if it has
+/// alternatives or repeated sequences.
+fn lower_ctor(rule: &Rule, label: Option<&str>, opt: bool, acc: &mut Vec<Param>) -> Option<()> {
+    let label = label.map(|it| it.to_string());
+    match rule {
+        Rule::Labeled { label, rule } => match &**rule {
+            Rule::Alt(alts) if alts.iter().all(|it| matches!(it, Rule::Token(_))) => {
+                acc.push(Param::TokenKind { label: label.clone() })
+            }
+            rule => lower_ctor(rule, Some(label), opt, acc)?,
+        },
+        Rule::Node(ty) => acc.push(Param::Node { label, ty: ty.clone(), opt, many: false }),
+        Rule::Token(text) => acc.push(Param::Token { label, text: text.clone(), opt }),
+        Rule::Opt(rule) => lower_ctor(rule, label.as_deref(), true, acc)?,
+        Rule::Rep(rule) => match &**rule {
+            Rule::Node(ty) => acc.push(Param::Node { label, ty: ty.clone(), opt, many: true }),
+            _ => return None,
+        },
+        Rule::Seq(rules) => {
+            for rule in rules {
+                lower_ctor(rule, None, opt, acc)?;
+            }
+        }
+        Rule::Alt(_) => return None,
+    }
+    Some(())
+}
+
+fn escape_ident(ident: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "box", "break", "const", "continue", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type",
+        "unsafe", "use", "where", "while", "yield",
+    ];
+    if KEYWORDS.contains(&ident) {
+        format!("r#{}", ident)
+    } else {
+        ident.to_string()
+    }
+}
+
+fn punct_name(c: char) -> &'static str {
+    match c {
+        '(' => "L_PAREN",
+        ')' => "R_PAREN",
+        '{' => "L_CURLY",
+        '}' => "R_CURLY",
+        '[' => "L_BRACK",
+        ']' => "R_BRACK",
+        '<' => "L_ANGLE",
+        '>' => "R_ANGLE",
+        ',' => "COMMA",
+        ';' => "SEMICOLON",
+        ':' => "COLON",
+        '.' => "DOT",
+        '=' => "EQ",
+        '+' => "PLUS",
+        '-' => "MINUS",
+        '*' => "STAR",
+        '/' => "SLASH",
+        '\\' => "BACKSLASH",
+        '!' => "BANG",
+        '&' => "AMP",
+        '|' => "PIPE",
+        '#' => "POUND",
+        '?' => "QUESTION",
+        '@' => "AT",
+        '^' => "CARET",
+        '%' => "PERCENT",
+        '~' => "TILDE",
+        '$' => "DOLLAR",
+        '\'' => "QUOTE",
+        '"' => "DOUBLE_QUOTE",
+        '`' => "BACKTICK",
+        _ => "PUNCT",
+    }
+}
+
+fn to_upper_snake_case(s: &str) -> String {
+    to_lower_snake_case(s).to_uppercase()
+}
+
+fn to_lower_snake_case(s: &str) -> String {
+    let mut buf = String::with_capacity(s.len());
+    let mut prev = false;
+    for c in s.chars() {
+        if c.is_ascii_uppercase() && prev {
+            buf.push('_');
+        }
+        prev = c != '_';
+        buf.push(c.to_ascii_lowercase());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = "
+// Arithmetic.
+Root = Expr*
+
+Expr =
+  Literal | ParenExpr | BinExpr
+
+Literal = 'number'
+
+ParenExpr = '(' Expr ')'
+
+BinExpr =
+  lhs:Expr op:('+' | '*') rhs:Expr
+";
+
+    #[test]
+    fn test_parse_grammar() {
+        let grammar: Grammar = GRAMMAR.parse().unwrap();
+        let names: Vec<&str> = grammar.nodes().iter().map(|it| it.name.as_str()).collect();
+        assert_eq!(names, ["Root", "Expr", "Literal", "ParenExpr", "BinExpr"]);
+        assert_eq!(grammar.tokens(), ["number", "(", ")", "+", "*"]);
+        assert_eq!(
+            grammar.node("Root").unwrap().rule,
+            Rule::Rep(Box::new(Rule::Node("Expr".into())))
+        );
+
+        let err = "A = B".parse::<Grammar>().unwrap_err();
+        assert_eq!(err.to_string(), "line 1: undefined node `B`");
+        let err = "A = 'a'\n\nB =\n  A\n  | C".parse::<Grammar>().unwrap_err();
+        assert_eq!(err.to_string(), "line 5: undefined node `C`");
+        let err = "A = 'a'\nB = ( 'b'".parse::<Grammar>().unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected `)`");
+    }
+
+    #[test]
+    fn test_codegen() {
+        let grammar: Grammar = GRAMMAR.parse().unwrap();
+        let codegen =
+            Codegen::new(&grammar, "Lang").token_name("number", "NUMBER").extra_kind("WHITESPACE");
+
+        // The expected outputs are kept next to this file, to be reviewed
+        // like the generator itself.
+        assert_eq!(codegen.kinds(), include_str!("codegen/test_data/kinds.txt"));
+        assert_eq!(codegen.nodes(), include_str!("codegen/test_data/nodes.txt"));
+        assert_eq!(codegen.factory(), include_str!("codegen/test_data/factory.txt"));
+        assert_eq!(codegen.visitor(), include_str!("codegen/test_data/visitor.txt"));
+    }
+
+    #[test]
+    fn test_codegen_enum_without_structs() {
+        // The enums only refer to each other, so no node casts to them.
+        let grammar: Grammar = "Root = A\nA = B | C\nB = A | C\nC = A | B".parse().unwrap();
+        let nodes = Codegen::new(&grammar, "Lang").nodes();
+        assert!(!nodes.contains("KIND_IDS"));
+        assert!(!nodes.contains("Some()"));
+        assert!(nodes.contains("        let _ = kind;\n        false\n"));
+        assert!(nodes.contains("        let _ = syntax;\n        None\n"));
+    }
+}
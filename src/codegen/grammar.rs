@@ -0,0 +1,308 @@
+//! Parser for the ungrammar format.
+//!
+//! ```text
+//! // Comments start with two slashes.
+//! ParenExpr =
+//!   '(' Expr ')'
+//!
+//! Expr =
+//!   Literal | ParenExpr | BinExpr
+//!
+//! BinExpr =
+//!   lhs:Expr op:('+' | '*') rhs:Expr
+//! ```
+use std::{error::Error, fmt, str::FromStr};
+
+/// A parsed `.ungram` grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grammar {
+    nodes: Vec<NodeData>,
+    tokens: Vec<String>,
+}
+
+/// A single `Name = rule` definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeData {
+    pub name: String,
+    pub rule: Rule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    Labeled { label: String, rule: Box<Rule> },
+    Node(String),
+    Token(String),
+    Seq(Vec<Rule>),
+    Alt(Vec<Rule>),
+    Opt(Box<Rule>),
+    Rep(Box<Rule>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarError {
+    line: u32,
+    message: String,
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for GrammarError {}
+
+impl Grammar {
+    /// Definitions, in the order of the grammar.
+    pub fn nodes(&self) -> &[NodeData] {
+        &self.nodes
+    }
+
+    /// Texts of all tokens, in the order of first use.
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    pub fn node(&self, name: &str) -> Option<&NodeData> {
+        self.nodes.iter().find(|it| it.name == name)
+    }
+}
+
+impl FromStr for Grammar {
+    type Err = GrammarError;
+
+    fn from_str(text: &str) -> Result<Grammar, GrammarError> {
+        let tokens = lex(text)?;
+        let mut parser = Parser { tokens, pos: 0, refs: Vec::new() };
+        let mut nodes: Vec<NodeData> = Vec::new();
+        while !parser.at_eof() {
+            let line = parser.line();
+            let name = match parser.bump() {
+                Some(Token::Ident(name)) => name,
+                _ => return Err(parser.error("expected a node name")),
+            };
+            if !parser.eat(&Token::Eq) {
+                return Err(parser.error("expected `=`"));
+            }
+            if nodes.iter().any(|it| it.name == name) {
+                return Err(GrammarError { line, message: format!("duplicate node `{}`", name) });
+            }
+            let rule = parser.alt()?;
+            nodes.push(NodeData { name, rule });
+        }
+
+        let mut grammar = Grammar { nodes, tokens: Vec::new() };
+        for node in grammar.nodes.iter() {
+            collect_tokens(&node.rule, &mut grammar.tokens);
+        }
+        if let Some((line, name)) =
+            parser.refs.iter().find(|(_, name)| grammar.node(name).is_none())
+        {
+            return Err(GrammarError {
+                line: *line,
+                message: format!("undefined node `{}`", name),
+            });
+        }
+        Ok(grammar)
+    }
+}
+
+fn collect_tokens(rule: &Rule, tokens: &mut Vec<String>) {
+    match rule {
+        Rule::Labeled { rule, .. } | Rule::Opt(rule) | Rule::Rep(rule) => {
+            collect_tokens(rule, tokens)
+        }
+        Rule::Node(_) => (),
+        Rule::Token(text) => {
+            if !tokens.contains(text) {
+                tokens.push(text.clone())
+            }
+        }
+        Rule::Seq(rules) | Rule::Alt(rules) => {
+            rules.iter().for_each(|rule| collect_tokens(rule, tokens))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Literal(String),
+    Eq,
+    Star,
+    QMark,
+    Pipe,
+    Colon,
+    LParen,
+    RParen,
+}
+
+fn lex(text: &str) -> Result<Vec<(u32, Token)>, GrammarError> {
+    let mut res = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            '=' => Token::Eq,
+            '*' => Token::Star,
+            '?' => Token::QMark,
+            '|' => Token::Pipe,
+            ':' => Token::Colon,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '\'' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ '\'') | Some(c @ '\\') => literal.push(c),
+                            _ => {
+                                return Err(GrammarError { line, message: "invalid escape".into() })
+                            }
+                        },
+                        Some('\n') | None => {
+                            return Err(GrammarError { line, message: "unterminated token".into() })
+                        }
+                        Some(c) => literal.push(c),
+                    }
+                }
+                Token::Literal(literal)
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&c) = chars.peek().filter(|&&c| c.is_alphanumeric() || c == '_') {
+                    ident.push(c);
+                    chars.next();
+                }
+                Token::Ident(ident)
+            }
+            c => {
+                return Err(GrammarError { line, message: format!("unexpected character `{}`", c) })
+            }
+        };
+        res.push((line, token));
+    }
+    Ok(res)
+}
+
+struct Parser {
+    tokens: Vec<(u32, Token)>,
+    pos: usize,
+    /// The referenced nodes and the lines of the references, checked once
+    /// all the definitions are parsed.
+    refs: Vec<(u32, String)>,
+}
+
+impl Parser {
+    fn alt(&mut self) -> Result<Rule, GrammarError> {
+        let mut alts = vec![self.seq()?];
+        while self.eat(&Token::Pipe) {
+            alts.push(self.seq()?);
+        }
+        Ok(if alts.len() == 1 { alts.pop().unwrap() } else { Rule::Alt(alts) })
+    }
+
+    fn seq(&mut self) -> Result<Rule, GrammarError> {
+        let mut seq = Vec::new();
+        while !self.at_rule_end() {
+            seq.push(self.postfix()?);
+        }
+        match seq.len() {
+            0 => Err(self.error("expected a rule")),
+            1 => Ok(seq.pop().unwrap()),
+            _ => Ok(Rule::Seq(seq)),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Rule, GrammarError> {
+        let mut rule = self.atom()?;
+        loop {
+            if self.eat(&Token::Star) {
+                rule = Rule::Rep(Box::new(rule));
+            } else if self.eat(&Token::QMark) {
+                rule = Rule::Opt(Box::new(rule));
+            } else {
+                return Ok(rule);
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Rule, GrammarError> {
+        let line = self.line();
+        match self.bump() {
+            Some(Token::Ident(name)) => {
+                if self.eat(&Token::Colon) {
+                    let rule = Box::new(self.postfix()?);
+                    return Ok(Rule::Labeled { label: name, rule });
+                }
+                self.refs.push((line, name.clone()));
+                Ok(Rule::Node(name))
+            }
+            Some(Token::Literal(text)) => Ok(Rule::Token(text)),
+            Some(Token::LParen) => {
+                let rule = self.alt()?;
+                if !self.eat(&Token::RParen) {
+                    return Err(self.error("expected `)`"));
+                }
+                Ok(rule)
+            }
+            Some(_) => {
+                self.pos -= 1;
+                Err(self.error("expected a rule"))
+            }
+            None => Err(self.error("expected a rule")),
+        }
+    }
+
+    /// Rules end before `|`, `)` and the `Name =` of the next definition.
+    fn at_rule_end(&self) -> bool {
+        match self.nth(0) {
+            None | Some(Token::Pipe) | Some(Token::RParen) => true,
+            Some(Token::Ident(_)) => self.nth(1) == Some(&Token::Eq),
+            Some(_) => false,
+        }
+    }
+
+    fn nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n).map(|it| &it.1)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    fn bump(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos)?.1.clone();
+        self.pos += 1;
+        Some(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.nth(0) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn line(&self) -> u32 {
+        self.tokens.get(self.pos).or_else(|| self.tokens.last()).map_or(1, |it| it.0)
+    }
+
+    fn error(&self, message: &str) -> GrammarError {
+        GrammarError { line: self.line(), message: message.to_string() }
+    }
+}
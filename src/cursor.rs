@@ -93,13 +93,16 @@ use std::{
 };
 
 use countme::Count;
+use rustc_hash::FxHashMap;
 
 use crate::{
-    green::{GreenChild, GreenElementRef, GreenNodeData, GreenTokenData, SyntaxKind},
+    green::{GreenChild, GreenElement, GreenElementRef, GreenNodeData, GreenTokenData, SyntaxKind},
     sll,
+    syntax_text::FilteredText,
+    token_text::TokenText,
     utility_types::Delta,
-    Direction, GreenNode, GreenToken, NodeOrToken, SyntaxText, TextRange, TextSize, TokenAtOffset,
-    WalkEvent,
+    Direction, GreenNode, GreenToken, NodeOrToken, RangeBase, SyntaxText, TextRange, TextSize,
+    TokenAtOffset, WalkEvent,
 };
 
 enum Green {
@@ -261,7 +264,7 @@ impl NodeData {
                         }
                     }
 
-                    Box::from_raw(res);
+                    drop(Box::from_raw(res));
                     res = node as *mut _;
                     (*res).inc_rc();
                 }
@@ -312,11 +315,11 @@ impl NodeData {
     fn green(&self) -> GreenElementRef<'_> {
         match &self.green {
             Green::Node { ptr } => GreenElementRef::Node(unsafe { &*ptr.get().as_ptr() }),
-            Green::Token { ptr } => GreenElementRef::Token(unsafe { &*ptr.as_ref() }),
+            Green::Token { ptr } => GreenElementRef::Token(unsafe { ptr.as_ref() }),
         }
     }
     #[inline]
-    fn green_siblings(&self) -> slice::Iter<GreenChild> {
+    fn green_siblings(&self) -> slice::Iter<'_, GreenChild> {
         match &self.parent().map(|it| &it.green) {
             Some(Green::Node { ptr }) => unsafe { &*ptr.get().as_ptr() }.children().raw,
             Some(Green::Token { .. }) => {
@@ -506,12 +509,14 @@ impl NodeData {
 
 impl SyntaxNode {
     pub fn new_root(green: GreenNode) -> SyntaxNode {
+        trace_span!("SyntaxNode::new_root");
         let green = GreenNode::into_raw(green);
         let green = Green::Node { ptr: Cell::new(green) };
         SyntaxNode { ptr: NodeData::new(None, 0, 0.into(), green, false) }
     }
 
     pub fn new_root_mut(green: GreenNode) -> SyntaxNode {
+        trace_span!("SyntaxNode::new_root_mut");
         let green = GreenNode::into_raw(green);
         let green = Green::Node { ptr: Cell::new(green) };
         SyntaxNode { ptr: NodeData::new(None, 0, 0.into(), green, true) }
@@ -561,6 +566,71 @@ impl SyntaxNode {
         }
     }
 
+    /// Returns the green tree of this node with all the `replacements` of its
+    /// descendants applied, rebuilding each affected ancestor once.
+    ///
+    /// If both an element and one of its descendants are replaced, the
+    /// replacement of the descendant is ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an element is not a descendant of this node.
+    pub fn replace_descendants<I>(&self, replacements: I) -> GreenNode
+    where
+        I: IntoIterator<Item = (SyntaxElement, GreenElement)>,
+    {
+        trace_span!("SyntaxNode::replace_descendants");
+        let replacements: FxHashMap<SyntaxElement, GreenElement> =
+            replacements.into_iter().collect();
+        if replacements.is_empty() {
+            return self.green_ref().to_owned();
+        }
+        // The ancestors of the replaced elements, up to `self`, with their
+        // depths below it.
+        let mut dirty: FxHashMap<SyntaxNode, usize> = FxHashMap::default();
+        dirty.insert(self.clone(), 0);
+        for element in replacements.keys() {
+            let mut path = Vec::new();
+            let mut depth = None;
+            for ancestor in element.ancestors().skip(element.as_node().is_some() as usize) {
+                if let Some(&it) = dirty.get(&ancestor) {
+                    depth = Some(it);
+                    break;
+                }
+                path.push(ancestor);
+            }
+            let depth =
+                depth.unwrap_or_else(|| panic!("{:?} is not a descendant of {:?}", element, self));
+            for (node, depth) in path.into_iter().rev().zip(depth + 1..) {
+                dirty.insert(node, depth);
+            }
+        }
+
+        let mut dirty: Vec<(SyntaxNode, usize)> = dirty.into_iter().collect();
+        dirty.sort_by_key(|&(_, depth)| std::cmp::Reverse(depth));
+        let mut rebuilt: FxHashMap<SyntaxNode, GreenNode> = FxHashMap::default();
+        for (node, _) in dirty {
+            let children: Vec<GreenElement> = node
+                .children_with_tokens()
+                .map(|child| {
+                    if let Some(it) = replacements.get(&child) {
+                        return it.clone();
+                    }
+                    match child {
+                        NodeOrToken::Node(it) => match rebuilt.remove(&it) {
+                            Some(green) => green.into(),
+                            None => it.green_ref().to_owned().into(),
+                        },
+                        NodeOrToken::Token(it) => it.green().to_owned().into(),
+                    }
+                })
+                .collect();
+            let green = GreenNode::new(node.kind(), children);
+            rebuilt.insert(node, green);
+        }
+        rebuilt.remove(self).unwrap()
+    }
+
     #[inline]
     pub fn kind(&self) -> SyntaxKind {
         self.data().kind()
@@ -581,11 +651,35 @@ impl SyntaxNode {
         self.data().index() as usize
     }
 
+    /// The coordinate system in which this node starts at zero, such as the
+    /// one of `self.clone_subtree()`.
+    #[inline]
+    pub fn range_base(&self) -> RangeBase {
+        RangeBase::new(self.offset())
+    }
+
+    /// Range of this node relative to the start of `ancestor`, or `None` if
+    /// `ancestor` is not an ancestor of this node (or the node itself).
+    pub fn text_range_relative_to(&self, ancestor: &SyntaxNode) -> Option<TextRange> {
+        if !self.ancestors().any(|it| &it == ancestor) {
+            return None;
+        }
+        ancestor.range_base().to_local(self.text_range())
+    }
+
     #[inline]
     pub fn text(&self) -> SyntaxText {
         SyntaxText::new(self.clone())
     }
 
+    pub fn text_trimmed(&self) -> SyntaxText {
+        self.text().trimmed()
+    }
+
+    pub fn text_without<F: Fn(SyntaxKind) -> bool>(&self, is_trivia: F) -> FilteredText<F> {
+        self.text().without(is_trivia)
+    }
+
     #[inline]
     pub fn green(&self) -> Cow<'_, GreenNodeData> {
         let green_ref = self.green_ref();
@@ -675,10 +769,47 @@ impl SyntaxNode {
     }
 
     pub fn first_token(&self) -> Option<SyntaxToken> {
-        self.first_child_or_token()?.first_token()
+        self.edge_token(Direction::Next)
     }
     pub fn last_token(&self) -> Option<SyntaxToken> {
-        self.last_child_or_token()?.last_token()
+        self.edge_token(Direction::Prev)
+    }
+
+    /// Descends to the leftmost or rightmost token, skipping over nodes
+    /// which have no tokens. A loop rather than recursion, so that deep trees
+    /// don't overflow the stack.
+    fn edge_token(&self, direction: Direction) -> Option<SyntaxToken> {
+        let edge_child = |node: &SyntaxNode| match direction {
+            Direction::Next => node.first_child_or_token(),
+            Direction::Prev => node.last_child_or_token(),
+        };
+        let sibling = |element: &SyntaxElement| match direction {
+            Direction::Next => element.next_sibling_or_token(),
+            Direction::Prev => element.prev_sibling_or_token(),
+        };
+
+        let mut element = edge_child(self)?;
+        loop {
+            element = match element {
+                NodeOrToken::Token(token) => return Some(token),
+                NodeOrToken::Node(node) => match edge_child(&node) {
+                    Some(child) => child,
+                    None => {
+                        let mut element: SyntaxElement = node.into();
+                        loop {
+                            if let Some(it) = sibling(&element) {
+                                break it;
+                            }
+                            let parent = element.parent()?;
+                            if parent == *self {
+                                return None;
+                            }
+                            element = parent.into();
+                        }
+                    }
+                },
+            };
+        }
     }
 
     #[inline]
@@ -717,41 +848,79 @@ impl SyntaxNode {
         })
     }
 
+    /// Like [`SyntaxNode::descendants_with_tokens`], but only visits the
+    /// elements whose ranges intersect `range`, including the ones which just
+    /// touch it, finding the children in range with a binary search.
+    pub fn descendants_with_tokens_in_range(
+        &self,
+        range: TextRange,
+    ) -> impl Iterator<Item = SyntaxElement> {
+        let in_range = move |it: &SyntaxElement| it.text_range().intersect(range).is_some();
+        let start: SyntaxElement = self.clone().into();
+        let first = Some(start.clone()).filter(in_range);
+        iter::successors(first, move |element| {
+            if let NodeOrToken::Node(node) = element {
+                if let Some(child) = node.first_child_or_token_in_range(range) {
+                    return Some(child);
+                }
+            }
+            // The children are sorted, so the first one past the range ends
+            // the level.
+            let mut element = element.clone();
+            loop {
+                if element == start {
+                    return None;
+                }
+                match element.next_sibling_or_token().filter(in_range) {
+                    Some(sibling) => return Some(sibling),
+                    None => element = element.parent().unwrap().into(),
+                }
+            }
+        })
+    }
+
+    fn first_child_or_token_in_range(&self, range: TextRange) -> Option<SyntaxElement> {
+        let offset = self.offset();
+        let rel_start = range.start().checked_sub(offset).unwrap_or_default();
+        let children = self.green_ref().children().raw.as_slice();
+        let index = children.partition_point(|it| it.rel_range().end() < rel_start);
+        let child = children.get(index)?;
+        let child_offset = offset + child.rel_offset();
+        TextRange::at(child_offset, child.rel_range().len()).intersect(range)?;
+        Some(SyntaxElement::new(child.as_ref(), self.clone(), index as u32, child_offset))
+    }
+
+    /// Like [`SyntaxNode::descendants`], but yields the children before
+    /// their parents, ending with this node.
+    #[inline]
+    pub fn descendants_postorder(&self) -> impl Iterator<Item = SyntaxNode> {
+        self.preorder().filter_map(|event| match event {
+            WalkEvent::Enter(_) => None,
+            WalkEvent::Leave(node) => Some(node),
+        })
+    }
+
+    #[inline]
+    pub fn descendants_with_tokens_postorder(&self) -> impl Iterator<Item = SyntaxElement> {
+        self.preorder_with_tokens().filter_map(|event| match event {
+            WalkEvent::Enter(_) => None,
+            WalkEvent::Leave(it) => Some(it),
+        })
+    }
+
     #[inline]
     pub fn preorder(&self) -> Preorder {
         Preorder::new(self.clone())
     }
 
     #[inline]
-    pub fn preorder_with_tokens(&self) -> impl Iterator<Item = WalkEvent<SyntaxElement>> {
-        let start: SyntaxElement = self.clone().into();
-        iter::successors(Some(WalkEvent::Enter(start.clone())), move |pos| {
-            let next = match pos {
-                WalkEvent::Enter(el) => match el {
-                    NodeOrToken::Node(node) => match node.first_child_or_token() {
-                        Some(child) => WalkEvent::Enter(child),
-                        None => WalkEvent::Leave(node.clone().into()),
-                    },
-                    NodeOrToken::Token(token) => WalkEvent::Leave(token.clone().into()),
-                },
-                WalkEvent::Leave(el) => {
-                    if el == &start {
-                        return None;
-                    }
-                    match el.next_sibling_or_token() {
-                        Some(sibling) => WalkEvent::Enter(sibling),
-                        None => WalkEvent::Leave(el.parent().unwrap().into()),
-                    }
-                }
-            };
-            Some(next)
-        })
+    pub fn preorder_with_tokens(&self) -> PreorderWithTokens {
+        PreorderWithTokens::new(self.clone())
     }
 
     pub fn token_at_offset(&self, offset: TextSize) -> TokenAtOffset<SyntaxToken> {
         // TODO: this could be faster if we first drill-down to node, and only
-        // then switch to token search. We should also replace explicit
-        // recursion with a loop.
+        // then switch to token search.
         let range = self.text_range();
         assert!(
             range.start() <= offset && offset <= range.end(),
@@ -763,29 +932,36 @@ impl SyntaxNode {
             return TokenAtOffset::None;
         }
 
-        let mut children = self.children_with_tokens().filter(|child| {
-            let child_range = child.text_range();
-            !child_range.is_empty()
-                && (child_range.start() <= offset && offset <= child_range.end())
-        });
-
-        let left = children.next().unwrap();
-        let right = children.next();
-        assert!(children.next().is_none());
-
-        if let Some(right) = right {
-            match (left.token_at_offset(offset), right.token_at_offset(offset)) {
-                (TokenAtOffset::Single(left), TokenAtOffset::Single(right)) => {
-                    TokenAtOffset::Between(left, right)
-                }
-                _ => unreachable!(),
+        // A loop rather than recursion, to handle arbitrarily deep trees.
+        let mut node = self.clone();
+        loop {
+            let mut children = node.children_with_tokens().filter(|child| {
+                let child_range = child.text_range();
+                !child_range.is_empty()
+                    && (child_range.start() <= offset && offset <= child_range.end())
+            });
+
+            let left = children.next().unwrap();
+            let right = children.next();
+            assert!(children.next().is_none());
+
+            if let Some(right) = right {
+                return match (left.token_at_offset(offset), right.token_at_offset(offset)) {
+                    (TokenAtOffset::Single(left), TokenAtOffset::Single(right)) => {
+                        TokenAtOffset::Between(left, right)
+                    }
+                    _ => unreachable!(),
+                };
             }
-        } else {
-            left.token_at_offset(offset)
+            node = match left {
+                NodeOrToken::Node(it) => it,
+                NodeOrToken::Token(it) => return TokenAtOffset::Single(it),
+            };
         }
     }
 
     pub fn covering_element(&self, range: TextRange) -> SyntaxElement {
+        trace_span!("SyntaxNode::covering_element");
         let mut res: SyntaxElement = self.clone().into();
         loop {
             assert!(
@@ -804,24 +980,47 @@ impl SyntaxNode {
         }
     }
 
+    pub fn covering_node(&self, range: TextRange) -> SyntaxNode {
+        match self.covering_element(range) {
+            NodeOrToken::Node(it) => it,
+            NodeOrToken::Token(it) => it.parent().unwrap(),
+        }
+    }
+
+    /// Returns the `n`th child which is not trivia, see
+    /// [`GreenNodeData::slots`].
+    pub fn slot<F: Fn(SyntaxKind) -> bool>(&self, n: usize, is_trivia: F) -> Option<SyntaxElement> {
+        let green = self.green_ref();
+        let (index, _) = green.slots(is_trivia).nth(n)?;
+        let child = green.children().raw.nth(index)?;
+        Some(SyntaxElement::new(
+            child.as_ref(),
+            self.clone(),
+            index as u32,
+            self.offset() + child.rel_offset(),
+        ))
+    }
+
     pub fn child_or_token_at_range(&self, range: TextRange) -> Option<SyntaxElement> {
         let rel_range = range - self.offset();
-        self.green_ref().child_at_range(rel_range).map(|(index, rel_offset, green)| {
+        self.green_ref().child_containing_range(rel_range).map(|(index, rel_offset, green)| {
             SyntaxElement::new(green, self.clone(), index as u32, self.offset() + rel_offset)
         })
     }
 
     pub fn splice_children(&self, to_delete: Range<usize>, to_insert: Vec<SyntaxElement>) {
+        trace_span!("SyntaxNode::splice_children");
         assert!(self.data().mutable, "immutable tree: {}", self);
         for (i, child) in self.children_with_tokens().enumerate() {
             if to_delete.contains(&i) {
                 child.detach();
             }
         }
-        let mut index = to_delete.start;
-        for child in to_insert {
+        for (index, child) in (to_delete.start..).zip(to_insert) {
             self.attach_child(index, child);
-            index += 1;
+        }
+        if cfg!(debug_assertions) {
+            self.assert_child_invariants();
         }
     }
 
@@ -830,6 +1029,44 @@ impl SyntaxNode {
         self.data().detach()
     }
 
+    /// Checks that the red tree agrees with the green tree in the subtree of
+    /// this node: kinds, indices, parents and offsets of all elements, and
+    /// text lengths. Panics with a description of the first violation.
+    ///
+    /// Mutations check the node they modify in debug builds.
+    pub fn assert_invariants(&self) {
+        if let Err(err) = self.green_ref().validate_text_lens() {
+            panic!("invalid green tree of {:?}: {}", self, err)
+        }
+        for node in self.descendants() {
+            node.assert_child_invariants();
+        }
+    }
+
+    fn assert_child_invariants(&self) {
+        let green = self.green_ref();
+        let mut offset = self.offset();
+        let mut green_children = green.children();
+        for (index, child) in self.children_with_tokens().enumerate() {
+            assert_eq!(child.index(), index, "wrong index of {:?}", child);
+            assert_eq!(child.parent().as_ref(), Some(self), "wrong parent of {:?}", child);
+            assert_eq!(child.text_range().start(), offset, "wrong offset of {:?}", child);
+            let same_green = match (&child, green_children.next()) {
+                (NodeOrToken::Node(node), Some(NodeOrToken::Node(green))) => {
+                    ptr::eq(node.green_ref(), green)
+                }
+                (NodeOrToken::Token(token), Some(NodeOrToken::Token(green))) => {
+                    ptr::eq(token.green(), green)
+                }
+                _ => false,
+            };
+            assert!(same_green, "{:?} doesn't match its green element", child);
+            offset = child.text_range().end();
+        }
+        assert!(green_children.next().is_none(), "{:?} is missing children", self);
+        assert_eq!(offset, self.text_range().end(), "children don't cover {:?}", self);
+    }
+
     fn attach_child(&self, index: usize, child: SyntaxElement) {
         assert!(self.data().mutable, "immutable tree: {}", self);
         child.detach();
@@ -858,6 +1095,27 @@ impl SyntaxToken {
         unsafe { self.ptr.as_ref() }
     }
 
+    /// Creates a mutable token without a parent, to be inserted into a
+    /// mutable tree with [`SyntaxNode::splice_children`].
+    pub fn new_detached(green: GreenToken) -> SyntaxToken {
+        let green = Green::Token { ptr: GreenToken::into_raw(green) };
+        SyntaxToken { ptr: NodeData::new(None, 0, 0.into(), green, true) }
+    }
+
+    /// Replaces this token of a mutable tree with `replacement` in place,
+    /// updating the green nodes up to the root, and returns the new token.
+    ///
+    /// Unlike [`SyntaxToken::replace_with`], the other nodes of the tree stay
+    /// valid, so many edits can be applied to one tree.
+    pub fn replace_in_place(&self, replacement: GreenToken) -> SyntaxToken {
+        assert!(self.data().mutable, "immutable tree: {}", self);
+        let parent = self.parent().expect("can't replace a token without a parent");
+        let index = self.index();
+        let new = SyntaxToken::new_detached(replacement);
+        parent.splice_children(index..index + 1, vec![new.clone().into()]);
+        new
+    }
+
     pub fn replace_with(&self, replacement: GreenToken) -> GreenNode {
         assert_eq!(self.kind(), replacement.kind());
         let parent = self.parent().unwrap();
@@ -882,6 +1140,21 @@ impl SyntaxToken {
         self.data().index() as usize
     }
 
+    /// Range of this token relative to the start of `ancestor`, or `None` if
+    /// `ancestor` is not an ancestor of this token.
+    pub fn text_range_relative_to(&self, ancestor: &SyntaxNode) -> Option<TextRange> {
+        if !self.ancestors().any(|it| &it == ancestor) {
+            return None;
+        }
+        ancestor.range_base().to_local(self.text_range())
+    }
+
+    /// Text of this token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token was created from bytes which are not valid UTF-8,
+    /// see [`GreenTokenData::text`].
     #[inline]
     pub fn text(&self) -> &str {
         match self.data().green().as_token() {
@@ -897,6 +1170,29 @@ impl SyntaxToken {
         }
     }
 
+    /// Text of the token which keeps the underlying green token alive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token was created from bytes which are not valid UTF-8,
+    /// see [`GreenTokenData::text`].
+    #[inline]
+    pub fn token_text(&self) -> TokenText<'static> {
+        TokenText::owned(self.green().to_owned())
+    }
+
+    /// Raw bytes of the token, see [`GreenTokenData::bytes`].
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        self.green().bytes()
+    }
+
+    /// See [`GreenTokenData::text_lossy`].
+    #[inline]
+    pub fn text_lossy(&self) -> Cow<'_, str> {
+        self.green().text_lossy()
+    }
+
     #[inline]
     pub fn green(&self) -> &GreenTokenData {
         self.data().green().into_token().unwrap()
@@ -932,21 +1228,29 @@ impl SyntaxToken {
     }
 
     pub fn next_token(&self) -> Option<SyntaxToken> {
-        match self.next_sibling_or_token() {
-            Some(element) => element.first_token(),
-            None => self
-                .ancestors()
-                .find_map(|it| it.next_sibling_or_token())
-                .and_then(|element| element.first_token()),
+        let mut element: SyntaxElement = self.clone().into();
+        loop {
+            // Siblings which are empty nodes have no tokens, keep walking
+            // past them instead of stopping.
+            element = match element.next_sibling_or_token() {
+                Some(sibling) => match sibling.first_token() {
+                    Some(token) => return Some(token),
+                    None => sibling,
+                },
+                None => element.parent()?.into(),
+            };
         }
     }
     pub fn prev_token(&self) -> Option<SyntaxToken> {
-        match self.prev_sibling_or_token() {
-            Some(element) => element.last_token(),
-            None => self
-                .ancestors()
-                .find_map(|it| it.prev_sibling_or_token())
-                .and_then(|element| element.last_token()),
+        let mut element: SyntaxElement = self.clone().into();
+        loop {
+            element = match element.prev_sibling_or_token() {
+                Some(sibling) => match sibling.last_token() {
+                    Some(token) => return Some(token),
+                    None => sibling,
+                },
+                None => element.parent()?.into(),
+            };
         }
     }
 
@@ -964,12 +1268,8 @@ impl SyntaxElement {
         offset: TextSize,
     ) -> SyntaxElement {
         match element {
-            NodeOrToken::Node(node) => {
-                SyntaxNode::new_child(node, parent, index as u32, offset).into()
-            }
-            NodeOrToken::Token(token) => {
-                SyntaxToken::new(token, parent, index as u32, offset).into()
-            }
+            NodeOrToken::Node(node) => SyntaxNode::new_child(node, parent, index, offset).into(),
+            NodeOrToken::Token(token) => SyntaxToken::new(token, parent, index, offset).into(),
         }
     }
 
@@ -1114,7 +1414,7 @@ impl Hash for SyntaxToken {
 
 impl fmt::Display for SyntaxToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(self.text(), f)
+        fmt::Display::fmt(&self.text_lossy(), f)
     }
 }
 
@@ -1182,17 +1482,40 @@ pub struct Preorder {
     root: SyntaxNode,
     next: Option<WalkEvent<SyntaxNode>>,
     skip_subtree: bool,
+    guard: DepthGuard,
 }
 
 impl Preorder {
     fn new(root: SyntaxNode) -> Preorder {
         let next = Some(WalkEvent::Enter(root.clone()));
-        Preorder { root, next, skip_subtree: false }
+        Preorder { root, next, skip_subtree: false, guard: DepthGuard::default() }
     }
 
     pub fn skip_subtree(&mut self) {
         self.skip_subtree = true;
     }
+
+    /// Limits the traversal to `max_depth` levels of nodes, counting the
+    /// root. The deepest nodes are entered and left, but their descendants
+    /// are skipped.
+    ///
+    /// Consumers which recurse on the events can use this to bound their
+    /// recursion, whatever the depth of the tree, like for a hundred thousand
+    /// nested parentheses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_depth` is zero.
+    pub fn max_depth(mut self, max_depth: usize) -> Preorder {
+        self.guard.set_max_depth(max_depth);
+        self
+    }
+
+    /// Whether some nodes were skipped because of the limit set with
+    /// [`Preorder::max_depth`].
+    pub fn depth_exceeded(&self) -> bool {
+        self.guard.exceeded
+    }
     #[cold]
     fn do_skip(&mut self) {
         self.next = self.next.take().map(|next| match next {
@@ -1202,6 +1525,88 @@ impl Preorder {
     }
 }
 
+pub struct PreorderWithTokens {
+    start: SyntaxElement,
+    next: Option<WalkEvent<SyntaxElement>>,
+    skip_subtree: bool,
+    guard: DepthGuard,
+}
+
+impl PreorderWithTokens {
+    fn new(start: SyntaxNode) -> PreorderWithTokens {
+        let start: SyntaxElement = start.into();
+        let next = Some(WalkEvent::Enter(start.clone()));
+        PreorderWithTokens { start, next, skip_subtree: false, guard: DepthGuard::default() }
+    }
+
+    /// Makes the iterator leave the node it entered last without visiting
+    /// its descendants.
+    pub fn skip_subtree(&mut self) {
+        self.skip_subtree = true;
+    }
+
+    /// Limits the traversal to `max_depth` levels of nodes, counting the
+    /// root, see [`Preorder::max_depth`]. The tokens of the deepest nodes are
+    /// skipped too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_depth` is zero.
+    pub fn max_depth(mut self, max_depth: usize) -> PreorderWithTokens {
+        self.guard.set_max_depth(max_depth);
+        self
+    }
+
+    /// Whether some elements were skipped because of the limit set with
+    /// [`PreorderWithTokens::max_depth`].
+    pub fn depth_exceeded(&self) -> bool {
+        self.guard.exceeded
+    }
+    #[cold]
+    fn do_skip(&mut self) {
+        self.next = self.next.take().map(|next| match next {
+            WalkEvent::Enter(first_child) => WalkEvent::Leave(first_child.parent().unwrap().into()),
+            WalkEvent::Leave(parent) => WalkEvent::Leave(parent),
+        })
+    }
+}
+
+impl Iterator for PreorderWithTokens {
+    type Item = WalkEvent<SyntaxElement>;
+
+    fn next(&mut self) -> Option<WalkEvent<SyntaxElement>> {
+        if self.skip_subtree {
+            self.do_skip();
+            self.skip_subtree = false;
+        }
+        let next = self.next.take();
+        self.next = next.as_ref().and_then(|next| {
+            Some(match next {
+                WalkEvent::Enter(el) => match el {
+                    NodeOrToken::Node(node) => match node.first_child_or_token() {
+                        Some(child) => WalkEvent::Enter(child),
+                        None => WalkEvent::Leave(node.clone().into()),
+                    },
+                    NodeOrToken::Token(token) => WalkEvent::Leave(token.clone().into()),
+                },
+                WalkEvent::Leave(el) => {
+                    if el == &self.start {
+                        return None;
+                    }
+                    match el.next_sibling_or_token() {
+                        Some(sibling) => WalkEvent::Enter(sibling),
+                        None => WalkEvent::Leave(el.parent().unwrap().into()),
+                    }
+                }
+            })
+        });
+        if let Some(event) = &next {
+            self.skip_subtree |= self.guard.step(event, &self.next);
+        }
+        next
+    }
+}
+
 impl Iterator for Preorder {
     type Item = WalkEvent<SyntaxNode>;
 
@@ -1228,7 +1633,387 @@ impl Iterator for Preorder {
                 }
             })
         });
+        if let Some(event) = &next {
+            self.skip_subtree |= self.guard.step(event, &self.next);
+        }
         next
     }
 }
+
+/// The depth limit of the preorder traversals.
+#[derive(Default)]
+struct DepthGuard {
+    depth: usize,
+    max_depth: Option<usize>,
+    exceeded: bool,
+}
+
+impl DepthGuard {
+    fn set_max_depth(&mut self, max_depth: usize) {
+        assert!(max_depth > 0, "the root needs a depth of one");
+        self.max_depth = Some(max_depth);
+    }
+
+    /// Tracks the depth after `event`, and returns whether the subtree of the
+    /// element it entered must be skipped, given the `next` event.
+    #[inline]
+    fn step<T>(&mut self, event: &WalkEvent<T>, next: &Option<WalkEvent<T>>) -> bool {
+        let max_depth = match self.max_depth {
+            Some(it) => it,
+            None => return false,
+        };
+        match event {
+            WalkEvent::Enter(_) => {
+                self.depth += 1;
+                // Entering the first child is the only way down.
+                let skip = self.depth >= max_depth && matches!(next, Some(WalkEvent::Enter(_)));
+                self.exceeded |= skip;
+                skip
+            }
+            WalkEvent::Leave(_) => {
+                self.depth -= 1;
+                false
+            }
+        }
+    }
+}
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GreenNodeBuilder;
+
+    #[test]
+    fn test_assert_invariants() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for text in ["a", "b", "c"] {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), text);
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root_mut(builder.finish());
+        root.assert_invariants();
+
+        let a = root.first_child().unwrap();
+        let c = root.last_child().unwrap();
+        c.detach();
+        root.splice_children(0..1, vec![c.into()]);
+        root.last_child().unwrap().splice_children(0..0, vec![a.into()]);
+        assert_eq!(root.to_string(), "cab");
+        root.assert_invariants();
+    }
+
+    #[test]
+    fn test_preorder_skip_subtree() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "a");
+        builder.finish_node();
+        builder.start_node(SyntaxKind(3));
+        builder.token(SyntaxKind(2), "b");
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let mut preorder = root.preorder_with_tokens();
+        let mut entered = Vec::new();
+        while let Some(event) = preorder.next() {
+            if let WalkEvent::Enter(it) = event {
+                entered.push(it.to_string());
+                if it.kind() == SyntaxKind(1) {
+                    preorder.skip_subtree();
+                }
+            }
+        }
+        assert_eq!(entered, vec!["ab", "a", "b", "b"]);
+    }
+
+    #[test]
+    fn test_preorder_max_depth() {
+        let depth = if cfg!(miri) { 1_000 } else { 100_000 };
+        let mut builder = GreenNodeBuilder::new();
+        for _ in 0..depth {
+            builder.start_node(SyntaxKind(0));
+            builder.token(SyntaxKind(1), "(");
+        }
+        for _ in 0..depth {
+            builder.token(SyntaxKind(1), ")");
+            builder.finish_node();
+        }
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let mut preorder = root.preorder().max_depth(3);
+        let events: Vec<_> = preorder
+            .by_ref()
+            .map(|event| match event {
+                WalkEvent::Enter(node) => node.ancestors().count() as isize,
+                WalkEvent::Leave(node) => -(node.ancestors().count() as isize),
+            })
+            .collect();
+        assert_eq!(events, vec![1, 2, 3, -3, -2, -1]);
+        assert!(preorder.depth_exceeded());
+
+        let mut preorder = root.preorder_with_tokens().max_depth(2);
+        let entered: Vec<_> = preorder
+            .by_ref()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(NodeOrToken::Token(it)) => Some(it.text().to_string()),
+                WalkEvent::Enter(NodeOrToken::Node(_)) => Some("node".to_string()),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect();
+        assert_eq!(entered, vec!["node", "(", "node", ")"]);
+        assert!(preorder.depth_exceeded());
+
+        let mut preorder = root.preorder().max_depth(depth + 1);
+        assert_eq!(preorder.by_ref().count(), 2 * depth);
+        assert!(!preorder.depth_exceeded());
+    }
+
+    #[test]
+    fn test_descendants_postorder() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "a");
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "b");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let kinds: Vec<_> = root.descendants_postorder().map(|it| it.kind().0).collect();
+        assert_eq!(kinds, vec![1, 0]);
+        let texts: Vec<_> =
+            root.descendants_with_tokens_postorder().map(|it| it.to_string()).collect();
+        assert_eq!(texts, vec!["a", "a", "b", "ab"]);
+    }
+
+    #[test]
+    fn test_descendants_in_range() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for text in ["ab", "cd", "", "ef", "gh"] {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), text);
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        for start in 0..=8u32 {
+            for end in start..=8u32 {
+                let range = TextRange::new(start.into(), end.into());
+                let expected: Vec<_> = root
+                    .descendants_with_tokens()
+                    .filter(|it| it.text_range().intersect(range).is_some())
+                    .collect();
+                let actual: Vec<_> = root.descendants_with_tokens_in_range(range).collect();
+                assert_eq!(actual, expected, "{:?}", range);
+            }
+        }
+    }
+
+    #[test]
+    fn test_covering_node() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "ab");
+        builder.token(SyntaxKind(2), "c");
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "d");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+        assert_eq!(root.covering_element(range(0, 1)).kind(), SyntaxKind(2));
+        assert_eq!(root.covering_node(range(0, 1)).kind(), SyntaxKind(1));
+        assert_eq!(root.covering_node(range(1, 3)).kind(), SyntaxKind(1));
+        assert_eq!(root.covering_node(range(2, 4)).kind(), SyntaxKind(0));
+    }
+
+    #[test]
+    fn test_replace_descendants() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for text in ["a", "b", "c"] {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), text);
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let nodes: Vec<_> = root.children().collect();
+        let token = |text| GreenToken::new(SyntaxKind(2), text).into();
+        let green = root.replace_descendants(vec![
+            (nodes[0].first_token().unwrap().into(), token("x")),
+            (nodes[2].clone().into(), token("y")),
+            (nodes[2].first_token().unwrap().into(), token("ignored")),
+        ]);
+        assert_eq!(green.to_string(), "xby");
+        let children: Vec<_> = green.children().collect();
+        assert!(ptr::eq(children[1].into_node().unwrap(), nodes[1].green_ref()));
+
+        let same = nodes[0].replace_descendants(None);
+        assert!(ptr::eq(&*same, nodes[0].green_ref()));
+        let other = SyntaxNode::new_root(root.green().into_owned());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            nodes[1].replace_descendants(vec![(other.into(), token("z"))])
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_replace_in_place() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "a");
+        builder.token(SyntaxKind(2), "b");
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "c");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish()).clone_for_update();
+        let inner = root.first_child().unwrap();
+        let c = root.last_token().unwrap();
+
+        let a = inner.first_token().unwrap();
+        let new = a.replace_in_place(GreenToken::new(SyntaxKind(3), "xy"));
+        assert_eq!(new.parent().as_ref(), Some(&inner));
+        assert_eq!(new.text_range(), TextRange::new(0.into(), 2.into()));
+        assert_eq!(a.parent(), None);
+        assert_eq!(c.text_range(), TextRange::new(3.into(), 4.into()));
+        assert_eq!(root.to_string(), "xybc");
+        root.assert_invariants();
+    }
+
+    #[test]
+    fn test_next_prev_token() {
+        // ROOT [ A ["a"], EMPTY [], B [ "b", C ["c"] ], EMPTY [] ], "d"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "a");
+        builder.finish_node();
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "b");
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "c");
+        builder.finish_node();
+        builder.finish_node();
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "d");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let first = root.first_token().unwrap();
+        let forward: Vec<String> = iter::successors(Some(first), SyntaxToken::next_token)
+            .map(|it| it.text().to_string())
+            .collect();
+        assert_eq!(forward, ["a", "b", "c", "d"]);
+
+        let last = root.last_token().unwrap();
+        let backward: Vec<String> = iter::successors(Some(last), SyntaxToken::prev_token)
+            .map(|it| it.text().to_string())
+            .collect();
+        assert_eq!(backward, ["d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_first_last_token() {
+        // ROOT [ EMPTY [], A [ EMPTY [], "a", "b" ], B [ "c", EMPTY [] ], EMPTY [] ]
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.start_node(SyntaxKind(1));
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "a");
+        builder.token(SyntaxKind(2), "b");
+        builder.finish_node();
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "c");
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.finish_node();
+        builder.start_node(SyntaxKind(3));
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        assert_eq!(root.first_token().unwrap().text(), "a");
+        assert_eq!(root.last_token().unwrap().text(), "c");
+        let empty = root.first_child().unwrap();
+        assert_eq!(empty.first_token(), None);
+        assert_eq!(empty.last_token(), None);
+        let b = root.children().nth(2).unwrap();
+        assert_eq!(b.first_token().unwrap().text(), "c");
+        assert_eq!(b.last_token().unwrap().text(), "c");
+    }
+
+    #[test]
+    fn test_range_base() {
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+
+        let base = RangeBase::new(10.into());
+        assert_eq!(base.origin(), 10.into());
+        assert_eq!(base.to_absolute_offset(2.into()), 12.into());
+        assert_eq!(base.to_absolute(range(1, 3)), range(11, 13));
+        assert_eq!(base.to_local_offset(12.into()), Some(2.into()));
+        assert_eq!(base.to_local_offset(9.into()), None);
+        assert_eq!(base.to_local(range(10, 13)), Some(range(0, 3)));
+        assert_eq!(base.to_local(range(9, 13)), None);
+        assert_eq!(base.join(RangeBase::new(5.into())).origin(), 15.into());
+        assert_eq!(RangeBase::default().to_absolute(range(1, 3)), range(1, 3));
+
+        // ROOT [ "ab", OUTER [ "cd", INNER [ "ef" ] ], "g" ]
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(2), "ab");
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "cd");
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "ef");
+        builder.finish_node();
+        builder.finish_node();
+        builder.token(SyntaxKind(2), "g");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let outer = root.first_child().unwrap();
+        let inner = outer.first_child().unwrap();
+        let ef = inner.first_token().unwrap();
+        let g = root.last_token().unwrap();
+
+        assert_eq!(root.range_base(), RangeBase::default());
+        assert_eq!(inner.range_base().origin(), 4.into());
+        let subtree = inner.clone_subtree();
+        assert_eq!(
+            inner.range_base().to_local(ef.text_range()),
+            Some(subtree.first_token().unwrap().text_range())
+        );
+        assert_eq!(outer.range_base().to_absolute(range(2, 4)), inner.text_range());
+
+        assert_eq!(inner.text_range_relative_to(&inner), Some(range(0, 2)));
+        assert_eq!(inner.text_range_relative_to(&outer), Some(range(2, 4)));
+        assert_eq!(inner.text_range_relative_to(&root), Some(range(4, 6)));
+        assert_eq!(ef.text_range_relative_to(&inner), Some(range(0, 2)));
+        assert_eq!(ef.text_range_relative_to(&root), Some(range(4, 6)));
+
+        // Not ancestors: descendants, nodes after and nodes of other trees,
+        // even if the range would be representable.
+        assert_eq!(outer.text_range_relative_to(&inner), None);
+        assert_eq!(root.text_range_relative_to(&outer), None);
+        assert_eq!(g.text_range_relative_to(&outer), None);
+        assert_eq!(inner.text_range_relative_to(&subtree), None);
+        let green = root.green();
+        let children = green.children().map(|it| it.to_owned());
+        let other = SyntaxNode::new_root(GreenNode::new(green.kind(), children));
+        assert_eq!(inner.text_range_relative_to(&other), None);
+        assert_eq!(ef.text_range_relative_to(&other), None);
+    }
+}
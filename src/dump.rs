@@ -0,0 +1,191 @@
+//! Reading the `{:#?}` dump of a syntax tree back into a green tree.
+use std::{error::Error, fmt};
+
+use crate::{GreenNode, GreenNodeBuilder, SyntaxKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpError {
+    /// One-based line of the dump.
+    pub line: u32,
+    pub message: String,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for DumpError {}
+
+/// Builds a green tree from the `{:#?}` dump of a [`SyntaxNode`], for
+/// example to load a golden test as a fixture.
+///
+/// Nesting is given by indentation, and `resolve` maps the kind names to
+/// kinds. Text ranges are ignored and may be omitted, so that the dump can
+/// be edited by hand:
+///
+/// ```text
+/// ROOT
+///   IDENT "foo"
+///   WHITESPACE " "
+/// ```
+///
+/// [`SyntaxNode`]: crate::SyntaxNode
+pub fn parse_dump<F>(dump: &str, mut resolve: F) -> Result<GreenNode, DumpError>
+where
+    F: FnMut(&str) -> Option<SyntaxKind>,
+{
+    let mut builder = GreenNodeBuilder::new();
+    // Indentation of the open nodes.
+    let mut stack: Vec<usize> = Vec::new();
+    let mut token_indent = None;
+    let mut has_root = false;
+    for (line_no, line) in (1..).zip(dump.lines()) {
+        let error = |message: &str| DumpError { line: line_no, message: message.to_string() };
+        let entry = line.trim_start();
+        if entry.is_empty() {
+            continue;
+        }
+        let indent = line.len() - entry.len();
+        if token_indent.is_some_and(|it| indent > it) {
+            return Err(error("tokens can't have children"));
+        }
+        while stack.last().is_some_and(|&it| it >= indent) {
+            stack.pop();
+            builder.finish_node();
+        }
+        if stack.is_empty() && has_root {
+            return Err(error("more than one root"));
+        }
+
+        let (name, text) = parse_entry(entry).map_err(|msg| error(&msg))?;
+        let kind = resolve(name).ok_or_else(|| error(&format!("unknown kind `{}`", name)))?;
+        match text {
+            Some(text) => {
+                if stack.is_empty() {
+                    return Err(error("the root must be a node"));
+                }
+                builder.token(kind, &text);
+                token_indent = Some(indent);
+            }
+            None => {
+                builder.start_node(kind);
+                stack.push(indent);
+                has_root = true;
+                token_indent = None;
+            }
+        }
+    }
+    if !has_root {
+        return Err(DumpError { line: 1, message: "empty dump".to_string() });
+    }
+    stack.iter().for_each(|_| builder.finish_node());
+    Ok(builder.finish())
+}
+
+/// Splits `KIND@0..3 "text"` into the kind name and the unescaped text.
+fn parse_entry(entry: &str) -> Result<(&str, Option<String>), String> {
+    let name_end = entry.find(|c: char| c == '@' || c.is_whitespace()).unwrap_or(entry.len());
+    let (name, mut rest) = entry.split_at(name_end);
+    if rest.starts_with('@') {
+        let range_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = &rest[range_end..];
+    }
+    let rest = rest.trim();
+    if rest.is_empty() {
+        return Ok((name, None));
+    }
+    let mut chars = rest.strip_prefix('"').ok_or("expected a quoted token text")?.chars();
+    let mut text = String::new();
+    loop {
+        let c = match chars.next().ok_or("unterminated token text")? {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or("invalid unicode escape")?;
+                    let code = rest[..end].strip_prefix('{').ok_or("invalid unicode escape")?;
+                    let code =
+                        u32::from_str_radix(code, 16).map_err(|_| "invalid unicode escape")?;
+                    chars = rest[end + 1..].chars();
+                    char::from_u32(code).ok_or("invalid unicode escape")?
+                }
+                _ => return Err("invalid escape".to_string()),
+            },
+            c => c,
+        };
+        text.push(c);
+    }
+    if !chars.as_str().trim().is_empty() {
+        return Err("unexpected text after the token text".to_string());
+    }
+    Ok((name, Some(text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Language, SyntaxNode};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    enum Lang {}
+    impl Language for Lang {
+        type Kind = SyntaxKind;
+        fn kind_from_raw(raw: SyntaxKind) -> SyntaxKind {
+            raw
+        }
+        fn kind_to_raw(kind: SyntaxKind) -> SyntaxKind {
+            kind
+        }
+    }
+
+    fn resolve(name: &str) -> Option<SyntaxKind> {
+        let raw = name.strip_prefix("SyntaxKind(")?.strip_suffix(')')?;
+        raw.parse().ok().map(SyntaxKind)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "\"quoted\"\n\t\\");
+        builder.start_node(SyntaxKind(2));
+        builder.finish_node();
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(3), "a very long token, longer than the debug limit, é\u{7f}");
+        builder.finish_node();
+        builder.token(SyntaxKind(1), "x");
+        builder.finish_node();
+        let green = builder.finish();
+
+        let dump = format!("{:#?}", SyntaxNode::<Lang>::new_root(green.clone()));
+        assert_eq!(parse_dump(&dump, resolve), Ok(green));
+    }
+
+    #[test]
+    fn test_hand_written() {
+        let dump = "
+SyntaxKind(0)
+  SyntaxKind(1)@0..5 \"hello\"
+  SyntaxKind(2)
+    SyntaxKind(1) \" \"
+";
+        let green = parse_dump(dump, resolve).unwrap();
+        assert_eq!(green.to_string(), "hello ");
+
+        let err = |dump: &str| parse_dump(dump, resolve).unwrap_err().to_string();
+        assert_eq!(
+            err("SyntaxKind(0)\n  SyntaxKind(1) \"a\"\n    SyntaxKind(2)"),
+            "line 3: tokens can't have children"
+        );
+        assert_eq!(err("SyntaxKind(0)\nSyntaxKind(0)"), "line 2: more than one root");
+        assert_eq!(err("SyntaxKind(0)\n  IDENT \"a\""), "line 2: unknown kind `IDENT`");
+        assert_eq!(err("SyntaxKind(0)\n  SyntaxKind(1) \"a"), "line 2: unterminated token text");
+    }
+}
@@ -2,21 +2,95 @@ mod node;
 mod token;
 mod element;
 mod builder;
+mod event;
+mod marker;
 
-use self::element::GreenElement;
-
-pub(crate) use self::{element::GreenElementRef, node::GreenChild};
+pub(crate) use self::{
+    element::{GreenElement, GreenElementRef},
+    node::GreenChild,
+};
 
 pub use self::{
-    builder::{Checkpoint, GreenNodeBuilder, NodeCache},
-    node::{Children, GreenNode, GreenNodeData},
+    builder::{
+        BuilderError, CacheStats, Checkpoint, GreenNodeBuilder, IncompleteTree, NodeCache,
+        SharedTokens,
+    },
+    event::Event,
+    marker::{CompletedMarker, Marker},
+    node::{
+        ByKinds, Children, Chunks, DescendantTokens, GreenNode, GreenNodeData, NewNodeError, Slots,
+        TextLenError, TextLenErrorKind,
+    },
     token::{GreenToken, GreenTokenData},
 };
 
+/// Counts of lines, of UTF-16 code units and of elements, as wide as
+/// `TextSize`.
+#[cfg(not(rowan_large_offsets))]
+pub(crate) type Counter = u32;
+#[cfg(rowan_large_offsets)]
+pub(crate) type Counter = u64;
+
 /// SyntaxKind is a type tag for each token or node.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SyntaxKind(pub u16);
 
+/// A set of [`SyntaxKind`]s below [`KindSet::CAPACITY`], stored as a bitset
+/// which fits in a cache line.
+///
+/// The sets can be built in constants, like `TokenSet` in parsers:
+///
+/// ```
+/// # use rowan::{KindSet, SyntaxKind};
+/// const TRIVIA: KindSet = KindSet::new(&[SyntaxKind(1), SyntaxKind(2)]);
+/// assert!(TRIVIA.contains(SyntaxKind(2)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct KindSet([u64; 8]);
+
+impl KindSet {
+    pub const CAPACITY: u16 = 512;
+    pub const EMPTY: KindSet = KindSet([0; 8]);
+
+    /// # Panics
+    ///
+    /// Panics if one of the kinds is not below [`KindSet::CAPACITY`].
+    pub const fn new(kinds: &[SyntaxKind]) -> KindSet {
+        let mut res = KindSet::EMPTY;
+        let mut i = 0;
+        while i < kinds.len() {
+            res = res.insert(kinds[i]);
+            i += 1;
+        }
+        res
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the kind is not below [`KindSet::CAPACITY`].
+    #[must_use]
+    pub const fn insert(mut self, kind: SyntaxKind) -> KindSet {
+        assert!(kind.0 < KindSet::CAPACITY, "kind is out of range of KindSet");
+        self.0[kind.0 as usize / 64] |= 1 << (kind.0 % 64);
+        self
+    }
+
+    #[must_use]
+    pub const fn union(mut self, other: KindSet) -> KindSet {
+        let mut i = 0;
+        while i < self.0.len() {
+            self.0[i] |= other.0[i];
+            i += 1;
+        }
+        self
+    }
+
+    #[inline]
+    pub const fn contains(&self, kind: SyntaxKind) -> bool {
+        kind.0 < KindSet::CAPACITY && self.0[kind.0 as usize / 64] & (1 << (kind.0 % 64)) != 0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,4 +111,236 @@ mod tests {
         eprintln!("GreenToken         {}", size_of::<GreenToken>());
         eprintln!("GreenElement       {}", size_of::<GreenElement>());
     }
+
+    #[test]
+    fn test_token_text_is_inline() {
+        let text = "a".repeat(100);
+        let token = GreenToken::new(SyntaxKind(1), &text);
+        let start = &*token as *const GreenTokenData as usize;
+        let text_start = token.text().as_ptr() as usize;
+        assert!(start < text_start);
+        assert!(text_start + text.len() <= start + token.allocation_size());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_try_new() {
+        let token = GreenToken::new(SyntaxKind(1), &"a".repeat(1 << 20));
+        let node = GreenNode::try_new(SyntaxKind(0), vec![token.into(); 64], 64).unwrap();
+        #[cfg(not(rowan_large_offsets))]
+        {
+            let result = GreenNode::try_new(SyntaxKind(0), vec![node.clone().into(); 64], 64);
+            assert_eq!(result, Err(NewNodeError::TextTooLong));
+        }
+        let result = GreenNode::try_new(SyntaxKind(0), vec![node.into(); 3], 2);
+        assert_eq!(result, Err(NewNodeError::TooManyChildren { count: 3, max: 2 }));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    #[cfg(not(rowan_large_offsets))]
+    #[should_panic(expected = "see `--cfg rowan_large_offsets`")]
+    fn test_text_len_overflow() {
+        let token = GreenToken::new(SyntaxKind(1), &"a".repeat(1 << 20));
+        let node = GreenNode::new(SyntaxKind(0), vec![token.into(); 64]);
+        GreenNode::new(SyntaxKind(0), vec![node.into(); 64]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    #[cfg(rowan_large_offsets)]
+    fn test_large_offsets() {
+        let token = GreenToken::new(SyntaxKind(1), &"a".repeat(1 << 20));
+        let node = GreenNode::new(SyntaxKind(0), vec![token.into(); 64]);
+        let root = GreenNode::new(SyntaxKind(0), vec![node.into(); 65]);
+        assert_eq!(u64::from(root.text_len()), 65 << 26);
+
+        let root = crate::cursor::SyntaxNode::new_root(root);
+        let last = root.last_token().unwrap();
+        assert_eq!(u64::from(last.text_range().end()), 65 << 26);
+        let offset = crate::TextSize::new(1 << 32);
+        let token = root.token_at_offset(offset).right_biased().unwrap();
+        assert_eq!(u64::from(token.text_range().start()), 1 << 32);
+    }
+
+    #[test]
+    fn test_byte_tokens() {
+        let token = GreenToken::new_bytes(SyntaxKind(1), b"\xFFab");
+        assert!(!token.is_utf8());
+        assert_eq!(token.bytes(), b"\xFFab");
+        assert_eq!(token.as_str(), None);
+        assert_eq!(token.text_lossy(), "\u{FFFD}ab");
+        assert_eq!(token.text_len(), 3.into());
+        assert_eq!(
+            GreenToken::new_bytes(SyntaxKind(1), b"ab"),
+            GreenToken::new(SyntaxKind(1), "ab")
+        );
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token_bytes(SyntaxKind(1), b"\xFFab");
+        builder.token(SyntaxKind(1), "c");
+        builder.finish_node();
+        let node = builder.finish();
+        assert_eq!(node.text_len(), 4.into());
+        assert_eq!(node.to_string(), "\u{FFFD}abc");
+    }
+
+    #[test]
+    fn test_child_at_offset() {
+        use crate::{TextRange, TextSize};
+
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let node = GreenNode::new(SyntaxKind(0), vec![token("ab"), token(""), token("cd")]);
+        let child = |offset: u32| {
+            let (idx, offset, child) = node.child_at_offset(offset.into())?;
+            Some((idx, usize::from(offset), child.to_string()))
+        };
+        assert_eq!(child(1), Some((0, 0, "ab".to_string())));
+        assert_eq!(child(2), Some((2, 2, "cd".to_string())));
+        assert_eq!(child(4), None);
+
+        let range = TextRange::new(TextSize::from(2), TextSize::from(4));
+        assert_eq!(node.child_containing_range(range).map(|it| it.0), Some(2));
+        let range = TextRange::new(TextSize::from(1), TextSize::from(3));
+        assert_eq!(node.child_containing_range(range).map(|it| it.0), None);
+    }
+
+    #[test]
+    fn test_drop_deep_tree() {
+        // A left-leaning chain, like `1 + 1 + ... + 1` from a fuzzer.
+        let depth = if cfg!(miri) { 1_000 } else { 100_000 };
+        let one = || GreenToken::new(SyntaxKind(1), "1").into();
+        let mut expr = GreenNode::new(SyntaxKind(0), vec![one()]);
+        for _ in 0..depth {
+            expr = GreenNode::new(SyntaxKind(0), vec![expr.into(), one()]);
+        }
+        let shared = expr.clone();
+        drop(expr);
+        assert_eq!(shared.text_len(), crate::TextSize::from(depth + 1));
+        drop(shared);
+    }
+
+    #[test]
+    fn test_eq_deep_tree() {
+        let depth = if cfg!(miri) { 1_000 } else { 100_000 };
+        let chain = |last: &str| {
+            let mut expr = GreenNode::new(SyntaxKind(0), vec![]);
+            for i in 0..depth {
+                let text = if i == 0 { last } else { "1" };
+                let token = GreenToken::new(SyntaxKind(1), text).into();
+                expr = GreenNode::new(SyntaxKind(0), vec![expr.into(), token]);
+            }
+            expr
+        };
+        let (a, b, c) = (chain("1"), chain("1"), chain("2"));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(a);
+        assert!(set.contains(&b));
+        assert!(!set.contains(&c));
+    }
+
+    #[test]
+    fn test_edit_children() {
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let node = GreenNode::new(SyntaxKind(0), vec![token("a"), token("b")]);
+        assert_eq!(node.insert_child(1, token("x")).to_string(), "axb");
+        assert_eq!(node.insert_child(2, token("x")).to_string(), "abx");
+        assert_eq!(node.remove_child(0).to_string(), "b");
+        let replaced = node.replace_child(1, token("xy"));
+        assert_eq!(replaced.to_string(), "axy");
+        assert_eq!(replaced.text_len(), 3.into());
+        assert_eq!(node.to_string(), "ab");
+
+        let result = std::panic::catch_unwind(|| node.replace_child(2, token("x")));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_set_child() {
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let mut node = GreenNode::new(SyntaxKind(0), vec![token("a"), token("b"), token("c")]);
+        let ptr = &*node as *const GreenNodeData;
+        node.set_child(1, token("xy"));
+        node.set_kind(SyntaxKind(2));
+        assert_eq!(&*node as *const GreenNodeData, ptr);
+        let expected = GreenNode::new(SyntaxKind(2), vec![token("a"), token("xy"), token("c")]);
+        assert_eq!(node, expected);
+        assert_eq!(node.subtree_hash(), expected.subtree_hash());
+        assert_eq!(node.validate_text_lens(), Ok(()));
+
+        let shared = node.clone();
+        node.set_child(0, token(""));
+        assert_eq!(node.to_string(), "xyc");
+        assert_eq!(shared.to_string(), "axyc");
+        assert_eq!(node.validate_text_lens(), Ok(()));
+    }
+
+    #[test]
+    fn test_splice_children() {
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let node = GreenNode::new(SyntaxKind(0), vec![token("a"), token("b"), token("c")]);
+        let node = node.splice_children(1..2, vec![token("x"), token("y")]);
+        assert_eq!(node.to_string(), "axyc");
+        assert_eq!(node.splice_children(..3, None).to_string(), "c");
+        assert_eq!(node.splice_children(4.., vec![token("z")]).to_string(), "axycz");
+    }
+
+    #[cfg(feature = "utf16")]
+    #[test]
+    fn test_utf16_offsets() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "a");
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(1), "\u{1F980}b");
+        builder.token(SyntaxKind(1), "\u{e9}");
+        builder.finish_node();
+        builder.token(SyntaxKind(1), "c");
+        builder.finish_node();
+        let node = builder.finish();
+        check_utf16_offsets(&node);
+        assert_eq!(node.offset_from_utf16(2), None);
+        assert_eq!(node.offset_from_utf16(8), None);
+
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let mut children = vec![token("")];
+        for i in 0..100 {
+            children.push(token(if i % 3 == 0 { "\u{1F980}" } else { "\u{e9}x" }));
+            children.push(token(""));
+        }
+        let mut node = GreenNode::new(SyntaxKind(0), children);
+        check_utf16_offsets(&node);
+        // The lengths of the preceding children are updated on edits.
+        node.set_child(1, token("ab"));
+        check_utf16_offsets(&node);
+    }
+
+    #[cfg(feature = "utf16")]
+    fn check_utf16_offsets(node: &GreenNode) {
+        use crate::TextSize;
+
+        let text = node.to_string();
+        assert_eq!(node.text_len_utf16(), text.encode_utf16().count() as Counter);
+        for (idx, _) in text.char_indices().chain(Some((text.len(), ' '))) {
+            let utf16 = text[..idx].encode_utf16().count() as Counter;
+            let offset = TextSize::from(idx as u32);
+            assert_eq!(node.utf16_offset(offset), utf16);
+            assert_eq!(node.offset_from_utf16(utf16), Some(offset));
+        }
+    }
+
+    #[cfg(feature = "utf16")]
+    #[test]
+    #[should_panic(expected = "is not a char boundary")]
+    fn test_utf16_offset_inside_char() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "\u{e9}");
+        builder.finish_node();
+        builder.finish().utf16_offset(1.into());
+    }
 }
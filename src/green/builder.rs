@@ -1,23 +1,432 @@
-use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::{
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    io::{self, Read},
+    ops, ptr,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 use hashbrown::hash_map::RawEntryMut;
-use rustc_hash::FxHasher;
+use rustc_hash::{FxHashMap, FxHasher};
 
 use crate::{
     cow_mut::CowMut,
-    green::{GreenElement, GreenNode, GreenToken, SyntaxKind},
-    NodeOrToken,
+    green::{
+        GreenElement, GreenElementRef, GreenNode, GreenNodeData, GreenToken, GreenTokenData,
+        SyntaxKind,
+    },
+    Language, NodeOrToken, TextRange, TextSize,
 };
 
-type HashMap<K, V> = hashbrown::HashMap<K, V, BuildHasherDefault<FxHasher>>;
+type HashMap<K, V> = hashbrown::HashMap<K, V, BuildHasherDefault<HashedHasher>>;
 
-#[derive(Default, Debug)]
+/// Interns the nodes and tokens built by a [`GreenNodeBuilder`], so that
+/// equal subtrees are shared.
+///
+/// Every node is interned, up to the roots of whole files, so a long-lived
+/// cache keeps the trees it built alive until [`NodeCache::gc`] finds them
+/// unused.
+#[derive(Default)]
 pub struct NodeCache {
-    nodes: HashMap<GreenNode, ()>,
-    tokens: HashMap<GreenToken, ()>,
+    nodes: HashMap<Hashed<GreenNode>, ()>,
+    tokens: HashMap<Hashed<GreenToken>, ()>,
+    deterministic: bool,
+    stats: CacheStats,
+    /// Where [`NodeCache::gc_with_budget`] resumes.
+    gc_pass: GcPass,
+    /// Used instead of `tokens` if set.
+    shared_tokens: Option<SharedTokens>,
+}
+
+/// The state of an incremental collection, see [`NodeCache::gc_with_budget`].
+///
+/// The entries of the table move when it grows, so rather than a position in
+/// the table, a pass keeps a list of the entries it has yet to examine, by
+/// their hashes and addresses.
+#[derive(Default)]
+enum GcPass {
+    #[default]
+    Idle,
+    Nodes(Vec<(u64, usize)>),
+    Tokens(Vec<(u64, usize)>),
+}
+
+/// Tokens shared between several [`NodeCache`]s, for example the caches of
+/// all the files of a workspace, so that identifiers and keywords repeated
+/// across the files are stored once.
+///
+/// This is a handle, its clones refer to the same set. The set is behind a
+/// lock, so that the caches can be used from several threads.
+#[derive(Clone, Default)]
+pub struct SharedTokens(Arc<Mutex<HashMap<Hashed<GreenToken>, ()>>>);
+
+impl SharedTokens {
+    pub fn new() -> SharedTokens {
+        SharedTokens::default()
+    }
+
+    /// The number of tokens in the set.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the tokens which are referenced only by the set, and returns
+    /// how many were removed. Run [`NodeCache::gc`] on the caches first, as
+    /// their nodes keep the tokens alive.
+    pub fn gc(&self) -> usize {
+        trace_span!("SharedTokens::gc");
+        let dead: Vec<GreenToken> = self
+            .lock()
+            .drain_filter(|token, ()| token.element.is_unique())
+            .map(|(it, ())| it.element)
+            .collect();
+        dead.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Hashed<GreenToken>, ()>> {
+        // The map is consistent even if a thread panicked while holding it.
+        self.0.lock().unwrap_or_else(|it| it.into_inner())
+    }
+}
+
+impl fmt::Debug for SharedTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedTokens").field("len", &self.len()).finish()
+    }
+}
+
+/// Counters of the work done by a [`NodeCache`], see [`NodeCache::stats`].
+///
+/// The lookup counters accumulate over the lifetime of the cache, the
+/// entry counts are the current ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub node_lookups: usize,
+    pub node_hits: usize,
+    pub token_lookups: usize,
+    pub token_hits: usize,
+    /// The number of nodes in the cache.
+    pub nodes: usize,
+    /// The number of tokens in the cache, including the ones of its
+    /// [`SharedTokens`], if any.
+    pub tokens: usize,
+    /// The bytes which would have been allocated for the hits without the
+    /// cache.
+    pub saved_bytes: usize,
+}
+
+impl CacheStats {
+    pub fn node_misses(&self) -> usize {
+        self.node_lookups - self.node_hits
+    }
+
+    pub fn token_misses(&self) -> usize {
+        self.token_lookups - self.token_hits
+    }
+}
+
+impl fmt::Debug for NodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeCache")
+            .field("nodes", &self.nodes().collect::<Vec<_>>())
+            .field("tokens", &self.tokens().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// An element together with the hash computed by `NodeCache`.
+///
+/// Deriving the hash of a node from its children rather than rehashing the
+/// whole subtree keeps the cache fast and the stack shallow for deep trees,
+/// also when the map grows and rehashes its keys.
+#[derive(Debug)]
+struct Hashed<T> {
+    hash: u64,
+    element: T,
+}
+
+impl<T> Hash for Hashed<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash)
+    }
+}
+
+/// Passes the hash of `Hashed` through.
+#[derive(Default)]
+struct HashedHasher(u64);
+
+impl Hasher for HashedHasher {
+    fn write(&mut self, _bytes: &[u8]) {
+        unreachable!("only `Hashed` keys are supported")
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
 impl NodeCache {
+    /// Creates a node out of already built children, sharing it with equal
+    /// nodes created by this cache.
+    pub fn make_node<I>(&mut self, kind: SyntaxKind, children: I) -> GreenNode
+    where
+        I: IntoIterator<Item = GreenElement>,
+    {
+        let mut children: Vec<(u64, GreenElement)> =
+            children.into_iter().map(|it| (element_hash(it.as_deref()), it)).collect();
+        self.node(kind, &mut children, 0).1
+    }
+
+    /// Creates a token, sharing it with equal tokens created by this cache.
+    pub fn make_token(&mut self, kind: SyntaxKind, text: &str) -> GreenToken {
+        self.token(kind, text.as_bytes(), || GreenToken::new(kind, text)).1
+    }
+
+    /// Makes [`NodeCache::nodes`], [`NodeCache::tokens`] and the `Debug`
+    /// output visit the elements sorted by kind and then by their children
+    /// or text rather than in the order of the hash table, so that snapshot
+    /// tests and reproducible builds don't depend on the history of the
+    /// cache.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Makes the cache take the tokens from `tokens` rather than keeping its
+    /// own ones, see [`SharedTokens`].
+    ///
+    /// Only the tokens created after this call are shared. The shared tokens
+    /// are counted by [`NodeCache::stats`], but, as they may belong to other
+    /// caches too, they are not listed by [`NodeCache::tokens`] and only the
+    /// ones used by the nodes of this cache are written by
+    /// [`NodeCache::save`].
+    pub fn set_shared_tokens(&mut self, tokens: SharedTokens) {
+        self.shared_tokens = Some(tokens);
+    }
+
+    /// The lookup counters and the number of entries of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let shared = self.shared_tokens.as_ref().map_or(0, SharedTokens::len);
+        CacheStats { nodes: self.nodes.len(), tokens: self.tokens.len() + shared, ..self.stats }
+    }
+
+    /// The nodes in the cache.
+    pub fn nodes(&self) -> impl Iterator<Item = &GreenNodeData> {
+        let mut res: Vec<&GreenNodeData> = self.nodes.keys().map(|it| &*it.element).collect();
+        if self.deterministic {
+            res.sort_by(|lhs, rhs| structural_cmp(lhs, rhs));
+        }
+        res.into_iter()
+    }
+
+    /// The tokens in the cache, not including the [`SharedTokens`].
+    pub fn tokens(&self) -> impl Iterator<Item = &GreenTokenData> {
+        let mut res: Vec<&GreenTokenData> = self.tokens.keys().map(|it| &*it.element).collect();
+        if self.deterministic {
+            res.sort_by_key(|it| (it.kind(), it.bytes()));
+        }
+        res.into_iter()
+    }
+
+    /// Removes the nodes and tokens which are referenced only by the cache,
+    /// and returns how many were removed.
+    pub fn gc(&mut self) -> usize {
+        trace_span!("NodeCache::gc");
+        let before = self.nodes.len() + self.tokens.len();
+        // Dropping a node can leave its children referenced only by the
+        // cache, so repeat until nothing changes.
+        loop {
+            let dead: Vec<GreenNode> = self
+                .nodes
+                .drain_filter(|node, ()| node.element.is_unique())
+                .map(|(it, ())| it.element)
+                .collect();
+            if dead.is_empty() {
+                break;
+            }
+        }
+        self.tokens.retain(|token, ()| !token.element.is_unique());
+        let removed = before - self.nodes.len() - self.tokens.len();
+        trace_event!("NodeCache::gc removed", removed);
+        removed
+    }
+
+    /// Like [`NodeCache::gc`], but examines at most `budget` entries and
+    /// resumes where the previous call stopped, to spread the work of
+    /// collecting a large cache over several calls, for example between
+    /// requests of a language server. Returns how many entries were removed.
+    ///
+    /// A pass over the whole cache takes about `len / budget` calls, each
+    /// doing work proportional to `budget`, except that starting the nodes
+    /// or the tokens of a pass lists their entries. Nodes freed by a pass may
+    /// leave their children referenced only by the cache, these are removed
+    /// by the next pass. Entries inserted during a pass are examined by the
+    /// next one too.
+    pub fn gc_with_budget(&mut self, budget: usize) -> usize {
+        trace_span!("NodeCache::gc_with_budget");
+        let mut budget = budget;
+        let mut removed = 0;
+        while budget > 0 {
+            match &mut self.gc_pass {
+                GcPass::Idle => self.gc_pass = GcPass::Nodes(gc_pending(&self.nodes)),
+                GcPass::Nodes(pending) if pending.is_empty() => {
+                    self.gc_pass = GcPass::Tokens(gc_pending(&self.tokens))
+                }
+                GcPass::Tokens(pending) if pending.is_empty() => {
+                    self.gc_pass = GcPass::Idle;
+                    break;
+                }
+                GcPass::Nodes(pending) => {
+                    let (dead, examined) =
+                        gc_step(&mut self.nodes, pending, budget, GreenNode::is_unique);
+                    budget -= examined;
+                    removed += dead.len();
+                }
+                GcPass::Tokens(pending) => {
+                    let (dead, examined) =
+                        gc_step(&mut self.tokens, pending, budget, GreenToken::is_unique);
+                    budget -= examined;
+                    removed += dead.len();
+                }
+            }
+        }
+        trace_event!("NodeCache::gc_with_budget removed", removed);
+        removed
+    }
+
+    /// Runs [`NodeCache::gc`] and panics if the cache still holds nodes or
+    /// tokens, which means that they leaked, for example through a
+    /// forgotten tree. Meant to be called at the end of tests.
+    #[track_caller]
+    pub fn assert_empty_after_gc(&mut self) {
+        self.gc();
+        if self.nodes.is_empty() && self.tokens.is_empty() {
+            return;
+        }
+        let mut kinds: Vec<SyntaxKind> = self.nodes.keys().map(|it| it.element.kind()).collect();
+        kinds.extend(self.tokens.keys().map(|it| it.element.kind()));
+        kinds.sort();
+        kinds.dedup();
+        panic!(
+            "{} nodes and {} tokens are still alive, of kinds {:?}",
+            self.nodes.len(),
+            self.tokens.len(),
+            kinds
+        )
+    }
+
+    /// Writes the nodes and tokens of the cache in a compact binary format,
+    /// so that a later process can start with a warm cache, see
+    /// [`NodeCache::load`].
+    ///
+    /// Call [`NodeCache::gc`] first to skip the elements no tree uses. Of
+    /// the [`SharedTokens`], only the ones used by the nodes are written.
+    pub fn save<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        // Elements are written after their children and referred to by their
+        // index in the output.
+        let mut indices: FxHashMap<*const (), u32> = FxHashMap::default();
+        w.write_all(CACHE_MAGIC)?;
+        for token in self.tokens() {
+            indices.insert(token as *const GreenTokenData as *const (), indices.len() as u32);
+            write_token(w, token)?;
+        }
+        for root in self.nodes() {
+            let mut stack = vec![(root, false)];
+            while let Some((node, children_done)) = stack.pop() {
+                if indices.contains_key(&(node as *const GreenNodeData as *const ())) {
+                    continue;
+                }
+                if !children_done {
+                    stack.push((node, true));
+                    for child in node.children() {
+                        match child {
+                            NodeOrToken::Node(it) => stack.push((it, false)),
+                            NodeOrToken::Token(it) => {
+                                let ptr = it as *const GreenTokenData as *const ();
+                                if !indices.contains_key(&ptr) {
+                                    indices.insert(ptr, indices.len() as u32);
+                                    write_token(w, it)?;
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                }
+                w.write_all(&[1])?;
+                w.write_all(&node.kind().0.to_le_bytes())?;
+                w.write_all(&(node.children().len() as u32).to_le_bytes())?;
+                for child in node.children() {
+                    let ptr = match child {
+                        NodeOrToken::Node(it) => it as *const GreenNodeData as *const (),
+                        NodeOrToken::Token(it) => it as *const GreenTokenData as *const (),
+                    };
+                    w.write_all(&indices[&ptr].to_le_bytes())?;
+                }
+                indices.insert(node as *const GreenNodeData as *const (), indices.len() as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the elements written by [`NodeCache::save`] to the cache.
+    ///
+    /// Returns an error of the [`io::ErrorKind::InvalidData`] kind if the
+    /// input was not written by `save`.
+    pub fn load<R: io::Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = [0; CACHE_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != *CACHE_MAGIC {
+            return Err(invalid("not a saved node cache"));
+        }
+        let mut elements: Vec<(u64, GreenElement)> = Vec::new();
+        loop {
+            let mut tag = [0];
+            match r.read_exact(&mut tag) {
+                Ok(()) => (),
+                // The end of the input between two records is the end of the cache.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            let kind = SyntaxKind(u16::from_le_bytes(read_array(r)?));
+            let len = u32::from_le_bytes(read_array(r)?);
+            let element = match tag[0] {
+                0 => {
+                    let mut bytes = Vec::new();
+                    r.take(len.into()).read_to_end(&mut bytes)?;
+                    if bytes.len() != len as usize {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    let (hash, token) =
+                        self.token(kind, &bytes, || GreenToken::new_bytes(kind, &bytes));
+                    (hash, token.into())
+                }
+                1 => {
+                    let mut children = Vec::new();
+                    for _ in 0..len {
+                        let index = u32::from_le_bytes(read_array(r)?) as usize;
+                        let child =
+                            elements.get(index).ok_or_else(|| invalid("bad child index"))?;
+                        children.push(child.clone());
+                    }
+                    let (hash, node) = self.node(kind, &mut children, 0);
+                    (hash, node.into())
+                }
+                _ => return Err(invalid("bad element tag")),
+            };
+            elements.push(element);
+        }
+    }
+
     fn node(
         &mut self,
         kind: SyntaxKind,
@@ -28,20 +437,17 @@ impl NodeCache {
             GreenNode::new(kind, children.drain(first_child..).map(|(_, it)| it))
         };
         let children_ref = &children[first_child..];
-        if children_ref.len() > 3 {
-            let node = build_node(children);
-            return (0, node);
-        }
 
         // Manually compute the hash to avoid repeatedly hashing subtrees.
+        // Same as `GreenNodeData::subtree_hash`.
+        //
+        // Nodes of any size are cheap to deduplicate: the hash is combined
+        // from the hashes of the children, and the children built by this
+        // cache are compared by pointer below.
         let hash = {
             let mut h = FxHasher::default();
             kind.hash(&mut h);
             for &(hash, _) in children_ref {
-                if hash == 0 {
-                    let node = build_node(children);
-                    return (0, node);
-                }
                 hash.hash(&mut h);
             }
             h.finish()
@@ -54,20 +460,26 @@ impl NodeCache {
         // For example, all `#[inline]` in this file share the same green node!
         // For `libsyntax/parse/parser.rs`, measurements show that deduping saves
         // 17% of the memory for green nodes!
-        let entry = self.nodes.raw_entry_mut().from_hash(hash, |node| {
-            node.kind() == kind
-                && node.children().len() == children_ref.len()
-                && node.children().eq(children_ref.iter().map(|(_, it)| it.as_deref()))
-        });
+        let entry =
+            self.nodes.raw_entry_mut().from_hash(hash, |Hashed { element: node, .. }| {
+                node.kind() == kind
+                    && node.children().len() == children_ref.len()
+                    // Comparing nodes checks the pointers first, which is
+                    // enough for the children that come from this cache.
+                    && node.children().eq(children_ref.iter().map(|(_, it)| it.as_deref()))
+            });
 
+        self.stats.node_lookups += 1;
         let node = match entry {
             RawEntryMut::Occupied(entry) => {
                 drop(children.drain(first_child..));
-                entry.key().clone()
+                self.stats.node_hits += 1;
+                self.stats.saved_bytes += entry.key().element.allocation_size();
+                entry.key().element.clone()
             }
             RawEntryMut::Vacant(entry) => {
                 let node = build_node(children);
-                entry.insert_hashed_nocheck(hash, node.clone(), ());
+                entry.insert_hashed_nocheck(hash, Hashed { hash, element: node.clone() }, ());
                 node
             }
         };
@@ -75,23 +487,50 @@ impl NodeCache {
         (hash, node)
     }
 
-    fn token(&mut self, kind: SyntaxKind, text: &str) -> (u64, GreenToken) {
+    /// Looks up the token with `kind` and `bytes`, creating it with `new` if
+    /// there is none.
+    fn token(
+        &mut self,
+        kind: SyntaxKind,
+        bytes: &[u8],
+        new: impl FnOnce() -> GreenToken,
+    ) -> (u64, GreenToken) {
+        if let Some(token) = GreenToken::find_static(kind, bytes) {
+            return (token.subtree_hash(), token);
+        }
+        // Same as `GreenTokenData::subtree_hash`.
         let hash = {
             let mut h = FxHasher::default();
             kind.hash(&mut h);
-            text.hash(&mut h);
+            bytes.hash(&mut h);
             h.finish()
         };
-        let entry = self
-            .tokens
-            .raw_entry_mut()
-            .from_hash(hash, |token| token.kind() == kind && token.text() == text);
+        let mut shared;
+        let tokens = match &self.shared_tokens {
+            Some(it) => {
+                shared = it.lock();
+                &mut *shared
+            }
+            None => &mut self.tokens,
+        };
+        let entry = tokens.raw_entry_mut().from_hash(hash, |Hashed { element: token, .. }| {
+            token.kind() == kind && token.bytes() == bytes
+        });
 
+        self.stats.token_lookups += 1;
         let token = match entry {
-            RawEntryMut::Occupied(entry) => entry.key().clone(),
+            RawEntryMut::Occupied(entry) => {
+                self.stats.token_hits += 1;
+                self.stats.saved_bytes += entry.key().element.allocation_size();
+                entry.key().element.clone()
+            }
             RawEntryMut::Vacant(entry) => {
-                let token = GreenToken::new(kind, text);
-                entry.insert_hashed_nocheck(hash, token.clone(), ());
+                let token = new();
+                // They would keep their whole source alive, see
+                // `GreenNodeBuilder::token_in`.
+                if !token.is_borrowed() {
+                    entry.insert_hashed_nocheck(hash, Hashed { hash, element: token.clone() }, ());
+                }
                 token
             }
         };
@@ -99,16 +538,198 @@ impl NodeCache {
     }
 }
 
+/// Lists the entries of `map` for a [`GcPass`].
+fn gc_pending<T: ops::Deref>(map: &HashMap<Hashed<T>, ()>) -> Vec<(u64, usize)> {
+    map.keys().map(|it| (it.hash, address(&*it.element))).collect()
+}
+
+/// Examines up to `budget` of the `pending` entries of `map`, and removes the
+/// ones which are referenced only by the map. Returns them, along with the
+/// number of examined entries.
+///
+/// The pending entries which were removed from the map in the meantime are
+/// skipped.
+fn gc_step<T: ops::Deref>(
+    map: &mut HashMap<Hashed<T>, ()>,
+    pending: &mut Vec<(u64, usize)>,
+    budget: usize,
+    is_unique: impl Fn(&T) -> bool,
+) -> (Vec<T>, usize) {
+    let examined = budget.min(pending.len());
+    let dead = pending
+        .drain(pending.len() - examined..)
+        .filter_map(|(hash, addr)| {
+            match map.raw_entry_mut().from_hash(hash, |it| address(&*it.element) == addr) {
+                RawEntryMut::Occupied(entry) if is_unique(&entry.key().element) => {
+                    Some(entry.remove_entry().0.element)
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    (dead, examined)
+}
+
+fn address<T: ?Sized>(it: &T) -> usize {
+    it as *const T as *const () as usize
+}
+
+/// A total order of nodes by their structure: by kind, then by children, with
+/// tokens ordered by kind and text and before nodes.
+fn structural_cmp(lhs: &GreenNodeData, rhs: &GreenNodeData) -> Ordering {
+    if lhs.kind() != rhs.kind() {
+        return lhs.kind().cmp(&rhs.kind());
+    }
+    // Not recursive, to handle arbitrarily deep trees.
+    let mut stack = vec![(lhs.children(), rhs.children())];
+    while let Some((lhs, rhs)) = stack.last_mut() {
+        match (lhs.next(), rhs.next()) {
+            (None, None) => {
+                stack.pop();
+            }
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(NodeOrToken::Token(lhs)), Some(NodeOrToken::Token(rhs))) => {
+                match (lhs.kind(), lhs.bytes()).cmp(&(rhs.kind(), rhs.bytes())) {
+                    Ordering::Equal => (),
+                    ord => return ord,
+                }
+            }
+            (Some(NodeOrToken::Token(_)), Some(NodeOrToken::Node(_))) => return Ordering::Less,
+            (Some(NodeOrToken::Node(_)), Some(NodeOrToken::Token(_))) => return Ordering::Greater,
+            (Some(NodeOrToken::Node(lhs)), Some(NodeOrToken::Node(rhs))) => {
+                if ptr::eq(lhs, rhs) {
+                    continue;
+                }
+                match lhs.kind().cmp(&rhs.kind()) {
+                    Ordering::Equal => stack.push((lhs.children(), rhs.children())),
+                    ord => return ord,
+                }
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+/// Computes the same hash as `NodeCache` does for elements it builds.
+fn element_hash(element: GreenElementRef<'_>) -> u64 {
+    match element {
+        NodeOrToken::Node(node) => node.subtree_hash(),
+        NodeOrToken::Token(token) => token.subtree_hash(),
+    }
+}
+
+const CACHE_MAGIC: &[u8; 16] = b"rowan-nodecache1";
+
+fn write_token<W: io::Write>(w: &mut W, token: &GreenTokenData) -> io::Result<()> {
+    w.write_all(&[0])?;
+    w.write_all(&token.kind().0.to_le_bytes())?;
+    // Only reachable with `--cfg rowan_large_offsets`.
+    let len = u32::try_from(token.bytes().len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "token text is longer than 4 GiB")
+    })?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(token.bytes())
+}
+
+fn read_array<R: io::Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut res = [0; N];
+    r.read_exact(&mut res)?;
+    Ok(res)
+}
+
+/// A misuse of [`GreenNodeBuilder`], returned by its `try_` methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuilderError {
+    /// A node was finished, but none was open.
+    NoOpenNode,
+    /// The tree was finished while these nodes were open, outermost first.
+    OpenNodes { kinds: Vec<SyntaxKind> },
+    /// The tree was finished with `count` top-level elements, rather than a
+    /// single root node.
+    NoSingleRoot { count: usize },
+    /// A forest was finished with a token among the roots.
+    TopLevelToken { index: usize },
+    /// The checkpoint is not inside of the current branch, which starts at
+    /// the child with the index `first_child`.
+    InvalidCheckpoint { checkpoint: usize, first_child: usize, children: usize },
+    /// The checkpoint was taken in a node which is no longer the current one.
+    StaleCheckpoint,
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuilderError::NoOpenNode => f.write_str("no node to finish, unmatched finish_node"),
+            BuilderError::OpenNodes { kinds } => write!(f, "unfinished nodes: {:?}", kinds),
+            BuilderError::NoSingleRoot { count } => {
+                write!(f, "expected a single root node, found {} elements", count)
+            }
+            BuilderError::TopLevelToken { index } => {
+                write!(f, "expected root nodes, found a token at index {}", index)
+            }
+            BuilderError::InvalidCheckpoint { checkpoint, children, .. }
+                if checkpoint > children =>
+            {
+                f.write_str("checkpoint no longer valid, was finish_node called early?")
+            }
+            BuilderError::InvalidCheckpoint { .. } => {
+                f.write_str("checkpoint no longer valid, was an unmatched start_node_at called?")
+            }
+            BuilderError::StaleCheckpoint => {
+                f.write_str("checkpoint no longer valid, it was taken in another node")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuilderError {}
+
+/// What [`GreenNodeBuilder::recover`] had to fix.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncompleteTree {
+    /// The kinds of the nodes which were left open, innermost first.
+    pub closed: Vec<SyntaxKind>,
+    /// Whether the tree was wrapped into an error node.
+    pub wrapped: bool,
+}
+
+impl IncompleteTree {
+    pub fn is_complete(&self) -> bool {
+        self.closed.is_empty() && !self.wrapped
+    }
+}
+
 /// A checkpoint for maybe wrapping a node. See `GreenNodeBuilder::checkpoint` for details.
+///
+/// A checkpoint remembers the node it was taken in, so that using it after
+/// that node is finished is an error even if the children line up.
 #[derive(Clone, Copy, Debug)]
-pub struct Checkpoint(usize);
+pub struct Checkpoint {
+    children: usize,
+    node: u64,
+}
+
+type KindAvailable = fn(SyntaxKind, u32) -> bool;
 
 /// A builder for a green tree.
 #[derive(Default, Debug)]
 pub struct GreenNodeBuilder<'cache> {
     cache: CowMut<'cache, NodeCache>,
-    parents: Vec<(SyntaxKind, usize)>,
+    /// The open nodes, with the indices of their first children and their
+    /// ids for checking checkpoints.
+    parents: Vec<(SyntaxKind, usize, u64)>,
+    /// The number of nodes started so far, the id of the last one.
+    started_nodes: u64,
     children: Vec<(u64, GreenElement)>,
+    /// The total length of `children`.
+    text_len: TextSize,
+    version: Option<(u32, KindAvailable)>,
+    unavailable_kinds: Vec<SyntaxKind>,
+    max_depth: Option<usize>,
+    /// The number of open nodes which were not created because of `max_depth`.
+    skipped_nodes: usize,
+    depth_exceeded: bool,
 }
 
 impl GreenNodeBuilder<'_> {
@@ -123,31 +744,269 @@ impl GreenNodeBuilder<'_> {
         GreenNodeBuilder {
             cache: CowMut::Borrowed(cache),
             parents: Vec::new(),
+            started_nodes: 0,
             children: Vec::new(),
+            text_len: TextSize::default(),
+            version: None,
+            unavailable_kinds: Vec::new(),
+            max_depth: None,
+            skipped_nodes: 0,
+            depth_exceeded: false,
+        }
+    }
+
+    /// Makes the builder record the kinds which are not available in the
+    /// given version of the language, see [`Language::kind_available`].
+    ///
+    /// The tree is built as usual, so that the parser can recover and the
+    /// errors can be reported later, with [`GreenNodeBuilder::unavailable_kinds`].
+    pub fn set_version<L: Language>(&mut self, version: u32) {
+        self.version =
+            Some((version, |kind, version| L::kind_available(L::kind_from_raw(kind), version)));
+    }
+
+    /// Kinds used so far which are not available in the version set with
+    /// [`GreenNodeBuilder::set_version`], in the order of their first use.
+    pub fn unavailable_kinds(&self) -> &[SyntaxKind] {
+        &self.unavailable_kinds
+    }
+
+    /// Limits the nesting of the tree to `max_depth` nodes, counting the root.
+    /// Deeper nodes are not created, and their children are added to the
+    /// deepest allowed node instead, so that the text is preserved.
+    ///
+    /// This protects the consumers of the tree from adversarial inputs, like
+    /// a hundred thousand nested parentheses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_depth` is zero.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        assert!(max_depth > 0, "the root needs a depth of one");
+        self.max_depth = Some(max_depth);
+    }
+
+    /// Whether some nodes were not created because of the limit set with
+    /// [`GreenNodeBuilder::set_max_depth`].
+    pub fn depth_exceeded(&self) -> bool {
+        self.depth_exceeded
+    }
+
+    /// The number of open nodes, including the ones not created because of
+    /// [`GreenNodeBuilder::set_max_depth`].
+    pub fn depth(&self) -> usize {
+        self.parents.len() + self.skipped_nodes
+    }
+
+    /// The kind of the innermost open node.
+    pub fn current_kind(&self) -> Option<SyntaxKind> {
+        self.parents.last().map(|&(kind, ..)| kind)
+    }
+
+    /// The kinds of the open nodes, outermost first.
+    pub fn open_kinds(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = SyntaxKind> + ExactSizeIterator + '_ {
+        self.parents.iter().map(|&(kind, ..)| kind)
+    }
+
+    /// The number of children added to the innermost open node so far, or of
+    /// the top-level elements if there is none.
+    pub fn pending_children(&self) -> usize {
+        let first_child = self.parents.last().map_or(0, |&(_, first_child, _)| first_child);
+        self.children.len() - first_child
+    }
+
+    #[inline]
+    fn skip_node(&mut self) -> bool {
+        if self.skipped_nodes > 0 || self.max_depth.is_some_and(|it| self.parents.len() >= it) {
+            self.skipped_nodes += 1;
+            self.depth_exceeded = true;
+            return true;
+        }
+        false
+    }
+
+    #[inline]
+    fn check_version(&mut self, kind: SyntaxKind) {
+        if let Some((version, kind_available)) = self.version {
+            if !kind_available(kind, version) && !self.unavailable_kinds.contains(&kind) {
+                self.unavailable_kinds.push(kind)
+            }
         }
     }
 
     /// Adds new token to the current branch.
     #[inline]
     pub fn token(&mut self, kind: SyntaxKind, text: &str) {
-        let (hash, token) = self.cache.token(kind, text);
-        self.children.push((hash, token.into()));
+        self.check_version(kind);
+        let (hash, token) = self.cache.token(kind, text.as_bytes(), || GreenToken::new(kind, text));
+        self.push_child(hash, token.into());
+    }
+
+    /// Adds new token with the text `&source[range]` to the current branch,
+    /// see [`GreenToken::new_in`].
+    ///
+    /// An equal token of the cache is reused if there is one. Otherwise, the
+    /// new token refers to `source` if its text is long, and such tokens are
+    /// not added to the cache, so that it doesn't keep the sources of old
+    /// trees alive. The nodes containing them are still cached until
+    /// [`NodeCache::gc`] removes them once no tree uses them.
+    #[inline]
+    pub fn token_in(&mut self, kind: SyntaxKind, source: &Arc<str>, range: TextRange) {
+        self.check_version(kind);
+        let text = &source[range];
+        let (hash, token) =
+            self.cache.token(kind, text.as_bytes(), || GreenToken::new_in(kind, source, range));
+        self.push_child(hash, token.into());
+    }
+
+    /// Adds new token with raw, possibly non-UTF-8, text to the current
+    /// branch. See [`GreenToken::new_bytes`].
+    #[inline]
+    pub fn token_bytes(&mut self, kind: SyntaxKind, bytes: &[u8]) {
+        self.check_version(kind);
+        let (hash, token) = self.cache.token(kind, bytes, || GreenToken::new_bytes(kind, bytes));
+        self.push_child(hash, token.into());
+    }
+
+    /// Adds an already built node to the current branch, like a subtree
+    /// reused by an incremental reparse.
+    ///
+    /// The node is added as is: it is not deduplicated with the nodes of the
+    /// cache, and the kinds in it are not checked against the version.
+    #[inline]
+    pub fn node(&mut self, node: GreenNode) {
+        self.element(node.into())
+    }
+
+    /// Adds an already built node or token to the current branch, see
+    /// [`GreenNodeBuilder::node`].
+    #[inline]
+    pub fn element(&mut self, element: GreenElement) {
+        let hash = element_hash(element.as_deref());
+        self.push_child(hash, element);
+    }
+
+    #[inline]
+    fn push_child(&mut self, hash: u64, element: GreenElement) {
+        self.text_len += element.text_len();
+        self.children.push((hash, element));
+    }
+
+    /// The offset of the end of the text added so far, where the next token
+    /// starts.
+    #[inline]
+    pub fn text_offset(&self) -> TextSize {
+        self.text_len
     }
 
     /// Start new node and make it current.
     #[inline]
     pub fn start_node(&mut self, kind: SyntaxKind) {
-        let len = self.children.len();
-        self.parents.push((kind, len));
+        self.check_version(kind);
+        if self.skip_node() {
+            return;
+        }
+        self.push_parent(kind, self.children.len());
+    }
+
+    #[inline]
+    fn push_parent(&mut self, kind: SyntaxKind, first_child: usize) {
+        self.started_nodes += 1;
+        self.parents.push((kind, first_child, self.started_nodes));
     }
 
     /// Finish current branch and restore previous
     /// branch as current.
     #[inline]
     pub fn finish_node(&mut self) {
-        let (kind, first_child) = self.parents.pop().unwrap();
+        if let Err(err) = self.try_finish_node() {
+            panic!("{}", err)
+        }
+    }
+
+    /// Like [`GreenNodeBuilder::finish_node`], but returns the range of the
+    /// finished node, for example to attach a diagnostic to it.
+    ///
+    /// Returns `None` if the node was not created because of
+    /// [`GreenNodeBuilder::set_max_depth`].
+    #[inline]
+    pub fn finish_node_range(&mut self) -> Option<TextRange> {
+        let created = self.skipped_nodes == 0;
+        self.finish_node();
+        if !created {
+            return None;
+        }
+        let len = self.children.last().unwrap().1.text_len();
+        Some(TextRange::at(self.text_len - len, len))
+    }
+
+    /// Like [`GreenNodeBuilder::finish_node`], but returns an error instead of
+    /// panicking if there is no open node.
+    #[inline]
+    pub fn try_finish_node(&mut self) -> Result<(), BuilderError> {
+        trace_span!("GreenNodeBuilder::finish_node");
+        if self.skipped_nodes > 0 {
+            self.skipped_nodes -= 1;
+            return Ok(());
+        }
+        let (kind, first_child, _) = self.parents.pop().ok_or(BuilderError::NoOpenNode)?;
         let (hash, node) = self.cache.node(kind, &mut self.children, first_child);
         self.children.push((hash, node.into()));
+        Ok(())
+    }
+
+    /// Changes the kind of the current branch, for example to an error kind
+    /// when the parser finds out that the node is malformed.
+    #[inline]
+    pub fn set_current_kind(&mut self, kind: SyntaxKind) {
+        self.check_version(kind);
+        if self.skipped_nodes > 0 {
+            return;
+        }
+        self.parents.last_mut().expect("set_current_kind called with no open node").0 = kind;
+    }
+
+    /// Like [`GreenNodeBuilder::finish_node`], but with the kind decided at
+    /// the end, see [`GreenNodeBuilder::set_current_kind`].
+    #[inline]
+    pub fn finish_node_as(&mut self, kind: SyntaxKind) {
+        self.set_current_kind(kind);
+        self.finish_node();
+    }
+
+    /// Closes the current branch without creating a node for it, so that its
+    /// children become the children of the parent branch.
+    #[inline]
+    pub fn abandon_node(&mut self) {
+        if self.skipped_nodes > 0 {
+            self.skipped_nodes -= 1;
+            return;
+        }
+        self.parents.pop().expect("abandon_node called with no open node");
+    }
+
+    /// Like [`GreenNodeBuilder::finish_node`], but leaves the trailing trivia
+    /// of the current branch to the parent, so that nodes end at their last
+    /// meaningful token. See [`Language::is_trivia`].
+    pub fn finish_node_before_trivia<L: Language>(&mut self) {
+        if self.skipped_nodes > 0 {
+            return self.finish_node();
+        }
+        let &(_, first_child, _) = self.parents.last().unwrap();
+        let mut split = self.children.len();
+        while split > first_child {
+            match &self.children[split - 1].1 {
+                NodeOrToken::Token(token) if L::is_trivia(L::kind_from_raw(token.kind())) => {
+                    split -= 1
+                }
+                _ => break,
+            }
+        }
+        let trivia = self.children.split_off(split);
+        self.finish_node();
+        self.children.extend(trivia);
     }
 
     /// Prepare for maybe wrapping the next node.
@@ -177,38 +1036,891 @@ impl GreenNodeBuilder<'_> {
     /// ```
     #[inline]
     pub fn checkpoint(&self) -> Checkpoint {
-        Checkpoint(self.children.len())
+        Checkpoint { children: self.children.len(), node: self.current_node() }
+    }
+
+    /// The id of the innermost open node, or zero at the top level.
+    #[inline]
+    fn current_node(&self) -> u64 {
+        self.parents.last().map_or(0, |&(.., id)| id)
     }
 
     /// Wrap the previous branch marked by `checkpoint` in a new branch and
     /// make it current.
     #[inline]
     pub fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
-        let Checkpoint(checkpoint) = checkpoint;
-        assert!(
-            checkpoint <= self.children.len(),
-            "checkpoint no longer valid, was finish_node called early?"
-        );
+        if let Err(err) = self.try_start_node_at(checkpoint, kind) {
+            panic!("{}", err)
+        }
+    }
 
-        if let Some(&(_, first_child)) = self.parents.last() {
-            assert!(
-                checkpoint >= first_child,
-                "checkpoint no longer valid, was an unmatched start_node_at called?"
-            );
+    /// Like [`GreenNodeBuilder::start_node_at`], but returns an error instead
+    /// of panicking if the checkpoint is no longer valid.
+    #[inline]
+    pub fn try_start_node_at(
+        &mut self,
+        checkpoint: Checkpoint,
+        kind: SyntaxKind,
+    ) -> Result<(), BuilderError> {
+        let checkpoint = self.check_checkpoint(checkpoint)?;
+        self.check_version(kind);
+        if self.skip_node() {
+            return Ok(());
         }
+        self.push_parent(kind, checkpoint);
+        Ok(())
+    }
 
-        self.parents.push((kind, checkpoint));
+    /// Wraps the branch since `checkpoint` together with whatever `f` adds
+    /// into a node of `kind`.
+    ///
+    /// The checkpoint stays valid, so calling this in a loop builds a
+    /// left-associative chain, like in a precedence-climbing parser:
+    ///
+    /// ```rust
+    /// # use rowan::{GreenNodeBuilder, SyntaxKind};
+    /// # const ROOT: SyntaxKind = SyntaxKind(0);
+    /// # const BIN: SyntaxKind = SyntaxKind(1);
+    /// # const ATOM: SyntaxKind = SyntaxKind(2);
+    /// let mut builder = GreenNodeBuilder::new();
+    /// builder.start_node(ROOT);
+    /// let checkpoint = builder.checkpoint();
+    /// builder.token(ATOM, "1");
+    /// for rhs in ["2", "3"] {
+    ///     builder.wrap_from(checkpoint, BIN, |builder| {
+    ///         builder.token(ATOM, "+");
+    ///         builder.token(ATOM, rhs);
+    ///     });
+    /// }
+    /// builder.finish_node();
+    /// let root = builder.finish();
+    /// let outer = root.children().next().unwrap().into_node().unwrap();
+    /// assert_eq!(outer.children().next().unwrap().to_string(), "1+2");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the checkpoint is no longer valid, or if `f` leaves nodes
+    /// open or finishes nodes it didn't start.
+    pub fn wrap_from<R>(
+        &mut self,
+        checkpoint: Checkpoint,
+        kind: SyntaxKind,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        self.start_node_at(checkpoint, kind);
+        let depth = self.depth();
+        let res = f(self);
+        assert_eq!(self.depth(), depth, "unbalanced nodes in wrap_from");
+        self.finish_node();
+        res
+    }
+
+    /// Returns the index of the first child after `checkpoint`, if it is
+    /// still inside of the current branch.
+    #[inline]
+    fn check_checkpoint(&self, checkpoint: Checkpoint) -> Result<usize, BuilderError> {
+        let Checkpoint { children: checkpoint, node } = checkpoint;
+        let first_child = self.children.len() - self.pending_children();
+        if checkpoint > self.children.len() || checkpoint < first_child {
+            return Err(BuilderError::InvalidCheckpoint {
+                checkpoint,
+                first_child,
+                children: self.children.len(),
+            });
+        }
+        if node != self.current_node() {
+            return Err(BuilderError::StaleCheckpoint);
+        }
+        Ok(checkpoint)
+    }
+
+    /// Removes the tokens and nodes added to the current branch since
+    /// `checkpoint`, to backtrack after a speculative parse.
+    ///
+    /// The nodes started after the checkpoint must be finished first.
+    #[inline]
+    pub fn revert_to(&mut self, checkpoint: Checkpoint) {
+        match self.check_checkpoint(checkpoint) {
+            Ok(checkpoint) => {
+                for (_, child) in self.children.drain(checkpoint..) {
+                    self.text_len -= child.text_len();
+                }
+            }
+            Err(err) => panic!("{}", err),
+        }
     }
 
     /// Complete tree building. Make sure that
     /// `start_node_at` and `finish_node` calls
     /// are paired!
     #[inline]
-    pub fn finish(mut self) -> GreenNode {
-        assert_eq!(self.children.len(), 1);
+    pub fn finish(self) -> GreenNode {
+        match self.try_finish() {
+            Ok(it) => it,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like [`GreenNodeBuilder::finish`], but returns an error instead of
+    /// panicking if the calls were not paired. Use
+    /// [`GreenNodeBuilder::recover`] to get a tree anyway.
+    pub fn try_finish(mut self) -> Result<GreenNode, BuilderError> {
+        trace_span!("GreenNodeBuilder::finish");
+        if !self.parents.is_empty() {
+            let kinds = self.open_kinds().collect();
+            return Err(BuilderError::OpenNodes { kinds });
+        }
+        match self.children.as_slice() {
+            [(_, NodeOrToken::Node(_))] => (),
+            children => return Err(BuilderError::NoSingleRoot { count: children.len() }),
+        }
         match self.children.pop().unwrap().1 {
-            NodeOrToken::Node(node) => node,
-            NodeOrToken::Token(_) => panic!(),
+            NodeOrToken::Node(node) => Ok(node),
+            NodeOrToken::Token(_) => unreachable!(),
+        }
+    }
+
+    /// Like [`GreenNodeBuilder::finish`], but allows any number of root
+    /// nodes, for inputs like the cells of a notebook which are parsed in
+    /// one session with a shared cache.
+    #[inline]
+    pub fn finish_list(self) -> Vec<GreenNode> {
+        match self.try_finish_list() {
+            Ok(it) => it,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like [`GreenNodeBuilder::finish_list`], but returns an error instead
+    /// of panicking if nodes are left open or a token is at the top level.
+    pub fn try_finish_list(self) -> Result<Vec<GreenNode>, BuilderError> {
+        trace_span!("GreenNodeBuilder::finish_list");
+        if !self.parents.is_empty() {
+            let kinds = self.open_kinds().collect();
+            return Err(BuilderError::OpenNodes { kinds });
+        }
+        self.children
+            .into_iter()
+            .enumerate()
+            .map(|(index, (_, element))| match element {
+                NodeOrToken::Node(node) => Ok(node),
+                NodeOrToken::Token(_) => Err(BuilderError::TopLevelToken { index }),
+            })
+            .collect()
+    }
+
+    /// Salvages the tree after the parser panicked, so that a language
+    /// server can keep working with a file which triggers a parser bug.
+    ///
+    /// The nodes left open are closed, and if the tree is incomplete, all of
+    /// it is wrapped into a node of the `error` kind, see
+    /// [`Language::error_kind`]. The returned [`IncompleteTree`] reports what
+    /// was fixed. The builder is in a consistent state between the calls of
+    /// its methods, so it can be used after a caught panic:
+    ///
+    /// ```
+    /// # use rowan::{GreenNodeBuilder, SyntaxKind};
+    /// # use std::panic::{self, AssertUnwindSafe};
+    /// # const ERROR: SyntaxKind = SyntaxKind(0);
+    /// # fn parse(builder: &mut GreenNodeBuilder) {
+    /// #     builder.start_node(SyntaxKind(1));
+    /// #     builder.token(SyntaxKind(2), "fn");
+    /// #     panic!("parser bug")
+    /// # }
+    /// let mut builder = GreenNodeBuilder::new();
+    /// let tree = match panic::catch_unwind(AssertUnwindSafe(|| parse(&mut builder))) {
+    ///     Ok(()) => builder.finish(),
+    ///     Err(_) => builder.recover(ERROR).0,
+    /// };
+    /// assert_eq!(tree.kind(), ERROR);
+    /// assert_eq!(tree.to_string(), "fn");
+    /// ```
+    ///
+    /// Unlike [`GreenNodeBuilder::finish`], this never panics, so it can also
+    /// be used to finish the tree of a parser which gave up midway.
+    pub fn recover(mut self, error: SyntaxKind) -> (GreenNode, IncompleteTree) {
+        let closed = self.close_all();
+        let wrapped =
+            !closed.is_empty() || !matches!(self.children.as_slice(), [(_, NodeOrToken::Node(_))]);
+        if wrapped {
+            self.push_parent(error, 0);
+            self.finish_node();
+        }
+        (self.finish(), IncompleteTree { closed, wrapped })
+    }
+
+    /// Finishes all the open nodes, returning their kinds, innermost first.
+    fn close_all(&mut self) -> Vec<SyntaxKind> {
+        self.skipped_nodes = 0;
+        let mut res = Vec::new();
+        while let Some(&mut (kind, ref mut first_child, _)) = self.parents.last_mut() {
+            // The panic might have happened while children were removed.
+            *first_child = (*first_child).min(self.children.len());
+            self.finish_node();
+            res.push(kind);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gc() {
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(2), "a");
+        builder.finish_node();
+        builder.token(SyntaxKind(3), "b");
+        builder.finish_node();
+        let tree = builder.finish();
+        assert_eq!((cache.nodes.len(), cache.tokens.len()), (2, 2));
+        assert!(cache.nodes.keys().all(|it| it.hash == it.element.subtree_hash()));
+        assert!(cache.tokens.keys().all(|it| it.hash == it.element.subtree_hash()));
+        let uncached = GreenNode::new(
+            SyntaxKind(0),
+            vec![
+                GreenNode::new(SyntaxKind(1), vec![GreenToken::new(SyntaxKind(2), "a").into()])
+                    .into(),
+                GreenToken::new(SyntaxKind(3), "b").into(),
+            ],
+        );
+        assert_eq!(uncached.subtree_hash(), tree.subtree_hash());
+
+        assert_eq!(cache.gc(), 0);
+        drop(tree);
+        assert_eq!(cache.gc(), 4);
+        assert_eq!((cache.nodes.len(), cache.tokens.len()), (0, 0));
+        cache.assert_empty_after_gc();
+    }
+
+    #[test]
+    fn test_shared_tokens() {
+        let shared = SharedTokens::new();
+        let mut trees = Vec::new();
+        let mut caches = Vec::new();
+        for text in ["a", "b"] {
+            let mut cache = NodeCache::default();
+            cache.set_shared_tokens(shared.clone());
+            let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+            builder.start_node(SyntaxKind(0));
+            builder.token(SyntaxKind(2), "shared");
+            builder.token(SyntaxKind(2), text);
+            builder.finish_node();
+            trees.push(builder.finish());
+            caches.push(cache);
+        }
+        assert_eq!(shared.len(), 3);
+        assert!(caches.iter().all(|it| it.tokens().next().is_none()));
+        assert!(caches.iter().all(|it| it.stats().tokens == 3));
+
+        // Only the tokens of the first file are saved with it.
+        let mut saved = Vec::new();
+        caches[0].save(&mut saved).unwrap();
+        let mut loaded = NodeCache::default();
+        loaded.load(&mut saved.as_slice()).unwrap();
+        let texts: Vec<&str> = loaded.tokens().map(|it| it.text()).collect();
+        assert_eq!(texts.len(), 2);
+        assert!(texts.contains(&"shared") && texts.contains(&"a"));
+        let first_token = |tree: &GreenNode| {
+            tree.children().next().unwrap().into_token().unwrap() as *const GreenTokenData
+        };
+        assert_eq!(first_token(&trees[0]), first_token(&trees[1]));
+
+        assert_eq!(shared.gc(), 0);
+        trees.pop();
+        caches.pop().unwrap().assert_empty_after_gc();
+        assert_eq!(shared.gc(), 1);
+        assert_eq!(shared.len(), 2);
+    }
+
+    #[test]
+    fn test_dedup_large_nodes() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for _ in 0..2 {
+            builder.start_node(SyntaxKind(1));
+            for text in ["a", "b", "c", "d", "e"] {
+                builder.token(SyntaxKind(2), text);
+            }
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let tree = builder.finish();
+        let children: Vec<_> = tree.children().map(|it| it.into_node().unwrap()).collect();
+        assert!(ptr::eq(children[0], children[1]));
+    }
+
+    #[test]
+    fn test_gc_with_budget() {
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        for text in ["a", "b", "c"] {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), text);
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let tree = builder.finish();
+        assert_eq!((cache.nodes.len(), cache.tokens.len()), (4, 3));
+        let alive = tree.children().nth(1).unwrap().into_node().unwrap().to_owned();
+        drop(tree);
+
+        let mut removed = 0;
+        for _ in 0..20 {
+            removed += cache.gc_with_budget(2);
+        }
+        assert_eq!(removed, 5);
+        assert_eq!((cache.nodes.len(), cache.tokens.len()), (1, 1));
+        drop(alive);
+        assert_eq!(cache.gc_with_budget(0), 0);
+        while cache.gc_with_budget(1) == 0 {}
+        cache.assert_empty_after_gc();
+    }
+
+    #[test]
+    fn test_gc_with_budget_while_growing() {
+        let mut cache = NodeCache::default();
+        for i in 0..10 {
+            cache.make_token(SyntaxKind(1), &i.to_string());
+        }
+        assert_eq!(cache.gc_with_budget(1), 1);
+
+        // Growing the table moves its entries in the middle of the pass.
+        let alive: Vec<GreenToken> =
+            (0..1000).map(|i| cache.make_token(SyntaxKind(2), &i.to_string())).collect();
+        let removed: Vec<usize> = (0..10).map(|_| cache.gc_with_budget(1)).collect();
+        assert_eq!(removed, [1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(cache.tokens.len(), alive.len());
+        drop(alive);
+        cache.assert_empty_after_gc();
+    }
+
+    #[test]
+    fn test_static_tokens() {
+        // The table is shared with the other tests, so use a text none of
+        // them does.
+        let kind = SyntaxKind(1);
+        GreenToken::register_static(&[(kind, "static_fn")]);
+        let token = GreenToken::new(kind, "static_fn");
+        assert!(ptr::eq::<GreenTokenData>(&*token, &*GreenToken::new(kind, "static_fn")));
+        assert!(ptr::eq::<GreenTokenData>(&*token, &*GreenToken::new_bytes(kind, b"static_fn")));
+        assert!(!ptr::eq::<GreenTokenData>(&*token, &*GreenToken::new(kind, "static_fn2")));
+        assert!(!ptr::eq::<GreenTokenData>(&*token, &*GreenToken::new(SyntaxKind(2), "static_fn")));
+
+        let mut cache = NodeCache::default();
+        assert!(ptr::eq::<GreenTokenData>(&*token, &*cache.make_token(kind, "static_fn")));
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        builder.token(kind, "static_fn");
+        builder.finish_node();
+        let node = builder.finish();
+        let child = node.children().next().unwrap().into_token().unwrap();
+        assert!(ptr::eq(child, &*token));
+        assert_eq!(cache.tokens().count(), 0);
+
+        let mut other = NodeCache::default();
+        assert!(ptr::eq::<GreenTokenData>(&*token, &*other.make_token(kind, "static_fn")));
+    }
+
+    #[test]
+    fn test_token_in() {
+        let source: Arc<str> =
+            "fn f() { /* a comment longer than the range of the source */ }".into();
+        let comment = TextRange::new(9.into(), 60.into());
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        builder.token_in(SyntaxKind(1), &source, TextRange::up_to(2.into()));
+        builder.token_in(SyntaxKind(2), &source, comment);
+        builder.token_in(SyntaxKind(2), &source, comment);
+        builder.finish_node();
+        let node = builder.finish();
+
+        let tokens: Vec<&GreenTokenData> =
+            node.children().map(|it| it.into_token().unwrap()).collect();
+        assert_eq!(tokens[0].text(), "fn");
+        assert_eq!(tokens[1].text(), &source[comment]);
+        assert_eq!(tokens[1].text_len(), comment.len());
+        // The long tokens are not copied, nor cached.
+        assert!(ptr::eq(tokens[1].text(), &source[comment]));
+        assert!(ptr::eq(tokens[2].text(), &source[comment]));
+        assert!(tokens[1].is_borrowed() && !tokens[0].is_borrowed());
+        assert_eq!(cache.tokens().count(), 1);
+        assert_eq!(Arc::strong_count(&source), 3);
+
+        // It is the same as a token which holds its text.
+        let inline = GreenToken::new(SyntaxKind(2), &source[comment]);
+        assert_eq!(tokens[1].to_owned(), inline);
+        assert_eq!(tokens[1].subtree_hash(), inline.subtree_hash());
+        let children = vec![GreenToken::new(SyntaxKind(1), "fn"), inline.clone(), inline];
+        assert_eq!(node, GreenNode::new(SyntaxKind(0), children.into_iter().map(Into::into)));
+
+        // An equal cached token is reused.
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        let fn_kw = builder.cache.make_token(SyntaxKind(1), "fn");
+        builder.start_node(SyntaxKind(0));
+        builder.token_in(SyntaxKind(1), &source, TextRange::up_to(2.into()));
+        builder.finish_node();
+        let other = builder.finish();
+        assert!(ptr::eq(other.children().next().unwrap().into_token().unwrap(), &*fn_kw));
+
+        // The cache releases the source once no tree uses it.
+        drop(node);
+        assert_eq!(Arc::strong_count(&source), 3);
+        cache.gc();
+        assert_eq!(Arc::strong_count(&source), 1);
+    }
+
+    #[test]
+    fn test_attach_node() {
+        let subtree =
+            GreenNode::new(SyntaxKind(1), vec![GreenToken::new(SyntaxKind(2), "a").into()]);
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.node(subtree.clone());
+        builder.element(GreenToken::new(SyntaxKind(2), "b").into());
+        builder.finish_node();
+        let root = builder.finish();
+        assert_eq!(root.to_string(), "ab");
+        let child = root.children().next().unwrap().into_node().unwrap();
+        assert!(ptr::eq(child, &*subtree));
+    }
+
+    #[test]
+    fn test_revert_to() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "a");
+        let checkpoint = builder.checkpoint();
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(1), "<");
+        builder.finish_node();
+        builder.token(SyntaxKind(1), "b");
+        builder.revert_to(checkpoint);
+        builder.token(SyntaxKind(1), "c");
+        builder.finish_node();
+        let root = builder.finish();
+        assert_eq!(root.to_string(), "ac");
+        assert_eq!(root.children().len(), 2);
+    }
+
+    #[test]
+    fn test_set_current_kind() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(3), "a");
+        builder.finish_node_as(SyntaxKind(2));
+        builder.set_current_kind(SyntaxKind(4));
+        builder.finish_node();
+        let root = builder.finish();
+        assert_eq!(root.kind(), SyntaxKind(4));
+        assert_eq!(root.children().next().unwrap().kind(), SyntaxKind(2));
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let mut builder = GreenNodeBuilder::new();
+        assert_eq!(builder.try_finish_node(), Err(BuilderError::NoOpenNode));
+        builder.start_node(SyntaxKind(0));
+        let outer = builder.checkpoint();
+        builder.token(SyntaxKind(3), "a");
+        builder.start_node(SyntaxKind(1));
+        let err = builder.try_start_node_at(outer, SyntaxKind(2)).unwrap_err();
+        assert_eq!(
+            err,
+            BuilderError::InvalidCheckpoint { checkpoint: 0, first_child: 1, children: 1 }
+        );
+        assert_eq!(
+            err.to_string(),
+            "checkpoint no longer valid, was an unmatched start_node_at called?"
+        );
+        builder.token(SyntaxKind(3), "b");
+        builder.token(SyntaxKind(3), "c");
+        let inner = builder.checkpoint();
+        builder.finish_node();
+        let err = builder.try_start_node_at(inner, SyntaxKind(2)).unwrap_err();
+        assert_eq!(err.to_string(), "checkpoint no longer valid, was finish_node called early?");
+        builder.start_node(SyntaxKind(1));
+        let err = builder.try_finish().unwrap_err();
+        assert_eq!(err, BuilderError::OpenNodes { kinds: vec![SyntaxKind(0), SyntaxKind(1)] });
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.token(SyntaxKind(3), "a");
+        let err = builder.try_finish().unwrap_err();
+        assert_eq!(err, BuilderError::NoSingleRoot { count: 1 });
+    }
+
+    #[test]
+    fn test_stale_checkpoint() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        let checkpoint = builder.checkpoint();
+        builder.abandon_node();
+        // The children line up, but the node the checkpoint was taken in is
+        // gone.
+        builder.start_node(SyntaxKind(1));
+        let err = builder.try_start_node_at(checkpoint, SyntaxKind(2)).unwrap_err();
+        assert_eq!(err, BuilderError::StaleCheckpoint);
+
+        let checkpoint = builder.checkpoint();
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(3), "a");
+        builder.finish_node();
+        builder.start_node_at(checkpoint, SyntaxKind(4));
+        builder.finish_node();
+        builder.revert_to(checkpoint);
+        builder.finish_node();
+        assert_eq!(builder.finish().to_string(), "");
+    }
+
+    #[test]
+    fn test_wrap_from() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        let checkpoint = builder.checkpoint();
+        builder.token(SyntaxKind(3), "a");
+        for _ in 0..2 {
+            let depth = builder.wrap_from(checkpoint, SyntaxKind(1), |builder| {
+                builder.token(SyntaxKind(3), "b");
+                builder.depth()
+            });
+            assert_eq!(depth, 2);
+        }
+        builder.finish_node();
+        let expected = GreenNode::new(
+            SyntaxKind(0),
+            vec![GreenNode::new(
+                SyntaxKind(1),
+                vec![
+                    GreenNode::new(
+                        SyntaxKind(1),
+                        vec![
+                            GreenToken::new(SyntaxKind(3), "a").into(),
+                            GreenToken::new(SyntaxKind(3), "b").into(),
+                        ],
+                    )
+                    .into(),
+                    GreenToken::new(SyntaxKind(3), "b").into(),
+                ],
+            )
+            .into()],
+        );
+        assert_eq!(builder.finish(), expected);
+
+        let mut builder = GreenNodeBuilder::new();
+        let checkpoint = builder.checkpoint();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            builder.wrap_from(checkpoint, SyntaxKind(1), |builder| {
+                builder.start_node(SyntaxKind(2));
+            })
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_node_ranges() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.set_max_depth(2);
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(3), "ab");
+        let checkpoint = builder.checkpoint();
+        builder.start_node(SyntaxKind(1));
+        assert_eq!(builder.text_offset(), 2.into());
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(3), "cd");
+        assert_eq!(builder.finish_node_range(), None);
+        builder.token(SyntaxKind(3), "e");
+        let range = builder.finish_node_range();
+        assert_eq!(range, Some(TextRange::new(2.into(), 5.into())));
+        builder.revert_to(checkpoint);
+        assert_eq!(builder.text_offset(), 2.into());
+        builder.token(SyntaxKind(3), "f");
+        assert_eq!(builder.finish_node_range(), Some(TextRange::new(0.into(), 3.into())));
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        for _ in 0..2 {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(3), "a");
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = builder.finish();
+        let inner = root.children().next().unwrap().into_node().unwrap();
+        let token = inner.children().next().unwrap().into_token().unwrap();
+        let stats = cache.stats();
+        assert_eq!(
+            stats,
+            CacheStats {
+                node_lookups: 3,
+                node_hits: 1,
+                token_lookups: 2,
+                token_hits: 1,
+                nodes: 2,
+                tokens: 1,
+                saved_bytes: inner.allocation_size() + token.allocation_size(),
+            }
+        );
+        assert_eq!((stats.node_misses(), stats.token_misses()), (2, 1));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let build = |cache: &mut NodeCache| {
+            let mut builder = GreenNodeBuilder::with_cache(cache);
+            builder.start_node(SyntaxKind(0));
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(3), "a");
+            builder.token_bytes(SyntaxKind(3), b"\xFF");
+            builder.finish_node();
+            builder.token(SyntaxKind(3), "b");
+            builder.finish_node();
+            builder.finish()
+        };
+        let mut cache = NodeCache::default();
+        let tree = build(&mut cache);
+        let mut saved = Vec::new();
+        cache.save(&mut saved).unwrap();
+
+        let mut loaded = NodeCache::default();
+        loaded.load(&mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.stats().nodes, 2);
+        assert_eq!(loaded.stats().tokens, 3);
+        let before = loaded.stats().node_hits;
+        assert_eq!(build(&mut loaded), tree);
+        assert_eq!(loaded.stats().node_hits - before, 2);
+
+        let err = NodeCache::default().load(&mut &saved[..saved.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = NodeCache::default().load(&mut &b"rowan"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        /// Reads a byte at a time, and is interrupted before every byte.
+        struct Interrupted<'a>(&'a [u8], bool);
+        impl io::Read for Interrupted<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 = !self.1;
+                if self.1 {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                let n = (&self.0[..self.0.len().min(1)]).read(buf)?;
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let mut loaded = NodeCache::default();
+        loaded.load(&mut Interrupted(&saved, false)).unwrap();
+        assert_eq!(loaded.stats().nodes, 2);
+        assert_eq!(loaded.stats().tokens, 3);
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.set_max_depth(2);
+        assert_eq!((builder.depth(), builder.current_kind()), (0, None));
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(3), "a");
+        builder.start_node(SyntaxKind(1));
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(3), "b");
+        builder.token(SyntaxKind(3), "c");
+        assert_eq!(builder.depth(), 3);
+        assert_eq!(builder.current_kind(), Some(SyntaxKind(1)));
+        assert_eq!(builder.open_kinds().collect::<Vec<_>>(), vec![SyntaxKind(0), SyntaxKind(1)]);
+        assert_eq!(builder.pending_children(), 2);
+        builder.finish_node();
+        builder.finish_node();
+        assert_eq!(builder.depth(), 1);
+        assert_eq!(builder.pending_children(), 2);
+    }
+
+    #[test]
+    fn test_finish_list() {
+        let mut builder = GreenNodeBuilder::new();
+        for text in ["a", "b"] {
+            builder.start_node(SyntaxKind(0));
+            builder.token(SyntaxKind(3), text);
+            builder.finish_node();
+        }
+        let roots = builder.finish_list();
+        let texts: Vec<_> = roots.iter().map(|it| it.to_string()).collect();
+        assert_eq!(texts, vec!["a", "b"]);
+        assert!(GreenNodeBuilder::new().finish_list().is_empty());
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.finish_node();
+        builder.token(SyntaxKind(3), "c");
+        let err = builder.try_finish_list().unwrap_err();
+        assert_eq!(err, BuilderError::TopLevelToken { index: 1 });
+    }
+
+    #[test]
+    fn test_recover_after_panic() {
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(1));
+        builder.token(SyntaxKind(3), "a");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            builder.start_node(SyntaxKind(2));
+            builder.token(SyntaxKind(3), "b");
+            builder.start_node(SyntaxKind(2));
+            panic!("parser bug")
+        }));
+        assert!(result.is_err());
+        let (tree, report) = builder.recover(SyntaxKind(0));
+        assert_eq!(tree.to_string(), "ab");
+        assert_eq!(tree.kind(), SyntaxKind(0));
+        let node = tree.children().next().and_then(|it| it.into_node()).unwrap();
+        assert_eq!((node.kind(), node.to_string()), (SyntaxKind(1), "ab".to_string()));
+        assert_eq!(report.closed, [SyntaxKind(2), SyntaxKind(2), SyntaxKind(1)]);
+        drop(tree);
+        cache.assert_empty_after_gc();
+    }
+
+    #[test]
+    fn test_recover_incomplete() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(1));
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(3), "a");
+        let (tree, report) = builder.recover(SyntaxKind(0));
+        assert_eq!(tree.kind(), SyntaxKind(0));
+        assert_eq!(tree.children().next().unwrap().kind(), SyntaxKind(1));
+        assert_eq!(
+            report,
+            IncompleteTree { closed: vec![SyntaxKind(2), SyntaxKind(1)], wrapped: true }
+        );
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.token(SyntaxKind(3), "a");
+        builder.token(SyntaxKind(3), "b");
+        let (tree, report) = builder.recover(SyntaxKind(0));
+        assert_eq!((tree.kind(), tree.to_string()), (SyntaxKind(0), "ab".to_string()));
+        assert_eq!(report, IncompleteTree { closed: vec![], wrapped: true });
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(1));
+        builder.finish_node();
+        let (tree, report) = builder.recover(SyntaxKind(0));
+        assert_eq!(tree.kind(), SyntaxKind(1));
+        assert!(report.is_complete());
+    }
+
+    #[test]
+    fn test_abandon_node() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "a");
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(1), "b");
+        builder.start_node(SyntaxKind(3));
+        builder.token(SyntaxKind(1), "c");
+        builder.finish_node();
+        builder.abandon_node();
+        builder.finish_node();
+        let root = builder.finish();
+        let kinds: Vec<_> = root.children().map(|it| it.kind().0).collect();
+        assert_eq!(kinds, [1, 1, 3]);
+        assert_eq!(root.to_string(), "abc");
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut cache = NodeCache::default();
+        cache.set_deterministic(true);
+        for text in ["c", "a", "b"].iter() {
+            cache.make_token(SyntaxKind(1), text);
+            cache.make_token(SyntaxKind(0), text);
+        }
+        let tokens: Vec<(u16, &str)> = cache.tokens().map(|it| (it.kind().0, it.text())).collect();
+        assert_eq!(tokens, [(0, "a"), (0, "b"), (0, "c"), (1, "a"), (1, "b"), (1, "c")]);
+
+        // These nodes have the same kind and text, but different structures.
+        let token = |kind, text| GreenToken::new(SyntaxKind(kind), text).into();
+        let nodes = [
+            GreenNode::new(SyntaxKind(0), vec![token(2, "a")]),
+            GreenNode::new(SyntaxKind(0), vec![token(1, "a")]),
+            GreenNode::new(SyntaxKind(0), vec![token(1, "a"), token(1, "")]),
+            GreenNode::new(
+                SyntaxKind(0),
+                vec![GreenNode::new(SyntaxKind(3), vec![token(1, "a")]).into()],
+            ),
+        ];
+        let sorted = |nodes: &mut dyn Iterator<Item = &GreenNode>| {
+            let mut cache = NodeCache::default();
+            cache.set_deterministic(true);
+            for node in nodes {
+                cache.make_node(node.kind(), node.children().map(|it| it.to_owned()));
+            }
+            cache
+                .nodes()
+                .filter(|it| it.kind() == SyntaxKind(0))
+                .map(|it| format!("{:?}", it))
+                .collect::<Vec<_>>()
+        };
+        let expected: Vec<String> =
+            [1, 2, 0, 3].iter().map(|&i| format!("{:?}", nodes[i])).collect();
+        assert_eq!(sorted(&mut nodes.iter()), expected);
+        assert_eq!(sorted(&mut nodes.iter().rev()), expected);
+    }
+
+    #[test]
+    fn test_deep_tree() {
+        let depth = if cfg!(miri) { 1_000 } else { 100_000 };
+        let mut cache = NodeCache::default();
+        let mut build = || {
+            let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+            for _ in 0..depth {
+                builder.start_node(SyntaxKind(0));
+                builder.token(SyntaxKind(1), "(");
+            }
+            for _ in 0..depth {
+                builder.token(SyntaxKind(1), ")");
+                builder.finish_node();
+            }
+            builder.finish()
+        };
+        let tree = build();
+        assert!(ptr::eq::<GreenNodeData>(&*tree, &*build()));
+        assert_eq!(tree.to_string().len(), 2 * depth as usize);
+        let root = crate::cursor::SyntaxNode::new_root(tree);
+        let token = root.token_at_offset(depth.into()).right_biased().unwrap();
+        assert_eq!(token.text(), ")");
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.set_max_depth(2);
+        builder.start_node(SyntaxKind(0));
+        for _ in 0..3 {
+            builder.start_node(SyntaxKind(2));
+            builder.token(SyntaxKind(1), "(");
+        }
+        for _ in 0..3 {
+            builder.token(SyntaxKind(1), ")");
+            builder.finish_node();
         }
+        builder.finish_node();
+        assert!(builder.depth_exceeded());
+        let tree = builder.finish();
+        assert_eq!(tree.to_string(), "((()))");
+        assert_eq!(tree.children().len(), 1);
+        let child = tree.children().next().unwrap().into_node().unwrap();
+        assert_eq!(child.children().len(), 6);
     }
 }
@@ -1,5 +1,7 @@
 use std::borrow::Cow;
 
+#[cfg(any(feature = "line-col", feature = "utf16"))]
+use crate::green::Counter;
 use crate::{
     green::{GreenNode, GreenToken, SyntaxKind},
     GreenNodeData, NodeOrToken, TextSize,
@@ -7,7 +9,7 @@ use crate::{
 
 use super::GreenTokenData;
 
-pub(super) type GreenElement = NodeOrToken<GreenNode, GreenToken>;
+pub(crate) type GreenElement = NodeOrToken<GreenNode, GreenToken>;
 pub(crate) type GreenElementRef<'a> = NodeOrToken<&'a GreenNodeData, &'a GreenTokenData>;
 
 impl From<GreenNode> for GreenElement {
@@ -86,4 +88,25 @@ impl GreenElementRef<'_> {
             NodeOrToken::Token(it) => it.text_len(),
         }
     }
+
+    /// The number of `\n` bytes in the text covered by this element.
+    #[cfg(feature = "line-col")]
+    #[inline]
+    pub fn newline_count(self) -> Counter {
+        match self {
+            NodeOrToken::Node(it) => it.newline_count(),
+            NodeOrToken::Token(it) => it.newline_count(),
+        }
+    }
+
+    /// Returns the length of the text covered by this element in UTF-16 code
+    /// units.
+    #[cfg(feature = "utf16")]
+    #[inline]
+    pub fn text_len_utf16(self) -> Counter {
+        match self {
+            NodeOrToken::Node(it) => it.text_len_utf16(),
+            NodeOrToken::Token(it) => it.text_len_utf16(),
+        }
+    }
 }
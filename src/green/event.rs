@@ -0,0 +1,107 @@
+use std::mem;
+
+use crate::green::{GreenNode, GreenNodeBuilder, SyntaxKind};
+
+/// A step of building a tree, as produced by parsers which don't drive a
+/// [`GreenNodeBuilder`] directly, see [`GreenNodeBuilder::build_from_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// Starts a node of `kind`.
+    ///
+    /// If the parser discovers later that the node needs a parent which
+    /// starts at the same position, like a binary expression around its left
+    /// operand, it pushes a `StartNode` for the parent and sets
+    /// `forward_parent` to the distance from this event to the parent's.
+    StartNode {
+        kind: SyntaxKind,
+        forward_parent: Option<u32>,
+    },
+    Token {
+        kind: SyntaxKind,
+        text: &'a str,
+    },
+    FinishNode,
+    /// An event which does nothing, like a `StartNode` of a node the parser
+    /// abandoned.
+    Tombstone,
+}
+
+impl Event<'_> {
+    pub fn start_node(kind: SyntaxKind) -> Event<'static> {
+        Event::StartNode { kind, forward_parent: None }
+    }
+}
+
+impl GreenNodeBuilder<'_> {
+    /// Builds the tree described by `events`, which must be balanced and
+    /// describe a single root node.
+    pub fn build_from_events(mut self, mut events: Vec<Event<'_>>) -> GreenNode {
+        let mut forward_parents = Vec::new();
+        for idx in 0..events.len() {
+            match mem::replace(&mut events[idx], Event::Tombstone) {
+                Event::StartNode { kind, forward_parent } => {
+                    // Parents are pushed after their children, so collect the
+                    // chain and start the nodes outermost first.
+                    forward_parents.push(kind);
+                    let (mut idx, mut forward_parent) = (idx, forward_parent);
+                    while let Some(distance) = forward_parent {
+                        idx += distance as usize;
+                        forward_parent = match mem::replace(&mut events[idx], Event::Tombstone) {
+                            Event::StartNode { kind, forward_parent } => {
+                                forward_parents.push(kind);
+                                forward_parent
+                            }
+                            event => panic!("forward parent is not a StartNode: {:?}", event),
+                        };
+                    }
+                    for kind in forward_parents.drain(..).rev() {
+                        self.start_node(kind);
+                    }
+                }
+                Event::Token { kind, text } => self.token(kind, text),
+                Event::FinishNode => self.finish_node(),
+                Event::Tombstone => (),
+            }
+        }
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_parents() {
+        const ROOT: SyntaxKind = SyntaxKind(0);
+        const BIN: SyntaxKind = SyntaxKind(1);
+        const LIT: SyntaxKind = SyntaxKind(2);
+        let token = |text| Event::Token { kind: SyntaxKind(3), text };
+        // `1+2`, where the parser wraps the literal `1` into a binary
+        // expression after it sees the `+`.
+        let events = vec![
+            Event::start_node(ROOT),
+            Event::StartNode { kind: LIT, forward_parent: Some(3) },
+            token("1"),
+            Event::FinishNode,
+            Event::start_node(BIN),
+            token("+"),
+            Event::start_node(LIT),
+            token("2"),
+            Event::FinishNode,
+            Event::FinishNode,
+            Event::Tombstone,
+            Event::FinishNode,
+        ];
+        let tree = GreenNodeBuilder::new().build_from_events(events);
+        let dump = |node: &crate::GreenNodeData| {
+            node.children().map(|it| (it.kind(), it.to_string())).collect::<Vec<_>>()
+        };
+        assert_eq!(dump(&tree), vec![(BIN, "1+2".to_string())]);
+        let bin = tree.children().next().unwrap().into_node().unwrap();
+        assert_eq!(
+            dump(bin),
+            vec![(LIT, "1".to_string()), (SyntaxKind(3), "+".to_string()), (LIT, "2".to_string())]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::{mem, thread};
+
+use crate::green::{Checkpoint, GreenNodeBuilder, SyntaxKind};
+
+/// A node which is not started yet, returned by [`GreenNodeBuilder::start`].
+///
+/// Unlike a [`Checkpoint`], a marker must be used up: dropping it without
+/// calling [`Marker::complete`] or [`Marker::abandon`] panics, which catches
+/// parser paths that forget to close their node.
+#[must_use = "a marker must be completed or abandoned"]
+#[derive(Debug)]
+pub struct Marker {
+    checkpoint: Checkpoint,
+}
+
+impl Marker {
+    /// Wraps everything added since the marker was created into a node of
+    /// `kind`.
+    pub fn complete(self, builder: &mut GreenNodeBuilder<'_>, kind: SyntaxKind) -> CompletedMarker {
+        let checkpoint = self.checkpoint;
+        mem::forget(self);
+        builder.start_node_at(checkpoint, kind);
+        builder.finish_node();
+        CompletedMarker { checkpoint, kind }
+    }
+
+    /// Leaves the elements added since the marker was created in the
+    /// current node.
+    pub fn abandon(self, _builder: &mut GreenNodeBuilder<'_>) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Marker {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            panic!("marker must be completed or abandoned")
+        }
+    }
+}
+
+/// A finished node, which can still be wrapped into a parent.
+#[derive(Debug, Clone, Copy)]
+pub struct CompletedMarker {
+    checkpoint: Checkpoint,
+    kind: SyntaxKind,
+}
+
+impl CompletedMarker {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    /// Starts a node which will begin with this one, like a binary expression
+    /// around its left operand.
+    pub fn precede(self) -> Marker {
+        Marker { checkpoint: self.checkpoint }
+    }
+}
+
+impl GreenNodeBuilder<'_> {
+    /// Starts a node whose kind is decided when it is finished, see
+    /// [`Marker`].
+    ///
+    /// ```
+    /// # use rowan::{GreenNodeBuilder, SyntaxKind};
+    /// # const ROOT: SyntaxKind = SyntaxKind(0);
+    /// # const BIN: SyntaxKind = SyntaxKind(1);
+    /// # const ATOM: SyntaxKind = SyntaxKind(2);
+    /// let mut builder = GreenNodeBuilder::new();
+    /// let root = builder.start();
+    /// let lhs = builder.start();
+    /// builder.token(ATOM, "1");
+    /// let lhs = lhs.complete(&mut builder, ATOM);
+    /// let bin = lhs.precede();
+    /// builder.token(ATOM, "+");
+    /// builder.token(ATOM, "2");
+    /// bin.complete(&mut builder, BIN);
+    /// root.complete(&mut builder, ROOT);
+    /// assert_eq!(builder.finish().to_string(), "1+2");
+    /// ```
+    pub fn start(&self) -> Marker {
+        Marker { checkpoint: self.checkpoint() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markers() {
+        let mut builder = GreenNodeBuilder::new();
+        let root = builder.start();
+        let abandoned = builder.start();
+        builder.token(SyntaxKind(2), "a");
+        abandoned.abandon(&mut builder);
+        let inner = builder.start();
+        builder.token(SyntaxKind(2), "b");
+        let inner = inner.complete(&mut builder, SyntaxKind(1));
+        assert_eq!(inner.kind(), SyntaxKind(1));
+        let outer = inner.precede();
+        builder.token(SyntaxKind(2), "c");
+        outer.complete(&mut builder, SyntaxKind(3));
+        root.complete(&mut builder, SyntaxKind(0));
+        let tree = builder.finish();
+
+        let kinds: Vec<_> = tree.children().map(|it| (it.kind(), it.to_string())).collect();
+        assert_eq!(
+            kinds,
+            vec![(SyntaxKind(2), "a".to_string()), (SyntaxKind(3), "bc".to_string())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "marker must be completed or abandoned")]
+    fn test_forgotten_marker() {
+        let builder = GreenNodeBuilder::new();
+        let _marker = builder.start();
+    }
+}
@@ -1,34 +1,81 @@
 use std::{
     borrow::{Borrow, Cow},
+    cmp::Ordering,
     fmt,
+    hash::{Hash, Hasher},
+    io,
     iter::{self, FusedIterator},
     mem::{self, ManuallyDrop},
     ops, ptr, slice,
 };
 
 use countme::Count;
+use rustc_hash::FxHasher;
 
+#[cfg(any(feature = "line-col", feature = "utf16"))]
+use std::convert::TryFrom;
+
+use crate::green::Counter;
+#[cfg(feature = "line-col")]
+use crate::LineCol;
 use crate::{
     arc::{Arc, HeaderSlice, ThinArc},
-    green::{GreenElement, GreenElementRef, SyntaxKind},
+    green::{GreenElement, GreenElementRef, GreenTokenData, KindSet, SyntaxKind},
     utility_types::static_assert,
-    GreenToken, NodeOrToken, TextRange, TextSize,
+    GreenToken, MemoryUsage, NodeOrToken, TextRange, TextSize,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(super) struct GreenNodeHead {
     kind: SyntaxKind,
     text_len: TextSize,
+    /// Hash of the kind and the hashes of the children, see
+    /// [`GreenNodeData::subtree_hash`].
+    hash: u64,
+    #[cfg(feature = "line-col")]
+    newlines: Counter,
+    descendants: Counter,
+    tokens: Counter,
+    #[cfg(feature = "utf16")]
+    text_len_utf16: Counter,
     _c: Count<GreenNode>,
 }
 
+/// A child together with its offset, so that finding the child at an offset
+/// is a binary search.
+///
+/// On 64-bit targets this is two words: the discriminant already fits into
+/// the padding after the 32-bit offset, so tagging the pointer instead would
+/// not make it smaller. Halving it would require dropping the offsets and
+/// scanning the children to find one. With `--cfg rowan_large_offsets` it is
+/// three words, and the features which store a [`ChildPrefix`] make it
+/// larger still.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) enum GreenChild {
-    Node { rel_offset: TextSize, node: GreenNode },
-    Token { rel_offset: TextSize, token: GreenToken },
+    Node { rel_offset: TextSize, prefix: ChildPrefix, node: GreenNode },
+    Token { rel_offset: TextSize, prefix: ChildPrefix, token: GreenToken },
+}
+#[cfg(all(target_pointer_width = "64", not(rowan_large_offsets)))]
+static_assert!(
+    mem::size_of::<GreenChild>()
+        == mem::size_of::<usize>() * 2 + mem::size_of::<ChildPrefix>().next_multiple_of(8)
+);
+#[cfg(rowan_large_offsets)]
+static_assert!(
+    mem::size_of::<GreenChild>()
+        == mem::size_of::<usize>() * 3 + mem::size_of::<ChildPrefix>().next_multiple_of(8)
+);
+
+/// The counts of the children before a child, so that finding the child at
+/// a line or a UTF-16 offset is a binary search too. Empty unless the counts
+/// are stored in the headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) struct ChildPrefix {
+    #[cfg(feature = "line-col")]
+    newlines: Counter,
+    #[cfg(feature = "utf16")]
+    len_utf16: Counter,
 }
-#[cfg(target_pointer_width = "64")]
-static_assert!(mem::size_of::<GreenChild>() == mem::size_of::<usize>() * 2);
 
 type Repr = HeaderSlice<GreenNodeHead, [GreenChild]>;
 type ReprThin = HeaderSlice<GreenNodeHead, [GreenChild; 0]>;
@@ -39,18 +86,65 @@ pub struct GreenNodeData {
 
 impl PartialEq for GreenNodeData {
     fn eq(&self, other: &Self) -> bool {
-        self.header() == other.header() && self.slice() == other.slice()
+        // Not recursive, to handle arbitrarily deep trees.
+        let mut stack = vec![(self, other)];
+        while let Some((lhs, rhs)) = stack.pop() {
+            if ptr::eq(lhs, rhs) {
+                continue;
+            }
+            // The headers include the subtree hashes, so this usually fails
+            // fast for different trees.
+            if lhs.header() != rhs.header() || lhs.slice().len() != rhs.slice().len() {
+                return false;
+            }
+            for pair in lhs.slice().iter().zip(rhs.slice()) {
+                match pair {
+                    (
+                        GreenChild::Node { rel_offset: lhs_offset, node: lhs, .. },
+                        GreenChild::Node { rel_offset: rhs_offset, node: rhs, .. },
+                    ) if lhs_offset == rhs_offset => stack.push((lhs, rhs)),
+                    (GreenChild::Token { .. }, GreenChild::Token { .. }) if pair.0 == pair.1 => (),
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Eq for GreenNodeData {}
+
+impl Hash for GreenNodeData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.subtree_hash())
     }
 }
 
 /// Internal node in the immutable tree.
 /// It has other nodes and tokens as children.
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone)]
 #[repr(transparent)]
 pub struct GreenNode {
     ptr: ThinArc<GreenNodeHead, GreenChild>,
 }
 
+impl PartialEq for GreenNode {
+    fn eq(&self, other: &GreenNode) -> bool {
+        let lhs: &GreenNodeData = self;
+        let rhs: &GreenNodeData = other;
+        lhs == rhs
+    }
+}
+
+impl Eq for GreenNode {}
+
+impl Hash for GreenNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let data: &GreenNodeData = self;
+        data.hash(state)
+    }
+}
+
 impl ToOwned for GreenNodeData {
     type Owned = GreenNode;
 
@@ -67,7 +161,7 @@ impl ToOwned for GreenNodeData {
 impl Borrow<GreenNodeData> for GreenNode {
     #[inline]
     fn borrow(&self) -> &GreenNodeData {
-        &*self
+        self
     }
 }
 
@@ -90,24 +184,22 @@ impl fmt::Debug for GreenNodeData {
 
 impl fmt::Debug for GreenNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let data: &GreenNodeData = &*self;
+        let data: &GreenNodeData = self;
         fmt::Debug::fmt(data, f)
     }
 }
 
 impl fmt::Display for GreenNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let data: &GreenNodeData = &*self;
+        let data: &GreenNodeData = self;
         fmt::Display::fmt(data, f)
     }
 }
 
 impl fmt::Display for GreenNodeData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for child in self.children() {
-            write!(f, "{}", child)?;
-        }
-        Ok(())
+        // Unlike `chunks`, this doesn't panic on tokens which aren't UTF-8.
+        self.descendant_tokens().try_for_each(|(token, _)| fmt::Display::fmt(token, f))
     }
 }
 
@@ -122,6 +214,43 @@ impl GreenNodeData {
         self.data.slice()
     }
 
+    /// A hash of the whole subtree, computed when the node is created from
+    /// the hashes of the children, so that hashing a node is O(1).
+    #[inline]
+    pub fn subtree_hash(&self) -> u64 {
+        self.header().hash
+    }
+
+    /// A 64-bit FNV-1a digest of the kinds, the shape and the token texts of
+    /// the subtree.
+    ///
+    /// Unlike [`GreenNodeData::subtree_hash`], this is the same across runs,
+    /// processes and platforms, so it can be persisted. It takes time linear
+    /// in the size of the tree.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = Fnv::default();
+        hasher.node(self);
+        let mut stack = vec![self.children()];
+        while let Some(children) = stack.last_mut() {
+            match children.next() {
+                Some(NodeOrToken::Node(node)) => {
+                    hasher.node(node);
+                    stack.push(node.children());
+                }
+                Some(NodeOrToken::Token(token)) => {
+                    hasher.write(&[1]);
+                    hasher.write(&token.kind().0.to_le_bytes());
+                    hasher.write(&(token.bytes().len() as u32).to_le_bytes());
+                    hasher.write(token.bytes());
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+        hasher.0
+    }
+
     /// Kind of this node.
     #[inline]
     pub fn kind(&self) -> SyntaxKind {
@@ -134,13 +263,397 @@ impl GreenNodeData {
         self.header().text_len
     }
 
+    /// The number of `\n` bytes in the text of this node.
+    #[cfg(feature = "line-col")]
+    #[inline]
+    pub fn newline_count(&self) -> Counter {
+        self.header().newlines
+    }
+
+    /// The number of nodes in the subtree of this node, including itself, as
+    /// counted by `SyntaxNode::descendants`.
+    ///
+    /// Shared subtrees are counted as many times as they occur, and the count
+    /// saturates at the maximum of the counter type, which is as wide as
+    /// `TextSize`.
+    #[inline]
+    pub fn descendant_count(&self) -> Counter {
+        self.header().descendants
+    }
+
+    /// The number of tokens in the subtree of this node, see
+    /// [`GreenNodeData::descendant_count`].
+    #[inline]
+    pub fn token_count(&self) -> Counter {
+        self.header().tokens
+    }
+
+    /// Converts an offset within this node into a line and column.
+    ///
+    /// Unlike [`LineIndex`](crate::LineIndex), this needs no index which has
+    /// to be updated on edits: only the paths from this node to the tokens
+    /// containing `offset` and the start of its line are visited, with a
+    /// binary search over the children at each level.
+    #[cfg(feature = "line-col")]
+    pub fn line_col(&self, offset: TextSize) -> LineCol {
+        assert!(offset <= self.text_len(), "offset {:?} is out of bounds", offset);
+        let mut line = 0;
+        let mut node = self;
+        let mut rel_offset = offset;
+        loop {
+            let (idx, child_offset, child) = match node.child_at_offset(rel_offset) {
+                Some(it) => it,
+                None => {
+                    line += node.newline_count();
+                    break;
+                }
+            };
+            line += node.slice()[idx].prefix().newlines;
+            rel_offset -= child_offset;
+            match child {
+                NodeOrToken::Node(it) => node = it,
+                NodeOrToken::Token(it) => {
+                    let prefix = &it.bytes()[..usize::from(rel_offset)];
+                    line += prefix.iter().filter(|&&it| it == b'\n').count() as Counter;
+                    break;
+                }
+            }
+        }
+        let line_start = self.line_start(line).unwrap();
+        // Can't overflow, as it is at most `text_len`.
+        let col = Counter::try_from(usize::from(offset - line_start)).unwrap();
+        LineCol { line, col }
+    }
+
+    /// Returns the offset of the start of the zero-based `line`, or `None`
+    /// if there are not as many lines.
+    #[cfg(feature = "line-col")]
+    pub fn line_start(&self, line: Counter) -> Option<TextSize> {
+        if line == 0 {
+            return Some(0.into());
+        }
+        if line > self.newline_count() {
+            return None;
+        }
+        // Looking for the `line`th newline.
+        let mut remaining = line;
+        let mut res = TextSize::from(0);
+        let mut node = self;
+        loop {
+            let idx = node.slice().partition_point(|child| {
+                child.prefix().newlines + child.as_ref().newline_count() < remaining
+            });
+            let child = &node.slice()[idx];
+            remaining -= child.prefix().newlines;
+            res += child.rel_offset();
+            match child {
+                GreenChild::Node { node: child, .. } => node = child,
+                GreenChild::Token { token, .. } => {
+                    let (idx, _) = token
+                        .bytes()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, &it)| it == b'\n')
+                        .nth(remaining as usize - 1)
+                        .unwrap();
+                    return Some(res + TextSize::try_from(idx + 1).unwrap());
+                }
+            }
+        }
+    }
+
+    /// Heap memory used by the subtree of this node, see [`MemoryUsage`].
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::collect_green(self)
+    }
+
+    #[inline]
+    pub(crate) fn allocation_size(&self) -> usize {
+        self.data.allocation_size()
+    }
+
+    /// Returns the length of the text covered by this node in UTF-16 code
+    /// units.
+    #[cfg(feature = "utf16")]
+    #[inline]
+    pub fn text_len_utf16(&self) -> Counter {
+        self.header().text_len_utf16
+    }
+
+    /// Converts a UTF-8 offset within this node into a UTF-16 one.
+    ///
+    /// Only the path from this node to the token containing `offset` is
+    /// visited, with a binary search over the children at each level, and the
+    /// text of the other tokens is not scanned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is out of bounds, or if it falls inside a multibyte
+    /// char of a UTF-8 token.
+    #[cfg(feature = "utf16")]
+    pub fn utf16_offset(&self, offset: TextSize) -> Counter {
+        assert!(offset <= self.text_len(), "offset {:?} is out of bounds", offset);
+        let mut res = 0;
+        let mut node = self;
+        let mut offset = offset;
+        loop {
+            // The child containing `offset`, or the last one if `offset` is
+            // the end of the node.
+            let idx = node.slice().partition_point(|it| it.rel_range().end() <= offset);
+            let child = match node.slice().get(idx).or_else(|| node.slice().last()) {
+                Some(it) => it,
+                None => return res,
+            };
+            res += child.prefix().len_utf16;
+            offset -= child.rel_offset();
+            match child {
+                GreenChild::Node { node: child, .. } => node = child,
+                GreenChild::Token { token, .. } => {
+                    let offset = usize::from(offset);
+                    return res
+                        + match token.as_str() {
+                            Some(text) => text[..offset].encode_utf16().count() as Counter,
+                            None => offset as Counter,
+                        };
+                }
+            }
+        }
+    }
+
+    /// Converts a UTF-16 offset within this node into a UTF-8 one.
+    ///
+    /// Returns `None` if the offset is out of bounds or points inside a
+    /// surrogate pair.
+    #[cfg(feature = "utf16")]
+    pub fn offset_from_utf16(&self, utf16_offset: Counter) -> Option<TextSize> {
+        if utf16_offset > self.text_len_utf16() {
+            return None;
+        }
+        let mut res = TextSize::from(0);
+        let mut node = self;
+        let mut utf16_offset = utf16_offset;
+        loop {
+            let idx = node.slice().partition_point(|it| {
+                it.prefix().len_utf16 + it.as_ref().text_len_utf16() <= utf16_offset
+            });
+            let child = match node.slice().get(idx).or_else(|| node.slice().last()) {
+                Some(it) => it,
+                None => return Some(res),
+            };
+            utf16_offset -= child.prefix().len_utf16;
+            res += child.rel_offset();
+            match child {
+                GreenChild::Node { node: child, .. } => node = child,
+                GreenChild::Token { token, .. } => {
+                    let text = match token.as_str() {
+                        Some(it) => it,
+                        None => {
+                            return Some(res + TextSize::try_from(utf16_offset as usize).unwrap())
+                        }
+                    };
+                    let mut units = 0;
+                    for (idx, c) in text.char_indices() {
+                        if units == utf16_offset {
+                            return Some(res + TextSize::try_from(idx).unwrap());
+                        }
+                        if units > utf16_offset {
+                            return None;
+                        }
+                        units += c.len_utf16() as Counter;
+                    }
+                    return if units == utf16_offset { Some(res + token.text_len()) } else { None };
+                }
+            }
+        }
+    }
+
+    /// Recomputes the text lengths of all nodes and the offsets of all
+    /// children in the subtree, and reports the first stored value which
+    /// disagrees with the recomputed one.
+    ///
+    /// Trees built through the public API are always consistent, this is
+    /// meant to vet trees which were constructed by other means, for example,
+    /// deserialized.
+    pub fn validate_text_lens(&self) -> Result<(), TextLenError> {
+        // (node, path to the node, index of the next child, accumulated len)
+        let mut stack = vec![(self, Vec::new(), 0, TextSize::from(0))];
+        while let Some((node, path, idx, len)) = stack.last_mut() {
+            let child = match node.slice().get(*idx) {
+                Some(it) => it,
+                None => {
+                    if node.text_len() != *len {
+                        return Err(TextLenError {
+                            path: path.clone(),
+                            kind: TextLenErrorKind::TextLen,
+                            expected: *len,
+                            actual: node.text_len(),
+                        });
+                    }
+                    stack.pop();
+                    continue;
+                }
+            };
+            if child.rel_offset() != *len {
+                let mut path = path.clone();
+                path.push(*idx);
+                return Err(TextLenError {
+                    path,
+                    kind: TextLenErrorKind::RelOffset,
+                    expected: *len,
+                    actual: child.rel_offset(),
+                });
+            }
+            *idx += 1;
+            *len += child.as_ref().text_len();
+            if let GreenChild::Node { node: child, .. } = child {
+                let mut path = path.clone();
+                path.push(*idx - 1);
+                stack.push((child, path, 0, TextSize::from(0)));
+            }
+        }
+        Ok(())
+    }
+
     /// Children of this node.
     #[inline]
     pub fn children(&self) -> Children<'_> {
         Children { raw: self.slice().iter() }
     }
 
-    pub(crate) fn child_at_range(
+    /// All the tokens in the subtree of this node, in document order, with
+    /// their offsets relative to the start of this node.
+    #[inline]
+    pub fn descendant_tokens(&self) -> DescendantTokens<'_> {
+        DescendantTokens { stack: vec![(self.slice().iter(), 0.into())] }
+    }
+
+    /// The flat list of the tokens in the subtree of this node, with ranges
+    /// relative to the start of this node.
+    ///
+    /// # Panics
+    ///
+    /// Panics on a token which is not valid UTF-8, see
+    /// [`GreenTokenData::text`].
+    pub fn to_tokens(&self) -> Vec<(SyntaxKind, TextRange, &str)> {
+        self.descendant_tokens()
+            .map(|(token, offset)| {
+                (token.kind(), TextRange::at(offset, token.text_len()), token.text())
+            })
+            .collect()
+    }
+
+    /// Whether the text of this node is `text`, without building the text of
+    /// the node.
+    pub fn text_eq(&self, text: &str) -> bool {
+        self.text_len() == TextSize::of(text) && self.text_cmp(text) == Ordering::Equal
+    }
+
+    /// Compares the text of this node with `text` byte-wise, which is the
+    /// same as comparing `str`s, without building the text of the node.
+    pub fn text_cmp(&self, text: &str) -> Ordering {
+        let mut rest = text.as_bytes();
+        for (token, _) in self.descendant_tokens() {
+            let bytes = token.bytes();
+            let n = bytes.len().min(rest.len());
+            match bytes[..n].cmp(&rest[..n]) {
+                Ordering::Equal if n < bytes.len() => return Ordering::Greater,
+                Ordering::Equal => rest = &rest[n..],
+                ord => return ord,
+            }
+        }
+        if rest.is_empty() {
+            Ordering::Equal
+        } else {
+            Ordering::Less
+        }
+    }
+
+    /// The texts of all the tokens in the subtree of this node, in document
+    /// order, which concatenate to the text of the node.
+    ///
+    /// # Panics
+    ///
+    /// Panics on a token which is not valid UTF-8, see
+    /// [`GreenTokenData::text`].
+    #[inline]
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks { tokens: self.descendant_tokens() }
+    }
+
+    /// Writes the text of this node to `w`, token by token, without building
+    /// the whole text in memory.
+    ///
+    /// The tokens are written as raw bytes, so tokens which are not valid
+    /// UTF-8 are written unchanged. Pass a buffered writer, as this issues a
+    /// `write_all` per token.
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.descendant_tokens().try_for_each(|(token, _)| w.write_all(token.bytes()))
+    }
+
+    /// Children which are not trivia, with their indices among all the
+    /// children.
+    ///
+    /// The `n`th slot of a node is its `n`th non-trivia child, whatever its
+    /// kind, which is handy for grammars with positional productions.
+    #[inline]
+    pub fn slots<F: Fn(SyntaxKind) -> bool>(&self, is_trivia: F) -> Slots<'_, F> {
+        Slots { raw: self.slice().iter().enumerate(), is_trivia }
+    }
+
+    /// Compares the trees like `==`, but skips the tokens and nodes whose kind
+    /// is trivia, so that trees which differ only in whitespace and comments
+    /// are equal.
+    pub fn structurally_eq_ignoring(
+        &self,
+        other: &GreenNodeData,
+        is_trivia: impl Fn(SyntaxKind) -> bool,
+    ) -> bool {
+        // Not recursive, to handle arbitrarily deep trees.
+        let mut stack = vec![(self, other)];
+        while let Some((lhs, rhs)) = stack.pop() {
+            if lhs.kind() != rhs.kind() {
+                return false;
+            }
+            if ptr::eq(lhs, rhs) {
+                continue;
+            }
+            let mut lhs = lhs.slots(&is_trivia);
+            let mut rhs = rhs.slots(&is_trivia);
+            loop {
+                match (lhs.next(), rhs.next()) {
+                    (None, None) => break,
+                    (Some((_, NodeOrToken::Node(lhs))), Some((_, NodeOrToken::Node(rhs)))) => {
+                        stack.push((lhs, rhs))
+                    }
+                    (Some((_, NodeOrToken::Token(lhs))), Some((_, NodeOrToken::Token(rhs))))
+                        if lhs == rhs => {}
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the child which contains `offset`, relative to the start of
+    /// this node, together with its index and relative offset.
+    ///
+    /// At the boundary between two children, this is the right one, and
+    /// empty children are never returned.
+    pub fn child_at_offset(
+        &self,
+        offset: TextSize,
+    ) -> Option<(usize, TextSize, GreenElementRef<'_>)> {
+        let idx = self.slice().partition_point(|it| it.rel_offset() <= offset).checked_sub(1)?;
+        let child = &self.slice()[idx];
+        if offset >= child.rel_range().end() {
+            return None;
+        }
+        Some((idx, child.rel_offset(), child.as_ref()))
+    }
+
+    /// Returns the child whose range contains `rel_range`, relative to the
+    /// start of this node, together with its index and relative offset.
+    pub fn child_containing_range(
         &self,
         rel_range: TextRange,
     ) -> Option<(usize, TextSize, GreenElementRef<'_>)> {
@@ -156,8 +669,60 @@ impl GreenNodeData {
         Some((idx, child.rel_offset(), child.as_ref()))
     }
 
+    /// Returns the smallest node which covers `range`, relative to the start
+    /// of this node, together with its relative offset.
+    ///
+    /// If `range` straddles some, but not all, of the children of the deepest
+    /// node which contains it, the result is a new node of the same kind with
+    /// just these children. Otherwise, for example if `range` lies within a
+    /// single token or is empty, it is that deepest node itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is not within the text of this node.
+    pub fn covering_subtree(&self, range: TextRange) -> (GreenNode, TextSize) {
+        assert!(range.end() <= self.text_len(), "range {:?} is out of bounds", range);
+        let mut node = self;
+        let mut offset = TextSize::from(0);
+        loop {
+            let rel_range = range - offset;
+            match node.child_containing_range(rel_range) {
+                Some((_, child_offset, NodeOrToken::Node(child))) => {
+                    node = child;
+                    offset += child_offset;
+                    continue;
+                }
+                // Within a single token.
+                Some((_, _, NodeOrToken::Token(_))) => return (node.to_owned(), offset),
+                None => (),
+            }
+            if rel_range.is_empty() {
+                return (node.to_owned(), offset);
+            }
+            let overlaps = |child: &GreenChild| {
+                let child_range = child.rel_range();
+                child_range.start() < rel_range.end() && rel_range.start() < child_range.end()
+            };
+            let first = node.slice().iter().position(overlaps).unwrap();
+            let last = node.slice().iter().rposition(overlaps).unwrap();
+            if first == 0 && last == node.slice().len() - 1 {
+                return (node.to_owned(), offset);
+            }
+            let children = node.slice()[first..=last].iter().map(|it| it.as_ref().to_owned());
+            let wrapper = GreenNode::new(node.kind(), children);
+            return (wrapper, offset + node.slice()[first].rel_offset());
+        }
+    }
+
+    /// Creates a copy of this node with the child at `index` replaced by
+    /// `new_child`. The other children are shared with this node.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
     #[must_use]
     pub fn replace_child(&self, index: usize, new_child: GreenElement) -> GreenNode {
+        assert!(index < self.children().len(), "child index {} is out of bounds", index);
         let mut replacement = Some(new_child);
         let children = self.children().enumerate().map(|(i, child)| {
             if i == index {
@@ -168,15 +733,29 @@ impl GreenNodeData {
         });
         GreenNode::new(self.kind(), children)
     }
+    /// Creates a copy of this node with `new_child` inserted at `index`,
+    /// shifting the following children to the right.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the number of children.
     #[must_use]
     pub fn insert_child(&self, index: usize, new_child: GreenElement) -> GreenNode {
         // https://github.com/rust-lang/rust/issues/34433
         self.splice_children(index..index, iter::once(new_child))
     }
+    /// Creates a copy of this node without the child at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
     #[must_use]
     pub fn remove_child(&self, index: usize) -> GreenNode {
         self.splice_children(index..=index, iter::empty())
     }
+    /// Creates a copy of this node with the children in `range` replaced by
+    /// `replace_with`, building the new node once for any number of
+    /// replaced, inserted, or deleted children.
     #[must_use]
     pub fn splice_children<R, I>(&self, range: R, replace_with: I) -> GreenNode
     where
@@ -189,6 +768,159 @@ impl GreenNodeData {
     }
 }
 
+/// Recomputes the offsets of the children and the parts of the header which
+/// are derived from them.
+fn fix_header(data: &mut Repr) {
+    let mut text_len: TextSize = 0.into();
+    #[cfg(feature = "line-col")]
+    let mut newlines = 0;
+    let mut descendants: Counter = 1;
+    let mut tokens: Counter = 0;
+    let mut hasher = FxHasher::default();
+    data.header.kind.hash(&mut hasher);
+    #[cfg(feature = "utf16")]
+    let mut text_len_utf16 = 0;
+    for child in data.slice_mut() {
+        match child {
+            GreenChild::Node { rel_offset, prefix, .. }
+            | GreenChild::Token { rel_offset, prefix, .. } => {
+                *rel_offset = text_len;
+                *prefix = ChildPrefix {
+                    #[cfg(feature = "line-col")]
+                    newlines,
+                    #[cfg(feature = "utf16")]
+                    len_utf16: text_len_utf16,
+                };
+            }
+        }
+        let child = child.as_ref();
+        text_len = text_len
+            .checked_add(child.text_len())
+            .expect("node text is longer than `TextSize` allows, see `--cfg rowan_large_offsets`");
+        #[cfg(feature = "line-col")]
+        {
+            // Can't overflow, as it is at most `text_len`.
+            newlines += child.newline_count();
+        }
+        match child {
+            NodeOrToken::Node(node) => {
+                descendants = descendants.saturating_add(node.descendant_count());
+                tokens = tokens.saturating_add(node.token_count());
+            }
+            NodeOrToken::Token(_) => tokens = tokens.saturating_add(1),
+        }
+        match child {
+            NodeOrToken::Node(node) => node.subtree_hash(),
+            NodeOrToken::Token(token) => token.subtree_hash(),
+        }
+        .hash(&mut hasher);
+        #[cfg(feature = "utf16")]
+        {
+            // Can't overflow, as it is at most `text_len`.
+            text_len_utf16 += child.text_len_utf16();
+        }
+    }
+    let header = &mut data.header;
+    header.text_len = text_len;
+    header.hash = hasher.finish();
+    #[cfg(feature = "line-col")]
+    {
+        header.newlines = newlines;
+    }
+    header.descendants = descendants;
+    header.tokens = tokens;
+    #[cfg(feature = "utf16")]
+    {
+        header.text_len_utf16 = text_len_utf16;
+    }
+}
+
+/// See [`GreenNodeData::content_hash`]. The layout of the hashed data must
+/// not change.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn node(&mut self, node: &GreenNodeData) {
+        self.write(&[0]);
+        self.write(&node.kind().0.to_le_bytes());
+        self.write(&(node.slice().len() as u32).to_le_bytes());
+    }
+}
+
+/// A text length inconsistency found by [`GreenNodeData::validate_text_lens`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextLenError {
+    /// Child indices leading from the validated node to the offending element.
+    pub path: Vec<usize>,
+    pub kind: TextLenErrorKind,
+    /// The value recomputed from the children.
+    pub expected: TextSize,
+    /// The value stored in the tree.
+    pub actual: TextSize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextLenErrorKind {
+    /// The offset of a child relative to its parent is wrong.
+    RelOffset,
+    /// The stored text length of a node is wrong.
+    TextLen,
+}
+
+impl fmt::Display for TextLenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            TextLenErrorKind::RelOffset => "child offset",
+            TextLenErrorKind::TextLen => "node text length",
+        };
+        write!(
+            f,
+            "invalid {} at {:?}: expected {:?}, found {:?}",
+            what, self.path, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for TextLenError {}
+
+/// The error returned by [`GreenNode::try_new`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NewNodeError {
+    /// The text of the node would be longer than 4 GiB, or, with the
+    /// `rowan_large_offsets` cfg, than `u64::MAX` bytes.
+    TextTooLong,
+    TooManyChildren {
+        count: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for NewNodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NewNodeError::TextTooLong => f.write_str("node text is longer than `TextSize` allows"),
+            NewNodeError::TooManyChildren { count, max } => {
+                write!(f, "node has {} children, at most {} are allowed", count, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NewNodeError {}
+
 impl ops::Deref for GreenNode {
     type Target = GreenNodeData;
 
@@ -204,43 +936,123 @@ impl ops::Deref for GreenNode {
 
 impl GreenNode {
     /// Creates new Node.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the text of the node is longer than `TextSize` allows, which
+    /// is 4 GiB unless rowan is built with `--cfg rowan_large_offsets`. Use
+    /// [`GreenNode::try_new`] for untrusted input.
     #[inline]
     pub fn new<I>(kind: SyntaxKind, children: I) -> GreenNode
     where
         I: IntoIterator<Item = GreenElement>,
         I::IntoIter: ExactSizeIterator,
     {
-        let mut text_len: TextSize = 0.into();
         let children = children.into_iter().map(|el| {
-            let rel_offset = text_len;
-            text_len += el.text_len();
+            let (rel_offset, prefix) = (0.into(), ChildPrefix::default());
             match el {
-                NodeOrToken::Node(node) => GreenChild::Node { rel_offset, node },
-                NodeOrToken::Token(token) => GreenChild::Token { rel_offset, token },
+                NodeOrToken::Node(node) => GreenChild::Node { rel_offset, prefix, node },
+                NodeOrToken::Token(token) => GreenChild::Token { rel_offset, prefix, token },
             }
         });
 
-        let data = ThinArc::from_header_and_iter(
-            GreenNodeHead { kind, text_len: 0.into(), _c: Count::new() },
+        let mut data = ThinArc::from_header_and_iter(
+            GreenNodeHead {
+                kind,
+                text_len: 0.into(),
+                hash: 0,
+                #[cfg(feature = "line-col")]
+                newlines: 0,
+                descendants: 0,
+                tokens: 0,
+                #[cfg(feature = "utf16")]
+                text_len_utf16: 0,
+                _c: Count::new(),
+            },
             children,
         );
 
-        // XXX: fixup `text_len` after construction, because we can't iterate
-        // `children` twice.
-        let data = {
-            let mut data = Arc::from_thin(data);
-            Arc::get_mut(&mut data).unwrap().header.text_len = text_len;
-            Arc::into_thin(data)
-        };
+        // XXX: fixup the offsets and `text_len` after construction, because we
+        // can't iterate `children` twice.
+        data.with_arc_mut(|data| fix_header(Arc::get_mut(data).unwrap()));
 
         GreenNode { ptr: data }
     }
 
+    /// Like [`GreenNode::new`], but returns an error instead of panicking if
+    /// the text is too long, and also fails if there are more than
+    /// `max_children` children, to limit the damage of untrusted input.
+    pub fn try_new<I>(
+        kind: SyntaxKind,
+        children: I,
+        max_children: usize,
+    ) -> Result<GreenNode, NewNodeError>
+    where
+        I: IntoIterator<Item = GreenElement>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let children = children.into_iter();
+        if children.len() > max_children {
+            return Err(NewNodeError::TooManyChildren { count: children.len(), max: max_children });
+        }
+        let children: Vec<GreenElement> = children.collect();
+        children
+            .iter()
+            .try_fold(TextSize::from(0), |acc, it| acc.checked_add(it.text_len()))
+            .ok_or(NewNodeError::TextTooLong)?;
+        Ok(GreenNode::new(kind, children))
+    }
+
+    /// Sets the kind of this node.
+    ///
+    /// Like `Arc::make_mut`, this mutates the node in place if it is not
+    /// shared, and replaces it with a modified copy otherwise.
+    pub fn set_kind(&mut self, kind: SyntaxKind) {
+        self.make_mut(|data| data.header.kind = kind)
+    }
+
+    /// Replaces the child at `index`, in place if the node is not shared, see
+    /// [`GreenNode::set_kind`]. Unlike [`GreenNodeData::replace_child`], this
+    /// doesn't allocate a new node for the uniquely owned nodes produced by
+    /// [`GreenNodeBuilder`](crate::GreenNodeBuilder) without a shared cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set_child(&mut self, index: usize, new_child: GreenElement) {
+        assert!(index < self.children().len(), "child index {} is out of bounds", index);
+        let (rel_offset, prefix) = (0.into(), ChildPrefix::default());
+        let new_child = match new_child {
+            NodeOrToken::Node(node) => GreenChild::Node { rel_offset, prefix, node },
+            NodeOrToken::Token(token) => GreenChild::Token { rel_offset, prefix, token },
+        };
+        self.make_mut(|data| data.slice_mut()[index] = new_child)
+    }
+
+    /// Copies the node if it is shared, and applies `f` to the now uniquely
+    /// owned data.
+    fn make_mut(&mut self, f: impl FnOnce(&mut Repr)) {
+        if !self.is_unique() {
+            *self = GreenNode::new(self.kind(), self.children().map(|it| it.to_owned()));
+        }
+        self.ptr.with_arc_mut(|data| {
+            let data = Arc::get_mut(data).unwrap();
+            f(data);
+            fix_header(data);
+        })
+    }
+
+    /// Whether this is the only reference to the node.
+    #[inline]
+    pub(crate) fn is_unique(&self) -> bool {
+        self.ptr.with_arc(|it| it.is_unique())
+    }
+
     #[inline]
     pub(crate) fn into_raw(this: GreenNode) -> ptr::NonNull<GreenNodeData> {
         let green = ManuallyDrop::new(this);
-        let green: &GreenNodeData = &*green;
-        ptr::NonNull::from(&*green)
+        let green: &GreenNodeData = &green;
+        ptr::NonNull::from(green)
     }
 
     #[inline]
@@ -249,11 +1061,53 @@ impl GreenNode {
         let arc = mem::transmute::<Arc<ReprThin>, ThinArc<GreenNodeHead, GreenChild>>(arc);
         GreenNode { ptr: arc }
     }
+
+    /// If this is the only reference to the node, moves its child nodes to
+    /// `acc`, replacing them with empty tokens.
+    fn take_child_nodes(&mut self, acc: &mut Vec<GreenNode>) {
+        thread_local! {
+            static PLACEHOLDER: GreenToken = GreenToken::new(SyntaxKind(0), "");
+        }
+        self.ptr.with_arc_mut(|arc| {
+            let data = match Arc::get_mut(arc) {
+                Some(it) => it,
+                None => return,
+            };
+            for child in data.slice_mut() {
+                if let GreenChild::Node { .. } = child {
+                    let token = match PLACEHOLDER.try_with(GreenToken::clone) {
+                        Ok(it) => it,
+                        Err(_) => return,
+                    };
+                    let placeholder = GreenChild::Token {
+                        rel_offset: 0.into(),
+                        prefix: ChildPrefix::default(),
+                        token,
+                    };
+                    if let GreenChild::Node { node, .. } = mem::replace(child, placeholder) {
+                        acc.push(node)
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl Drop for GreenNode {
+    fn drop(&mut self) {
+        // Dropping the children recursively overflows the stack for deeply
+        // nested trees, so drop the nodes no longer shared in a loop.
+        let mut stack = Vec::new();
+        self.take_child_nodes(&mut stack);
+        while let Some(mut node) = stack.pop() {
+            node.take_child_nodes(&mut stack);
+        }
+    }
 }
 
 impl GreenChild {
     #[inline]
-    pub(crate) fn as_ref(&self) -> GreenElementRef {
+    pub(crate) fn as_ref(&self) -> GreenElementRef<'_> {
         match self {
             GreenChild::Node { node, .. } => NodeOrToken::Node(node),
             GreenChild::Token { token, .. } => NodeOrToken::Token(token),
@@ -267,8 +1121,15 @@ impl GreenChild {
             }
         }
     }
+    #[cfg(any(feature = "line-col", feature = "utf16"))]
     #[inline]
-    fn rel_range(&self) -> TextRange {
+    pub(crate) fn prefix(&self) -> ChildPrefix {
+        match self {
+            GreenChild::Node { prefix, .. } | GreenChild::Token { prefix, .. } => *prefix,
+        }
+    }
+    #[inline]
+    pub(crate) fn rel_range(&self) -> TextRange {
         let len = self.as_ref().text_len();
         TextRange::at(self.rel_offset(), len)
     }
@@ -327,7 +1188,7 @@ impl<'a> Iterator for Children<'a> {
         Fold: FnMut(Acc, Self::Item) -> Acc,
     {
         let mut accum = init;
-        while let Some(x) = self.next() {
+        for x in self.by_ref() {
             accum = f(accum, x);
         }
         accum
@@ -359,3 +1220,390 @@ impl<'a> DoubleEndedIterator for Children<'a> {
 }
 
 impl FusedIterator for Children<'_> {}
+
+impl<'a> Children<'a> {
+    /// The remaining children of `kind`.
+    #[inline]
+    pub fn by_kind(self, kind: SyntaxKind) -> impl Iterator<Item = GreenElementRef<'a>> {
+        self.filter(move |it| it.kind() == kind)
+    }
+
+    /// The remaining children whose kind is in `kinds`.
+    #[inline]
+    pub fn by_kinds(self, kinds: KindSet) -> ByKinds<'a> {
+        ByKinds { raw: self.raw, kinds }
+    }
+}
+
+/// Children with a kind from a [`KindSet`], see [`Children::by_kinds`].
+#[derive(Debug, Clone)]
+pub struct ByKinds<'a> {
+    raw: slice::Iter<'a, GreenChild>,
+    kinds: KindSet,
+}
+
+impl<'a> Iterator for ByKinds<'a> {
+    type Item = GreenElementRef<'a>;
+
+    #[inline]
+    fn next(&mut self) -> Option<GreenElementRef<'a>> {
+        let kinds = &self.kinds;
+        self.raw.by_ref().map(GreenChild::as_ref).find(|it| kinds.contains(it.kind()))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.raw.size_hint().1)
+    }
+}
+
+impl FusedIterator for ByKinds<'_> {}
+
+/// Non-trivia children of a node, see [`GreenNodeData::slots`].
+#[derive(Debug, Clone)]
+pub struct Slots<'a, F> {
+    raw: iter::Enumerate<slice::Iter<'a, GreenChild>>,
+    is_trivia: F,
+}
+
+impl<'a, F: Fn(SyntaxKind) -> bool> Iterator for Slots<'a, F> {
+    type Item = (usize, GreenElementRef<'a>);
+
+    #[inline]
+    fn next(&mut self) -> Option<(usize, GreenElementRef<'a>)> {
+        let is_trivia = &self.is_trivia;
+        self.raw
+            .by_ref()
+            .map(|(index, child)| (index, child.as_ref()))
+            .find(|(_, child)| !is_trivia(child.kind()))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.raw.size_hint().1)
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool> FusedIterator for Slots<'_, F> {}
+
+/// Tokens in the subtree of a node, see [`GreenNodeData::descendant_tokens`].
+#[derive(Debug, Clone)]
+pub struct DescendantTokens<'a> {
+    stack: Vec<(slice::Iter<'a, GreenChild>, TextSize)>,
+}
+
+impl<'a> Iterator for DescendantTokens<'a> {
+    type Item = (&'a GreenTokenData, TextSize);
+
+    fn next(&mut self) -> Option<(&'a GreenTokenData, TextSize)> {
+        loop {
+            let (children, offset) = self.stack.last_mut()?;
+            let offset = *offset;
+            match children.next() {
+                Some(GreenChild::Node { rel_offset, node, .. }) => {
+                    self.stack.push((node.slice().iter(), offset + *rel_offset))
+                }
+                Some(GreenChild::Token { rel_offset, token, .. }) => {
+                    return Some((token, offset + *rel_offset))
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for DescendantTokens<'_> {}
+
+/// Texts of the tokens in the subtree of a node, see
+/// [`GreenNodeData::chunks`].
+#[derive(Debug, Clone)]
+pub struct Chunks<'a> {
+    tokens: DescendantTokens<'a>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a str> {
+        self.tokens.next().map(|(token, _)| token.text())
+    }
+}
+
+impl FusedIterator for Chunks<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_text_lens() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("ab"), token("c")]);
+        let root = GreenNode::new(SyntaxKind(3), vec![token("x"), inner.clone().into()]);
+        assert_eq!(root.validate_text_lens(), Ok(()));
+
+        let children = inner.slice().iter().cloned().map(|mut child| {
+            if let GreenChild::Token { rel_offset, .. } = &mut child {
+                if *rel_offset > 0.into() {
+                    *rel_offset += TextSize::from(1);
+                }
+            }
+            child
+        });
+        let corrupted =
+            GreenNode { ptr: ThinArc::from_header_and_iter(inner.header().clone(), children) };
+        let root = GreenNode::new(SyntaxKind(3), vec![token("x"), corrupted.into()]);
+        let err = root.validate_text_lens().unwrap_err();
+        assert_eq!(err.path, vec![1, 1]);
+        assert_eq!(err.kind, TextLenErrorKind::RelOffset);
+        assert_eq!((err.expected, err.actual), (2.into(), 3.into()));
+    }
+
+    #[test]
+    fn test_descendant_tokens() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("bc"), empty.into(), token("d")]);
+        let root = GreenNode::new(SyntaxKind(3), vec![token("a"), inner.into(), token("ef")]);
+        let tokens: Vec<_> = root
+            .descendant_tokens()
+            .map(|(token, offset)| (token.text(), usize::from(offset)))
+            .collect();
+        assert_eq!(tokens, vec![("a", 0), ("bc", 1), ("d", 3), ("ef", 4)]);
+        assert_eq!(root.to_string(), "abcdef");
+    }
+
+    #[test]
+    fn test_descendant_and_token_counts() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let counts = |node: &GreenNodeData| (node.descendant_count(), node.token_count());
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        assert_eq!(counts(&empty), (1, 0));
+
+        // Shared subtrees are counted at every occurrence, empty tokens are
+        // counted, and the last child may be a node.
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("a"), token(""), empty.into()]);
+        assert_eq!(counts(&inner), (2, 2));
+        let mut root = GreenNode::new(
+            SyntaxKind(3),
+            vec![token("b"), inner.clone().into(), inner.clone().into()],
+        );
+        assert_eq!(counts(&root), (5, 5));
+        let cursor = crate::cursor::SyntaxNode::new_root(root.clone());
+        assert_eq!(cursor.descendants().count(), 5);
+        assert_eq!(
+            cursor.descendants_with_tokens().filter(|it| it.as_token().is_some()).count(),
+            5
+        );
+
+        // Edits update the counts.
+        assert_eq!(counts(&root.remove_child(1)), (3, 3));
+        assert_eq!(counts(&root.replace_child(0, inner.clone().into())), (7, 6));
+        root.set_child(2, token("c"));
+        assert_eq!(counts(&root), (3, 4));
+    }
+
+    #[test]
+    fn test_to_tokens() {
+        let token = |kind, text| NodeOrToken::Token(GreenToken::new(SyntaxKind(kind), text));
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        assert_eq!(empty.to_tokens(), []);
+
+        // Empty tokens get empty ranges, and the ranges are relative to the
+        // node even if the last child is a nested node.
+        let inner = GreenNode::new(SyntaxKind(2), vec![token(4, "cd"), empty.clone().into()]);
+        let middle = GreenNode::new(SyntaxKind(2), vec![token(1, ""), inner.into()]);
+        let root = GreenNode::new(
+            SyntaxKind(3),
+            vec![empty.into(), token(1, "ab"), middle.clone().into(), token(5, "e")],
+        );
+        assert_eq!(
+            root.to_tokens(),
+            [
+                (SyntaxKind(1), range(0, 2), "ab"),
+                (SyntaxKind(1), range(2, 2), ""),
+                (SyntaxKind(4), range(2, 4), "cd"),
+                (SyntaxKind(5), range(4, 5), "e"),
+            ]
+        );
+        assert_eq!(
+            middle.to_tokens(),
+            [(SyntaxKind(1), range(0, 0), ""), (SyntaxKind(4), range(0, 2), "cd")]
+        );
+    }
+
+    #[test]
+    fn test_write_to() {
+        let token = |text: &[u8]| NodeOrToken::Token(GreenToken::new_bytes(SyntaxKind(1), text));
+        let write = |node: &GreenNode| {
+            let mut buf = Vec::new();
+            node.write_to(&mut buf).unwrap();
+            buf
+        };
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        assert_eq!(write(&empty), b"");
+
+        // Bytes which are not UTF-8 are written unchanged, and the last
+        // child may be a node.
+        let inner = GreenNode::new(SyntaxKind(2), vec![token(b"\xFFb"), empty.clone().into()]);
+        let root = GreenNode::new(
+            SyntaxKind(3),
+            vec![empty.into(), token(b"a"), token(b""), inner.into()],
+        );
+        assert_eq!(write(&root), b"a\xFFb");
+
+        // Errors of the writer are returned.
+        let mut short = [0u8; 2];
+        let err = root.write_to(&mut &mut short[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        assert_eq!(empty.chunks().next(), None);
+        assert_eq!(empty.to_string(), "");
+
+        // Empty tokens are chunks too, empty nodes are skipped, and the last
+        // child may be a node.
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("b"), empty.clone().into()]);
+        let root = GreenNode::new(
+            SyntaxKind(3),
+            vec![empty.into(), token("a"), token(""), inner.clone().into(), inner.into()],
+        );
+        let mut chunks = root.chunks();
+        assert_eq!(chunks.clone().collect::<Vec<_>>(), ["a", "", "b", "b"]);
+        assert_eq!(chunks.by_ref().count(), 4);
+        assert_eq!(chunks.next(), None);
+        assert_eq!(root.to_string(), "abb");
+    }
+
+    #[test]
+    #[should_panic(expected = "token text is not valid UTF-8")]
+    fn test_chunks_of_bytes() {
+        let token = GreenToken::new_bytes(SyntaxKind(1), b"\xFF");
+        let root = GreenNode::new(SyntaxKind(0), vec![token.into()]);
+        // Unlike `chunks`, `Display` is lossy.
+        assert_eq!(root.to_string(), "\u{FFFD}");
+        root.chunks().for_each(drop);
+    }
+
+    #[test]
+    fn test_covering_subtree() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("bc"), token("d"), token("e")]);
+        let root = GreenNode::new(SyntaxKind(3), vec![token("a"), inner.clone().into()]);
+        let covering = |start: u32, end: u32| {
+            let (node, offset) = root.covering_subtree(TextRange::new(start.into(), end.into()));
+            (node.kind().0, node.to_string(), usize::from(offset))
+        };
+        assert_eq!(covering(0, 2), (3, "abcde".to_string(), 0));
+        assert_eq!(covering(1, 5), (2, "bcde".to_string(), 1));
+        assert_eq!(covering(2, 4), (2, "bcd".to_string(), 1));
+        assert_eq!(covering(4, 5), (2, "bcde".to_string(), 1));
+        assert_eq!(covering(1, 2), (2, "bcde".to_string(), 1));
+        assert_eq!(covering(0, 1), (3, "abcde".to_string(), 0));
+        assert_eq!(covering(3, 3), (2, "bcde".to_string(), 1));
+    }
+
+    #[test]
+    fn test_text_cmp() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("bc"), token("")]);
+        let root = GreenNode::new(SyntaxKind(3), vec![token("a"), inner.into(), token("d")]);
+        assert!(root.text_eq("abcd"));
+        for text in ["", "a", "abc", "abcc", "abcd", "abcda", "abd", "b"].iter() {
+            assert_eq!(root.text_cmp(text), "abcd".cmp(text), "{:?}", text);
+            assert_eq!(root.text_eq(text), *text == "abcd");
+        }
+    }
+
+    #[test]
+    fn test_by_kinds() {
+        let token = |kind| NodeOrToken::Token(GreenToken::new(SyntaxKind(kind), "a"));
+        let node = GreenNode::new(SyntaxKind(0), vec![token(1), token(2), token(300), token(1)]);
+        let kinds = |it: &mut dyn Iterator<Item = GreenElementRef<'_>>| {
+            it.map(|it| it.kind().0).collect::<Vec<_>>()
+        };
+        assert_eq!(kinds(&mut node.children().by_kind(SyntaxKind(1))), [1, 1]);
+        let set = KindSet::new(&[SyntaxKind(2), SyntaxKind(300)]);
+        assert_eq!(kinds(&mut node.children().by_kinds(set)), [2, 300]);
+        let set = set.union(KindSet::EMPTY.insert(SyntaxKind(1)));
+        assert_eq!(kinds(&mut node.children().by_kinds(set)), [1, 2, 300, 1]);
+        assert!(!set.contains(SyntaxKind(u16::MAX)));
+    }
+
+    #[test]
+    fn test_structurally_eq_ignoring() {
+        let token = |kind, text| NodeOrToken::Token(GreenToken::new(SyntaxKind(kind), text));
+        let is_trivia = |kind| kind == SyntaxKind(0);
+        let node = |children| GreenNode::new(SyntaxKind(2), children);
+
+        let lhs = node(vec![token(1, "a"), node(vec![token(0, " "), token(1, "b")]).into()]);
+        let rhs = node(vec![token(0, " "), token(1, "a"), node(vec![token(1, "b")]).into()]);
+        assert!(lhs.structurally_eq_ignoring(&rhs, is_trivia));
+        assert_ne!(lhs, rhs);
+
+        let rhs = node(vec![token(1, "a"), node(vec![token(1, "c")]).into()]);
+        assert!(!lhs.structurally_eq_ignoring(&rhs, is_trivia));
+        let rhs = node(vec![token(1, "a"), token(1, "b")]);
+        assert!(!lhs.structurally_eq_ignoring(&rhs, is_trivia));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let node = |children| GreenNode::new(SyntaxKind(2), children);
+        let tree = node(vec![token("a"), node(vec![token("b")]).into()]);
+        // Must never change, the hashes may be stored on disk.
+        assert_eq!(tree.content_hash(), 0xd8ee_66c8_a7d6_251b);
+
+        let other = node(vec![node(vec![token("a")]).into(), token("b")]);
+        assert_ne!(tree.content_hash(), other.content_hash());
+    }
+
+    #[test]
+    #[cfg(feature = "line-col")]
+    fn test_line_col() {
+        let token = |text| NodeOrToken::Token(GreenToken::new(SyntaxKind(1), text));
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("a\nb"), token("\n\n")]);
+        let root = GreenNode::new(
+            SyntaxKind(3),
+            vec![inner.clone().into(), token("cd\ne"), token(""), inner.into()],
+        );
+        check_line_col(&root);
+
+        // The counts of the preceding children are updated on edits.
+        let mut edited = root.clone();
+        edited.set_child(1, token("\n"));
+        check_line_col(&edited);
+
+        let mut children = vec![token("")];
+        for i in 0..100 {
+            children.push(token(if i % 3 == 0 { "x\ny" } else { "z" }));
+            children.push(token(""));
+        }
+        check_line_col(&GreenNode::new(SyntaxKind(3), children));
+    }
+
+    #[cfg(feature = "line-col")]
+    fn check_line_col(root: &GreenNode) {
+        let text = root.to_string();
+        let index = crate::LineIndex::new(&text);
+        assert_eq!(root.newline_count(), index.line_count() - 1);
+        for offset in 0..=text.len() as u32 {
+            let offset = TextSize::from(offset);
+            assert_eq!(root.line_col(offset), index.line_col(offset), "{:?}", offset);
+        }
+        for line in 0..=index.line_count() {
+            let expected = (line < index.line_count())
+                .then(|| index.offset(LineCol { line, col: 0 }).unwrap());
+            assert_eq!(root.line_start(line), expected, "{}", line);
+        }
+    }
+}
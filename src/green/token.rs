@@ -1,24 +1,81 @@
 use std::{
-    borrow::Borrow,
+    borrow::{Borrow, Cow},
+    convert::TryFrom,
     fmt,
+    hash::{Hash, Hasher},
+    iter,
     mem::{self, ManuallyDrop},
     ops, ptr,
+    sync::{
+        self,
+        atomic::{AtomicPtr, Ordering},
+        Mutex,
+    },
 };
 
 use countme::Count;
+use rustc_hash::FxHasher;
 
+#[cfg(any(feature = "line-col", feature = "utf16"))]
+use crate::green::Counter;
 use crate::{
     arc::{Arc, HeaderSlice, ThinArc},
     green::SyntaxKind,
-    TextSize,
+    TextRange, TextSize,
 };
 
 #[derive(PartialEq, Eq, Hash)]
 struct GreenTokenHead {
     kind: SyntaxKind,
+    /// Whether the token's bytes are valid UTF-8.
+    utf8: bool,
+    /// Whether the slice holds a [`SourceRange`] rather than the bytes.
+    borrowed: bool,
+    #[cfg(feature = "line-col")]
+    newlines: Counter,
+    #[cfg(feature = "utf16")]
+    text_len_utf16: Counter,
     _c: Count<GreenToken>,
 }
 
+impl GreenTokenHead {
+    fn new(kind: SyntaxKind, bytes: &[u8], utf8: bool, borrowed: bool) -> GreenTokenHead {
+        debug_assert_eq!(utf8, std::str::from_utf8(bytes).is_ok());
+        assert!(
+            TextSize::try_from(bytes.len()).is_ok(),
+            "token text is longer than `TextSize` allows, see `--cfg rowan_large_offsets`"
+        );
+        GreenTokenHead {
+            kind,
+            utf8,
+            borrowed,
+            #[cfg(feature = "line-col")]
+            // Can't overflow, as it is at most the length of the text.
+            newlines: bytes.iter().filter(|&&it| it == b'\n').count() as Counter,
+            #[cfg(feature = "utf16")]
+            text_len_utf16: Counter::try_from(if utf8 {
+                let text = unsafe { std::str::from_utf8_unchecked(bytes) };
+                text.encode_utf16().count()
+            } else {
+                bytes.len()
+            })
+            // At most the length in bytes, which is checked above.
+            .unwrap(),
+            _c: Count::new(),
+        }
+    }
+}
+
+/// The text of a token created by [`GreenToken::new_in`], stored unaligned
+/// in place of the bytes.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SourceRange {
+    /// From [`sync::Arc::into_raw`], released when the token is freed.
+    source: *const str,
+    range: TextRange,
+}
+
 type Repr = HeaderSlice<GreenTokenHead, [u8]>;
 type ReprThin = HeaderSlice<GreenTokenHead, [u8; 0]>;
 #[repr(transparent)]
@@ -28,15 +85,64 @@ pub struct GreenTokenData {
 
 impl PartialEq for GreenTokenData {
     fn eq(&self, other: &Self) -> bool {
-        self.kind() == other.kind() && self.text() == other.text()
+        self.kind() == other.kind() && self.bytes() == other.bytes()
+    }
+}
+
+impl Eq for GreenTokenData {}
+
+impl Hash for GreenTokenData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind().hash(state);
+        self.bytes().hash(state);
     }
 }
 
 /// Leaf node in the immutable tree.
-#[derive(PartialEq, Eq, Hash, Clone)]
+#[derive(Clone)]
 #[repr(transparent)]
 pub struct GreenToken {
-    ptr: ThinArc<GreenTokenHead, u8>,
+    /// Dropped by hand, to release the source of a borrowed token.
+    ptr: ManuallyDrop<ThinArc<GreenTokenHead, u8>>,
+}
+
+impl PartialEq for GreenToken {
+    fn eq(&self, other: &GreenToken) -> bool {
+        let lhs: &GreenTokenData = self;
+        let rhs: &GreenTokenData = other;
+        ptr::eq(lhs, rhs) || lhs == rhs
+    }
+}
+
+impl Eq for GreenToken {}
+
+impl Hash for GreenToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let data: &GreenTokenData = self;
+        data.hash(state)
+    }
+}
+
+impl Drop for GreenToken {
+    #[inline]
+    fn drop(&mut self) {
+        let ptr = unsafe { ManuallyDrop::take(&mut self.ptr) };
+        ThinArc::drop_with(ptr, |repr| {
+            if repr.header.borrowed {
+                let source = unsafe { source_range(repr.slice()) }.source;
+                drop(unsafe { sync::Arc::from_raw(source) });
+            }
+        })
+    }
+}
+
+/// # Safety
+///
+/// `slice` must be the slice of a borrowed token.
+#[inline]
+unsafe fn source_range(slice: &[u8]) -> SourceRange {
+    debug_assert_eq!(slice.len(), mem::size_of::<SourceRange>());
+    ptr::read_unaligned(slice.as_ptr() as *const SourceRange)
 }
 
 impl ToOwned for GreenTokenData {
@@ -55,7 +161,7 @@ impl ToOwned for GreenTokenData {
 impl Borrow<GreenTokenData> for GreenToken {
     #[inline]
     fn borrow(&self) -> &GreenTokenData {
-        &*self
+        self
     }
 }
 
@@ -63,32 +169,46 @@ impl fmt::Debug for GreenTokenData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("GreenToken")
             .field("kind", &self.kind())
-            .field("text", &self.text())
+            .field("text", &self.text_lossy())
             .finish()
     }
 }
 
 impl fmt::Debug for GreenToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let data: &GreenTokenData = &*self;
+        let data: &GreenTokenData = self;
         fmt::Debug::fmt(data, f)
     }
 }
 
 impl fmt::Display for GreenToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let data: &GreenTokenData = &*self;
+        let data: &GreenTokenData = self;
         fmt::Display::fmt(data, f)
     }
 }
 
 impl fmt::Display for GreenTokenData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.text())
+        write!(f, "{}", self.text_lossy())
     }
 }
 
 impl GreenTokenData {
+    /// A hash of the kind and the text, consistent with
+    /// [`GreenNodeData::subtree_hash`](crate::GreenNodeData::subtree_hash).
+    pub fn subtree_hash(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.kind().hash(&mut hasher);
+        self.bytes().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[inline]
+    pub(crate) fn allocation_size(&self) -> usize {
+        self.data.allocation_size()
+    }
+
     /// Kind of this Token.
     #[inline]
     pub fn kind(&self) -> SyntaxKind {
@@ -96,38 +216,214 @@ impl GreenTokenData {
     }
 
     /// Text of this Token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token was created from bytes which are not valid UTF-8,
+    /// use [`GreenTokenData::bytes`] or [`GreenTokenData::text_lossy`] for
+    /// such tokens.
     #[inline]
     pub fn text(&self) -> &str {
-        unsafe { std::str::from_utf8_unchecked(self.data.slice()) }
+        match self.as_str() {
+            Some(it) => it,
+            None => panic!("token text is not valid UTF-8: {:?}", self.text_lossy()),
+        }
+    }
+
+    /// Text of this Token, or `None` if it is not valid UTF-8.
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        if self.is_utf8() {
+            Some(unsafe { std::str::from_utf8_unchecked(self.bytes()) })
+        } else {
+            None
+        }
+    }
+
+    /// Raw bytes of this Token.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        if self.data.header.borrowed {
+            let SourceRange { source, range } = unsafe { source_range(self.data.slice()) };
+            // The token holds a reference to the source.
+            let source = unsafe { &*source };
+            source[range].as_bytes()
+        } else {
+            self.data.slice()
+        }
     }
 
-    /// Returns the length of the text covered by this token.
+    /// Text of this Token, with invalid UTF-8 sequences replaced by
+    /// `U+FFFD REPLACEMENT CHARACTER`.
+    #[inline]
+    pub fn text_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.bytes())
+    }
+
+    /// Whether the bytes of this Token are valid UTF-8.
+    #[inline]
+    pub fn is_utf8(&self) -> bool {
+        self.data.header.utf8
+    }
+
+    /// Whether the text of this Token is a range of a shared source rather
+    /// than a part of its allocation, see [`GreenToken::new_in`].
+    #[inline]
+    pub fn is_borrowed(&self) -> bool {
+        self.data.header.borrowed
+    }
+
+    /// Returns the length of the text covered by this token, in bytes.
     #[inline]
     pub fn text_len(&self) -> TextSize {
-        TextSize::of(self.text())
+        // Checked on construction.
+        TextSize::try_from(self.bytes().len()).unwrap()
+    }
+
+    /// The number of `\n` bytes in the text of this token.
+    #[cfg(feature = "line-col")]
+    #[inline]
+    pub fn newline_count(&self) -> Counter {
+        self.data.header.newlines
+    }
+
+    /// Returns the length of the text of this token in UTF-16 code units.
+    ///
+    /// Tokens which are not valid UTF-8 count one code unit per byte.
+    #[cfg(feature = "utf16")]
+    #[inline]
+    pub fn text_len_utf16(&self) -> Counter {
+        self.data.header.text_len_utf16
     }
 }
 
+/// The tokens registered with [`GreenToken::register_static`], indexed by
+/// kind, or null if there are none.
+///
+/// Readers only load the pointer. Registering replaces the whole table and
+/// leaks the previous one, as readers may still look at it.
+static STATIC_TOKENS: AtomicPtr<Vec<Option<GreenToken>>> = AtomicPtr::new(ptr::null_mut());
+/// Serializes the updates of `STATIC_TOKENS`.
+static STATIC_TOKENS_LOCK: Mutex<()> = Mutex::new(());
+
 impl GreenToken {
     /// Creates new Token.
+    ///
+    /// If the token is one of the [registered static
+    /// tokens](GreenToken::register_static), returns a clone of it instead.
     #[inline]
     pub fn new(kind: SyntaxKind, text: &str) -> GreenToken {
-        let head = GreenTokenHead { kind, _c: Count::new() };
-        let ptr = ThinArc::from_header_and_iter(head, text.bytes());
-        GreenToken { ptr }
+        if let Some(token) = GreenToken::find_static(kind, text.as_bytes()) {
+            return token;
+        }
+        GreenToken::new_raw(kind, text.as_bytes(), true)
+    }
+
+    /// Registers the tokens with a fixed text, like punctuation and keywords,
+    /// for the whole process.
+    ///
+    /// Afterwards, [`GreenToken::new`], [`GreenToken::new_bytes`] and the
+    /// [`NodeCache`](crate::NodeCache)s return a clone of the registered token
+    /// for one of the `kind`s with its `text`, without an allocation or a hash
+    /// table lookup. Tokens of these kinds with other texts are created as
+    /// usual.
+    ///
+    /// Registering a kind again replaces its token, so languages which reuse
+    /// the kinds of each other keep only the last token of each kind static.
+    /// This is meant to be called once per language, at startup: the
+    /// registered tokens are never freed.
+    pub fn register_static(tokens: &[(SyntaxKind, &str)]) {
+        let _guard = STATIC_TOKENS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+        let old = STATIC_TOKENS.load(Ordering::Acquire);
+        let mut table = if old.is_null() { Vec::new() } else { unsafe { (*old).clone() } };
+        for &(kind, text) in tokens {
+            let idx = usize::from(kind.0);
+            if table.len() <= idx {
+                table.resize(idx + 1, None);
+            }
+            table[idx] = Some(GreenToken::new_raw(kind, text.as_bytes(), true));
+        }
+        STATIC_TOKENS.store(Box::into_raw(Box::new(table)), Ordering::Release);
+    }
+
+    /// Returns the registered static token with `kind` and `bytes`, if any.
+    #[inline]
+    pub(crate) fn find_static(kind: SyntaxKind, bytes: &[u8]) -> Option<GreenToken> {
+        let table = STATIC_TOKENS.load(Ordering::Acquire);
+        if table.is_null() {
+            return None;
+        }
+        // The tables are never freed.
+        let table: &'static Vec<Option<GreenToken>> = unsafe { &*table };
+        match table.get(usize::from(kind.0)) {
+            Some(Some(token)) if token.bytes() == bytes => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    /// Creates new Token from raw bytes, which need not be valid UTF-8.
+    ///
+    /// If the bytes happen to be valid UTF-8, the token is the same as the one
+    /// created by [`GreenToken::new`].
+    #[inline]
+    pub fn new_bytes(kind: SyntaxKind, bytes: &[u8]) -> GreenToken {
+        if let Some(token) = GreenToken::find_static(kind, bytes) {
+            return token;
+        }
+        GreenToken::new_raw(kind, bytes, std::str::from_utf8(bytes).is_ok())
+    }
+
+    /// Creates new Token with the text `&source[range]`.
+    ///
+    /// Unless the text is as short as a couple of words, the token refers to
+    /// `source` rather than copying the text, so that the long tokens of a
+    /// file, like comments and string literals, are not stored twice. The
+    /// token then keeps the whole `source` alive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds of `source` or does not lie on
+    /// `char` boundaries.
+    pub fn new_in(kind: SyntaxKind, source: &sync::Arc<str>, range: TextRange) -> GreenToken {
+        let text = &source[range];
+        if text.len() <= mem::size_of::<SourceRange>() {
+            return GreenToken::new(kind, text);
+        }
+        let head = GreenTokenHead::new(kind, text.as_bytes(), true, true);
+        let payload = SourceRange { source: sync::Arc::into_raw(source.clone()), range };
+        let mut ptr = ThinArc::from_header_and_iter(
+            head,
+            iter::repeat(0).take(mem::size_of::<SourceRange>()),
+        );
+        ptr.with_arc_mut(|it| {
+            let slice = Arc::get_mut(it).unwrap().slice_mut();
+            unsafe { ptr::write_unaligned(slice.as_mut_ptr() as *mut SourceRange, payload) }
+        });
+        GreenToken { ptr: ManuallyDrop::new(ptr) }
+    }
+
+    fn new_raw(kind: SyntaxKind, bytes: &[u8], utf8: bool) -> GreenToken {
+        let head = GreenTokenHead::new(kind, bytes, utf8, false);
+        let ptr = ThinArc::from_header_and_iter(head, bytes.iter().copied());
+        GreenToken { ptr: ManuallyDrop::new(ptr) }
+    }
+    /// Whether this is the only reference to the token.
+    #[inline]
+    pub(crate) fn is_unique(&self) -> bool {
+        self.ptr.with_arc(|it| it.is_unique())
     }
     #[inline]
     pub(crate) fn into_raw(this: GreenToken) -> ptr::NonNull<GreenTokenData> {
         let green = ManuallyDrop::new(this);
-        let green: &GreenTokenData = &*green;
-        ptr::NonNull::from(&*green)
+        let green: &GreenTokenData = &green;
+        ptr::NonNull::from(green)
     }
 
     #[inline]
     pub(crate) unsafe fn from_raw(ptr: ptr::NonNull<GreenTokenData>) -> GreenToken {
         let arc = Arc::from_raw(&ptr.as_ref().data as *const ReprThin);
         let arc = mem::transmute::<Arc<ReprThin>, ThinArc<GreenTokenHead, u8>>(arc);
-        GreenToken { ptr: arc }
+        GreenToken { ptr: ManuallyDrop::new(arc) }
     }
 }
 
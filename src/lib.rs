@@ -8,14 +8,39 @@
 )]
 #![deny(unsafe_code)]
 
+/// Enters a span of the `tracing` feature until the end of the scope.
+macro_rules! trace_span {
+    ($name:expr) => {
+        #[cfg(feature = "tracing")]
+        let _span = crate::trace::Span::enter($name);
+    };
+}
+
+/// Reports an event of the `tracing` feature.
+macro_rules! trace_event {
+    ($name:expr, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        crate::trace::event($name, $value as u64);
+    };
+}
+
 #[allow(unsafe_code)]
 mod green;
 #[allow(unsafe_code)]
 pub mod cursor;
 
 pub mod api;
+pub mod ast;
+pub mod validation;
+pub mod reparse;
 mod syntax_text;
 mod utility_types;
+mod text_edit;
+mod dump;
+mod report;
+mod summary;
+mod line_index;
+mod token_text;
 
 mod cow_mut;
 #[allow(unsafe_code)]
@@ -24,17 +49,43 @@ mod sll;
 mod arc;
 #[cfg(feature = "serde1")]
 mod serde_impls;
+#[cfg(rowan_large_offsets)]
+mod text_size64;
+#[cfg(feature = "unicode")]
+pub mod unicode;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+#[cfg(feature = "arbitrary")]
+pub mod arbitrary;
+#[cfg(feature = "tracing")]
+pub mod trace;
+#[cfg(feature = "stats")]
+pub mod stats;
 
+#[cfg(not(rowan_large_offsets))]
 pub use text_size::{TextLen, TextRange, TextSize};
+#[cfg(rowan_large_offsets)]
+pub use text_size64::{TextLen, TextRange, TextSize};
 
 pub use crate::{
     api::{
         Language, SyntaxElement, SyntaxElementChildren, SyntaxNode, SyntaxNodeChildren, SyntaxToken,
     },
+    dump::{parse_dump, DumpError},
     green::{
-        Checkpoint, Children, GreenNode, GreenNodeBuilder, GreenNodeData, GreenToken,
-        GreenTokenData, SyntaxKind,
+        BuilderError, ByKinds, CacheStats, Checkpoint, Children, Chunks, CompletedMarker,
+        DescendantTokens, Event, GreenNode, GreenNodeBuilder, GreenNodeData, GreenToken,
+        GreenTokenData, IncompleteTree, KindSet, Marker, NewNodeError, NodeCache, SharedTokens,
+        Slots, SyntaxKind, TextLenError, TextLenErrorKind,
     },
-    syntax_text::SyntaxText,
-    utility_types::{Direction, NodeOrToken, TokenAtOffset, WalkEvent},
+    line_index::{LineCol, LineIndex},
+    report::{MemoryUsage, TreeReport},
+    summary::{Summaries, Summary},
+    syntax_text::{CaseFolding, FilteredText, SyntaxText},
+    text_edit::{Bias, TextEdit},
+    token_text::TokenText,
+    utility_types::{Direction, NodeOrToken, RangeBase, TokenAtOffset, WalkEvent},
 };
+
+#[cfg(feature = "macros")]
+pub use rowan_macros::Language;
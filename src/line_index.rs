@@ -0,0 +1,182 @@
+//! Conversion between byte offsets and line/column positions.
+use std::convert::TryFrom;
+
+use crate::{green::Counter, SyntaxText, TextEdit, TextRange, TextSize};
+
+/// Zero-based line and column. The column is measured in bytes (UTF-8 code
+/// units) from the start of the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    pub line: Counter,
+    pub col: Counter,
+}
+
+/// Maps offsets to `LineCol`s and back.
+///
+/// A `LineIndex` can be kept in sync with a changing tree by feeding it the
+/// same `TextEdit`s which were applied to the text, which is much cheaper
+/// than rebuilding the index from scratch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    /// Offset of the start of each line. The first element is always zero.
+    line_starts: Vec<TextSize>,
+    len: TextSize,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> LineIndex {
+        let mut res = LineIndex { line_starts: vec![0.into()], len: 0.into() };
+        res.push_chunk(text);
+        res
+    }
+
+    /// Builds an index of the text of a tree, without materializing it.
+    pub fn of_text(text: &SyntaxText) -> LineIndex {
+        let mut res = LineIndex { line_starts: vec![0.into()], len: 0.into() };
+        text.for_each_chunk(|chunk| res.push_chunk(chunk));
+        res
+    }
+
+    fn push_chunk(&mut self, chunk: &str) {
+        let start = self.len;
+        self.line_starts.extend(newlines(chunk).map(|it| start + it + TextSize::from(1)));
+        self.len += TextSize::of(chunk);
+    }
+
+    /// Length of the indexed text.
+    pub fn len(&self) -> TextSize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0.into()
+    }
+
+    /// Number of lines; the empty text consists of one empty line.
+    pub fn line_count(&self) -> Counter {
+        self.line_starts.len() as Counter
+    }
+
+    pub fn line_col(&self, offset: TextSize) -> LineCol {
+        assert!(offset <= self.len, "offset {:?} is out of bounds 0..{:?}", offset, self.len);
+        let line = self.line_starts.partition_point(|&it| it <= offset) - 1;
+        let col = offset - self.line_starts[line];
+        LineCol {
+            // Neither can overflow, as both are at most `len`.
+            line: Counter::try_from(line).unwrap(),
+            col: Counter::try_from(usize::from(col)).unwrap(),
+        }
+    }
+
+    /// Returns `None` if the position lies outside of the text, or if the
+    /// column lies past the end of the line.
+    pub fn offset(&self, line_col: LineCol) -> Option<TextSize> {
+        let range = self.line_range(line_col.line)?;
+        // The position just past the end of the line is still valid, the
+        // positions past its newline are not.
+        let end = if (line_col.line as usize) + 1 < self.line_starts.len() {
+            range.end() - TextSize::from(1)
+        } else {
+            range.end()
+        };
+        let col = usize::try_from(line_col.col).ok().and_then(|it| TextSize::try_from(it).ok())?;
+        let offset = range.start().checked_add(col)?;
+        if offset > end {
+            return None;
+        }
+        Some(offset)
+    }
+
+    /// Range of the line, including the trailing newline, if any.
+    pub fn line_range(&self, line: Counter) -> Option<TextRange> {
+        let line = line as usize;
+        let start = *self.line_starts.get(line)?;
+        let end = self.line_starts.get(line + 1).copied().unwrap_or(self.len);
+        Some(TextRange::new(start, end))
+    }
+
+    /// Updates the index to account for `edit`, which is applied to the text
+    /// the index currently describes.
+    pub fn apply_edit(&mut self, edit: &TextEdit) {
+        let TextEdit { delete, insert } = edit;
+        assert!(delete.end() <= self.len, "edit {:?} is out of bounds 0..{:?}", delete, self.len);
+
+        // A newline at `pos` starts a line at `pos + 1`, so the lines
+        // starting in `start + 1..=end` are removed by the edit.
+        let first_removed = self.line_starts.partition_point(|&it| it <= delete.start());
+        let first_kept = self.line_starts.partition_point(|&it| it <= delete.end());
+
+        let insert_len = TextSize::of(insert.as_str());
+        let inserted =
+            newlines(insert).map(|it| delete.start() + it + TextSize::from(1)).collect::<Vec<_>>();
+        let shifted = self.line_starts[first_kept..]
+            .iter()
+            .map(|&it| it - delete.end() + delete.start() + insert_len)
+            .collect::<Vec<_>>();
+        self.line_starts.truncate(first_removed);
+        self.line_starts.extend(inserted);
+        self.line_starts.extend(shifted);
+        self.len = self.len - delete.len() + insert_len;
+    }
+
+    /// Applies `edits` in order, see [`TextEdit::apply_all`].
+    pub fn apply_edits<'a>(&mut self, edits: impl IntoIterator<Item = &'a TextEdit>) {
+        edits.into_iter().for_each(|edit| self.apply_edit(edit))
+    }
+}
+
+fn newlines(text: &str) -> impl Iterator<Item = TextSize> + '_ {
+    text.bytes()
+        .enumerate()
+        .filter(|&(_, b)| b == b'\n')
+        .map(|(i, _)| TextSize::try_from(i).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let index = LineIndex::new("hello\nworld\n\n!");
+        assert_eq!(index.line_count(), 4);
+        assert_eq!(index.line_col(0.into()), LineCol { line: 0, col: 0 });
+        assert_eq!(index.line_col(5.into()), LineCol { line: 0, col: 5 });
+        assert_eq!(index.line_col(6.into()), LineCol { line: 1, col: 0 });
+        assert_eq!(index.line_col(12.into()), LineCol { line: 2, col: 0 });
+        assert_eq!(index.line_col(14.into()), LineCol { line: 3, col: 1 });
+        assert_eq!(index.offset(LineCol { line: 1, col: 3 }), Some(9.into()));
+        assert_eq!(index.offset(LineCol { line: 2, col: 2 }), None);
+        assert_eq!(index.offset(LineCol { line: 4, col: 0 }), None);
+    }
+
+    #[test]
+    fn test_offset_past_line_end() {
+        let index = LineIndex::new("hello\nworld\n\n!");
+        assert_eq!(index.offset(LineCol { line: 0, col: 5 }), Some(5.into()));
+        assert_eq!(index.offset(LineCol { line: 0, col: 6 }), None);
+        assert_eq!(index.offset(LineCol { line: 0, col: 9 }), None);
+        assert_eq!(index.offset(LineCol { line: 2, col: 0 }), Some(12.into()));
+        assert_eq!(index.offset(LineCol { line: 2, col: 1 }), None);
+        assert_eq!(index.offset(LineCol { line: 3, col: 1 }), Some(14.into()));
+        assert_eq!(index.offset(LineCol { line: 3, col: 2 }), None);
+        assert_eq!(index.offset(LineCol { line: 3, col: Counter::MAX }), None);
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut text = String::from("fn main() {\n    92\n}\n");
+        let mut index = LineIndex::new(&text);
+        let edits = [
+            TextEdit::insert(12.into(), "    let x = 1;\n"),
+            TextEdit::delete(TextRange::new(0.into(), 12.into())),
+            TextEdit::replace(TextRange::new(4.into(), 20.into()), "a\nb\nc"),
+            TextEdit::insert(0.into(), "\n"),
+        ];
+        for edit in edits.iter() {
+            edit.apply(&mut text);
+            index.apply_edit(edit);
+            assert_eq!(index, LineIndex::new(&text), "{}", edit);
+        }
+    }
+}
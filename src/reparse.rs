@@ -0,0 +1,336 @@
+//! Incremental reparsing, which rebuilds only the part of a tree affected by
+//! an edit.
+//!
+//! After an edit, [`reparse`] looks for the smallest element containing the
+//! edited range which the language can lex or parse in isolation, like a
+//! single identifier token or a block with balanced braces, and splices the
+//! new element into the old tree. All the subtrees outside of it are reused.
+use std::ops::Range;
+
+use crate::{
+    GreenNode, GreenNodeData, GreenToken, GreenTokenData, NodeOrToken, SyntaxKind, TextEdit,
+    TextSize,
+};
+
+/// Lexes and parses fragments of text for [`reparse`].
+pub trait Reparser {
+    /// Returns the kind of the single token which `text` lexes to, where
+    /// `text` is the edited text of a token of `kind`, or `None` if it isn't
+    /// a single token.
+    ///
+    /// `prev` and `next` are the non-empty tokens around it, which are kept
+    /// as they are. If the text lexes differently next to them, this must
+    /// return `None`, for example if it would fuse with `next` into a single
+    /// token, like `+` with `=`, or if an inserted quote swallows them.
+    fn relex(
+        &mut self,
+        kind: SyntaxKind,
+        text: &str,
+        prev: Option<&GreenTokenData>,
+        next: Option<&GreenTokenData>,
+    ) -> Option<SyntaxKind> {
+        let _ = (kind, text, prev, next);
+        None
+    }
+
+    /// Parses `text`, the edited text of a node of `kind`, returning `None`
+    /// if it can't be parsed in isolation, in which case the enclosing node is
+    /// tried instead.
+    fn reparse(&mut self, kind: SyntaxKind, text: &str) -> Option<GreenNode>;
+}
+
+/// Returns the tree after applying `edit` to the text of `root`, or `None`
+/// if even `root` could not be reparsed.
+pub fn reparse(
+    root: &GreenNodeData,
+    edit: &TextEdit,
+    reparser: &mut dyn Reparser,
+) -> Option<GreenNode> {
+    trace_span!("reparse");
+    assert!(edit.delete.end() <= root.text_len(), "edit {} is out of bounds", edit);
+    // The nodes containing the edit, with their offsets and indices in the
+    // parents, starting from the root.
+    let mut path = vec![(root, TextSize::from(0), 0)];
+    let mut token = None;
+    loop {
+        let &(node, offset, _) = path.last().unwrap();
+        match node.child_containing_range(edit.delete - offset) {
+            Some((idx, rel_offset, NodeOrToken::Node(it))) => {
+                path.push((it, offset + rel_offset, idx))
+            }
+            Some((idx, rel_offset, NodeOrToken::Token(it))) => {
+                token = Some((it, offset + rel_offset, idx));
+                break;
+            }
+            None => break,
+        }
+    }
+
+    let mut reparsed = None;
+    if let Some((token, offset, idx)) = token {
+        if let Some(text) = edited(token.bytes().to_vec(), offset, edit) {
+            let prev = offset.checked_sub(1.into()).and_then(|it| token_at(root, it));
+            let next = token_at(root, offset + token.text_len());
+            if let Some(kind) = reparser.relex(token.kind(), &text, prev, next) {
+                let &(parent, _, _) = path.last().unwrap();
+                reparsed = Some((
+                    path.len() - 1,
+                    parent.replace_child(idx, GreenToken::new(kind, &text).into()),
+                ));
+            }
+        }
+    }
+    if reparsed.is_none() {
+        reparsed = path.iter().enumerate().rev().find_map(|(depth, &(node, offset, _))| {
+            let mut bytes = Vec::new();
+            node.write_to(&mut bytes).unwrap();
+            let text = edited(bytes, offset, edit)?;
+            Some((depth, reparser.reparse(node.kind(), &text)?))
+        });
+    }
+    let (depth, mut res) = reparsed?;
+    trace_event!("reparse depth", depth);
+    for depth in (0..depth).rev() {
+        let idx = path[depth + 1].2;
+        res = path[depth].0.replace_child(idx, res.into());
+    }
+    Some(res)
+}
+
+/// Returns the non-empty token of `node` which contains `offset`.
+fn token_at(mut node: &GreenNodeData, mut offset: TextSize) -> Option<&GreenTokenData> {
+    loop {
+        let (_, rel_offset, child) = node.child_at_offset(offset)?;
+        offset -= rel_offset;
+        match child {
+            NodeOrToken::Node(it) => node = it,
+            NodeOrToken::Token(it) => return Some(it),
+        }
+    }
+}
+
+/// Applies `edit` to `text`, which starts at `offset`.
+///
+/// The text is edited as bytes, so that tokens which are not valid UTF-8 are
+/// not replaced by their lossy text. Returns `None` if the edited text is
+/// still not valid UTF-8, as it can't be lexed or parsed.
+fn edited(mut text: Vec<u8>, offset: TextSize, edit: &TextEdit) -> Option<String> {
+    let delete: Range<usize> = (edit.delete - offset).into();
+    text.splice(delete, edit.insert.bytes());
+    String::from_utf8(text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GreenNodeBuilder, TextRange};
+
+    const ROOT: SyntaxKind = SyntaxKind(0);
+    const LIST: SyntaxKind = SyntaxKind(1);
+    const WORD: SyntaxKind = SyntaxKind(2);
+    const WS: SyntaxKind = SyntaxKind(3);
+    const PAREN: SyntaxKind = SyntaxKind(4);
+    const OP: SyntaxKind = SyntaxKind(5);
+
+    /// Parses s-expressions like `(a (b c))`, returning `None` on unbalanced
+    /// parentheses.
+    fn parse(kind: SyntaxKind, text: &str) -> Option<GreenNode> {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(kind);
+        let mut depth = 0;
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '(' | ')' => {
+                    if c == '(' {
+                        builder.start_node(LIST);
+                        depth += 1;
+                    }
+                    builder.token(PAREN, &rest[..1]);
+                    if c == ')' {
+                        if depth == 0 {
+                            return None;
+                        }
+                        builder.finish_node();
+                        depth -= 1;
+                    }
+                    1
+                }
+                _ => {
+                    let kind = if c == ' ' { WS } else { WORD };
+                    let len = rest
+                        .find(|it: char| if kind == WS { it != ' ' } else { "() ".contains(it) })
+                        .unwrap_or(rest.len());
+                    builder.token(kind, &rest[..len]);
+                    len
+                }
+            };
+            rest = &rest[len..];
+        }
+        if depth != 0 {
+            return None;
+        }
+        builder.finish_node();
+        Some(builder.finish())
+    }
+
+    struct SExprs {
+        reparsed: Vec<SyntaxKind>,
+    }
+
+    impl Reparser for SExprs {
+        fn relex(
+            &mut self,
+            kind: SyntaxKind,
+            text: &str,
+            _prev: Option<&GreenTokenData>,
+            _next: Option<&GreenTokenData>,
+        ) -> Option<SyntaxKind> {
+            // Words are always delimited by spaces or parentheses.
+            let ok = match kind {
+                WORD => !text.is_empty() && !text.contains(|it| "() ".contains(it)),
+                _ => false,
+            };
+            ok.then_some(kind)
+        }
+
+        fn reparse(&mut self, kind: SyntaxKind, text: &str) -> Option<GreenNode> {
+            self.reparsed.push(kind);
+            if kind == LIST {
+                // A list must stay a single list.
+                let root = parse(ROOT, text)?;
+                let child = match root.children().collect::<Vec<_>>().as_slice() {
+                    [NodeOrToken::Node(it)] => (*it).to_owned(),
+                    _ => return None,
+                };
+                return Some(child);
+            }
+            parse(kind, text)
+        }
+    }
+
+    #[test]
+    fn test_reparse() {
+        let text = "(a (b c) (d))";
+        let root = parse(ROOT, text).unwrap();
+        let check = |delete: (u32, u32), insert: &str, reparsed: Vec<SyntaxKind>| {
+            let edit = TextEdit::replace(TextRange::new(delete.0.into(), delete.1.into()), insert);
+            let mut reparser = SExprs { reparsed: Vec::new() };
+            let new = reparse(&root, &edit, &mut reparser).unwrap();
+            let mut text = text.to_string();
+            edit.apply(&mut text);
+            assert_eq!(new, parse(ROOT, &text).unwrap(), "{}", edit);
+            assert_eq!(reparser.reparsed, reparsed, "{}", edit);
+            new
+        };
+
+        // Relexed token, the other lists are reused.
+        let new = check((4, 5), "bx", vec![]);
+        let last_list = |root: &GreenNodeData| {
+            let list = root.children().next().unwrap().into_node().unwrap();
+            list.children().rev().find_map(|it| it.into_node()).unwrap() as *const GreenNodeData
+        };
+        assert_eq!(last_list(&new), last_list(&root));
+
+        check((6, 6), " (e)", vec![LIST]);
+        check((8, 9), "", vec![LIST]);
+        check((12, 13), ") (x)", vec![LIST, ROOT]);
+
+        let edit = TextEdit::delete(TextRange::new(7.into(), 8.into()));
+        let mut reparser = SExprs { reparsed: Vec::new() };
+        assert_eq!(reparse(&root, &edit, &mut reparser), None);
+        assert_eq!(reparser.reparsed, vec![LIST, LIST, ROOT]);
+    }
+
+    #[test]
+    fn test_reparse_bytes() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.token(WORD, "a");
+        builder.token(WS, " ");
+        builder.token_bytes(WORD, b"b\xFF");
+        builder.finish_node();
+        let root = builder.finish();
+        let mut reparser = SExprs { reparsed: Vec::new() };
+
+        // Replacing the invalid byte makes the token text again.
+        let edit = TextEdit::replace(TextRange::new(3.into(), 4.into()), "c");
+        let new = reparse(&root, &edit, &mut reparser).unwrap();
+        assert_eq!(new, parse(ROOT, "a bc").unwrap());
+
+        // Keeping it can't be reparsed, rather than reparsing the lossy text.
+        let edit = TextEdit::insert(2.into(), "c");
+        assert_eq!(reparse(&root, &edit, &mut reparser), None);
+        let edit = TextEdit::insert(0.into(), "c");
+        let new = reparse(&root, &edit, &mut reparser).unwrap();
+        assert_eq!(new.children().nth(2).unwrap().into_token().unwrap().bytes(), b"b\xFF");
+    }
+
+    /// Lexes runs of `+` and `=` and runs of other chars, which fuse when
+    /// they meet.
+    struct Ops {
+        reparsed: Vec<SyntaxKind>,
+    }
+
+    fn op_kind(c: char) -> SyntaxKind {
+        if "+=".contains(c) {
+            OP
+        } else {
+            WORD
+        }
+    }
+
+    impl Reparser for Ops {
+        fn relex(
+            &mut self,
+            _kind: SyntaxKind,
+            text: &str,
+            prev: Option<&GreenTokenData>,
+            next: Option<&GreenTokenData>,
+        ) -> Option<SyntaxKind> {
+            let kind = op_kind(text.chars().next()?);
+            if text.chars().any(|it| op_kind(it) != kind)
+                || prev.is_some_and(|it| it.kind() == kind)
+                || next.is_some_and(|it| it.kind() == kind)
+            {
+                return None;
+            }
+            Some(kind)
+        }
+
+        fn reparse(&mut self, kind: SyntaxKind, text: &str) -> Option<GreenNode> {
+            self.reparsed.push(kind);
+            Some(lex_ops(kind, text))
+        }
+    }
+
+    fn lex_ops(kind: SyntaxKind, text: &str) -> GreenNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(kind);
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let len = rest.find(|it| op_kind(it) != op_kind(c)).unwrap_or(rest.len());
+            builder.token(op_kind(c), &rest[..len]);
+            rest = &rest[len..];
+        }
+        builder.finish_node();
+        builder.finish()
+    }
+
+    #[test]
+    fn test_reparse_fused_tokens() {
+        let root = lex_ops(ROOT, "a+b");
+        let mut reparser = Ops { reparsed: Vec::new() };
+        let edit = TextEdit::replace(TextRange::new(2.into(), 3.into()), "c");
+        let new = reparse(&root, &edit, &mut reparser).unwrap();
+        assert_eq!(new, lex_ops(ROOT, "a+c"));
+        assert_eq!(reparser.reparsed, vec![]);
+
+        // `=` is a single token on its own, but fuses with the `+` before it.
+        let edit = TextEdit::replace(TextRange::new(2.into(), 3.into()), "=");
+        let new = reparse(&root, &edit, &mut reparser).unwrap();
+        assert_eq!(new, lex_ops(ROOT, "a+="));
+        assert_eq!(new.children().len(), 2);
+        assert_eq!(reparser.reparsed, vec![ROOT]);
+    }
+}
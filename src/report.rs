@@ -0,0 +1,219 @@
+//! Statistics about the shape of a syntax tree.
+use std::{collections::BTreeMap, fmt};
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    GreenNodeData, GreenTokenData, Language, NodeOrToken, SyntaxKind, SyntaxNode, TextSize,
+};
+
+/// A summary of a tree, to render when investigating the output of a parser
+/// or to assert on in tests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeReport {
+    /// The number of nodes of each kind.
+    pub nodes: BTreeMap<SyntaxKind, usize>,
+    /// The number of tokens of each kind.
+    pub tokens: BTreeMap<SyntaxKind, usize>,
+    /// The number of nodes at each depth, starting with the root at zero.
+    pub depths: Vec<usize>,
+    /// The number of distinct green nodes, which is less than the number of
+    /// nodes if some subtrees are shared.
+    pub unique_nodes: usize,
+    pub unique_tokens: usize,
+    pub text_len: TextSize,
+}
+
+impl TreeReport {
+    /// Collects the report for the subtree of `node`.
+    pub fn collect<L: Language>(node: &SyntaxNode<L>) -> TreeReport {
+        TreeReport::collect_green(&node.green())
+    }
+
+    pub fn collect_green(root: &GreenNodeData) -> TreeReport {
+        let mut res = TreeReport { text_len: root.text_len(), ..TreeReport::default() };
+        let mut seen_nodes = FxHashSet::default();
+        let mut seen_tokens = FxHashSet::default();
+        let mut stack = vec![(root, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            *res.nodes.entry(node.kind()).or_default() += 1;
+            if res.depths.len() <= depth {
+                res.depths.resize(depth + 1, 0);
+            }
+            res.depths[depth] += 1;
+            seen_nodes.insert(node as *const GreenNodeData);
+            for child in node.children() {
+                match child {
+                    NodeOrToken::Node(it) => stack.push((it, depth + 1)),
+                    NodeOrToken::Token(it) => {
+                        *res.tokens.entry(it.kind()).or_default() += 1;
+                        seen_tokens.insert(it as *const _);
+                    }
+                }
+            }
+        }
+        res.unique_nodes = seen_nodes.len();
+        res.unique_tokens = seen_tokens.len();
+        res
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.values().sum()
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.tokens.values().sum()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.depths.len().saturating_sub(1)
+    }
+
+    /// The share of nodes and tokens which reuse an equal green element,
+    /// between zero for no sharing and almost one.
+    pub fn sharing_ratio(&self) -> f64 {
+        let total = self.node_count() + self.token_count();
+        if total == 0 {
+            return 0.0;
+        }
+        1.0 - (self.unique_nodes + self.unique_tokens) as f64 / total as f64
+    }
+}
+
+/// Heap memory used by a green tree, in bytes.
+///
+/// Each distinct green element is counted once, however many times it occurs
+/// in the tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub nodes: usize,
+    /// Token allocations, without the text.
+    pub tokens: usize,
+    /// The text of the tokens, except for the tokens borrowing it from a
+    /// shared source, see [`GreenToken::new_in`](crate::GreenToken::new_in).
+    pub text: usize,
+    /// How much more the tree would use if no element were shared.
+    pub saved_by_sharing: usize,
+}
+
+impl MemoryUsage {
+    pub fn collect_green(root: &GreenNodeData) -> MemoryUsage {
+        let mut res = MemoryUsage::default();
+        let mut unshared = 0;
+        let mut seen_nodes = FxHashSet::default();
+        let mut seen_tokens = FxHashSet::default();
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            unshared += node.allocation_size();
+            if seen_nodes.insert(node as *const GreenNodeData) {
+                res.nodes += node.allocation_size();
+            }
+            for child in node.children() {
+                match child {
+                    NodeOrToken::Node(it) => stack.push(it),
+                    NodeOrToken::Token(it) => {
+                        unshared += it.allocation_size();
+                        if seen_tokens.insert(it as *const GreenTokenData) {
+                            // The text of a borrowed token belongs to its
+                            // source, and is not counted.
+                            let text = if it.is_borrowed() { 0 } else { it.bytes().len() };
+                            res.tokens += it.allocation_size() - text;
+                            res.text += text;
+                        }
+                    }
+                }
+            }
+        }
+        res.saved_by_sharing = unshared - res.total();
+        res
+    }
+
+    pub fn total(&self) -> usize {
+        self.nodes + self.tokens + self.text
+    }
+}
+
+impl fmt::Display for TreeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "text length: {}", usize::from(self.text_len))?;
+        writeln!(f, "nodes: {} ({} unique)", self.node_count(), self.unique_nodes)?;
+        writeln!(f, "tokens: {} ({} unique)", self.token_count(), self.unique_tokens)?;
+        writeln!(f, "sharing: {:.1}%", self.sharing_ratio() * 100.0)?;
+        writeln!(f, "max depth: {}", self.max_depth())?;
+        for (kind, count) in self.nodes.iter().chain(self.tokens.iter()) {
+            writeln!(f, "  {:?}: {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GreenNodeBuilder, NodeCache, TextRange};
+
+    #[test]
+    fn test_report() {
+        // (a a) (a a)
+        let mut cache = NodeCache::default();
+        let mut builder = GreenNodeBuilder::with_cache(&mut cache);
+        builder.start_node(SyntaxKind(0));
+        for _ in 0..2 {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), "a");
+            builder.token(SyntaxKind(2), "a");
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = builder.finish();
+        let report = TreeReport::collect_green(&root);
+
+        assert_eq!(
+            report.nodes,
+            vec![(SyntaxKind(0), 1), (SyntaxKind(1), 2)].into_iter().collect()
+        );
+        assert_eq!(report.tokens, vec![(SyntaxKind(2), 4)].into_iter().collect());
+        assert_eq!(report.depths, [1, 2]);
+        assert_eq!((report.unique_nodes, report.unique_tokens), (2, 1));
+        assert_eq!(report.text_len, 4.into());
+        assert!((report.sharing_ratio() - 4.0 / 7.0).abs() < 1e-9);
+
+        let usage = root.memory_usage();
+        let (node, token) = (root.children().next().unwrap(), root.descendant_tokens().next());
+        let (node, token) = (node.into_node().unwrap(), token.unwrap().0);
+        assert_eq!(usage.nodes, root.allocation_size() + node.allocation_size());
+        assert_eq!((usage.tokens + usage.text, usage.text), (token.allocation_size(), 1));
+        assert_eq!(usage.saved_by_sharing, node.allocation_size() + 3 * token.allocation_size());
+        assert_eq!(
+            report.to_string(),
+            "text length: 4
+nodes: 3 (2 unique)
+tokens: 4 (1 unique)
+sharing: 57.1%
+max depth: 1
+  SyntaxKind(0): 1
+  SyntaxKind(1): 2
+  SyntaxKind(2): 4
+"
+        );
+    }
+    #[test]
+    fn test_memory_usage_of_borrowed_tokens() {
+        let source: std::sync::Arc<str> = "a".repeat(200).into();
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token_in(SyntaxKind(1), &source, TextRange::up_to(200.into()));
+        builder.token(SyntaxKind(1), "b");
+        builder.finish_node();
+        let root = builder.finish();
+
+        let usage = root.memory_usage();
+        let (borrowed, inline) = (root.children().next().unwrap(), root.children().nth(1));
+        let (borrowed, inline) =
+            (borrowed.into_token().unwrap(), inline.unwrap().into_token().unwrap());
+        assert!(borrowed.is_borrowed());
+        assert_eq!(usage.text, 1);
+        assert_eq!(usage.tokens, borrowed.allocation_size() + inline.allocation_size() - 1);
+        assert_eq!(usage.saved_by_sharing, 0);
+    }
+}
@@ -44,11 +44,26 @@ impl<L: Language> Serialize for SyntaxToken<L> {
         let mut state = serializer.serialize_map(Some(3))?;
         state.serialize_entry("kind", &SerDisplay(DisplayDebug(self.kind())))?;
         state.serialize_entry("text_range", &self.text_range())?;
-        state.serialize_entry("text", &self.text())?;
+        // Tokens which are not valid UTF-8 are serialized as bytes, so that
+        // they are not lost in a lossy conversion.
+        match self.green().as_str() {
+            Some(text) => state.serialize_entry("text", text)?,
+            None => state.serialize_entry("bytes", &SerBytes(self.bytes()))?,
+        }
         state.end()
     }
 }
 
+struct SerBytes<'a>(&'a [u8]);
+impl Serialize for SerBytes<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
 struct Children<T>(T);
 
 impl<L: Language> Serialize for Children<&'_ SyntaxNode<L>> {
@@ -64,3 +79,69 @@ impl<L: Language> Serialize for Children<&'_ SyntaxNode<L>> {
         state.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::{GreenNode, GreenNodeBuilder, SyntaxKind};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    enum Lang {}
+    impl crate::Language for Lang {
+        type Kind = SyntaxKind;
+        fn kind_from_raw(raw: SyntaxKind) -> SyntaxKind {
+            raw
+        }
+        fn kind_to_raw(kind: SyntaxKind) -> SyntaxKind {
+            kind
+        }
+    }
+
+    /// Rebuilds the green tree from its serialized form.
+    fn deserialize(value: &Value, builder: &mut GreenNodeBuilder<'_>) {
+        let kind = value["kind"].as_str().unwrap();
+        let kind = SyntaxKind(kind["SyntaxKind(".len()..kind.len() - 1].parse().unwrap());
+        if let Some(children) = value.get("children") {
+            builder.start_node(kind);
+            children.as_array().unwrap().iter().for_each(|it| deserialize(it, builder));
+            builder.finish_node();
+        } else if let Some(text) = value.get("text") {
+            builder.token(kind, text.as_str().unwrap());
+        } else {
+            let bytes: Vec<u8> = value["bytes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|it| it.as_u64().unwrap() as u8)
+                .collect();
+            builder.token_bytes(kind, &bytes);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "a\u{e9}");
+        builder.start_node(SyntaxKind(2));
+        builder.token_bytes(SyntaxKind(3), b"\xFFb\xE2\x82");
+        builder.finish_node();
+        builder.finish_node();
+        let green = builder.finish();
+        let node = crate::SyntaxNode::<Lang>::new_root(green.clone());
+
+        let value = serde_json::to_value(&node).unwrap();
+        assert_eq!(value["children"][0]["text"], "a\u{e9}");
+        assert_eq!(
+            value["children"][1]["children"][0]["bytes"],
+            serde_json::json!([255, 98, 226, 130])
+        );
+        assert_eq!(value["children"][1]["text_range"], serde_json::json!([3, 7]));
+
+        let mut builder = GreenNodeBuilder::new();
+        deserialize(&value, &mut builder);
+        let rebuilt: GreenNode = builder.finish();
+        assert_eq!(rebuilt, green);
+    }
+}
@@ -4,6 +4,10 @@ use std::{cell::Cell, cmp::Ordering, ptr};
 
 use crate::utility_types::Delta;
 
+/// # Safety
+///
+/// `prev` and `next` must be used exclusively by this module, and `key` must
+/// not change while the element is linked, other than via `adjust`.
 pub(crate) unsafe trait Elem {
     fn prev(&self) -> &Cell<*const Self>;
     fn next(&self) -> &Cell<*const Self>;
@@ -0,0 +1,94 @@
+//! Counters of the allocated green nodes, green tokens and cursors.
+//!
+//! The counting is done by [`countme`], which this feature enables. It is
+//! off at run time until [`enable`] is called, so call it before creating
+//! any tree. Benchmarks and tests can then compare the counts before and
+//! after an operation to catch regressions in sharing or cursor churn:
+//!
+//! ```
+//! rowan::stats::enable(true);
+//! let before = rowan::stats::get();
+//! // ...
+//! let after = rowan::stats::get();
+//! assert!(after.cursors.total - before.cursors.total < 1000);
+//! ```
+//!
+//! The counters are global, so concurrently running tests see each other's
+//! allocations.
+use crate::{cursor::SyntaxElement, GreenNode, GreenToken};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    /// The number of instances created so far.
+    pub total: usize,
+    /// The number of instances currently alive.
+    pub live: usize,
+    /// The maximum of `live` so far.
+    pub peak: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub green_nodes: Counts,
+    pub green_tokens: Counts,
+    /// Red nodes and tokens, shared between all the clones of a
+    /// [`SyntaxNode`](crate::SyntaxNode).
+    pub cursors: Counts,
+}
+
+/// Turns the counting on or off, see [`countme::enable`].
+///
+/// Instances created while the counting is off are not counted when they
+/// are dropped either, so that `live` stays correct as long as the counting
+/// is turned on before creating any instance.
+pub fn enable(yes: bool) {
+    countme::enable(yes)
+}
+
+pub fn get() -> Stats {
+    Stats {
+        green_nodes: counts::<GreenNode>(),
+        green_tokens: counts::<GreenToken>(),
+        cursors: counts::<SyntaxElement>(),
+    }
+}
+
+/// Panics if there are live cursors, which means that a `SyntaxNode` or
+/// `SyntaxToken` leaked. Meant to be called at the end of tests, which
+/// must not run concurrently with other tests creating cursors.
+#[track_caller]
+pub fn assert_no_live_cursors() {
+    let live = counts::<SyntaxElement>().live;
+    assert!(live == 0, "{} cursors are still alive", live);
+}
+
+fn counts<T>() -> Counts {
+    let counts = countme::get::<T>();
+    Counts { total: counts.total, live: counts.live, peak: counts.max_live }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cursor::SyntaxNode, GreenNodeBuilder, SyntaxKind};
+
+    #[test]
+    fn test_stats() {
+        super::enable(true);
+        let before = super::get();
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        builder.token(SyntaxKind(1), "a");
+        builder.token(SyntaxKind(2), "b");
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let tokens: Vec<_> = root.descendants_with_tokens().collect();
+
+        let after = super::get();
+        assert!(after.green_nodes.total > before.green_nodes.total);
+        assert!(after.green_tokens.total >= before.green_tokens.total + 2);
+        assert!(after.cursors.total >= before.cursors.total + 3);
+        assert!(after.cursors.live >= 3);
+        assert!(after.cursors.peak >= after.cursors.live);
+        drop((root, tokens));
+    }
+}
@@ -0,0 +1,200 @@
+//! User-defined summaries of green subtrees.
+//!
+//! A [`Summary`] is a monoid computed for every token from its text and
+//! combined bottom-up, like the number of newlines or error tokens.
+//! [`Summaries`] caches, for every green node, the summaries of all the
+//! prefixes of its children, so that the summary of a prefix of the text is
+//! computed with a binary search per level of a single path of the tree, and
+//! an edited tree only recomputes the tables of the new nodes.
+use rustc_hash::FxHashMap;
+
+use crate::{green::GreenElementRef, GreenNode, GreenNodeData, NodeOrToken, SyntaxKind, TextSize};
+
+pub trait Summary: Sized {
+    /// The summary of the empty text, the identity of [`Summary::combine`].
+    fn empty() -> Self;
+
+    /// The summary of `text` of a token of `kind`, where `text` may be a
+    /// prefix of the token text.
+    fn token(kind: SyntaxKind, text: &[u8]) -> Self;
+
+    /// The summary of the concatenation of the texts, must be associative.
+    fn combine(self, other: &Self) -> Self;
+}
+
+/// A cache of the summaries of green nodes.
+///
+/// The cached nodes are kept alive by the cache, use [`Summaries::clear`]
+/// to free them.
+#[derive(Debug)]
+pub struct Summaries<S> {
+    /// The key is the address of the node, which can't be reused while the
+    /// node is kept alive in the value.
+    map: FxHashMap<*const GreenNodeData, (GreenNode, Prefixes<S>)>,
+}
+
+/// The summaries of the first `i` children of a node, for every `i` from
+/// zero to the number of children, the last one being the summary of the
+/// whole node.
+type Prefixes<S> = Vec<S>;
+
+impl<S> Default for Summaries<S> {
+    fn default() -> Self {
+        Summaries { map: FxHashMap::default() }
+    }
+}
+
+impl<S: Summary> Summaries<S> {
+    pub fn new() -> Summaries<S> {
+        Summaries::default()
+    }
+
+    /// The summary of the subtree of `node`.
+    pub fn get(&mut self, node: &GreenNodeData) -> &S {
+        // Not recursive, to handle arbitrarily deep trees.
+        let mut stack = vec![(node, false)];
+        while let Some((node, children_done)) = stack.pop() {
+            if self.map.contains_key(&(node as *const _)) {
+                continue;
+            }
+            if !children_done {
+                stack.push((node, true));
+                stack.extend(node.children().filter_map(|it| it.into_node()).map(|it| (it, false)));
+                continue;
+            }
+            let mut prefixes = Vec::with_capacity(node.children().len() + 1);
+            prefixes.push(S::empty());
+            for child in node.children() {
+                // `empty().combine(last)` is a copy of `last` by the monoid
+                // laws, so that `S` need not be `Clone`.
+                let last = S::empty().combine(prefixes.last().unwrap());
+                prefixes.push(self.combine(last, child));
+            }
+            self.map.insert(node, (node.to_owned(), prefixes));
+        }
+        self.prefixes(node).last().unwrap()
+    }
+
+    /// The summary of the text of `node` before `offset`.
+    pub fn prefix(&mut self, node: &GreenNodeData, offset: TextSize) -> S {
+        assert!(offset <= node.text_len(), "offset {:?} is out of bounds", offset);
+        self.get(node);
+        let (mut node, mut offset) = (node, offset);
+        let mut res = S::empty();
+        loop {
+            let (idx, rel_offset, child) = match node.child_at_offset(offset) {
+                Some(it) => it,
+                None => return res.combine(self.prefixes(node).last().unwrap()),
+            };
+            res = res.combine(&self.prefixes(node)[idx]);
+            offset -= rel_offset;
+            match child {
+                NodeOrToken::Node(it) => node = it,
+                NodeOrToken::Token(it) => {
+                    let text = &it.bytes()[..usize::from(offset)];
+                    return res.combine(&S::token(it.kind(), text));
+                }
+            }
+        }
+    }
+
+    /// Forgets all the summaries.
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    /// The prefix table of `node`, which must be cached.
+    fn prefixes(&self, node: &GreenNodeData) -> &Prefixes<S> {
+        &self.map[&(node as *const _)].1
+    }
+
+    /// Combines `acc` with the summary of `child`, which must be cached if it
+    /// is a node.
+    fn combine(&self, acc: S, child: GreenElementRef<'_>) -> S {
+        match child {
+            NodeOrToken::Node(it) => acc.combine(self.prefixes(it).last().unwrap()),
+            NodeOrToken::Token(it) => acc.combine(&S::token(it.kind(), it.bytes())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GreenToken;
+
+    #[derive(Debug, PartialEq)]
+    struct Newlines(usize);
+
+    impl Summary for Newlines {
+        fn empty() -> Self {
+            Newlines(0)
+        }
+        fn token(_kind: SyntaxKind, text: &[u8]) -> Self {
+            Newlines(text.iter().filter(|&&it| it == b'\n').count())
+        }
+        fn combine(self, other: &Self) -> Self {
+            Newlines(self.0 + other.0)
+        }
+    }
+
+    #[test]
+    fn test_summaries() {
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let inner = GreenNode::new(SyntaxKind(2), vec![token("a\nb"), token("\n")]);
+        let root = GreenNode::new(
+            SyntaxKind(3),
+            vec![inner.clone().into(), token("c\n\nd"), inner.into()],
+        );
+        let text = root.to_string();
+
+        let mut summaries = Summaries::<Newlines>::new();
+        assert_eq!(summaries.get(&root), &Newlines(6));
+        for offset in 0..=text.len() {
+            let expected = text[..offset].matches('\n').count();
+            let actual = summaries.prefix(&root, TextSize::from(offset as u32));
+            assert_eq!(actual, Newlines(expected), "{}", offset);
+        }
+    }
+
+    #[test]
+    fn test_prefix_of_wide_node() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static TOKEN_CALLS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Len(usize);
+
+        impl Summary for Len {
+            fn empty() -> Self {
+                Len(0)
+            }
+            fn token(_kind: SyntaxKind, text: &[u8]) -> Self {
+                TOKEN_CALLS.with(|it| it.set(it.get() + 1));
+                Len(text.len())
+            }
+            fn combine(self, other: &Self) -> Self {
+                Len(self.0 + other.0)
+            }
+        }
+
+        let token = |text| GreenToken::new(SyntaxKind(1), text).into();
+        let empty = GreenNode::new(SyntaxKind(2), vec![]);
+        let mut children = vec![empty.clone().into()];
+        children.extend((0..1000).map(|_| token("ab")));
+        children.push(empty.into());
+        let root = GreenNode::new(SyntaxKind(3), children);
+
+        let mut summaries = Summaries::<Len>::new();
+        assert_eq!(summaries.get(&root), &Len(2000));
+        TOKEN_CALLS.with(|it| it.set(0));
+        assert_eq!(summaries.prefix(&root, TextSize::from(1501)), Len(1501));
+        assert_eq!(summaries.prefix(&root, TextSize::from(0)), Len(0));
+        assert_eq!(summaries.prefix(&root, TextSize::from(2000)), Len(2000));
+        // Only the token containing the offset is summarized again.
+        assert_eq!(TOKEN_CALLS.with(|it| it.get()), 2);
+    }
+}
@@ -1,10 +1,24 @@
-use std::fmt;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt,
+    hash::{Hash, Hasher},
+    io, iter, ops, ptr, str,
+};
+
+#[cfg(feature = "xxhash")]
+use xxhash_rust::xxh3::Xxh3;
 
 use crate::{
-    cursor::{SyntaxNode, SyntaxToken},
-    TextRange, TextSize,
+    cursor::{SyntaxElement, SyntaxNode, SyntaxToken},
+    GreenTokenData, NodeOrToken, SyntaxKind, TextRange, TextSize,
 };
 
+/// What the invalid bytes of non-UTF-8 tokens are shown as, like in
+/// [`String::from_utf8_lossy`].
+const REPLACEMENT: &str = "\u{FFFD}";
+
 #[derive(Clone)]
 pub struct SyntaxText {
     node: SyntaxNode,
@@ -25,33 +39,85 @@ impl SyntaxText {
         self.range.is_empty()
     }
 
+    /// Returns the node or token whose text is exactly this text, if any.
+    ///
+    /// Green elements are shared, so comparing the green data of covered
+    /// elements is a cheap way to detect equal texts.
+    pub fn covered_element(&self) -> Option<SyntaxElement> {
+        if self.node.text_range() == self.range {
+            return Some(self.node.clone().into());
+        }
+        let element = self.node.covering_element(self.range);
+        if element.text_range() == self.range {
+            Some(element)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the text as a `&str` if it lies within a single token, and
+    /// allocates a `String` otherwise.
+    ///
+    /// Texts of mutable trees and texts with non-UTF-8 bytes are always
+    /// copied.
+    pub fn as_cow(&self) -> Cow<'_, str> {
+        let green = match self.node.green() {
+            Cow::Borrowed(it) => it,
+            Cow::Owned(_) => return Cow::Owned(self.to_string()),
+        };
+        let mut node = green;
+        let mut range = self.range - self.node.text_range().start();
+        loop {
+            if range.is_empty() {
+                return Cow::Borrowed("");
+            }
+            let (_, offset, child) = match node.child_containing_range(range) {
+                Some(it) => it,
+                None => return Cow::Owned(self.to_string()),
+            };
+            range -= offset;
+            match child {
+                NodeOrToken::Node(it) => node = it,
+                NodeOrToken::Token(it) => {
+                    return match it.as_str() {
+                        Some(text) => Cow::Borrowed(&text[range]),
+                        None => Cow::Owned(self.to_string()),
+                    }
+                }
+            }
+        }
+    }
+
     pub fn contains_char(&self, c: char) -> bool {
         self.try_for_each_chunk(|chunk| if chunk.contains(c) { Err(()) } else { Ok(()) }).is_err()
     }
 
     pub fn find_char(&self, c: char) -> Option<TextSize> {
-        let mut acc: TextSize = 0.into();
-        let res = self.try_for_each_chunk(|chunk| {
-            if let Some(pos) = chunk.find(c) {
-                let pos: TextSize = (pos as u32).into();
-                return Err(acc + pos);
+        let res = try_for_each_piece(self.tokens_with_ranges(), |range, piece| {
+            let pos = match piece {
+                Some(text) => text.find(c).map(|pos| TextSize::try_from(pos).unwrap()),
+                None => (c == char::REPLACEMENT_CHARACTER).then(TextSize::default),
+            };
+            match pos {
+                Some(pos) => Err(range.start() + pos),
+                None => Ok(()),
             }
-            acc += TextSize::of(chunk);
-            Ok(())
         });
         found(res)
     }
 
     pub fn char_at(&self, offset: TextSize) -> Option<char> {
-        let offset = offset.into();
-        let mut start: TextSize = 0.into();
-        let res = self.try_for_each_chunk(|chunk| {
-            let end = start + TextSize::of(chunk);
-            if start <= offset && offset < end {
-                let off: usize = u32::from(offset - start) as usize;
-                return Err(chunk[off..].chars().next().unwrap());
+        let res = try_for_each_piece(self.tokens_with_ranges(), |range, piece| {
+            if range.contains(offset) {
+                let c = match piece {
+                    Some(text) => {
+                        let off = usize::from(offset - range.start());
+                        text[off..].chars().next().unwrap()
+                    }
+                    None => char::REPLACEMENT_CHARACTER,
+                };
+                return Err(c);
             }
-            start = end;
             Ok(())
         });
         found(res)
@@ -80,12 +146,259 @@ impl SyntaxText {
         SyntaxText { node: self.node.clone(), range }
     }
 
+    /// Returns the offset at which the line containing `offset` starts.
+    ///
+    /// Only the tokens of that line are looked at, so this is cheap even for
+    /// large texts.
+    pub fn line_start(&self, offset: TextSize) -> TextSize {
+        assert!(offset <= self.len(), "invalid offset: {:?}, len: {:?}", offset, self.len());
+        let before = TextRange::new(self.range.start(), self.range.start() + offset);
+        let mut token = self.node.token_at_offset(before.end()).left_biased();
+        while let Some(curr) = token {
+            let range = match curr.text_range().intersect(before) {
+                Some(it) => it,
+                None => break,
+            };
+            let bytes = token_bytes(&curr, range - curr.text_range().start());
+            if let Some(pos) = bytes.iter().rposition(|&b| b == b'\n') {
+                return range.start() + TextSize::try_from(pos + 1).unwrap() - self.range.start();
+            }
+            if range.start() == before.start() {
+                break;
+            }
+            token = curr.prev_token();
+        }
+        0.into()
+    }
+
+    /// Returns the visual column of `offset` within its line, with tabs
+    /// advancing to the next multiple of `tab_width`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tab_width` is zero.
+    pub fn visual_column(&self, offset: TextSize, tab_width: u32) -> u32 {
+        let mut col = 0;
+        self.slice(self.line_start(offset)..offset).for_each_chunk(|chunk| {
+            for c in chunk.chars() {
+                col += if c == '\t' { tab_width - col % tab_width } else { 1 };
+            }
+        });
+        col
+    }
+
+    /// Returns this text without leading and trailing whitespace.
+    pub fn trimmed(&self) -> SyntaxText {
+        let mut start = None;
+        let mut end = TextSize::from(0);
+        for_each_piece(self.tokens_with_ranges(), |range, piece| {
+            let is_significant = |c: char| !c.is_whitespace();
+            let (first, last) = match piece {
+                Some(text) => match (text.find(is_significant), text.rfind(is_significant)) {
+                    (Some(first), Some(last)) => {
+                        let c = text[last..].chars().next().unwrap();
+                        let last = TextSize::try_from(last).unwrap() + TextSize::of(c);
+                        (range.start() + TextSize::try_from(first).unwrap(), range.start() + last)
+                    }
+                    _ => return,
+                },
+                None => (range.start(), range.end()),
+            };
+            start.get_or_insert(first);
+            end = last;
+        });
+        match start {
+            Some(start) => self.slice(start..end),
+            None => self.slice(..TextSize::from(0)),
+        }
+    }
+
+    /// Returns a view of this text which skips the tokens for which
+    /// `is_trivia` returns `true`.
+    pub fn without<F: Fn(SyntaxKind) -> bool>(&self, is_trivia: F) -> FilteredText<F> {
+        FilteredText { text: self.clone(), is_trivia }
+    }
+
+    /// Writes the text chunk by chunk, without materializing it.
+    ///
+    /// Invalid UTF-8 sequences are written as `U+FFFD REPLACEMENT CHARACTER`.
+    pub fn write_to(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        self.try_for_each_chunk(|chunk| w.write_str(chunk))
+    }
+
+    /// Like [`SyntaxText::write_to`], but for byte sinks such as files and
+    /// sockets. The bytes of the tokens are written as is, even if they are
+    /// not valid UTF-8.
+    pub fn write_to_io(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        self.tokens_with_ranges()
+            .try_for_each(|(token, range)| w.write_all(token_bytes(&token, range)))
+    }
+
+    /// Feeds the text to `state` chunk by chunk, without materializing it.
+    ///
+    /// The bytes are buffered so that the result does not depend on how the
+    /// text is split into tokens: equal texts always hash equally.
+    pub fn hash_with<H: Hasher>(&self, state: &mut H) {
+        let mut buf = HashBuf::new(state);
+        for (token, range) in self.tokens_with_ranges() {
+            buf.write(token_bytes(&token, range));
+        }
+        buf.finish();
+    }
+
+    /// Like [`SyntaxText::hash_with`], but hashes `\r\n` as `\n`, consistently
+    /// with [`SyntaxText::eq_ignore_line_endings`].
+    pub fn hash_ignore_line_endings<H: Hasher>(&self, state: &mut H) {
+        let mut buf = HashBuf::new(state);
+        for c in normalize_line_endings(self.chars().map(|(_, c)| c)) {
+            buf.write(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        buf.finish();
+    }
+
+    /// Computes the 64-bit XXH3 hash of the bytes of the text.
+    ///
+    /// Unlike [`SyntaxText::hash_with`], the result is the same across rowan
+    /// versions and platforms, and equals the hash of the materialized text,
+    /// so it can be persisted or compared with hashes computed elsewhere.
+    #[cfg(feature = "xxhash")]
+    pub fn xxh3_hash(&self) -> u64 {
+        let mut state = Xxh3::new();
+        for (token, range) in self.tokens_with_ranges() {
+            state.update(token_bytes(&token, range));
+        }
+        state.digest()
+    }
+
+    /// Compares this text with `other` (a `str` or a `SyntaxText`), ignoring
+    /// case according to `folding`.
+    pub fn eq_ignore_case<T: private::TextOperand + ?Sized>(
+        &self,
+        other: &T,
+        folding: CaseFolding,
+    ) -> bool {
+        self.cmp_ignore_case(other, folding) == Ordering::Equal
+    }
+
+    /// Orders this text and `other` by their case-folded characters.
+    pub fn cmp_ignore_case<T: private::TextOperand + ?Sized>(
+        &self,
+        other: &T,
+        folding: CaseFolding,
+    ) -> Ordering {
+        let lhs = self.chars().map(|(_, c)| folding.fold(c));
+        let rhs = other.chars().map(|c| folding.fold(c));
+        lhs.cmp(rhs)
+    }
+
+    /// Compares this text with `other` (a `str` or a `SyntaxText`), treating
+    /// `\r\n` and `\n` as equal.
+    pub fn eq_ignore_line_endings<T: private::TextOperand + ?Sized>(&self, other: &T) -> bool {
+        let lhs = normalize_line_endings(self.chars().map(|(_, c)| c));
+        let rhs = normalize_line_endings(other.chars());
+        lhs.eq(rhs)
+    }
+
+    /// Returns the ranges of all line terminators, `\n` or `\r\n`, in this
+    /// text.
+    pub fn line_terminators(&self) -> impl Iterator<Item = TextRange> {
+        let mut chars = self.chars().peekable();
+        iter::from_fn(move || loop {
+            let (offset, c) = chars.next()?;
+            match c {
+                '\n' => return Some(TextRange::at(offset, 1.into())),
+                '\r' if chars.peek().map(|&(_, c)| c) == Some('\n') => {
+                    chars.next();
+                    return Some(TextRange::at(offset, 2.into()));
+                }
+                _ => (),
+            }
+        })
+    }
+
+    /// Iterates over the lines of this text, without their terminators, like
+    /// [`str::lines`].
+    ///
+    /// Ranges are relative to the start of this text.
+    pub fn lines(&self) -> impl Iterator<Item = (TextRange, SyntaxText)> {
+        let text = self.clone();
+        let mut terminators = self.line_terminators();
+        let mut start = Some(TextSize::from(0));
+        iter::from_fn(move || {
+            let line_start = start?;
+            let range = match terminators.next() {
+                Some(terminator) => {
+                    start = Some(terminator.end()).filter(|&it| it < text.len());
+                    TextRange::new(line_start, terminator.start())
+                }
+                None => {
+                    start = None;
+                    if line_start == text.len() {
+                        return None;
+                    }
+                    TextRange::new(line_start, text.len())
+                }
+            };
+            Some((range, text.slice(range)))
+        })
+    }
+
+    /// Returns the offset of the first occurrence of `needle`, ignoring case
+    /// according to `folding`.
+    ///
+    /// The text is scanned once, keeping only needle-sized state.
+    pub fn find_ignore_case(&self, needle: &str, folding: CaseFolding) -> Option<TextSize> {
+        let needle: Vec<char> = needle.chars().map(|c| folding.fold(c)).collect();
+        if needle.is_empty() {
+            return Some(0.into());
+        }
+        // Knuth-Morris-Pratt: `fallback[i]` is the length of the longest
+        // proper prefix of `needle[..=i]` which is also its suffix.
+        let mut fallback = vec![0; needle.len()];
+        let mut len = 0;
+        for i in 1..needle.len() {
+            while len > 0 && needle[i] != needle[len] {
+                len = fallback[len - 1];
+            }
+            if needle[i] == needle[len] {
+                len += 1;
+            }
+            fallback[i] = len;
+        }
+
+        // Offsets of the last `needle.len()` characters, to report the start
+        // of a match.
+        let mut offsets = vec![TextSize::from(0); needle.len()];
+        let mut matched = 0;
+        for (i, (offset, c)) in self.chars().enumerate() {
+            offsets[i % needle.len()] = offset;
+            let c = folding.fold(c);
+            while matched > 0 && c != needle[matched] {
+                matched = fallback[matched - 1];
+            }
+            if c == needle[matched] {
+                matched += 1;
+            }
+            if matched == needle.len() {
+                return Some(offsets[(i + 1) % needle.len()]);
+            }
+        }
+        None
+    }
+
+    /// Folds the text chunk by chunk. Tokens with non-UTF-8 bytes are passed
+    /// in pieces, with `U+FFFD REPLACEMENT CHARACTER` in place of each invalid
+    /// sequence.
     pub fn try_fold_chunks<T, F, E>(&self, init: T, mut f: F) -> Result<T, E>
     where
         F: FnMut(T, &str) -> Result<T, E>,
     {
-        self.tokens_with_ranges()
-            .try_fold(init, move |acc, (token, range)| f(acc, &token.text()[range]))
+        let mut acc = Some(init);
+        try_for_each_piece(self.tokens_with_ranges(), |_, piece| {
+            acc = Some(f(acc.take().unwrap(), piece.unwrap_or(REPLACEMENT))?);
+            Ok(())
+        })?;
+        Ok(acc.unwrap())
     }
 
     pub fn try_for_each_chunk<F: FnMut(&str) -> Result<(), E>, E>(
@@ -97,12 +410,20 @@ impl SyntaxText {
 
     pub fn for_each_chunk<F: FnMut(&str)>(&self, mut f: F) {
         enum Void {}
-        match self.try_for_each_chunk(|chunk| Ok::<(), Void>(f(chunk))) {
+        match self.try_for_each_chunk(|chunk| {
+            f(chunk);
+            Ok::<(), Void>(())
+        }) {
             Ok(()) => (),
             Err(void) => match void {},
         }
     }
 
+    /// Characters of the text together with their offsets.
+    fn chars(&self) -> Chars<impl Iterator<Item = (SyntaxToken, TextRange)>> {
+        Chars { tokens: self.tokens_with_ranges(), current: None, offset: 0.into() }
+    }
+
     fn tokens_with_ranges(&self) -> impl Iterator<Item = (SyntaxToken, TextRange)> {
         let text_range = self.range;
         self.node.descendants_with_tokens().filter_map(|element| element.into_token()).filter_map(
@@ -116,9 +437,200 @@ impl SyntaxText {
 }
 
 fn found<T>(res: Result<(), T>) -> Option<T> {
-    match res {
-        Ok(()) => None,
-        Err(it) => Some(it),
+    res.err()
+}
+
+fn token_bytes(token: &SyntaxToken, range: TextRange) -> &[u8] {
+    &token.bytes()[ops::Range::<usize>::from(range)]
+}
+
+/// Calls `f` with the text of `tokens` piece by piece, together with the
+/// range of each piece relative to the start of the first token.
+///
+/// Tokens with non-UTF-8 bytes are split into their valid runs and their
+/// invalid sequences, which are passed as `None`.
+fn try_for_each_piece<F, E>(
+    tokens: impl Iterator<Item = (SyntaxToken, TextRange)>,
+    mut f: F,
+) -> Result<(), E>
+where
+    F: FnMut(TextRange, Option<&str>) -> Result<(), E>,
+{
+    let mut offset = TextSize::from(0);
+    for (token, range) in tokens {
+        match token.green().as_str() {
+            Some(text) => f(TextRange::at(offset, range.len()), Some(&text[range]))?,
+            None => {
+                let mut start = offset;
+                let mut bytes = token_bytes(&token, range);
+                while !bytes.is_empty() {
+                    let (valid, invalid) = match str::from_utf8(bytes) {
+                        Ok(valid) => (valid, 0),
+                        Err(err) => {
+                            let valid = str::from_utf8(&bytes[..err.valid_up_to()]).unwrap();
+                            // A truncated sequence at the end has no error length.
+                            let invalid = err.error_len().unwrap_or(bytes.len() - valid.len());
+                            (valid, invalid)
+                        }
+                    };
+                    if !valid.is_empty() {
+                        f(TextRange::at(start, TextSize::of(valid)), Some(valid))?;
+                        start += TextSize::of(valid);
+                    }
+                    if invalid > 0 {
+                        let invalid_len = TextSize::from(invalid as u32);
+                        f(TextRange::at(start, invalid_len), None)?;
+                        start += invalid_len;
+                    }
+                    bytes = &bytes[valid.len() + invalid..];
+                }
+            }
+        }
+        offset += range.len();
+    }
+    Ok(())
+}
+
+fn for_each_piece<F: FnMut(TextRange, Option<&str>)>(
+    tokens: impl Iterator<Item = (SyntaxToken, TextRange)>,
+    mut f: F,
+) {
+    enum Void {}
+    match try_for_each_piece(tokens, |range, piece| {
+        f(range, piece);
+        Ok::<(), Void>(())
+    }) {
+        Ok(()) => (),
+        Err(void) => match void {},
+    }
+}
+
+/// Returns the first character of `range` in `token` and its length in
+/// bytes. An invalid UTF-8 sequence is read as a single `U+FFFD`, like in
+/// [`try_for_each_piece`].
+fn first_char(token: &GreenTokenData, range: TextRange) -> (char, TextSize) {
+    if let Some(text) = token.as_str() {
+        let c = text[range].chars().next().unwrap();
+        return (c, TextSize::of(c));
+    }
+    let bytes = &token.bytes()[ops::Range::<usize>::from(range)];
+    let head = &bytes[..bytes.len().min(4)];
+    let valid = match std::str::from_utf8(head) {
+        Ok(it) => it,
+        Err(err) if err.valid_up_to() > 0 => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).unwrap()
+        }
+        Err(err) => {
+            let len = err.error_len().unwrap_or(head.len());
+            return (char::REPLACEMENT_CHARACTER, TextSize::from(len as u32));
+        }
+    };
+    let c = valid.chars().next().unwrap();
+    (c, TextSize::of(c))
+}
+
+/// Compares the bytes of `tokens` with `rhs`.
+fn bytes_eq(mut tokens: impl Iterator<Item = (SyntaxToken, TextRange)>, mut rhs: &[u8]) -> bool {
+    tokens.all(|(token, range)| match rhs.strip_prefix(token_bytes(&token, range)) {
+        Some(rest) => {
+            rhs = rest;
+            true
+        }
+        None => false,
+    }) && rhs.is_empty()
+}
+
+/// Buffers the bytes written to a hasher, so that the hash does not depend on
+/// how the input is split.
+struct HashBuf<'a, H> {
+    state: &'a mut H,
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl<'a, H: Hasher> HashBuf<'a, H> {
+    fn new(state: &'a mut H) -> HashBuf<'a, H> {
+        HashBuf { state, buf: [0; 64], len: 0 }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let n = std::cmp::min(self.buf.len() - self.len, bytes.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+            if self.len == self.buf.len() {
+                self.state.write(&self.buf);
+                self.len = 0;
+            }
+        }
+    }
+
+    fn finish(self) {
+        self.state.write(&self.buf[..self.len]);
+        self.state.write_u8(0xff);
+    }
+}
+
+fn normalize_line_endings(chars: impl Iterator<Item = char>) -> impl Iterator<Item = char> {
+    let mut chars = chars.peekable();
+    iter::from_fn(move || {
+        let c = chars.next()?;
+        if c == '\r' && chars.peek() == Some(&'\n') {
+            chars.next()
+        } else {
+            Some(c)
+        }
+    })
+}
+
+struct Chars<I> {
+    tokens: I,
+    current: Option<(SyntaxToken, TextRange)>,
+    offset: TextSize,
+}
+
+impl<I: Iterator<Item = (SyntaxToken, TextRange)>> Iterator for Chars<I> {
+    type Item = (TextSize, char);
+
+    fn next(&mut self) -> Option<(TextSize, char)> {
+        loop {
+            match &mut self.current {
+                Some((token, range)) if !range.is_empty() => {
+                    let (c, len) = first_char(token.green(), *range);
+                    let offset = self.offset;
+                    self.offset += len;
+                    *range = TextRange::new(range.start() + len, range.end());
+                    return Some((offset, c));
+                }
+                _ => self.current = Some(self.tokens.next()?),
+            }
+        }
+    }
+}
+
+/// How characters are folded by the case-insensitive methods of
+/// [`SyntaxText`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaseFolding {
+    /// Only ASCII letters are folded.
+    Ascii,
+    /// Every character with a single-character lowercase mapping is folded.
+    Simple,
+}
+
+impl CaseFolding {
+    pub fn fold(self, c: char) -> char {
+        match self {
+            CaseFolding::Ascii => c.to_ascii_lowercase(),
+            CaseFolding::Simple => {
+                let mut lower = c.to_lowercase();
+                match (lower.next(), lower.next()) {
+                    (Some(l), None) => l,
+                    _ => c,
+                }
+            }
+        }
     }
 }
 
@@ -141,16 +653,8 @@ impl From<SyntaxText> for String {
 }
 
 impl PartialEq<str> for SyntaxText {
-    fn eq(&self, mut rhs: &str) -> bool {
-        self.try_for_each_chunk(|chunk| {
-            if !rhs.starts_with(chunk) {
-                return Err(());
-            }
-            rhs = &rhs[chunk.len()..];
-            Ok(())
-        })
-        .is_ok()
-            && rhs.is_empty()
+    fn eq(&self, rhs: &str) -> bool {
+        bytes_eq(self.tokens_with_ranges(), rhs.as_bytes())
     }
 }
 
@@ -177,30 +681,41 @@ impl PartialEq for SyntaxText {
         if self.range.len() != other.range.len() {
             return false;
         }
-        let mut lhs = self.tokens_with_ranges();
-        let mut rhs = other.tokens_with_ranges();
-        zip_texts(&mut lhs, &mut rhs).is_none()
-            && lhs.all(|it| it.1.is_empty())
-            && rhs.all(|it| it.1.is_empty())
+        if self.node.text_range() == self.range
+            && other.node.text_range() == other.range
+            && ptr::eq(&*self.node.green(), &*other.node.green())
+        {
+            return true;
+        }
+        texts_eq(self.tokens_with_ranges(), other.tokens_with_ranges())
     }
 }
 
-fn zip_texts<I: Iterator<Item = (SyntaxToken, TextRange)>>(xs: &mut I, ys: &mut I) -> Option<()> {
-    let mut x = xs.next()?;
-    let mut y = ys.next()?;
+fn texts_eq(
+    mut xs: impl Iterator<Item = (SyntaxToken, TextRange)>,
+    mut ys: impl Iterator<Item = (SyntaxToken, TextRange)>,
+) -> bool {
+    let mut x: Option<(SyntaxToken, TextRange)> = None;
+    let mut y: Option<(SyntaxToken, TextRange)> = None;
     loop {
-        while x.1.is_empty() {
-            x = xs.next()?;
+        if x.as_ref().map_or(true, |it| it.1.is_empty()) {
+            x = xs.find(|it| !it.1.is_empty());
         }
-        while y.1.is_empty() {
-            y = ys.next()?;
-        }
-        let x_text = &x.0.text()[x.1];
-        let y_text = &y.0.text()[y.1];
-        if !(x_text.starts_with(y_text) || y_text.starts_with(x_text)) {
-            return Some(());
+        if y.as_ref().map_or(true, |it| it.1.is_empty()) {
+            y = ys.find(|it| !it.1.is_empty());
         }
+        let (x, y) = match (&mut x, &mut y) {
+            (None, None) => return true,
+            (Some(x), Some(y)) => (x, y),
+            _ => return false,
+        };
         let advance = std::cmp::min(x.1.len(), y.1.len());
+        let x_text = token_bytes(&x.0, x.1);
+        let y_text = token_bytes(&y.0, y.1);
+        let n = usize::from(advance);
+        if x_text[..n] != y_text[..n] {
+            return false;
+        }
         x.1 = TextRange::new(x.1.start() + advance, x.1.end());
         y.1 = TextRange::new(y.1.start() + advance, y.1.end());
     }
@@ -208,11 +723,96 @@ fn zip_texts<I: Iterator<Item = (SyntaxToken, TextRange)>>(xs: &mut I, ys: &mut
 
 impl Eq for SyntaxText {}
 
+impl Hash for SyntaxText {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash_with(state)
+    }
+}
+
+/// Text of a syntax tree with some tokens, usually trivia, skipped.
+///
+/// Created by [`SyntaxText::without`]. Comparing two filtered texts checks
+/// that the trees are the same modulo the skipped tokens, without allocating.
+#[derive(Clone)]
+pub struct FilteredText<F> {
+    text: SyntaxText,
+    is_trivia: F,
+}
+
+impl<F: Fn(SyntaxKind) -> bool> FilteredText<F> {
+    pub fn try_for_each_chunk<G: FnMut(&str) -> Result<(), E>, E>(
+        &self,
+        mut f: G,
+    ) -> Result<(), E> {
+        try_for_each_piece(self.tokens_with_ranges(), move |_, piece| {
+            f(piece.unwrap_or(REPLACEMENT))
+        })
+    }
+
+    pub fn for_each_chunk<G: FnMut(&str)>(&self, mut f: G) {
+        for_each_piece(self.tokens_with_ranges(), move |_, piece| f(piece.unwrap_or(REPLACEMENT)))
+    }
+
+    fn tokens_with_ranges(&self) -> impl Iterator<Item = (SyntaxToken, TextRange)> + '_ {
+        self.text.tokens_with_ranges().filter(move |(token, _)| !(self.is_trivia)(token.kind()))
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool> fmt::Debug for FilteredText<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool> fmt::Display for FilteredText<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.try_for_each_chunk(|chunk| fmt::Display::fmt(chunk, f))
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool, G: Fn(SyntaxKind) -> bool> PartialEq<FilteredText<G>>
+    for FilteredText<F>
+{
+    fn eq(&self, other: &FilteredText<G>) -> bool {
+        texts_eq(self.tokens_with_ranges(), other.tokens_with_ranges())
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool> PartialEq<str> for FilteredText<F> {
+    fn eq(&self, rhs: &str) -> bool {
+        bytes_eq(self.tokens_with_ranges(), rhs.as_bytes())
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> bool> PartialEq<&'_ str> for FilteredText<F> {
+    fn eq(&self, rhs: &&str) -> bool {
+        self == *rhs
+    }
+}
+
 mod private {
     use std::ops;
 
     use crate::{TextRange, TextSize};
 
+    use super::SyntaxText;
+
+    pub trait TextOperand {
+        fn chars(&self) -> Box<dyn Iterator<Item = char> + '_>;
+    }
+
+    impl TextOperand for str {
+        fn chars(&self) -> Box<dyn Iterator<Item = char> + '_> {
+            Box::new(str::chars(self))
+        }
+    }
+
+    impl TextOperand for SyntaxText {
+        fn chars(&self) -> Box<dyn Iterator<Item = char> + '_> {
+            Box::new(SyntaxText::chars(self).map(|(_, c)| c))
+        }
+    }
+
     pub trait SyntaxTextRange {
         fn start(&self) -> Option<TextSize>;
         fn end(&self) -> Option<TextSize>;
@@ -266,6 +866,8 @@ mod private {
 
 #[cfg(test)]
 mod tests {
+    use rustc_hash::FxHasher;
+
     use crate::{green::SyntaxKind, GreenNodeBuilder};
 
     use super::*;
@@ -274,7 +876,7 @@ mod tests {
         let mut builder = GreenNodeBuilder::new();
         builder.start_node(SyntaxKind(62));
         for &chunk in chunks.iter() {
-            builder.token(SyntaxKind(92), chunk.into())
+            builder.token(SyntaxKind(92), chunk)
         }
         builder.finish_node();
         SyntaxNode::new_root(builder.finish())
@@ -288,7 +890,7 @@ mod tests {
             let expected = t1.to_string() == t2.to_string();
             let actual = t1 == t2;
             assert_eq!(expected, actual, "`{}` (SyntaxText) `{}` (SyntaxText)", t1, t2);
-            let actual = t1 == &*t2.to_string();
+            let actual = t1 == *t2.to_string();
             assert_eq!(expected, actual, "`{}` (SyntaxText) `{}` (&str)", t1, t2);
         }
         fn check(t1: &[&str], t2: &[&str]) {
@@ -308,4 +910,218 @@ mod tests {
         check(&["{", "abc", "}"], &["{", "123", "}", "{"]);
         check(&["{", "abc", "}ab"], &["{", "abc", "}", "ab"]);
     }
+
+    #[test]
+    fn test_text_without_trivia() {
+        let is_ws = |kind: SyntaxKind| kind == SyntaxKind(93);
+        let build = |chunks: &[&str]| {
+            let mut builder = GreenNodeBuilder::new();
+            builder.start_node(SyntaxKind(62));
+            for &chunk in chunks.iter() {
+                let kind = if chunk.trim().is_empty() { 93 } else { 92 };
+                builder.token(SyntaxKind(kind), chunk)
+            }
+            builder.finish_node();
+            SyntaxNode::new_root(builder.finish())
+        };
+        let lhs = build(&[" ", "foo", "(", "  ", ")", "\n"]);
+        let rhs = build(&["fo", "o(", ")"]);
+        assert_eq!(lhs.text().trimmed(), "foo(  )");
+        assert_eq!(lhs.text_trimmed(), "foo(  )");
+        assert_eq!(lhs.text_without(is_ws), "foo()");
+        assert!(lhs.text_without(is_ws) == rhs.text_without(is_ws));
+        assert!(lhs.text_without(is_ws) != build(&["foo", "(", " "]).text_without(is_ws));
+        assert_eq!(build(&["  "]).text_trimmed(), "");
+    }
+
+    #[test]
+    #[cfg(feature = "xxhash")]
+    fn test_hash_with() {
+        let long = "x".repeat(100);
+        let a = build_tree(&["hel", "lo", &long]).text();
+        let b = build_tree(&["h", "ello", &long[..30], &long[30..]]).text();
+        assert_eq!(a.xxh3_hash(), b.xxh3_hash());
+        assert_ne!(a.xxh3_hash(), build_tree(&["hello"]).text().xxh3_hash());
+        assert_eq!(a.xxh3_hash(), xxhash_rust::xxh3::xxh3_64(format!("hello{}", long).as_bytes()));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let text = build_tree(&["SeL", "ect ", "Straße"]).text();
+        assert!(!text.eq_ignore_case("select STRASSE", CaseFolding::Simple));
+        assert!(text.eq_ignore_case("select STRAßE", CaseFolding::Ascii));
+        assert!(text.eq_ignore_case(&build_tree(&["SELECT straße"]).text(), CaseFolding::Ascii));
+        assert!(!text.eq_ignore_case("select", CaseFolding::Ascii));
+        assert!(build_tree(&["ÄB"]).text().eq_ignore_case("äb", CaseFolding::Simple));
+        assert!(!build_tree(&["ÄB"]).text().eq_ignore_case("äb", CaseFolding::Ascii));
+        assert_eq!(text.cmp_ignore_case("SELECT", CaseFolding::Ascii), Ordering::Greater);
+        assert_eq!(text.cmp_ignore_case("SELECTZ", CaseFolding::Ascii), Ordering::Less);
+
+        assert_eq!(text.find_ignore_case("CT s", CaseFolding::Ascii), Some(4.into()));
+        assert_eq!(text.find_ignore_case("STRASSE", CaseFolding::Simple), None);
+        assert_eq!(text.find_ignore_case("", CaseFolding::Ascii), Some(0.into()));
+        assert_eq!(text.find_ignore_case("Text", CaseFolding::Ascii), None);
+        let split = build_tree(&["éaA", "aB", "é"]).text();
+        assert_eq!(split.find_ignore_case("aab", CaseFolding::Ascii), Some(3.into()));
+        assert_eq!(split.find_ignore_case("BÉ", CaseFolding::Simple), Some(5.into()));
+        assert_eq!(split.find_ignore_case("BÉ", CaseFolding::Ascii), None);
+        assert_eq!(
+            text.slice(TextSize::from(2)..).find_ignore_case("l", CaseFolding::Ascii),
+            Some(0.into())
+        );
+    }
+
+    #[test]
+    fn test_visual_column() {
+        let text = build_tree(&["fn f() {\n", "\tx", " =", "\t1;\n", "}"]).text();
+        assert_eq!(text.line_start(3.into()), 0.into());
+        assert_eq!(text.line_start(9.into()), 9.into());
+        assert_eq!(text.line_start(14.into()), 9.into());
+        assert_eq!(text.visual_column(3.into(), 4), 3);
+        assert_eq!(text.visual_column(10.into(), 4), 4);
+        assert_eq!(text.visual_column(14.into(), 4), 8);
+        assert_eq!(text.visual_column(15.into(), 8), 17);
+        assert_eq!(text.visual_column(text.len(), 4), 1);
+
+        let tail = text.slice(TextSize::from(10)..);
+        assert_eq!(tail.line_start(3.into()), 0.into());
+        assert_eq!(tail.visual_column(3.into(), 4), 3);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let crlf = build_tree(&["a\r", "\nb\r\n", "\rc\n"]).text();
+        let lf = build_tree(&["a\nb", "\n\rc\n"]).text();
+        assert!(crlf.eq_ignore_line_endings(&lf));
+        assert!(crlf.eq_ignore_line_endings("a\nb\n\rc\n"));
+        assert!(!crlf.eq_ignore_line_endings("a\nb\nc\n"));
+        assert!(crlf != lf);
+
+        let hash = |text: &SyntaxText| {
+            let mut state = FxHasher::default();
+            text.hash_ignore_line_endings(&mut state);
+            state.finish()
+        };
+        assert_eq!(hash(&crlf), hash(&lf));
+
+        let terminators: Vec<TextRange> = crlf.line_terminators().collect();
+        let expected = [(1, 3), (4, 6), (8, 9)]
+            .iter()
+            .map(|&(s, e)| TextRange::new(s.into(), e.into()))
+            .collect::<Vec<_>>();
+        assert_eq!(terminators, expected);
+    }
+
+    #[test]
+    fn test_lines() {
+        fn check(chunks: &[&str]) {
+            let text = build_tree(chunks).text();
+            let string = text.to_string();
+            let actual: Vec<String> = text.lines().map(|(_, line)| line.to_string()).collect();
+            let expected: Vec<&str> = string.lines().collect();
+            assert_eq!(actual, expected);
+            for (range, line) in text.lines() {
+                assert_eq!(line, string[range]);
+            }
+        }
+        check(&[""]);
+        check(&["\n"]);
+        check(&["a"]);
+        check(&["a\nb"]);
+        check(&["a\r", "\nb\n"]);
+        check(&["fn f() {", "\n  x\n", "\n}"]);
+        check(&["\n\n", "a\r\n"]);
+    }
+
+    #[test]
+    fn test_as_cow() {
+        let node = build_tree(&["hello", " ", "world"]);
+        let text = node.text();
+        assert!(matches!(text.as_cow(), Cow::Owned(it) if it == "hello world"));
+        assert!(matches!(text.slice(TextSize::from(7)..).as_cow(), Cow::Borrowed("orld")));
+        assert!(matches!(
+            text.slice(TextSize::from(5)..TextSize::from(5)).as_cow(),
+            Cow::Borrowed("")
+        ));
+        assert!(
+            matches!(text.slice(TextSize::from(4)..TextSize::from(7)).as_cow(), Cow::Owned(it) if it == "o w")
+        );
+
+        let mutable = node.clone_for_update();
+        assert!(
+            matches!(mutable.text().slice(TextSize::from(6)..).as_cow(), Cow::Owned(it) if it == "world")
+        );
+    }
+
+    #[test]
+    fn test_write_to() {
+        let text = build_tree(&["hello", " ", "world"]).text().slice(TextSize::from(3)..);
+        let mut buf = String::new();
+        text.write_to(&mut buf).unwrap();
+        assert_eq!(buf, "lo world");
+        let mut bytes = Vec::new();
+        text.write_to_io(&mut bytes).unwrap();
+        assert_eq!(bytes, b"lo world");
+    }
+
+    #[test]
+    fn test_covered_element() {
+        let node = build_tree(&["hello", " ", "world"]);
+        let text = node.text();
+        assert_eq!(text.covered_element(), Some(node.clone().into()));
+
+        let world = text.slice(TextSize::from(6)..);
+        let token = world.covered_element().and_then(|it| it.into_token()).unwrap();
+        assert_eq!(token.text(), "world");
+
+        assert!(text.slice(TextSize::from(3)..TextSize::from(8)).covered_element().is_none());
+
+        let copy = SyntaxNode::new_root(node.green().into_owned());
+        assert_eq!(text, copy.text());
+    }
+
+    #[test]
+    fn test_non_utf8_tokens() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(62));
+        builder.token(SyntaxKind(92), " x");
+        builder.token_bytes(SyntaxKind(92), b"\xFFa\xE2\x82b\n");
+        builder.token(SyntaxKind(92), "y ");
+        builder.finish_node();
+        let node = SyntaxNode::new_root(builder.finish());
+        let text = node.text();
+
+        assert_eq!(text.to_string(), " x\u{FFFD}a\u{FFFD}b\ny ");
+        assert_eq!(text.as_cow(), " x\u{FFFD}a\u{FFFD}b\ny ");
+        assert!(text != " x\u{FFFD}a\u{FFFD}b\ny ");
+        assert_eq!(text, text.clone());
+        assert_eq!(text, SyntaxNode::new_root(node.green().into_owned()).text());
+        #[cfg(feature = "xxhash")]
+        assert_ne!(text.xxh3_hash(), build_tree(&[" x\u{FFFD}a\u{FFFD}b\ny "]).text().xxh3_hash());
+
+        let mut bytes = Vec::new();
+        text.write_to_io(&mut bytes).unwrap();
+        assert_eq!(bytes, b" x\xFFa\xE2\x82b\ny ");
+
+        assert_eq!(text.find_char('b'), Some(6.into()));
+        assert_eq!(text.find_char('\u{FFFD}'), Some(2.into()));
+        assert_eq!(text.char_at(2.into()), Some('\u{FFFD}'));
+        assert_eq!(text.char_at(5.into()), Some('\u{FFFD}'));
+        assert_eq!(text.char_at(6.into()), Some('b'));
+        assert_eq!(text.line_start(9.into()), 8.into());
+        assert_eq!(text.trimmed().len(), 8.into());
+        assert!(text.eq_ignore_case(" X\u{FFFD}A\u{FFFD}B\nY ", CaseFolding::Ascii));
+        let filtered = text.without(|_| false);
+        assert_eq!(filtered.to_string(), text.to_string());
+        assert!(filtered != " x\u{FFFD}a\u{FFFD}b\ny ");
+
+        // A sequence cut off by the end of the token.
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(62));
+        builder.token_bytes(SyntaxKind(92), b"a\xE2\x82");
+        builder.finish_node();
+        let node = SyntaxNode::new_root(builder.finish());
+        assert_eq!(node.text().to_string(), "a\u{FFFD}");
+        assert_eq!(node.text().char_at(1.into()), Some('\u{FFFD}'));
+    }
 }
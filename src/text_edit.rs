@@ -0,0 +1,69 @@
+use std::fmt;
+
+use crate::{TextRange, TextSize};
+
+/// A single text replacement: the text in `delete` is replaced with `insert`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TextEdit {
+    pub delete: TextRange,
+    pub insert: String,
+}
+
+impl TextEdit {
+    pub fn replace(range: TextRange, replace_with: impl Into<String>) -> TextEdit {
+        TextEdit { delete: range, insert: replace_with.into() }
+    }
+
+    pub fn insert(offset: TextSize, text: impl Into<String>) -> TextEdit {
+        TextEdit::replace(TextRange::empty(offset), text)
+    }
+
+    pub fn delete(range: TextRange) -> TextEdit {
+        TextEdit::replace(range, String::new())
+    }
+
+    /// Applies the edit to `text`.
+    pub fn apply(&self, text: &mut String) {
+        let range: std::ops::Range<usize> = self.delete.into();
+        text.replace_range(range, &self.insert);
+    }
+
+    /// Applies edits one after another: the range of each edit is interpreted
+    /// relative to the text produced by the previous edits.
+    pub fn apply_all<'a>(edits: impl IntoIterator<Item = &'a TextEdit>, text: &mut String) {
+        edits.into_iter().for_each(|edit| edit.apply(text))
+    }
+
+    /// Maps an offset in the text before the edit to the text after it.
+    ///
+    /// Offsets before the edit stay, offsets after it move by the change in
+    /// length. Offsets in the deleted range, including its ends, go to the
+    /// start or to the end of the inserted text, according to `bias`.
+    pub fn rebase_offset(&self, offset: TextSize, bias: Bias) -> TextSize {
+        if offset < self.delete.start() {
+            return offset;
+        }
+        if offset > self.delete.end() {
+            return offset - self.delete.len() + TextSize::of(self.insert.as_str());
+        }
+        match bias {
+            Bias::Left => self.delete.start(),
+            Bias::Right => self.delete.start() + TextSize::of(self.insert.as_str()),
+        }
+    }
+}
+
+/// Which side of an edit an offset sticks to, see [`TextEdit::rebase_offset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bias {
+    /// The offset stays before text inserted at it.
+    Left,
+    /// The offset moves after text inserted at it.
+    Right,
+}
+
+impl fmt::Display for TextEdit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} => {:?}", self.delete, self.insert)
+    }
+}
@@ -0,0 +1,514 @@
+//! Vendored version of text-size with 64-bit offsets, used instead of
+//! text-size when rowan is built with `RUSTFLAGS="--cfg rowan_large_offsets"`,
+//! for texts larger than 4 GiB.
+//!
+//! This is a cfg rather than a feature because it changes the types of the
+//! API: all crates of a build have to agree on it, so it is up to the final
+//! binary to enable it. The UTF-16 lengths, line numbers and element counts
+//! are widened to `u64` as well, and the green children and the cursors get
+//! a word larger.
+//!
+//! The API is the same as the one of text-size, except that the conversion to
+//! `u32` is fallible and that [`TextSize::new`] and the conversion from
+//! `TextSize` take and return `u64`. Literals still convert from `u32`, so
+//! that `TextSize::from(1)` keeps compiling.
+use std::{
+    cmp::{self, Ordering},
+    convert::TryFrom,
+    fmt, iter,
+    num::TryFromIntError,
+    ops::{Add, AddAssign, Bound, Index, IndexMut, Range, RangeBounds, Sub, SubAssign},
+};
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("`--cfg rowan_large_offsets` requires a 64-bit target");
+
+/// A measure of text length. Also, equivalently, an index into text.
+///
+/// This is a UTF-8 bytes offset stored as `u64`, but most clients should
+/// treat it as an opaque measure.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextSize {
+    raw: u64,
+}
+
+impl fmt::Debug for TextSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl TextSize {
+    /// Creates a new instance of `TextSize` from a raw `u64`.
+    #[inline]
+    pub const fn new(raw: u64) -> TextSize {
+        TextSize { raw }
+    }
+
+    /// The text size of some primitive text-like object.
+    ///
+    /// Accepts `char`, `&str`, and `&String`.
+    #[inline]
+    pub fn of<T: TextLen>(text: T) -> TextSize {
+        text.text_len()
+    }
+
+    /// Checked addition. Returns `None` if overflow occurred.
+    #[inline]
+    pub const fn checked_add(self, rhs: TextSize) -> Option<TextSize> {
+        match self.raw.checked_add(rhs.raw) {
+            Some(raw) => Some(TextSize { raw }),
+            None => None,
+        }
+    }
+
+    /// Checked subtraction. Returns `None` if overflow occurred.
+    #[inline]
+    pub const fn checked_sub(self, rhs: TextSize) -> Option<TextSize> {
+        match self.raw.checked_sub(rhs.raw) {
+            Some(raw) => Some(TextSize { raw }),
+            None => None,
+        }
+    }
+}
+
+impl From<u32> for TextSize {
+    #[inline]
+    fn from(raw: u32) -> Self {
+        TextSize { raw: raw.into() }
+    }
+}
+
+impl TryFrom<TextSize> for u32 {
+    type Error = TryFromIntError;
+    #[inline]
+    fn try_from(value: TextSize) -> Result<Self, TryFromIntError> {
+        u32::try_from(value.raw)
+    }
+}
+
+impl From<TextSize> for u64 {
+    #[inline]
+    fn from(value: TextSize) -> Self {
+        value.raw
+    }
+}
+
+impl TryFrom<usize> for TextSize {
+    type Error = TryFromIntError;
+    #[inline]
+    fn try_from(value: usize) -> Result<Self, TryFromIntError> {
+        Ok(TextSize::new(u64::try_from(value)?))
+    }
+}
+
+impl From<TextSize> for usize {
+    #[inline]
+    fn from(value: TextSize) -> Self {
+        // Lossless, as this is a 64-bit target.
+        value.raw as usize
+    }
+}
+
+macro_rules! ops {
+    (impl $Op:ident for TextSize by fn $f:ident = $op:tt) => {
+        impl $Op<TextSize> for TextSize {
+            type Output = TextSize;
+            #[inline]
+            fn $f(self, other: TextSize) -> TextSize {
+                TextSize { raw: self.raw $op other.raw }
+            }
+        }
+        impl $Op<&TextSize> for TextSize {
+            type Output = TextSize;
+            #[inline]
+            fn $f(self, other: &TextSize) -> TextSize {
+                self $op *other
+            }
+        }
+        impl<T> $Op<T> for &TextSize
+        where
+            TextSize: $Op<T, Output = TextSize>,
+        {
+            type Output = TextSize;
+            #[inline]
+            fn $f(self, other: T) -> TextSize {
+                *self $op other
+            }
+        }
+    };
+}
+
+ops!(impl Add for TextSize by fn add = +);
+ops!(impl Sub for TextSize by fn sub = -);
+
+impl<A> AddAssign<A> for TextSize
+where
+    TextSize: Add<A, Output = TextSize>,
+{
+    #[inline]
+    fn add_assign(&mut self, rhs: A) {
+        *self = *self + rhs
+    }
+}
+
+impl<S> SubAssign<S> for TextSize
+where
+    TextSize: Sub<S, Output = TextSize>,
+{
+    #[inline]
+    fn sub_assign(&mut self, rhs: S) {
+        *self = *self - rhs
+    }
+}
+
+impl<A> iter::Sum<A> for TextSize
+where
+    TextSize: Add<A, Output = TextSize>,
+{
+    #[inline]
+    fn sum<I: Iterator<Item = A>>(iter: I) -> TextSize {
+        iter.fold(TextSize::default(), Add::add)
+    }
+}
+
+use priv_in_pub::Sealed;
+mod priv_in_pub {
+    pub trait Sealed {}
+}
+
+/// Primitives with a textual length that can be passed to [`TextSize::of`].
+pub trait TextLen: Copy + Sealed {
+    /// The textual length of this primitive.
+    fn text_len(self) -> TextSize;
+}
+
+impl Sealed for &'_ str {}
+impl TextLen for &'_ str {
+    #[inline]
+    fn text_len(self) -> TextSize {
+        TextSize::try_from(self.len()).unwrap()
+    }
+}
+
+impl Sealed for &'_ String {}
+impl TextLen for &'_ String {
+    #[inline]
+    fn text_len(self) -> TextSize {
+        self.as_str().text_len()
+    }
+}
+
+impl Sealed for char {}
+impl TextLen for char {
+    #[inline]
+    fn text_len(self) -> TextSize {
+        (self.len_utf8() as u32).into()
+    }
+}
+
+/// A range in text, represented as a pair of [`TextSize`][struct@TextSize].
+///
+/// It is a logic error for `start` to be greater than `end`.
+#[derive(Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct TextRange {
+    // Invariant: start <= end
+    start: TextSize,
+    end: TextSize,
+}
+
+impl fmt::Debug for TextRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start().raw, self.end().raw)
+    }
+}
+
+impl TextRange {
+    /// Creates a new `TextRange` with the given `start` and `end` (`start..end`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `end < start`.
+    #[inline]
+    pub const fn new(start: TextSize, end: TextSize) -> TextRange {
+        assert!(start.raw <= end.raw);
+        TextRange { start, end }
+    }
+
+    /// Create a new `TextRange` with the given `offset` and `len` (`offset..offset + len`).
+    #[inline]
+    pub const fn at(offset: TextSize, len: TextSize) -> TextRange {
+        TextRange::new(offset, TextSize::new(offset.raw + len.raw))
+    }
+
+    /// Create a zero-length range at the specified offset (`offset..offset`).
+    #[inline]
+    pub const fn empty(offset: TextSize) -> TextRange {
+        TextRange { start: offset, end: offset }
+    }
+
+    /// Create a range up to the given end (`..end`).
+    #[inline]
+    pub const fn up_to(end: TextSize) -> TextRange {
+        TextRange { start: TextSize::new(0), end }
+    }
+
+    /// The start point of this range.
+    #[inline]
+    pub const fn start(self) -> TextSize {
+        self.start
+    }
+
+    /// The end point of this range.
+    #[inline]
+    pub const fn end(self) -> TextSize {
+        self.end
+    }
+
+    /// The size of this range.
+    #[inline]
+    pub const fn len(self) -> TextSize {
+        // HACK for const fn: math on primitives only
+        TextSize { raw: self.end().raw - self.start().raw }
+    }
+
+    /// Check if this range is empty.
+    #[inline]
+    pub const fn is_empty(self) -> bool {
+        // HACK for const fn: math on primitives only
+        self.start().raw == self.end().raw
+    }
+
+    /// Check if this range contains an offset.
+    ///
+    /// The end index is considered excluded.
+    #[inline]
+    pub fn contains(self, offset: TextSize) -> bool {
+        self.start() <= offset && offset < self.end()
+    }
+
+    /// Check if this range contains an offset.
+    ///
+    /// The end index is considered included.
+    #[inline]
+    pub fn contains_inclusive(self, offset: TextSize) -> bool {
+        self.start() <= offset && offset <= self.end()
+    }
+
+    /// Check if this range completely contains another range.
+    #[inline]
+    pub fn contains_range(self, other: TextRange) -> bool {
+        self.start() <= other.start() && other.end() <= self.end()
+    }
+
+    /// The range covered by both ranges, if it exists.
+    /// If the ranges touch but do not overlap, the output range is empty.
+    #[inline]
+    pub fn intersect(self, other: TextRange) -> Option<TextRange> {
+        let start = cmp::max(self.start(), other.start());
+        let end = cmp::min(self.end(), other.end());
+        if end < start {
+            return None;
+        }
+        Some(TextRange::new(start, end))
+    }
+
+    /// Extends the range to cover `other` as well.
+    #[inline]
+    pub fn cover(self, other: TextRange) -> TextRange {
+        let start = cmp::min(self.start(), other.start());
+        let end = cmp::max(self.end(), other.end());
+        TextRange::new(start, end)
+    }
+
+    /// Extends the range to cover `offset` as well.
+    #[inline]
+    pub fn cover_offset(self, offset: TextSize) -> TextRange {
+        self.cover(TextRange::empty(offset))
+    }
+
+    /// Add an offset to this range.
+    ///
+    /// Note that this is not appropriate for changing where a `TextRange` is
+    /// within some string; rather, it is for changing the reference anchor
+    /// that the `TextRange` is measured relative to.
+    #[inline]
+    pub fn checked_add(self, offset: TextSize) -> Option<TextRange> {
+        Some(TextRange {
+            start: self.start.checked_add(offset)?,
+            end: self.end.checked_add(offset)?,
+        })
+    }
+
+    /// Subtract an offset from this range.
+    ///
+    /// Note that this is not appropriate for changing where a `TextRange` is
+    /// within some string; rather, it is for changing the reference anchor
+    /// that the `TextRange` is measured relative to.
+    #[inline]
+    pub fn checked_sub(self, offset: TextSize) -> Option<TextRange> {
+        Some(TextRange {
+            start: self.start.checked_sub(offset)?,
+            end: self.end.checked_sub(offset)?,
+        })
+    }
+
+    /// Relative order of the two ranges (overlapping ranges are considered
+    /// equal).
+    #[inline]
+    pub fn ordering(self, other: TextRange) -> Ordering {
+        if self.end() <= other.start() {
+            Ordering::Less
+        } else if other.end() <= self.start() {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+}
+
+impl Index<TextRange> for str {
+    type Output = str;
+    #[inline]
+    fn index(&self, index: TextRange) -> &str {
+        &self[Range::<usize>::from(index)]
+    }
+}
+
+impl Index<TextRange> for String {
+    type Output = str;
+    #[inline]
+    fn index(&self, index: TextRange) -> &str {
+        &self[Range::<usize>::from(index)]
+    }
+}
+
+impl IndexMut<TextRange> for str {
+    #[inline]
+    fn index_mut(&mut self, index: TextRange) -> &mut str {
+        &mut self[Range::<usize>::from(index)]
+    }
+}
+
+impl IndexMut<TextRange> for String {
+    #[inline]
+    fn index_mut(&mut self, index: TextRange) -> &mut str {
+        &mut self[Range::<usize>::from(index)]
+    }
+}
+
+impl RangeBounds<TextSize> for TextRange {
+    fn start_bound(&self) -> Bound<&TextSize> {
+        Bound::Included(&self.start)
+    }
+
+    fn end_bound(&self) -> Bound<&TextSize> {
+        Bound::Excluded(&self.end)
+    }
+}
+
+impl<T> From<TextRange> for Range<T>
+where
+    T: From<TextSize>,
+{
+    #[inline]
+    fn from(r: TextRange) -> Self {
+        r.start().into()..r.end().into()
+    }
+}
+
+macro_rules! range_ops {
+    (impl $Op:ident for TextRange by fn $f:ident = $op:tt) => {
+        impl $Op<&TextSize> for TextRange {
+            type Output = TextRange;
+            #[inline]
+            fn $f(self, other: &TextSize) -> TextRange {
+                self $op *other
+            }
+        }
+        impl<T> $Op<T> for &TextRange
+        where
+            TextRange: $Op<T, Output = TextRange>,
+        {
+            type Output = TextRange;
+            #[inline]
+            fn $f(self, other: T) -> TextRange {
+                *self $op other
+            }
+        }
+    };
+}
+
+impl Add<TextSize> for TextRange {
+    type Output = TextRange;
+    #[inline]
+    fn add(self, offset: TextSize) -> TextRange {
+        self.checked_add(offset).expect("TextRange +offset overflowed")
+    }
+}
+
+impl Sub<TextSize> for TextRange {
+    type Output = TextRange;
+    #[inline]
+    fn sub(self, offset: TextSize) -> TextRange {
+        self.checked_sub(offset).expect("TextRange -offset overflowed")
+    }
+}
+
+range_ops!(impl Add for TextRange by fn add = +);
+range_ops!(impl Sub for TextRange by fn sub = -);
+
+impl<A> AddAssign<A> for TextRange
+where
+    TextRange: Add<A, Output = TextRange>,
+{
+    #[inline]
+    fn add_assign(&mut self, rhs: A) {
+        *self = *self + rhs
+    }
+}
+
+impl<S> SubAssign<S> for TextRange
+where
+    TextRange: Sub<S, Output = TextRange>,
+{
+    #[inline]
+    fn sub_assign(&mut self, rhs: S) {
+        *self = *self - rhs
+    }
+}
+
+#[cfg(feature = "serde1")]
+mod serde_impls {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{TextRange, TextSize};
+
+    impl Serialize for TextSize {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.raw.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for TextSize {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            u64::deserialize(deserializer).map(TextSize::new)
+        }
+    }
+
+    impl Serialize for TextRange {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            (self.start(), self.end()).serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for TextRange {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let (start, end): (TextSize, TextSize) = Deserialize::deserialize(deserializer)?;
+            if start > end {
+                return Err(de::Error::custom(format!("invalid range: {:?}..{:?}", start, end)));
+            }
+            Ok(TextRange::new(start, end))
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Yet another version of owned string, backed by a syntax tree token.
+
+use std::{cmp::Ordering, fmt, ops};
+
+use crate::green::GreenToken;
+
+/// Text of a token, either borrowed or keeping the green token alive.
+///
+/// `TokenText` derefs to `&str`, so APIs can return token text without
+/// copying it and without tying the result to the lifetime of a cursor.
+#[derive(Clone)]
+pub struct TokenText<'a>(pub(crate) Repr<'a>);
+
+#[derive(Clone)]
+pub(crate) enum Repr<'a> {
+    Borrowed(&'a str),
+    Owned(GreenToken),
+}
+
+impl<'a> TokenText<'a> {
+    pub fn borrowed(text: &'a str) -> Self {
+        TokenText(Repr::Borrowed(text))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the text of the token is not valid UTF-8.
+    pub(crate) fn owned(green: GreenToken) -> Self {
+        assert!(green.is_utf8(), "token text is not valid UTF-8: {:?}", green.text_lossy());
+        TokenText(Repr::Owned(green))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            &Repr::Borrowed(it) => it,
+            Repr::Owned(green) => green.text(),
+        }
+    }
+}
+
+impl ops::Deref for TokenText<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+impl AsRef<str> for TokenText<'_> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<TokenText<'_>> for String {
+    fn from(token_text: TokenText) -> Self {
+        token_text.as_str().into()
+    }
+}
+
+impl PartialEq<&'_ str> for TokenText<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+impl PartialEq<TokenText<'_>> for &'_ str {
+    fn eq(&self, other: &TokenText) -> bool {
+        other == self
+    }
+}
+impl PartialEq<String> for TokenText<'_> {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+impl PartialEq<TokenText<'_>> for String {
+    fn eq(&self, other: &TokenText) -> bool {
+        other == self
+    }
+}
+impl PartialEq for TokenText<'_> {
+    fn eq(&self, other: &TokenText) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+impl Eq for TokenText<'_> {}
+impl Ord for TokenText<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+impl PartialOrd for TokenText<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl std::hash::Hash for TokenText<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+impl std::borrow::Borrow<str> for TokenText<'_> {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for TokenText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+impl fmt::Debug for TokenText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{cursor::SyntaxNode, GreenNodeBuilder, SyntaxKind};
+
+    fn tokens(texts: &[&[u8]]) -> SyntaxNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for text in texts {
+            builder.token_bytes(SyntaxKind(1), text);
+        }
+        builder.finish_node();
+        SyntaxNode::new_root(builder.finish())
+    }
+
+    #[test]
+    fn test_token_text() {
+        let root = tokens(&[b"fn", b"main"]);
+        let texts: Vec<TokenText<'static>> =
+            root.children_with_tokens().map(|it| it.into_token().unwrap().token_text()).collect();
+        drop(root);
+        assert_eq!(texts[0], "fn");
+        assert_eq!(texts[1], String::from("main"));
+        assert_eq!(texts[0], TokenText::borrowed("fn"));
+        assert!(texts[0] < texts[1]);
+        assert_eq!(texts[1].len(), 4);
+        assert_eq!(format!("{} {:?}", texts[0], texts[1]), "fn \"main\"");
+
+        let copy = texts[1].clone();
+        assert_eq!(String::from(copy), "main");
+        assert_eq!(TokenText::borrowed("main").clone().as_str(), "main");
+
+        let set: HashSet<TokenText<'static>> = texts.into_iter().collect();
+        assert!(set.contains("main"));
+        assert!(!set.contains("mai"));
+    }
+
+    #[test]
+    fn test_empty_token_text() {
+        let root = tokens(&[b""]);
+        let text = root.first_token().unwrap().token_text();
+        assert_eq!(text, "");
+        assert_eq!(text.clone(), TokenText::borrowed(""));
+    }
+
+    #[test]
+    #[should_panic(expected = "token text is not valid UTF-8")]
+    fn test_token_text_of_bytes() {
+        let root = tokens(&[b"\xFF"]);
+        root.first_token().unwrap().token_text();
+    }
+}
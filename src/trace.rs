@@ -0,0 +1,81 @@
+//! Instrumentation of the potentially expensive operations, for profiling.
+//!
+//! Rowan doesn't depend on a particular tracing library. Instead, a
+//! [`Subscriber`] installed with [`set_subscriber`] receives the spans and
+//! events and can forward them to `tracing`, a flame graph, or a log:
+//!
+//! ```
+//! use std::time::Instant;
+//!
+//! struct Log;
+//! impl rowan::trace::Subscriber for Log {
+//!     fn enter(&self, span: &'static str) {
+//!         eprintln!("{:?} > {}", Instant::now(), span);
+//!     }
+//!     fn exit(&self, span: &'static str) {
+//!         eprintln!("{:?} < {}", Instant::now(), span);
+//!     }
+//! }
+//!
+//! rowan::trace::set_subscriber(&Log).unwrap();
+//! ```
+//!
+//! The spans are named after the function they cover, like
+//! `"NodeCache::gc"`.
+use std::{fmt, sync::OnceLock};
+
+pub trait Subscriber: Sync {
+    fn enter(&self, span: &'static str);
+    fn exit(&self, span: &'static str);
+    /// A point in time with an associated number, like the count of entries
+    /// removed by a gc.
+    fn event(&self, name: &'static str, value: u64) {
+        let _ = (name, value);
+    }
+}
+
+static SUBSCRIBER: OnceLock<&'static dyn Subscriber> = OnceLock::new();
+
+/// Installs the global subscriber. Returns an error if one is already set.
+pub fn set_subscriber(subscriber: &'static dyn Subscriber) -> Result<(), SetSubscriberError> {
+    SUBSCRIBER.set(subscriber).map_err(|_| SetSubscriberError(()))
+}
+
+#[derive(Debug)]
+pub struct SetSubscriberError(());
+
+impl fmt::Display for SetSubscriberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a trace subscriber is already set")
+    }
+}
+
+impl std::error::Error for SetSubscriberError {}
+
+pub(crate) struct Span {
+    name: &'static str,
+}
+
+impl Span {
+    #[inline]
+    pub(crate) fn enter(name: &'static str) -> Option<Span> {
+        let subscriber = SUBSCRIBER.get()?;
+        subscriber.enter(name);
+        Some(Span { name })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(subscriber) = SUBSCRIBER.get() {
+            subscriber.exit(self.name)
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn event(name: &'static str, value: u64) {
+    if let Some(subscriber) = SUBSCRIBER.get() {
+        subscriber.event(name, value)
+    }
+}
@@ -0,0 +1,107 @@
+//! Display column computation by grapheme clusters.
+//!
+//! Grapheme clusters are the extended ones of [UAX #29], and the widths are
+//! the East Asian widths of [UAX #11], as implemented by the
+//! `unicode-segmentation` and `unicode-width` crates.
+//!
+//! [UAX #29]: https://www.unicode.org/reports/tr29/
+//! [UAX #11]: https://www.unicode.org/reports/tr11/
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{SyntaxText, TextSize};
+
+/// What a single column step means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnUnit {
+    /// Each grapheme cluster is one column.
+    Graphemes,
+    /// Each grapheme cluster is one or two columns, according to its East
+    /// Asian width.
+    EastAsianWidth,
+}
+
+impl SyntaxText {
+    /// Returns the display column of `offset` within its line.
+    pub fn display_column(&self, offset: TextSize, unit: ColumnUnit) -> u32 {
+        // Grapheme clusters may span several tokens, so the line is
+        // segmented as a whole.
+        let line = self.slice(self.line_start(offset)..offset).to_string();
+        display_width(&line, unit)
+    }
+}
+
+/// Returns the number of columns `text` occupies, which must not contain
+/// newlines.
+pub fn display_width(text: &str, unit: ColumnUnit) -> u32 {
+    text.graphemes(true)
+        .map(|grapheme| match unit {
+            ColumnUnit::Graphemes => 1,
+            ColumnUnit::EastAsianWidth => grapheme.width().clamp(1, 2) as u32,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(text: &str, graphemes: u32, width: u32) {
+        assert_eq!(display_width(text, ColumnUnit::Graphemes), graphemes, "{:?}", text);
+        assert_eq!(display_width(text, ColumnUnit::EastAsianWidth), width, "{:?}", text);
+    }
+
+    #[test]
+    fn test_display_width() {
+        check("abc", 3, 3);
+        check("e\u{301}", 1, 1);
+        check("\u{65E5}\u{672C}", 2, 4);
+        check("\u{1F469}\u{200D}\u{1F4BB}", 1, 2);
+        // A flag and a lone regional indicator.
+        check("\u{1F1FA}\u{1F1E6}\u{1F1EB}", 2, 3);
+    }
+
+    #[test]
+    fn test_cjk() {
+        check("\u{4F60}\u{597D}!", 3, 5);
+        check("\u{3053}\u{3093}\u{306B}\u{3061}\u{306F}", 5, 10);
+        check("\u{FF21}\u{FF22}", 2, 4);
+        check("\u{20B9F}", 1, 2);
+    }
+
+    #[test]
+    fn test_emoji() {
+        check("\u{1F680}", 1, 2);
+        check("\u{1FA70}", 1, 2);
+        check("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}", 1, 2);
+        check("\u{1F44D}\u{1F3FD}", 1, 2);
+        check("\u{1F3F3}\u{FE0F}\u{200D}\u{1F308}", 1, 2);
+    }
+
+    #[test]
+    fn test_hangul_and_indic() {
+        // Precomposed and conjoining jamo (L V T) spellings of HAN.
+        check("\u{D55C}", 1, 2);
+        check("\u{1112}\u{1161}\u{11AB}", 1, 2);
+        // Consonants with spacing vowel signs, which are one cluster but take
+        // a column of their own: Devanagari KA with AA, Tamil NA with I.
+        check("\u{0915}\u{093E}", 1, 2);
+        check("\u{0BA8}\u{0BBF}", 1, 2);
+    }
+
+    #[test]
+    fn test_display_column() {
+        let mut builder = crate::GreenNodeBuilder::new();
+        builder.start_node(crate::SyntaxKind(0));
+        builder.token(crate::SyntaxKind(1), "x\n\u{65E5}e");
+        builder.token(crate::SyntaxKind(1), "\u{301}!");
+        builder.finish_node();
+        let node = crate::cursor::SyntaxNode::new_root(builder.finish());
+        let text = node.text();
+        let end = text.len();
+        assert_eq!(text.display_column(end, ColumnUnit::Graphemes), 3);
+        assert_eq!(text.display_column(end, ColumnUnit::EastAsianWidth), 4);
+        assert_eq!(text.display_column(1.into(), ColumnUnit::Graphemes), 1);
+        assert_eq!(text.display_column(2.into(), ColumnUnit::Graphemes), 0);
+    }
+}
@@ -1,8 +1,10 @@
+use crate::{TextRange, TextSize};
 use std::{
     fmt,
     ops::{AddAssign, Deref},
 };
-use text_size::TextSize;
+
+use crate::Bias;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum NodeOrToken<N, T> {
@@ -43,8 +45,8 @@ impl<N, T> NodeOrToken<N, T> {
 impl<N: Deref, T: Deref> NodeOrToken<N, T> {
     pub(crate) fn as_deref(&self) -> NodeOrToken<&N::Target, &T::Target> {
         match self {
-            NodeOrToken::Node(node) => NodeOrToken::Node(&*node),
-            NodeOrToken::Token(token) => NodeOrToken::Token(&*token),
+            NodeOrToken::Node(node) => NodeOrToken::Node(node),
+            NodeOrToken::Token(token) => NodeOrToken::Token(token),
         }
     }
 }
@@ -119,6 +121,22 @@ impl<T> TokenAtOffset<T> {
             TokenAtOffset::Between(left, _) => Some(left),
         }
     }
+
+    /// Convert to option, preferring the leaf which is not trivia in case of
+    /// a tie, and the one on the side of `bias` if both or neither are.
+    pub fn pick(self, bias: Bias, is_trivia: impl Fn(&T) -> bool) -> Option<T> {
+        match self {
+            TokenAtOffset::Between(left, right) => match (is_trivia(&left), is_trivia(&right)) {
+                (true, false) => Some(right),
+                (false, true) => Some(left),
+                _ => match bias {
+                    Bias::Left => Some(left),
+                    Bias::Right => Some(right),
+                },
+            },
+            it => it.right_biased(),
+        }
+    }
 }
 
 impl<T> Iterator for TokenAtOffset<T> {
@@ -149,6 +167,48 @@ impl<T> Iterator for TokenAtOffset<T> {
 
 impl<T> ExactSizeIterator for TokenAtOffset<T> {}
 
+/// Origin of a local coordinate system within some text.
+///
+/// A tree parsed from a fragment of a larger text (for example, code embedded
+/// in a doc comment) has offsets starting at zero. `RangeBase` translates
+/// between such local offsets and the offsets in the enclosing text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RangeBase(TextSize);
+
+impl RangeBase {
+    pub fn new(origin: TextSize) -> RangeBase {
+        RangeBase(origin)
+    }
+
+    /// Absolute offset of the local zero.
+    pub fn origin(self) -> TextSize {
+        self.0
+    }
+
+    pub fn to_absolute_offset(self, local: TextSize) -> TextSize {
+        self.0 + local
+    }
+
+    pub fn to_absolute(self, local: TextRange) -> TextRange {
+        local + self.0
+    }
+
+    /// Returns `None` if the offset precedes the origin.
+    pub fn to_local_offset(self, absolute: TextSize) -> Option<TextSize> {
+        absolute.checked_sub(self.0)
+    }
+
+    /// Returns `None` if the range starts before the origin.
+    pub fn to_local(self, absolute: TextRange) -> Option<TextRange> {
+        absolute.checked_sub(self.0)
+    }
+
+    /// Composes two bases: `inner` is interpreted relative to `self`.
+    pub fn join(self, inner: RangeBase) -> RangeBase {
+        RangeBase(self.0 + inner.0)
+    }
+}
+
 macro_rules! _static_assert {
     ($expr:expr) => {
         const _: i32 = 0 / $expr as i32;
@@ -0,0 +1,357 @@
+//! Collecting diagnostics with per-kind validators.
+//!
+//! Languages register checks for the node types they care about, and
+//! [`Validator::validate`] runs all of them in a single traversal of the tree.
+use std::{collections::HashMap, fmt, ptr};
+
+use crate::{ast::AstNode, Language, NodeOrToken, SyntaxKind, SyntaxNode, TextRange, WalkEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Hint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub range: TextRange,
+    pub message: String,
+    pub severity: Severity,
+}
+
+/// Where validators report their findings.
+#[derive(Debug, Default, Clone)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.items.push(diagnostic)
+    }
+
+    pub fn error(&mut self, range: TextRange, message: impl Into<String>) {
+        self.push(Diagnostic { range, message: message.into(), severity: Severity::Error })
+    }
+
+    pub fn warning(&mut self, range: TextRange, message: impl Into<String>) {
+        self.push(Diagnostic { range, message: message.into(), severity: Severity::Warning })
+    }
+
+    pub fn hint(&mut self, range: TextRange, message: impl Into<String>) {
+        self.push(Diagnostic { range, message: message.into(), severity: Severity::Hint })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn into_vec(self) -> Vec<Diagnostic> {
+        self.items
+    }
+}
+
+type KindFilter = Box<dyn Fn(SyntaxKind) -> bool>;
+type Check<L> = Box<dyn Fn(&SyntaxNode<L>, &mut Diagnostics)>;
+type Table = (Vec<usize>, Vec<usize>);
+
+/// A set of checks, keyed by the kinds of nodes they apply to.
+pub struct Validator<L: Language> {
+    checks: Vec<(KindFilter, Check<L>)>,
+    nested: Vec<(KindFilter, Validator<L>)>,
+    version: Option<u32>,
+}
+
+impl<L: Language> Validator<L> {
+    pub fn new() -> Validator<L> {
+        Validator { checks: Vec::new(), nested: Vec::new(), version: None }
+    }
+
+    /// Reports an error for every node and token whose kind is not available
+    /// in the given version of the language, see [`Language::kind_available`].
+    pub fn check_version(&mut self, version: u32) -> &mut Validator<L> {
+        self.version = Some(version);
+        self
+    }
+
+    /// Registers a check for every node which can be cast to `N`.
+    pub fn register<N, F>(&mut self, check: F) -> &mut Validator<L>
+    where
+        N: AstNode<Language = L> + 'static,
+        F: Fn(&N, &mut Diagnostics) + 'static,
+    {
+        let filter = |raw| N::can_cast(L::kind_from_raw(raw));
+        let check = move |node: &SyntaxNode<L>, acc: &mut Diagnostics| {
+            if let Some(node) = N::cast(node.clone()) {
+                check(&node, acc)
+            }
+        };
+        self.checks.push((Box::new(filter), Box::new(check)));
+        self
+    }
+
+    /// Registers a check for every node of the given kind.
+    pub fn register_kind<F>(&mut self, kind: L::Kind, check: F) -> &mut Validator<L>
+    where
+        F: Fn(&SyntaxNode<L>, &mut Diagnostics) + 'static,
+    {
+        let kind = L::kind_to_raw(kind);
+        self.checks.push((Box::new(move |raw| raw == kind), Box::new(check)));
+        self
+    }
+
+    /// Runs the checks of `inner` only for the descendants of nodes which
+    /// can be cast to `N`, in addition to the checks of this validator.
+    ///
+    /// The checks of `inner` run once per node, even if nodes which can be
+    /// cast to `N` are nested in each other. If `inner` checks a version, the
+    /// descendants are checked against it as well.
+    pub fn nest<N>(&mut self, inner: Validator<L>) -> &mut Validator<L>
+    where
+        N: AstNode<Language = L>,
+    {
+        let filter = |raw| N::can_cast(L::kind_from_raw(raw));
+        self.nested.push((Box::new(filter), inner));
+        self
+    }
+
+    /// Adds all the checks of `other` to this validator.
+    pub fn extend(&mut self, other: Validator<L>) -> &mut Validator<L> {
+        self.checks.extend(other.checks);
+        self.nested.extend(other.nested);
+        self.version = self.version.or(other.version);
+        self
+    }
+
+    /// Runs the checks over the subtree of `root`, in preorder.
+    pub fn validate(&self, root: &SyntaxNode<L>) -> Vec<Diagnostic> {
+        trace_span!("Validator::validate");
+        let mut acc = Diagnostics::default();
+        // Indices of the checks and nested validators applicable to a kind,
+        // per validator.
+        let mut tables: HashMap<(*const Validator<L>, SyntaxKind), Table> = HashMap::new();
+        let mut active: Vec<(&Validator<L>, Option<SyntaxNode<L>>)> = vec![(self, None)];
+        for event in root.preorder_with_tokens() {
+            let element = match event {
+                WalkEvent::Enter(element) => element,
+                WalkEvent::Leave(NodeOrToken::Node(node)) => {
+                    while active.last().is_some_and(|(_, scope)| scope.as_ref() == Some(&node)) {
+                        active.pop();
+                    }
+                    continue;
+                }
+                WalkEvent::Leave(NodeOrToken::Token(_)) => continue,
+            };
+            for (idx, &(validator, _)) in active.iter().enumerate() {
+                let version = match validator.version {
+                    Some(it) => it,
+                    None => continue,
+                };
+                // Nested validators may check the same version as the outer ones.
+                if active[..idx].iter().any(|(it, _)| it.version == Some(version)) {
+                    continue;
+                }
+                if !L::kind_available(element.kind(), version) {
+                    let message =
+                        format!("{:?} is not available in version {}", element.kind(), version);
+                    acc.error(element.text_range(), message);
+                }
+            }
+            let node = match element {
+                NodeOrToken::Node(node) => node,
+                NodeOrToken::Token(_) => continue,
+            };
+            let raw = L::kind_to_raw(node.kind());
+            let mut entered = Vec::new();
+            for &(validator, _) in active.iter() {
+                let (checks, nested) =
+                    tables.entry((validator as *const _, raw)).or_insert_with(|| {
+                        let checks = validator.checks.iter().enumerate();
+                        let nested = validator.nested.iter().enumerate();
+                        (
+                            checks.filter(|(_, it)| (it.0)(raw)).map(|(idx, _)| idx).collect(),
+                            nested.filter(|(_, it)| (it.0)(raw)).map(|(idx, _)| idx).collect(),
+                        )
+                    });
+                for &idx in checks.iter() {
+                    (validator.checks[idx].1)(&node, &mut acc);
+                }
+                for &idx in nested.iter() {
+                    let inner = &validator.nested[idx].1;
+                    // With nodes of the same kind nested in each other, the
+                    // inner validator is already running.
+                    let mut running = active.iter().map(|it| it.0).chain(entered.iter().copied());
+                    if !running.any(|it| ptr::eq(it, inner)) {
+                        entered.push(inner);
+                    }
+                }
+            }
+            active.extend(entered.into_iter().map(|it| (it, Some(node.clone()))));
+        }
+        acc.into_vec()
+    }
+}
+
+impl<L: Language> Default for Validator<L> {
+    fn default() -> Validator<L> {
+        Validator::new()
+    }
+}
+
+impl<L: Language> fmt::Debug for Validator<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validator")
+            .field("checks", &self.checks.len())
+            .field("nested", &self.nested.iter().map(|it| &it.1).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GreenNodeBuilder;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    enum Lang {}
+    impl Language for Lang {
+        type Kind = SyntaxKind;
+        fn kind_from_raw(raw: SyntaxKind) -> SyntaxKind {
+            raw
+        }
+        fn kind_to_raw(kind: SyntaxKind) -> SyntaxKind {
+            kind
+        }
+        // `fn` items appear in version 2.
+        fn kind_available(kind: SyntaxKind, version: u32) -> bool {
+            kind != FN || version >= 2
+        }
+    }
+
+    const FN: SyntaxKind = SyntaxKind(0);
+    const BLOCK: SyntaxKind = SyntaxKind(1);
+    const RETURN: SyntaxKind = SyntaxKind(2);
+    const WORD: SyntaxKind = SyntaxKind(3);
+
+    struct Return(SyntaxNode<Lang>);
+    impl AstNode for Return {
+        type Language = Lang;
+        fn can_cast(kind: SyntaxKind) -> bool {
+            kind == RETURN
+        }
+        fn cast(node: SyntaxNode<Lang>) -> Option<Self> {
+            if Self::can_cast(node.kind()) {
+                Some(Return(node))
+            } else {
+                None
+            }
+        }
+        fn syntax(&self) -> &SyntaxNode<Lang> {
+            &self.0
+        }
+    }
+
+    struct FnDef(SyntaxNode<Lang>);
+    impl AstNode for FnDef {
+        type Language = Lang;
+        fn can_cast(kind: SyntaxKind) -> bool {
+            kind == FN
+        }
+        fn cast(node: SyntaxNode<Lang>) -> Option<Self> {
+            if Self::can_cast(node.kind()) {
+                Some(FnDef(node))
+            } else {
+                None
+            }
+        }
+        fn syntax(&self) -> &SyntaxNode<Lang> {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        // block { return fn { return } return }
+        let mut builder = GreenNodeBuilder::new();
+        builder.set_version::<Lang>(1);
+        builder.start_node(BLOCK);
+        builder.start_node(RETURN);
+        builder.token(WORD, "return");
+        builder.finish_node();
+        builder.start_node(FN);
+        builder.start_node(RETURN);
+        builder.token(WORD, "return");
+        builder.finish_node();
+        builder.finish_node();
+        builder.start_node(RETURN);
+        builder.token(WORD, "return");
+        builder.finish_node();
+        builder.finish_node();
+        assert_eq!(builder.unavailable_kinds(), [FN]);
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+
+        let mut in_fn = Validator::new();
+        in_fn.register(|it: &Return, acc| acc.hint(it.syntax().text_range(), "return in fn"));
+        let mut validator = Validator::new();
+        validator
+            .register_kind(BLOCK, |it, acc| acc.warning(it.text_range(), "block"))
+            .register(|it: &Return, acc| acc.error(it.syntax().text_range(), "return"))
+            .nest::<FnDef>(in_fn);
+
+        let diagnostics = validator.validate(&root);
+        let actual: Vec<(usize, &str, Severity)> = diagnostics
+            .iter()
+            .map(|it| (usize::from(it.range.start()), it.message.as_str(), it.severity))
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                (0, "block", Severity::Warning),
+                (0, "return", Severity::Error),
+                (6, "return", Severity::Error),
+                (6, "return in fn", Severity::Hint),
+                (12, "return", Severity::Error),
+            ]
+        );
+
+        let mut validator = Validator::new();
+        validator.check_version(1);
+        let diagnostics = validator.validate(&root);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "SyntaxKind(0) is not available in version 1");
+        assert!(Validator::new().check_version(2).validate(&root).is_empty());
+    }
+
+    #[test]
+    fn test_nested_same_kind() {
+        // fn { fn { return } }
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(FN);
+        builder.token(WORD, "fn");
+        builder.start_node(FN);
+        builder.start_node(RETURN);
+        builder.token(WORD, "return");
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+
+        let mut in_fn = Validator::new();
+        in_fn
+            .check_version(1)
+            .register(|it: &Return, acc| acc.hint(it.syntax().text_range(), "return in fn"));
+        let mut validator = Validator::new();
+        validator.nest::<FnDef>(in_fn);
+
+        let diagnostics = validator.validate(&root);
+        let actual: Vec<(usize, &str)> = diagnostics
+            .iter()
+            .map(|it| (usize::from(it.range.start()), it.message.as_str()))
+            .collect();
+        assert_eq!(
+            actual,
+            [(2, "SyntaxKind(0) is not available in version 1"), (2, "return in fn")]
+        );
+    }
+}
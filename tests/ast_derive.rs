@@ -0,0 +1,83 @@
+#![cfg(feature = "macros")]
+
+use rowan::{ast::AstNode, GreenNodeBuilder, Language};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[repr(u16)]
+enum SyntaxKind {
+    WORD,
+    FN,
+    CLOSURE,
+    STRUCT,
+    ROOT,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Lang {}
+impl Language for Lang {
+    type Kind = SyntaxKind;
+    fn kind_from_raw(raw: rowan::SyntaxKind) -> SyntaxKind {
+        assert!(raw.0 <= SyntaxKind::ROOT as u16);
+        unsafe { std::mem::transmute::<u16, SyntaxKind>(raw.0) }
+    }
+    fn kind_to_raw(kind: SyntaxKind) -> rowan::SyntaxKind {
+        rowan::SyntaxKind(kind as u16)
+    }
+}
+
+type SyntaxNode = rowan::SyntaxNode<Lang>;
+
+#[derive(Debug, AstNode)]
+#[ast(language = Lang, kind = SyntaxKind::FN | SyntaxKind::CLOSURE)]
+struct Callable(SyntaxNode);
+
+#[derive(Debug, AstNode)]
+#[ast(language = Lang, kind = SyntaxKind::STRUCT)]
+pub(crate) struct Struct {
+    syntax: SyntaxNode,
+}
+
+#[derive(Debug, AstNode)]
+#[ast(language = Lang)]
+enum Item {
+    Callable(Callable),
+    /// Doc comments on variants are fine.
+    Struct(Struct),
+}
+
+fn parse() -> SyntaxNode {
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(Lang::kind_to_raw(SyntaxKind::ROOT));
+    for &kind in [SyntaxKind::FN, SyntaxKind::STRUCT, SyntaxKind::CLOSURE].iter() {
+        builder.start_node(Lang::kind_to_raw(kind));
+        builder.token(Lang::kind_to_raw(SyntaxKind::WORD), &format!("{:?}", kind));
+        builder.finish_node();
+    }
+    builder.finish_node();
+    SyntaxNode::new_root(builder.finish())
+}
+
+#[test]
+fn derive_ast_node() {
+    let root = parse();
+    assert!(Callable::cast(root.clone()).is_none());
+    assert!(Item::cast(root.clone()).is_none());
+
+    let items: Vec<Item> = root.children().filter_map(Item::cast).collect();
+    let kinds: Vec<&str> = items
+        .iter()
+        .map(|it| match it {
+            Item::Callable(_) => "callable",
+            Item::Struct(_) => "struct",
+        })
+        .collect();
+    assert_eq!(kinds, ["callable", "struct", "callable"]);
+    assert_eq!(items[2].syntax().text(), "CLOSURE");
+
+    let s = Struct::cast(root.children().nth(1).unwrap()).unwrap();
+    assert_eq!(s.syntax().kind(), SyntaxKind::STRUCT);
+    assert!(matches!(Item::from(s), Item::Struct(_)));
+    assert!(Item::can_cast(SyntaxKind::FN));
+    assert!(!Item::can_cast(SyntaxKind::WORD));
+}
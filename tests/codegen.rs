@@ -0,0 +1,83 @@
+//! Compiles the expected outputs of `rowan::codegen`, so that the generated
+//! code is checked by rustc and clippy, not only compared as text.
+#![cfg(feature = "codegen")]
+#![deny(warnings, clippy::all)]
+
+use rowan::{ast::AstNode, Language};
+
+mod kinds {
+    include!("../src/codegen/test_data/kinds.txt");
+}
+
+use self::kinds::SyntaxKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lang {}
+impl Language for Lang {
+    type Kind = SyntaxKind;
+    fn kind_from_raw(raw: rowan::SyntaxKind) -> SyntaxKind {
+        assert!(raw.0 <= SyntaxKind::BIN_EXPR as u16);
+        unsafe { std::mem::transmute::<u16, SyntaxKind>(raw.0) }
+    }
+    fn kind_to_raw(kind: SyntaxKind) -> rowan::SyntaxKind {
+        rowan::SyntaxKind(kind as u16)
+    }
+}
+
+mod nodes {
+    use super::{Lang, SyntaxKind};
+
+    include!("../src/codegen/test_data/nodes.txt");
+}
+
+mod factory {
+    use super::{nodes::*, Lang, SyntaxKind};
+
+    include!("../src/codegen/test_data/factory.txt");
+}
+
+mod visitor {
+    use super::{nodes::*, Lang};
+
+    include!("../src/codegen/test_data/visitor.txt");
+}
+
+use self::nodes::{BinExpr, Expr, Literal, ParenExpr, Root};
+
+#[test]
+fn generated_code() {
+    let mut f = rowan::ast::SyntaxFactory::<Lang>::new();
+    let one = factory::literal(&mut f, "1");
+    let two = factory::literal(&mut f, "2");
+    let paren = factory::paren_expr(&mut f, one.into());
+    let sum = factory::bin_expr(&mut f, paren.into(), SyntaxKind::PLUS, two.into());
+    let root = factory::root(&mut f, vec![Expr::from(sum)]);
+    assert_eq!(root.syntax().to_string(), "(1)+2");
+
+    let sum = match root.exprs().next().unwrap() {
+        Expr::BinExpr(it) => it,
+        it => panic!("{:?}", it),
+    };
+    assert_eq!(sum.op().unwrap().kind(), SyntaxKind::PLUS);
+    assert_eq!(sum.rhs().unwrap().syntax().to_string(), "2");
+    let paren = ParenExpr::cast(sum.lhs().unwrap().syntax().clone()).unwrap();
+    assert!(paren.l_paren_token().is_some() && paren.r_paren_token().is_some());
+    let one = Literal::cast(paren.expr().unwrap().syntax().clone()).unwrap();
+    assert_eq!(one.number_token().unwrap().text(), "1");
+    assert_eq!(SyntaxKind::BIN_EXPR.name(), "BinExpr");
+    assert_eq!(SyntaxKind::WHITESPACE.name(), "WHITESPACE");
+    let product = factory::bin_expr(&mut f, sum.into(), SyntaxKind::STAR, one.into());
+    assert_eq!(product.op().unwrap().kind(), SyntaxKind::STAR);
+    assert!(BinExpr::cast(root.syntax().clone()).is_none());
+    assert!(Root::cast(root.syntax().clone()).is_some());
+
+    struct Literals(Vec<String>);
+    impl visitor::Visitor for Literals {
+        fn visit_literal(&mut self, node: Literal) {
+            self.0.push(node.syntax().to_string())
+        }
+    }
+    let mut literals = Literals(Vec::new());
+    visitor::Visitor::visit(&mut literals, root.syntax().clone());
+    assert_eq!(literals.0, ["1", "2"]);
+}
@@ -0,0 +1,87 @@
+#![cfg(feature = "macros")]
+
+use rowan::{GreenNodeBuilder, Language};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Language)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[repr(u16)]
+#[language(name = Lang, trivia = WHITESPACE | COMMENT, literal = NUMBER, punct = PLUS, error = ERROR)]
+pub enum SyntaxKind {
+    WHITESPACE,
+    COMMENT,
+    /// Explicit discriminants are respected.
+    NUMBER = 10,
+    PLUS,
+    ERROR,
+    ROOT,
+    BIN_EXPR,
+}
+
+#[test]
+fn derive_language() {
+    assert_eq!(Lang::kind_to_raw(SyntaxKind::ROOT), rowan::SyntaxKind(13));
+    assert_eq!(Lang::kind_from_raw(rowan::SyntaxKind(10)), SyntaxKind::NUMBER);
+    assert_eq!(SyntaxKind::from_raw(2), None);
+    assert_eq!(SyntaxKind::ALL.len(), 7);
+    assert_eq!(SyntaxKind::COMMENT.name(), "COMMENT");
+    assert!(SyntaxKind::COMMENT.is_trivia());
+    assert!(!SyntaxKind::NUMBER.is_trivia());
+    assert!(SyntaxKind::NUMBER.is_literal());
+    assert!(Lang::is_trivia(SyntaxKind::WHITESPACE));
+    assert!(Lang::is_punct(SyntaxKind::PLUS));
+    assert!(!Lang::is_keyword(SyntaxKind::PLUS));
+    assert_eq!(Lang::error_kind(), Some(SyntaxKind::ERROR));
+
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(SyntaxKind::ROOT.into());
+    builder.token(SyntaxKind::NUMBER.into(), "92");
+    builder.finish_node();
+    let root = rowan::SyntaxNode::<Lang>::new_root(builder.finish());
+    assert_eq!(root.kind(), SyntaxKind::ROOT);
+    assert_eq!(root.first_token().unwrap().kind(), SyntaxKind::NUMBER);
+}
+
+#[test]
+fn trivia_hooks() {
+    // ROOT
+    //   BIN_EXPR "1 + " (trailing trivia is left to ROOT)
+    //   " "
+    //   ERROR "+"
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(SyntaxKind::ROOT.into());
+    builder.start_node(SyntaxKind::BIN_EXPR.into());
+    builder.token(SyntaxKind::NUMBER.into(), "1");
+    builder.token(SyntaxKind::WHITESPACE.into(), " ");
+    builder.token(SyntaxKind::PLUS.into(), "+");
+    builder.token(SyntaxKind::WHITESPACE.into(), " ");
+    builder.finish_node_before_trivia::<Lang>();
+    builder.start_node(SyntaxKind::ERROR.into());
+    builder.token(SyntaxKind::PLUS.into(), "+");
+    builder.finish_node();
+    builder.finish_node();
+    let root = rowan::SyntaxNode::<Lang>::new_root(builder.finish());
+
+    let bin_expr = root.first_child().unwrap();
+    assert_eq!(bin_expr.text(), "1 +");
+    assert_eq!(bin_expr.text_without_trivia(), "1+");
+    let number = bin_expr.first_token().unwrap();
+    assert_eq!(number.next_non_trivia_token().unwrap().kind(), SyntaxKind::PLUS);
+    let errors: Vec<SyntaxKind> = root.errors().map(|it| it.kind()).collect();
+    assert_eq!(errors, [SyntaxKind::ERROR]);
+    assert_eq!(
+        root.dump_without_trivia(),
+        "ROOT@0..5
+  BIN_EXPR@0..3
+    NUMBER@0..1 \"1\"
+    PLUS@2..3 \"+\"
+  ERROR@4..5
+    PLUS@4..5 \"+\"
+"
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid SyntaxKind: 3")]
+fn invalid_raw_kind() {
+    Lang::kind_from_raw(rowan::SyntaxKind(3));
+}
@@ -0,0 +1,30 @@
+#![cfg(feature = "stats")]
+
+// The counters are global, so this is the only test of this binary, to not
+// see the cursors of concurrently running tests.
+
+use std::panic;
+
+use rowan::{cursor::SyntaxNode, stats, GreenNodeBuilder, SyntaxKind};
+
+#[test]
+fn assert_no_live_cursors() {
+    stats::enable(true);
+    stats::assert_no_live_cursors();
+
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(SyntaxKind(0));
+    builder.token(SyntaxKind(1), "a");
+    builder.finish_node();
+    let root = SyntaxNode::new_root(builder.finish());
+    let token = root.first_token().unwrap();
+    drop(root);
+
+    // The token keeps its parent alive.
+    let err = panic::catch_unwind(stats::assert_no_live_cursors).unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert_eq!(message, "2 cursors are still alive");
+
+    drop(token);
+    stats::assert_no_live_cursors();
+}
@@ -1,6 +1,6 @@
 use std::env;
 
-use xaction::{cargo_toml, cmd, git, section, Result};
+use xaction::{cargo_toml, cmd, git, pushd, section, Result};
 
 fn main() {
     if let Err(err) = try_main() {
@@ -29,6 +29,16 @@ fn try_main() -> Result<()> {
     {
         let _s = section("TEST");
         cmd!("cargo test --workspace -- --nocapture").run()?;
+        cmd!("cargo test --workspace --all-features -- --nocapture").run()?;
+        cmd!("cargo test --release --lib arc").env("RUSTFLAGS", "--cfg loom").run()?;
+        cmd!("cargo test --workspace --all-features")
+            .env("RUSTFLAGS", "--cfg rowan_large_offsets")
+            .run()?;
+    }
+
+    {
+        let _s = section("LINT");
+        cmd!("cargo clippy --workspace --all-targets --all-features -- -D warnings").run()?;
     }
 
     let version = cargo_toml.version()?;
@@ -40,6 +50,17 @@ fn try_main() -> Result<()> {
 
     {
         let _s = section("PUBLISH");
+        // The `macros` feature of rowan depends on rowan-macros, so it has to
+        // be released first, whenever its version changes.
+        {
+            let _d = pushd("rowan-macros")?;
+            let macros_toml = xaction::cargo_toml()?;
+            let macros_tag = format!("rowan-macros-v{}", macros_toml.version()?);
+            if !git::has_tag(&macros_tag)? {
+                macros_toml.publish()?;
+                git::tag(&macros_tag)?;
+            }
+        }
         cargo_toml.publish()?;
         git::tag(&tag)?;
         git::push_tags()?;